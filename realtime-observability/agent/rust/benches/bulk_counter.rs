@@ -0,0 +1,74 @@
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use telemetry_agent::{Agent, BulkCollect, Config, CounterHandle};
+
+const SHARDS: usize = 1024;
+
+/// `items_processed{shard}` as 1024 family counters
+fn family(agent: &Agent) -> Vec<CounterHandle> {
+    let family = agent.counter_family("items_processed", &["shard"]).unwrap();
+    (0..SHARDS)
+        .map(|shard| family.with(&[&shard.to_string()]))
+        .collect()
+}
+
+fn bench_record(c: &mut Criterion) {
+    let mut group = c.benchmark_group("bulk_counter_record");
+
+    let agent = Agent::new(Config::default());
+    let handles = family(&agent);
+    let mut shard = 0;
+    group.bench_function("family_1024", |b| {
+        b.iter(|| {
+            shard = (shard + 1) % SHARDS;
+            handles[black_box(shard)].inc();
+        });
+    });
+
+    let agent = Agent::new(Config::default());
+    let bulk = agent.bulk_counter("items_processed", "shard", SHARDS, BulkCollect::All);
+    let mut shard = 0;
+    group.bench_function("bulk_1024", |b| {
+        b.iter(|| {
+            shard = (shard + 1) % SHARDS;
+            bulk.inc(black_box(shard));
+        });
+    });
+
+    group.finish();
+}
+
+fn bench_collect(c: &mut Criterion) {
+    let mut group = c.benchmark_group("bulk_counter_collect");
+
+    let agent = Agent::new(Config::default());
+    let handles = family(&agent);
+    group.bench_function("family_1024", |b| {
+        b.iter(|| {
+            handles[0].inc();
+            black_box(agent.collect_now())
+        });
+    });
+
+    for (name, mode) in [
+        ("bulk_1024", BulkCollect::All),
+        ("bulk_1024_changed", BulkCollect::Changed),
+    ] {
+        let agent = Agent::new(Config::default());
+        let bulk = agent.bulk_counter("items_processed", "shard", SHARDS, mode);
+        for shard in 0..SHARDS {
+            bulk.inc(shard);
+        }
+        group.bench_function(name, |b| {
+            b.iter(|| {
+                // One shard busy between collects
+                bulk.inc(0);
+                black_box(agent.collect_now())
+            });
+        });
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, bench_record, bench_collect);
+criterion_main!(benches);
@@ -0,0 +1,36 @@
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use telemetry_agent::{Agent, Config};
+
+fn bench_disabled(c: &mut Criterion) {
+    let mut group = c.benchmark_group("disabled_metric");
+    let agent = Agent::new(Config::default());
+    let hist = agent
+        .histogram_family("debug_parse_ms", &["stage"])
+        .unwrap()
+        .with(&["lex"]);
+
+    group.bench_function("handle_enabled", |b| {
+        b.iter(|| hist.record(black_box(3.0)));
+    });
+
+    group.bench_function("string_enabled", |b| {
+        b.iter(|| agent.record_histogram(black_box("rpc_ms"), black_box(3.0)));
+    });
+
+    agent.set_prefix_enabled("debug_", false);
+    group.bench_function("handle_disabled", |b| {
+        b.iter(|| hist.record(black_box(3.0)));
+    });
+    group.bench_function("string_disabled", |b| {
+        b.iter(|| agent.record_histogram(black_box("debug_io_ms"), black_box(3.0)));
+    });
+    // Another metric's switch is off; this one's is cached with its series
+    group.bench_function("string_enabled_after_other_disabled", |b| {
+        b.iter(|| agent.record_histogram(black_box("rpc_ms"), black_box(3.0)));
+    });
+
+    group.finish();
+}
+
+criterion_group!(benches, bench_disabled);
+criterion_main!(benches);
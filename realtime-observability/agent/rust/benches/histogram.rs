@@ -0,0 +1,38 @@
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion};
+use telemetry_agent::{FixedHistogram, Histogram};
+
+fn bench_record(c: &mut Criterion) {
+    let mut group = c.benchmark_group("histogram_record");
+
+    let cases = [
+        ("default_12", Histogram::new()),
+        ("exponential_160", Histogram::exponential(0.01, 1.1, 160)),
+    ];
+
+    for (name, hist) in cases.iter() {
+        group.bench_with_input(BenchmarkId::from_parameter(name), hist, |b, hist| {
+            let mut value = 0.0;
+            b.iter(|| {
+                value = (value + 7.3) % 12_000.0;
+                hist.record(black_box(value));
+            });
+        });
+    }
+
+    // Same bounds as `default_12`, stored inline
+    let fixed = FixedHistogram::new([
+        1.0, 5.0, 10.0, 25.0, 50.0, 100.0, 250.0, 500.0, 1000.0, 2500.0, 5000.0, 10000.0,
+    ]);
+    group.bench_function(BenchmarkId::from_parameter("fixed_12"), |b| {
+        let mut value = 0.0;
+        b.iter(|| {
+            value = (value + 7.3) % 12_000.0;
+            fixed.record(black_box(value));
+        });
+    });
+
+    group.finish();
+}
+
+criterion_group!(benches, bench_record);
+criterion_main!(benches);
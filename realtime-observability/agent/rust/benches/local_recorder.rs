@@ -0,0 +1,29 @@
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use telemetry_agent::{Agent, Config};
+
+fn bench_hot_loop(c: &mut Criterion) {
+    let mut group = c.benchmark_group("hot_loop_record");
+    let agent = Agent::new(Config::default());
+
+    group.bench_function("agent", |b| {
+        let mut value = 0.0;
+        b.iter(|| {
+            value = (value + 7.3) % 12_000.0;
+            agent.record_histogram("hot_ms", black_box(value));
+        });
+    });
+
+    group.bench_function("local", |b| {
+        let mut local = agent.local();
+        let mut value = 0.0;
+        b.iter(|| {
+            value = (value + 7.3) % 12_000.0;
+            local.record_histogram("hot_ms", black_box(value));
+        });
+    });
+
+    group.finish();
+}
+
+criterion_group!(benches, bench_hot_loop);
+criterion_main!(benches);
@@ -0,0 +1,99 @@
+//! What the recording API costs the calling thread; the numbers quoted in
+//! the rustdoc of `Agent`'s methods come from here. `tests/overhead.rs`
+//! fails on gross regressions without a bench run.
+//!
+//! To compare a change against a saved baseline on one machine:
+//!
+//! ```text
+//! cargo bench --bench overhead -- --save-baseline before
+//! # ...apply the change...
+//! cargo bench --bench overhead -- --baseline before
+//! ```
+
+use criterion::{black_box, criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion};
+use telemetry_agent::{Agent, Config};
+
+fn bench_record(c: &mut Criterion) {
+    let mut group = c.benchmark_group("record");
+
+    // First use of a name: creates the series
+    group.bench_function("inc_counter_cold", |b| {
+        b.iter_batched(
+            || Agent::new(Config::default()),
+            |agent| {
+                agent.inc_counter(black_box("requests_total"));
+                agent
+            },
+            BatchSize::SmallInput,
+        );
+    });
+
+    let agent = Agent::new(Config::default());
+    agent.inc_counter("requests_total");
+    group.bench_function("inc_counter_hot", |b| {
+        b.iter(|| agent.inc_counter(black_box("requests_total")));
+    });
+
+    group.bench_function("set_gauge", |b| {
+        b.iter(|| agent.set_gauge(black_box("queue_depth"), black_box(3.0)));
+    });
+
+    group.bench_function("record_histogram", |b| {
+        b.iter(|| agent.record_histogram(black_box("parse_ms"), black_box(3.0)));
+    });
+
+    // With no tap anywhere, `record_histogram` above pays one relaxed
+    // load; a tap on any name adds a read lock and a lookup to every record
+    {
+        let _elsewhere = agent.tap_histogram("render_ms", 1024);
+        group.bench_function("record_histogram_tapped_elsewhere", |b| {
+            b.iter(|| agent.record_histogram(black_box("parse_ms"), black_box(3.0)));
+        });
+    }
+
+    {
+        let _tap = agent.tap_histogram("parse_ms", 1024);
+        group.bench_function("record_histogram_tapped", |b| {
+            b.iter(|| agent.record_histogram(black_box("parse_ms"), black_box(3.0)));
+        });
+    }
+
+    group.bench_function("track_request", |b| {
+        // The guard is dropped, and its latency recorded, within the timing
+        b.iter(|| black_box(agent.track_request_named("checkout")));
+    });
+
+    group.finish();
+}
+
+fn bench_collect(c: &mut Criterion) {
+    let mut group = c.benchmark_group("collect_now");
+
+    // A third each of counters, gauges and histograms, all recorded into
+    // between collects as a busy service would
+    for metrics in [100, 1_000, 10_000] {
+        let agent = Agent::new(Config::default());
+        let names: Vec<String> = (0..metrics).map(|i| format!("metric_{}", i)).collect();
+        let record = |agent: &Agent| {
+            for (i, name) in names.iter().enumerate() {
+                match i % 3 {
+                    0 => agent.inc_counter(name),
+                    1 => agent.set_gauge(name, i as f64),
+                    _ => agent.record_histogram(name, i as f64),
+                }
+            }
+        };
+        group.bench_with_input(BenchmarkId::from_parameter(metrics), &agent, |b, agent| {
+            b.iter_batched(
+                || record(agent),
+                |()| agent.collect_now(),
+                BatchSize::SmallInput,
+            );
+        });
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, bench_record, bench_collect);
+criterion_main!(benches);
@@ -0,0 +1,74 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Barrier};
+use std::time::{Duration, Instant};
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use telemetry_agent::{Agent, Config};
+
+/// Run `per_thread` increments on each of `threads` threads, timing the
+/// whole run from a common start line
+fn run_threads(threads: usize, per_thread: u64, inc: Arc<dyn Fn() + Send + Sync>) -> Duration {
+    let barrier = Arc::new(Barrier::new(threads + 1));
+    let handles: Vec<_> = (0..threads)
+        .map(|_| {
+            let barrier = barrier.clone();
+            let inc = inc.clone();
+            std::thread::spawn(move || {
+                barrier.wait();
+                for _ in 0..per_thread {
+                    inc();
+                }
+            })
+        })
+        .collect();
+
+    barrier.wait();
+    let start = Instant::now();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+    start.elapsed()
+}
+
+fn bench_contention(c: &mut Criterion) {
+    let mut group = c.benchmark_group("counter_contention");
+    group.sample_size(10);
+
+    for threads in [8usize, 32, 64] {
+        group.bench_with_input(
+            BenchmarkId::new("atomic", threads),
+            &threads,
+            |b, &threads| {
+                let counter = Arc::new(AtomicU64::new(0));
+                b.iter_custom(|iters| {
+                    let counter = counter.clone();
+                    run_threads(
+                        threads,
+                        iters,
+                        Arc::new(move || {
+                            counter.fetch_add(1, Ordering::Relaxed);
+                        }),
+                    )
+                });
+            },
+        );
+
+        group.bench_with_input(
+            BenchmarkId::new("sharded", threads),
+            &threads,
+            |b, &threads| {
+                let agent = Agent::new(Config::default());
+                let handle = agent.sharded_counter("requests_total");
+                b.iter_custom(|iters| {
+                    let handle = handle.clone();
+                    run_threads(threads, iters, Arc::new(move || handle.inc()))
+                });
+            },
+        );
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, bench_contention);
+criterion_main!(benches);
@@ -1,6 +1,10 @@
 fn main() -> Result<(), Box<dyn std::error::Error>> {
+    // The noop build mirrors the proto types by hand and needs no protoc
+    #[cfg(all(feature = "runtime", not(feature = "noop")))]
     tonic_build::configure()
-        .build_server(false)
+        .build_server(true)
+        // Ordered label maps keep encoded batches byte-for-byte stable
+        .btree_map(["."])
         .compile(&["../../proto/telemetry.proto"], &["../../proto"])?;
     Ok(())
 }
@@ -0,0 +1,62 @@
+//! Per-route metrics for an axum app.
+//!
+//! Start the aggregator (`docker-compose up aggregator`) then run:
+//!
+//! ```bash
+//! cargo run --example axum --features axum
+//! curl localhost:3000/users/42
+//! ```
+//!
+//! `noop` has no axum integration, so with it on as well this only says
+//! so.
+
+#[cfg(not(feature = "noop"))]
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    app::main()
+}
+
+#[cfg(feature = "noop")]
+fn main() {
+    eprintln!("telemetry is compiled out with `noop`; run this example without it");
+}
+
+#[cfg(not(feature = "noop"))]
+mod app {
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use axum::extract::Path;
+    use axum::routing::get;
+    use axum::Router;
+    use telemetry_agent::axum::{TelemetryExt, Tracked};
+    use telemetry_agent::{Agent, Config};
+
+    async fn user(Path(id): Path<u32>, _tracked: Tracked) -> String {
+        tokio::time::sleep(Duration::from_millis(5)).await;
+        format!("user {}", id)
+    }
+
+    #[tokio::main]
+    pub async fn main() -> Result<(), Box<dyn std::error::Error>> {
+        let config = Config {
+            service_name: "axum-example".to_string(),
+            aggregator_addr: std::env::var("AGGREGATOR_ADDR")
+                .unwrap_or_else(|_| "http://localhost:9000".to_string()),
+            ..Default::default()
+        };
+
+        let mut agent = Agent::new(config);
+        agent.start().await?;
+
+        let app = Router::new()
+            .route("/", get(|| async { "ok" }))
+            .route("/users/:id", get(user))
+            .layer(TelemetryExt::new(Arc::new(agent)));
+
+        axum::Server::bind(&"127.0.0.1:3000".parse()?)
+            .serve(app.into_make_service())
+            .await?;
+
+        Ok(())
+    }
+}
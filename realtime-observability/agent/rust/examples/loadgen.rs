@@ -0,0 +1,183 @@
+//! Load generator and sizing tool for the agent and aggregator.
+//!
+//! ```bash
+//! cargo run --release --example loadgen -- \
+//!     --endpoint http://agg:9000 --histograms 100 --rate 1000000
+//! ```
+
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use clap::Parser;
+use telemetry_agent::{Agent, Config};
+
+#[derive(Parser, Debug)]
+#[command(about = "Drive a telemetry agent at a fixed record rate")]
+struct Args {
+    /// Aggregator endpoint
+    #[arg(long, default_value = "http://localhost:9000")]
+    endpoint: String,
+
+    /// Service name reported in every batch
+    #[arg(long, default_value = "loadgen")]
+    service: String,
+
+    /// Distinct counters
+    #[arg(long, default_value_t = 10)]
+    counters: usize,
+
+    /// Distinct gauges
+    #[arg(long, default_value_t = 10)]
+    gauges: usize,
+
+    /// Distinct histograms
+    #[arg(long, default_value_t = 10)]
+    histograms: usize,
+
+    /// Label values per metric (series = metrics x cardinality)
+    #[arg(long, default_value_t = 1)]
+    cardinality: usize,
+
+    /// Target records per second across all threads
+    #[arg(long, default_value_t = 100_000)]
+    rate: u64,
+
+    /// Recording threads
+    #[arg(long, default_value_t = 4)]
+    threads: usize,
+
+    /// Run time in seconds
+    #[arg(long, default_value_t = 10)]
+    duration: u64,
+
+    /// Push interval in milliseconds
+    #[arg(long, default_value_t = 20)]
+    push_interval_ms: u64,
+}
+
+/// Pre-built names so the hot loop does not allocate
+struct Workload {
+    counters: Vec<String>,
+    gauges: Vec<String>,
+    histograms: Vec<String>,
+    labels: Vec<String>,
+}
+
+impl Workload {
+    fn new(args: &Args) -> Self {
+        let names = |kind: &str, n: usize| -> Vec<String> {
+            (0..n).map(|i| format!("loadgen_{}_{}", kind, i)).collect()
+        };
+        Self {
+            counters: names("counter", args.counters),
+            gauges: names("gauge", args.gauges),
+            histograms: names("histogram", args.histograms),
+            labels: (0..args.cardinality.max(1))
+                .map(|i| format!("shard-{}", i))
+                .collect(),
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.counters.len() + self.gauges.len() + self.histograms.len()
+    }
+
+    fn record(&self, agent: &Agent, i: usize) {
+        let label = self.labels[i % self.labels.len()].as_str();
+        let labels = [("shard", label)];
+        let mut slot = i % self.len().max(1);
+
+        if slot < self.counters.len() {
+            agent.inc_counter_with(&self.counters[slot], &labels);
+            return;
+        }
+        slot -= self.counters.len();
+        if slot < self.gauges.len() {
+            agent.set_gauge_with(&self.gauges[slot], &labels, i as f64);
+            return;
+        }
+        slot -= self.gauges.len();
+        if slot < self.histograms.len() {
+            agent.record_histogram_with(&self.histograms[slot], &labels, (i % 5000) as f64);
+        }
+    }
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let args = Args::parse();
+
+    let mut agent = Agent::new(Config {
+        aggregator_addr: args.endpoint.clone(),
+        service_name: args.service.clone(),
+        push_interval: Duration::from_millis(args.push_interval_ms),
+        ..Default::default()
+    });
+    agent.start().await?;
+    let agent = Arc::new(agent);
+
+    let workload = Arc::new(Workload::new(&args));
+    let recorded = Arc::new(AtomicU64::new(0));
+    let running = Arc::new(AtomicBool::new(true));
+    let threads = args.threads.max(1);
+    let per_thread = args.rate / threads as u64;
+
+    let started = Instant::now();
+    let handles: Vec<_> = (0..threads)
+        .map(|t| {
+            let agent = agent.clone();
+            let workload = workload.clone();
+            let recorded = recorded.clone();
+            let running = running.clone();
+            std::thread::spawn(move || {
+                // Record in 10ms slices to hold the target rate
+                let slice = Duration::from_millis(10);
+                let per_slice = (per_thread / 100).max(1);
+                let mut i = t;
+                while running.load(Ordering::Relaxed) {
+                    let slice_start = Instant::now();
+                    for _ in 0..per_slice {
+                        workload.record(&agent, i);
+                        i += threads;
+                    }
+                    recorded.fetch_add(per_slice, Ordering::Relaxed);
+                    if let Some(rest) = slice.checked_sub(slice_start.elapsed()) {
+                        std::thread::sleep(rest);
+                    }
+                }
+            })
+        })
+        .collect();
+
+    tokio::time::sleep(Duration::from_secs(args.duration)).await;
+    running.store(false, Ordering::Relaxed);
+    for handle in handles {
+        let _ = handle.join();
+    }
+    // Let the last window go out
+    tokio::time::sleep(Duration::from_millis(args.push_interval_ms * 2)).await;
+
+    let elapsed = started.elapsed().as_secs_f64();
+    let records = recorded.load(Ordering::Relaxed);
+    let diag = agent.diagnostics();
+
+    println!("{:<24} {:>16}", "metric", "value");
+    println!("{:-<41}", "");
+    let rows: [(&str, String); 9] = [
+        ("elapsed_s", format!("{:.2}", elapsed)),
+        ("records", records.to_string()),
+        ("records_per_s", format!("{:.0}", records as f64 / elapsed)),
+        ("batches_sent", diag.batches_sent.to_string()),
+        ("bytes_sent", diag.bytes_sent.to_string()),
+        ("batches_dropped", diag.batches_dropped.to_string()),
+        ("counter_series", diag.counter_series.to_string()),
+        ("gauge_series", diag.gauge_series.to_string()),
+        ("histogram_series", diag.histogram_series.to_string()),
+    ];
+    for (name, value) in rows {
+        println!("{:<24} {:>16}", name, value);
+    }
+
+    Ok(())
+}
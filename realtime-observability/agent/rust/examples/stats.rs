@@ -0,0 +1,68 @@
+//! A `/stats` endpoint computed in-process, without an aggregator.
+//!
+//! ```bash
+//! cargo run --example stats --features axum,serde
+//! curl localhost:3000/users/42
+//! curl localhost:3000/stats
+//! ```
+//!
+//! `noop` has no axum integration, so with it on as well this only says
+//! so.
+
+#[cfg(not(feature = "noop"))]
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    app::main()
+}
+
+#[cfg(feature = "noop")]
+fn main() {
+    eprintln!("telemetry is compiled out with `noop`; run this example without it");
+}
+
+#[cfg(not(feature = "noop"))]
+mod app {
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use axum::extract::{Extension, Path};
+    use axum::routing::get;
+    use axum::{Json, Router};
+    use telemetry_agent::axum::{TelemetryExt, Tracked};
+    use telemetry_agent::{Agent, Config, LocalStats};
+
+    async fn user(Path(id): Path<u32>, _tracked: Tracked) -> String {
+        tokio::time::sleep(Duration::from_millis(5)).await;
+        format!("user {}", id)
+    }
+
+    /// p50/p95/p99, request rate and error ratio per route over the last
+    /// minute
+    async fn stats(Extension(agent): Extension<Arc<Agent>>) -> Json<LocalStats> {
+        Json(agent.local_stats())
+    }
+
+    #[tokio::main]
+    pub async fn main() -> Result<(), Box<dyn std::error::Error>> {
+        let mut agent = Agent::new(Config {
+            service_name: "stats-example".to_string(),
+            aggregator_addr: std::env::var("AGGREGATOR_ADDR")
+                .unwrap_or_else(|_| "http://localhost:9000".to_string()),
+            // Nothing needs to answer: batches are collected either way
+            lazy_connect: true,
+            local_stats: true,
+            ..Default::default()
+        });
+        agent.start().await?;
+
+        let app = Router::new()
+            .route("/users/:id", get(user))
+            .route("/stats", get(stats))
+            .layer(TelemetryExt::new(Arc::new(agent)));
+
+        axum::Server::bind(&"127.0.0.1:3000".parse()?)
+            .serve(app.into_make_service())
+            .await?;
+
+        Ok(())
+    }
+}
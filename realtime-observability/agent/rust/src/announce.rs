@@ -0,0 +1,103 @@
+//! Static metadata announced to the aggregator
+//!
+//! The announce rides as an extra metrics-free batch at the head of a push
+//! stream: on the first push, after any failed push (the channel may have
+//! reconnected), and after `Agent::set_announce_field`.
+
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use parking_lot::Mutex;
+
+use crate::agent::empty_batch;
+use crate::telemetry::{self, Announce, TelemetryBatch};
+use crate::Config;
+
+pub(crate) struct Announcer {
+    fields: Mutex<BTreeMap<String, String>>,
+    pending: AtomicBool,
+}
+
+impl Announcer {
+    pub(crate) fn new(config: &Config) -> Self {
+        let mut fields = BTreeMap::from([
+            (
+                "agent_version".to_string(),
+                telemetry::AGENT_VERSION.to_string(),
+            ),
+            ("os".to_string(), std::env::consts::OS.to_string()),
+            ("arch".to_string(), std::env::consts::ARCH.to_string()),
+            (
+                "push_interval_ms".to_string(),
+                config.push_interval.as_millis().to_string(),
+            ),
+        ]);
+        // User metadata wins over the auto-filled fields
+        fields.extend(config.metadata.clone());
+
+        Self {
+            fields: Mutex::new(fields),
+            pending: AtomicBool::new(true),
+        }
+    }
+
+    pub(crate) fn set(&self, key: &str, value: &str) {
+        self.fields
+            .lock()
+            .insert(key.to_string(), value.to_string());
+        self.mark_pending();
+    }
+
+    #[cfg(feature = "toml")]
+    pub(crate) fn remove(&self, key: &str) {
+        if self.fields.lock().remove(key).is_some() {
+            self.mark_pending();
+        }
+    }
+
+    /// Send the announce again with the next push
+    pub(crate) fn mark_pending(&self) {
+        self.pending.store(true, Ordering::Relaxed);
+    }
+
+    /// The announce batch, if one is due
+    pub(crate) fn take_pending(&self, config: &Config) -> Option<TelemetryBatch> {
+        if !self.pending.swap(false, Ordering::Relaxed) {
+            return None;
+        }
+        let mut batch = TelemetryBatch {
+            announce: Some(Announce {
+                metadata: self.fields.lock().clone(),
+            }),
+            ..empty_batch(config)
+        };
+        if config.checksum_batches {
+            batch.seal();
+        }
+        Some(batch)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pending() {
+        let config = Config {
+            metadata: [("os".to_string(), "custom".to_string())].into(),
+            ..Default::default()
+        };
+        let announcer = Announcer::new(&config);
+
+        let batch = announcer.take_pending(&config).unwrap();
+        let metadata = batch.announce.unwrap().metadata;
+        assert_eq!(metadata["os"], "custom");
+        assert_eq!(metadata["agent_version"], env!("CARGO_PKG_VERSION"));
+        assert!(announcer.take_pending(&config).is_none());
+
+        announcer.set("build_sha", "abc123");
+        let batch = announcer.take_pending(&config).unwrap();
+        assert_eq!(batch.announce.unwrap().metadata["build_sha"], "abc123");
+    }
+}
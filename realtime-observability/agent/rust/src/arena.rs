@@ -0,0 +1,228 @@
+//! Reuse of the last batch's allocations in the next collect
+//!
+//! prost messages own their strings and maps, so a collect that builds
+//! every `Metric` afresh makes a handful of allocations per series: the
+//! name, the label map and each label, the sample vector. Most series are
+//! the same from one collect to the next, so a batch done with goes back
+//! to the arena (the push loop's as gRPC encodes it, `collect_now`'s by
+//! `Agent::recycle`), and the next collect takes each series' metric from
+//! there and only replaces its sample. The batch's metric vector is kept
+//! as well, and sized from the last collect if there is none.
+//!
+//! A metric is found by the hash of its series, which the registry key
+//! hashes to as well (see `series`), and taken only if its name and
+//! labels spell out the key. Metrics no series asked for in a collect are
+//! dropped at its end, so what the arena holds is at most one batch.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use parking_lot::{Mutex, MutexGuard};
+use prost::Message;
+use tonic::codec::{Codec, DecodeBuf, Decoder, EncodeBuf, Encoder};
+use tonic::Status;
+use xxhash_rust::xxh64::xxh64;
+
+use crate::series;
+use crate::telemetry::{Ack, Metric, MetricSample, TelemetryBatch};
+
+#[derive(Default)]
+pub(crate) struct MetricArena {
+    recycled: Mutex<Recycled>,
+}
+
+/// What the arena holds between collects; locked for the whole of one
+#[derive(Default)]
+pub(crate) struct Recycled {
+    /// By `series_hash` of their name and labels
+    metrics: HashMap<u64, Metric>,
+    /// The metric vector of a recycled batch, emptied
+    vec: Vec<Metric>,
+    /// Metrics in the last batch collected
+    last_len: usize,
+}
+
+impl MetricArena {
+    /// Keep `batch`'s metrics for the next collect to reuse
+    pub(crate) fn recycle(&self, mut batch: TelemetryBatch) {
+        let mut recycled = self.recycled.lock();
+        for metric in batch.metrics.drain(..) {
+            // Sent by id; the name and labels went to the definitions
+            if metric.name.is_empty() {
+                continue;
+            }
+            let hash = match metric.series_hash {
+                0 => {
+                    let labels = metric.labels.iter().map(|(k, v)| (k.as_str(), v.as_str()));
+                    series::hash_sorted(&metric.name, labels)
+                }
+                hash => hash,
+            };
+            recycled.metrics.entry(hash).or_insert(metric);
+        }
+        if batch.metrics.capacity() > recycled.vec.capacity() {
+            recycled.vec = batch.metrics;
+        }
+    }
+
+    pub(crate) fn lock(&self) -> MutexGuard<'_, Recycled> {
+        self.recycled.lock()
+    }
+
+    #[cfg(test)]
+    pub(crate) fn recycled(&self) -> usize {
+        self.recycled.lock().metrics.len()
+    }
+}
+
+impl Recycled {
+    /// An empty vector for the batch being collected
+    pub(crate) fn vec(&mut self) -> Vec<Metric> {
+        let mut vec = std::mem::take(&mut self.vec);
+        vec.reserve(self.last_len);
+        vec
+    }
+
+    /// The metric of series `key`, carrying only `sample`: the recycled
+    /// one if there is, otherwise decoded from the key
+    pub(crate) fn metric(&mut self, key: &str, sample: MetricSample) -> Metric {
+        match self.metrics.remove(&xxh64(key.as_bytes(), 0)) {
+            Some(mut metric) if series::matches(key, &metric.name, &metric.labels) => {
+                metric.samples.clear();
+                metric.samples.push(sample);
+                metric.series_id = 0;
+                metric.series_hash = 0;
+                metric
+            }
+            _ => {
+                let (name, labels) = series::decode(key);
+                Metric {
+                    name,
+                    labels,
+                    samples: vec![sample],
+                    series_id: 0,
+                    series_hash: 0,
+                }
+            }
+        }
+    }
+
+    /// The collect is done, with `len` metrics; drop what it didn't take
+    pub(crate) fn finish(&mut self, len: usize) {
+        self.metrics.clear();
+        self.last_len = len;
+    }
+}
+
+/// `ProstCodec` for `StreamTelemetry`, handing each batch to the arena
+/// once encoded instead of dropping it
+#[derive(Clone)]
+pub(crate) struct RecyclingCodec(pub(crate) Arc<MetricArena>);
+
+impl Codec for RecyclingCodec {
+    type Encode = TelemetryBatch;
+    type Decode = Ack;
+    type Encoder = Self;
+    type Decoder = AckDecoder;
+
+    fn encoder(&mut self) -> Self {
+        self.clone()
+    }
+
+    fn decoder(&mut self) -> AckDecoder {
+        AckDecoder
+    }
+}
+
+impl Encoder for RecyclingCodec {
+    type Item = TelemetryBatch;
+    type Error = Status;
+
+    fn encode(&mut self, batch: TelemetryBatch, buf: &mut EncodeBuf<'_>) -> Result<(), Status> {
+        batch
+            .encode(buf)
+            .expect("EncodeBuf grows to fit the message");
+        self.0.recycle(batch);
+        Ok(())
+    }
+}
+
+pub(crate) struct AckDecoder;
+
+impl Decoder for AckDecoder {
+    type Item = Ack;
+    type Error = Status;
+
+    fn decode(&mut self, buf: &mut DecodeBuf<'_>) -> Result<Option<Ack>, Status> {
+        Ack::decode(buf)
+            .map(Some)
+            .map_err(|e| Status::internal(e.to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Agent, Config};
+
+    fn record(agent: &Agent, round: u64) {
+        for route in ["/users", "/orders", "/a,b=c}"] {
+            agent.add_counter(&format!("plain_{}", &route[1..2]), round);
+            agent.inc_counter_with("requests_total", &[("route", route), ("method", "GET")]);
+            agent.set_gauge_with("queue_depth", &[("queue", route)], round as f64);
+            agent.record_histogram_with("latency_ms", &[("route", route)], round as f64);
+        }
+    }
+
+    #[test]
+    fn test_recycled_metrics_are_reused_unchanged() {
+        let agent = Agent::new(Config::default());
+        let fresh = Agent::new(Config::default());
+        let mut previous: Option<Vec<*const u8>> = None;
+        for round in 1..=3 {
+            record(&agent, round);
+            record(&fresh, round);
+            let batch = agent.collect_now();
+            let mut expected = fresh.collect_now();
+            // Only timestamps differ between the agents
+            for (metric, expected) in batch.metrics.iter().zip(&mut expected.metrics) {
+                expected.samples[0].timestamp_ns = metric.samples[0].timestamp_ns;
+            }
+            assert_eq!(batch.metrics, expected.metrics);
+
+            // Same series, same buffers; `inflight` is built afresh
+            let names: Vec<_> = batch.metrics.iter().map(|m| m.name.as_ptr()).collect();
+            if let Some(previous) = previous {
+                let reused = names.iter().zip(&previous).filter(|(a, b)| a == b);
+                assert_eq!(reused.count(), names.len() - 2);
+            }
+            previous = Some(names);
+            agent.recycle(batch);
+        }
+    }
+
+    #[test]
+    fn test_unclaimed_and_interned_metrics_are_dropped() {
+        let arena = MetricArena::default();
+        let metric = |name: &str| Metric {
+            name: name.to_string(),
+            samples: vec![MetricSample::default()],
+            ..Default::default()
+        };
+        arena.recycle(TelemetryBatch {
+            metrics: vec![metric("up"), metric("gone"), metric("")],
+            ..Default::default()
+        });
+        let mut recycled = arena.lock();
+        assert_eq!(recycled.metrics.len(), 2);
+        assert!(recycled.vec.capacity() >= 3);
+
+        let up = recycled.metric("up", MetricSample::default());
+        assert_eq!(up.name, "up");
+        recycled.finish(1);
+        assert!(recycled.metrics.is_empty());
+        assert!(recycled.vec().capacity() >= 3);
+        // The vector went to the collect; the next is sized from it
+        assert!(recycled.vec().capacity() >= 1);
+    }
+}
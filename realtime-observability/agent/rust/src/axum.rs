@@ -0,0 +1,285 @@
+//! axum integration: per-route latency and status-code counters
+//!
+//! Add `.layer(TelemetryExt::new(agent))` to a `Router` and every matched
+//! route records its latency into `http_request_latency{route}` (split by
+//! `outcome`, with 5xx counted as errors) and counts its response in
+//! `http_responses_total{status}`. The layer also inserts the
+//! `Arc<Agent>` into request extensions so handlers can use `Tracked` or
+//! `Extension<Arc<Agent>>` directly.
+
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{ready, Context, Poll};
+
+use ::axum::async_trait;
+use ::axum::extract::{FromRequestParts, MatchedPath};
+use ::axum::http::request::Parts;
+use ::axum::http::{HeaderMap, Request, Response, StatusCode};
+use http_body::{Body, SizeHint};
+use pin_project_lite::pin_project;
+use tower::{Layer, Service};
+
+use crate::{name, Agent, RequestGuard};
+
+/// Route label used when no route matched (404s, fallbacks)
+const UNMATCHED_ROUTE: &str = "unmatched";
+
+fn route_of(extensions: &::axum::http::Extensions) -> String {
+    extensions
+        .get::<MatchedPath>()
+        .map(|path| path.as_str().to_string())
+        .unwrap_or_else(|| UNMATCHED_ROUTE.to_string())
+}
+
+/// Layer that tracks every request passing through a `Router`
+#[derive(Clone)]
+pub struct TelemetryExt {
+    agent: Arc<Agent>,
+    measure_body: bool,
+}
+
+impl TelemetryExt {
+    pub fn new(agent: Arc<Agent>) -> Self {
+        Self {
+            agent,
+            measure_body: false,
+        }
+    }
+
+    /// Measure latency to the last body byte instead of the response head.
+    /// Useful for streaming responses.
+    pub fn measure_body(mut self, enabled: bool) -> Self {
+        self.measure_body = enabled;
+        self
+    }
+}
+
+impl<S> Layer<S> for TelemetryExt {
+    type Service = TelemetryService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        TelemetryService {
+            inner,
+            agent: self.agent.clone(),
+            measure_body: self.measure_body,
+        }
+    }
+}
+
+/// Service produced by `TelemetryExt`
+#[derive(Clone)]
+pub struct TelemetryService<S> {
+    inner: S,
+    agent: Arc<Agent>,
+    measure_body: bool,
+}
+
+impl<S, ReqBody, ResBody> Service<Request<ReqBody>> for TelemetryService<S>
+where
+    S: Service<Request<ReqBody>, Response = Response<ResBody>> + Clone + Send + 'static,
+    S::Future: Send + 'static,
+    ReqBody: Send + 'static,
+{
+    type Response = Response<TrackedBody<ResBody>>;
+    type Error = S::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, mut req: Request<ReqBody>) -> Self::Future {
+        let route = route_of(req.extensions());
+        req.extensions_mut().insert(self.agent.clone());
+
+        let mut guard = self
+            .agent
+            .track_request_with(name!("http_request_latency"), &[("route", &route)]);
+        let agent = self.agent.clone();
+        let measure_body = self.measure_body;
+
+        // The ready inner service is the one we were polled with; leave a
+        // fresh clone behind for the next call.
+        let clone = self.inner.clone();
+        let mut inner = std::mem::replace(&mut self.inner, clone);
+        let fut = inner.call(req);
+
+        Box::pin(async move {
+            let res = fut.await?;
+            agent.inc_counter_with(
+                name!("http_responses_total"),
+                &[("status", res.status().as_str())],
+            );
+            if res.status().is_server_error() {
+                guard.fail();
+            }
+
+            let guard = if measure_body { Some(guard) } else { None };
+            Ok(res.map(|inner| TrackedBody { inner, guard }))
+        })
+    }
+}
+
+pin_project! {
+    /// Response body that holds the request guard until the last byte
+    pub struct TrackedBody<B> {
+        #[pin]
+        inner: B,
+        guard: Option<RequestGuard>,
+    }
+}
+
+impl<B: Body> Body for TrackedBody<B> {
+    type Data = B::Data;
+    type Error = B::Error;
+
+    fn poll_data(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Self::Data, Self::Error>>> {
+        let this = self.project();
+        let chunk = ready!(this.inner.poll_data(cx));
+        if chunk.is_none() {
+            this.guard.take();
+        }
+        Poll::Ready(chunk)
+    }
+
+    fn poll_trailers(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<Option<HeaderMap>, Self::Error>> {
+        self.project().inner.poll_trailers(cx)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+/// Extractor timing the handler body into `handler_latency{route}`.
+///
+/// Requires `TelemetryExt` on the router. Does not touch the inflight
+/// gauge, which the layer already maintains.
+pub struct Tracked(pub RequestGuard);
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for Tracked {
+    type Rejection = (StatusCode, &'static str);
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        let agent = parts.extensions.get::<Arc<Agent>>().ok_or((
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "TelemetryExt layer is not installed",
+        ))?;
+        let route = route_of(&parts.extensions);
+        Ok(Tracked(agent.start_timer_with(
+            name!("handler_latency"),
+            &[("route", &route)],
+        )))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ::axum::body::Body;
+    use ::axum::extract::Path;
+    use ::axum::routing::get;
+    use ::axum::Router;
+    use tower::ServiceExt;
+
+    use super::*;
+    use crate::telemetry::{metric_sample, TelemetryBatch};
+    use crate::Config;
+
+    async fn user(Path(id): Path<u32>, _tracked: Tracked) -> String {
+        format!("user {}", id)
+    }
+
+    /// The counter series `name` with `status`
+    fn responses(batch: &TelemetryBatch, status: &str) -> Option<u64> {
+        let metric = batch.metrics.iter().find(|m| {
+            m.name == "http_responses_total"
+                && m.labels.get("status").map(String::as_str) == Some(status)
+        })?;
+        match metric.samples[0].value {
+            Some(metric_sample::Value::Counter(value)) => Some(value),
+            ref other => panic!("http_responses_total is {:?}", other),
+        }
+    }
+
+    /// Records in the histogram series `name` with `route` and `outcome`
+    fn latencies(batch: &TelemetryBatch, name: &str, route: &str, outcome: &str) -> u64 {
+        batch
+            .metrics
+            .iter()
+            .filter(|m| {
+                m.name == name
+                    && m.labels.get("route").map(String::as_str) == Some(route)
+                    && m.labels.get("outcome").map(String::as_str) == Some(outcome)
+            })
+            .map(|m| match &m.samples[0].value {
+                Some(metric_sample::Value::Histogram(hist)) => hist.counts.iter().sum::<u64>(),
+                other => panic!("{} is {:?}", name, other),
+            })
+            .sum()
+    }
+
+    async fn get_status(app: &Router, uri: &str) -> StatusCode {
+        let request = Request::get(uri).body(Body::empty()).unwrap();
+        app.clone().oneshot(request).await.unwrap().status()
+    }
+
+    #[tokio::test]
+    async fn test_routes_are_counted_and_timed() {
+        let agent = Arc::new(Agent::new(Config::default()));
+        let app = Router::new()
+            .route("/users/:id", get(user))
+            .route(
+                "/boom",
+                get(|| async { (StatusCode::INTERNAL_SERVER_ERROR, "boom") }),
+            )
+            .layer(TelemetryExt::new(agent.clone()));
+
+        assert_eq!(get_status(&app, "/users/1").await, StatusCode::OK);
+        assert_eq!(get_status(&app, "/users/2").await, StatusCode::OK);
+        assert_eq!(
+            get_status(&app, "/boom").await,
+            StatusCode::INTERNAL_SERVER_ERROR
+        );
+
+        let batch = agent.collect_now();
+        assert_eq!(responses(&batch, "200"), Some(2));
+        assert_eq!(responses(&batch, "500"), Some(1));
+        // One series per route template, not per path, under one name
+        let latency = "http_request_latency";
+        assert_eq!(latencies(&batch, latency, "/users/:id", "success"), 2);
+        assert_eq!(latencies(&batch, latency, "/users/:id", "error"), 0);
+        assert_eq!(latencies(&batch, latency, "/boom", "error"), 1);
+        assert!(!batch.metrics.iter().any(|m| m.name.contains(':')));
+        // Only the handler taking `Tracked` is timed
+        assert_eq!(
+            latencies(&batch, "handler_latency", "/users/:id", "success"),
+            2
+        );
+        assert!(!batch.metrics.iter().any(|m| {
+            m.name == "handler_latency"
+                && m.labels.get("route").map(String::as_str) == Some("/boom")
+        }));
+        assert_eq!(agent.inflight(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_tracked_without_the_layer_is_rejected() {
+        let app: Router = Router::new().route("/users/:id", get(user));
+        assert_eq!(
+            get_status(&app, "/users/1").await,
+            StatusCode::INTERNAL_SERVER_ERROR
+        );
+    }
+}
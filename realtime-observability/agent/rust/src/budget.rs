@@ -0,0 +1,95 @@
+//! A time budget for collecting a batch (`Config::max_collect_budget`)
+//!
+//! Collecting and encoding a batch is timed against a share of the push
+//! interval. A cycle over that share is counted in
+//! `agent_collect_budget_exceeded_total`, and the next `LEAN_CYCLES`
+//! cycles leave out series that haven't changed since the last collect:
+//! `Last` gauges not set since, and counters that didn't move. Their
+//! values are still current at the aggregator, so nothing is lost, and the
+//! cycle after those is collected whole again to see whether it fits.
+
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::time::Duration;
+
+/// Cycles that skip unchanged series after one overruns the budget
+pub(crate) const LEAN_CYCLES: u32 = 8;
+
+pub(crate) struct CollectBudget {
+    /// Share of the push interval collecting may take
+    fraction: Option<f64>,
+    /// Lean cycles left
+    lean: AtomicU32,
+}
+
+impl CollectBudget {
+    pub(crate) fn new(fraction: Option<f64>) -> Self {
+        Self {
+            fraction,
+            lean: AtomicU32::new(0),
+        }
+    }
+
+    /// Whether the cycle starting now skips unchanged series; counts it
+    /// against the lean cycles left
+    pub(crate) fn start_cycle(&self) -> bool {
+        self.lean
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |left| {
+                left.checked_sub(1)
+            })
+            .is_ok()
+    }
+
+    /// Time one collect against `interval`; returns whether it was over
+    /// budget, in which case the next cycles are lean
+    pub(crate) fn on_collect(&self, elapsed: Duration, interval: Duration) -> bool {
+        let Some(fraction) = self.fraction else {
+            return false;
+        };
+        // Pooled agents collect on the pool's schedule, not their own
+        if interval.is_zero() || elapsed <= interval.mul_f64(fraction) {
+            return false;
+        }
+        self.lean.store(LEAN_CYCLES, Ordering::Relaxed);
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const INTERVAL: Duration = Duration::from_millis(10);
+
+    #[test]
+    fn test_overrun_makes_the_next_cycles_lean() {
+        let budget = CollectBudget::new(Some(0.2));
+        assert!(!budget.start_cycle());
+        assert!(!budget.on_collect(Duration::from_millis(2), INTERVAL));
+        assert!(!budget.start_cycle());
+        assert!(budget.on_collect(Duration::from_millis(3), INTERVAL));
+        for _ in 0..LEAN_CYCLES {
+            assert!(budget.start_cycle());
+            assert!(!budget.on_collect(Duration::from_micros(100), INTERVAL));
+        }
+        // Whole again, to measure
+        assert!(!budget.start_cycle());
+    }
+
+    #[test]
+    fn test_overrun_while_lean_stays_lean() {
+        let budget = CollectBudget::new(Some(0.2));
+        budget.on_collect(INTERVAL, INTERVAL);
+        assert!(budget.start_cycle());
+        assert!(budget.on_collect(INTERVAL, INTERVAL));
+        assert_eq!(budget.lean.load(Ordering::Relaxed), LEAN_CYCLES);
+    }
+
+    #[test]
+    fn test_no_budget() {
+        let budget = CollectBudget::new(None);
+        assert!(!budget.on_collect(INTERVAL * 10, INTERVAL));
+        assert!(!budget.start_cycle());
+        let pooled = CollectBudget::new(Some(0.2));
+        assert!(!pooled.on_collect(INTERVAL, Duration::ZERO));
+    }
+}
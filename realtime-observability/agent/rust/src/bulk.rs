@@ -0,0 +1,203 @@
+//! Bulk counters: one counter per index, in one array
+//!
+//! Counting per shard, per partition or per worker with `counter_family`
+//! makes every index a registry entry, and each collect walks the map and
+//! decodes every key. A bulk counter is registered once under a name and a
+//! label, `items_processed{shard}`, and keeps a contiguous slice of atomics
+//! indexed by the label's value. `inc(idx)` is a bounds check and one
+//! `fetch_add`; a collect reads the slice in order and builds the series
+//! from label values formatted at registration.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+use parking_lot::Mutex;
+
+use crate::switches::Switch;
+use crate::telemetry::metric_sample::Value;
+use crate::telemetry::{Metric, MetricSample};
+
+/// Which indexes of a bulk counter a collect sends
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum BulkCollect {
+    /// Every index, every collect
+    #[default]
+    All,
+    /// Only indexes counted since the last collect, for counters where
+    /// most indexes are idle at any one time
+    Changed,
+}
+
+pub(crate) struct BulkCounter {
+    label: String,
+    /// `0..len` as label values
+    values: Box<[String]>,
+    counts: Box<[AtomicU64]>,
+    /// Totals as of the last collect
+    collected: Mutex<Box<[u64]>>,
+    mode: BulkCollect,
+}
+
+impl BulkCounter {
+    pub(crate) fn new(label: &str, len: usize, mode: BulkCollect) -> Self {
+        Self {
+            label: label.to_string(),
+            values: (0..len).map(|i| i.to_string()).collect(),
+            counts: (0..len).map(|_| AtomicU64::new(0)).collect(),
+            collected: Mutex::new(vec![0; len].into_boxed_slice()),
+            mode,
+        }
+    }
+
+    #[inline]
+    pub(crate) fn add(&self, idx: usize, n: u64) {
+        self.counts[idx].fetch_add(n, Ordering::Relaxed);
+    }
+
+    pub(crate) fn get(&self, idx: usize) -> u64 {
+        self.counts[idx].load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.counts.len()
+    }
+
+    /// Bytes allocated for the counts, totals and label values
+    pub(crate) fn heap_bytes(&self) -> usize {
+        self.label.capacity()
+            + self.len() * (2 * std::mem::size_of::<u64>() + std::mem::size_of::<String>())
+            + self.values.iter().map(String::capacity).sum::<usize>()
+    }
+
+    /// Append a series per index to `metrics`, leaving out those not
+    /// counted since the last collect in `Changed` mode or if
+    /// `skip_unchanged`
+    pub(crate) fn collect(
+        &self,
+        name: &str,
+        now: u64,
+        skip_unchanged: bool,
+        metrics: &mut Vec<Metric>,
+    ) {
+        let changed_only = skip_unchanged || self.mode == BulkCollect::Changed;
+        let mut collected = self.collected.lock();
+        for (idx, count) in self.counts.iter().enumerate() {
+            let total = count.load(Ordering::Relaxed);
+            let previous = std::mem::replace(&mut collected[idx], total);
+            if changed_only && total == previous {
+                continue;
+            }
+            metrics.push(Metric {
+                name: name.to_string(),
+                labels: [(self.label.clone(), self.values[idx].clone())].into(),
+                samples: vec![MetricSample {
+                    timestamp_ns: now,
+                    window_start_ns: 0,
+                    value: Some(Value::Counter(total)),
+                }],
+                series_id: 0,
+                series_hash: 0,
+            });
+        }
+    }
+}
+
+/// Handle to a bulk counter, from `Agent::bulk_counter`
+#[derive(Clone)]
+pub struct BulkCounterHandle {
+    pub(crate) counter: Arc<BulkCounter>,
+    pub(crate) enabled: Switch,
+}
+
+impl BulkCounterHandle {
+    /// Count one at index `idx`
+    ///
+    /// # Panics
+    ///
+    /// If `idx` is not below `len()`.
+    #[inline]
+    pub fn inc(&self, idx: usize) {
+        self.add(idx, 1);
+    }
+
+    /// # Panics
+    ///
+    /// If `idx` is not below `len()`.
+    #[inline]
+    pub fn add(&self, idx: usize, n: u64) {
+        if !self.enabled.is_on() {
+            return;
+        }
+        self.counter.add(idx, n);
+    }
+
+    /// Current total at index `idx`
+    ///
+    /// # Panics
+    ///
+    /// If `idx` is not below `len()`.
+    pub fn get(&self, idx: usize) -> u64 {
+        self.counter.get(idx)
+    }
+
+    /// Number of indexes
+    pub fn len(&self) -> usize {
+        self.counter.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.counter.len() == 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn collect(counter: &BulkCounter, skip_unchanged: bool) -> Vec<(String, u64)> {
+        let mut metrics = Vec::new();
+        counter.collect("items_total", 1, skip_unchanged, &mut metrics);
+        metrics
+            .into_iter()
+            .map(|m| {
+                let total = match m.samples[0].value {
+                    Some(Value::Counter(total)) => total,
+                    ref other => panic!("{:?}", other),
+                };
+                (m.labels["shard"].clone(), total)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_every_index_is_a_series() {
+        let counter = BulkCounter::new("shard", 3, BulkCollect::All);
+        counter.add(2, 5);
+        counter.add(0, 1);
+        let all = [
+            ("0".to_string(), 1),
+            ("1".to_string(), 0),
+            ("2".to_string(), 5),
+        ];
+        assert_eq!(collect(&counter, false), all);
+        assert_eq!(collect(&counter, false), all);
+        // Budgeted collects send only what changed, as for other counters
+        counter.add(1, 1);
+        assert_eq!(collect(&counter, true), [("1".to_string(), 1)]);
+    }
+
+    #[test]
+    fn test_changed_mode_sends_only_counted_indexes() {
+        let counter = BulkCounter::new("shard", 1024, BulkCollect::Changed);
+        assert!(collect(&counter, false).is_empty());
+        counter.add(7, 2);
+        counter.add(1000, 1);
+        assert_eq!(
+            collect(&counter, false),
+            [("7".to_string(), 2), ("1000".to_string(), 1)]
+        );
+        assert!(collect(&counter, false).is_empty());
+        counter.add(7, 1);
+        assert_eq!(collect(&counter, false), [("7".to_string(), 3)]);
+    }
+}
@@ -0,0 +1,147 @@
+//! Hit, miss and eviction counts for a cache (`Agent::cache`)
+//!
+//! `agent.cache("session_cache")` maintains `cache_hits_total`,
+//! `cache_misses_total` and `cache_evictions_total` counters and a
+//! `cache_size` gauge, all labelled `cache="session_cache"`, so every
+//! cache in a fleet is named alike and its hit ratio can be computed from
+//! the summed counters.
+//!
+//! With `Config::cache_hit_ratio`, each push also carries a
+//! `cache_hit_ratio{cache}` gauge: hits over lookups since the previous
+//! push, in this instance only. Averaging it across instances weighs a
+//! quiet instance like a busy one; use the counters for the fleet-wide
+//! ratio. A window without lookups sends no ratio at all rather than 0 or
+//! 1.
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+use parking_lot::Mutex;
+
+use crate::agent::GaugeRegistry;
+use crate::{CounterHandle, GaugeHandle};
+
+/// Handle from `Agent::cache`. Clones, and further `Agent::cache` calls
+/// for the same name, share the series.
+#[derive(Clone)]
+pub struct CacheHandle {
+    pub(crate) hits: CounterHandle,
+    pub(crate) misses: CounterHandle,
+    pub(crate) evictions: CounterHandle,
+    pub(crate) size: GaugeHandle,
+    /// Set with `Config::cache_hit_ratio`
+    pub(crate) lookups: Option<Arc<Lookups>>,
+}
+
+impl CacheHandle {
+    #[inline]
+    pub fn hit(&self) {
+        self.hits.inc();
+        if let Some(lookups) = &self.lookups {
+            lookups.hits.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    #[inline]
+    pub fn miss(&self) {
+        self.misses.inc();
+        if let Some(lookups) = &self.lookups {
+            lookups.misses.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    #[inline]
+    pub fn eviction(&self) {
+        self.evictions.inc();
+    }
+
+    /// Entries in the cache now
+    #[inline]
+    pub fn size(&self, entries: u64) {
+        self.size.set(entries as f64);
+    }
+}
+
+/// Hits and misses since the last push
+#[derive(Default)]
+pub(crate) struct Lookups {
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl Lookups {
+    /// Hits over lookups since the last call; `None` without lookups
+    fn take_ratio(&self) -> Option<f64> {
+        // A lookup landing between the two swaps counts in the next window
+        let hits = self.hits.swap(0, Ordering::Relaxed);
+        let misses = self.misses.swap(0, Ordering::Relaxed);
+        hit_ratio(hits, misses)
+    }
+}
+
+fn hit_ratio(hits: u64, misses: u64) -> Option<f64> {
+    match hits + misses {
+        0 => None,
+        lookups => Some(hits as f64 / lookups as f64),
+    }
+}
+
+/// Lookup windows by `cache_hit_ratio` series key
+#[derive(Default)]
+pub(crate) struct CacheRatios {
+    windows: Mutex<HashMap<String, Arc<Lookups>>>,
+}
+
+impl CacheRatios {
+    pub(crate) fn lookups(&self, key: &str) -> Arc<Lookups> {
+        self.windows
+            .lock()
+            .entry(key.to_string())
+            .or_default()
+            .clone()
+    }
+
+    /// Set each ratio gauge from the window ending now. The gauges are
+    /// windowed (`GaugeAggregation::Max`), so one not set this window
+    /// sends nothing.
+    pub(crate) fn record(&self, gauges: &GaugeRegistry) {
+        let windows = self.windows.lock();
+        if windows.is_empty() {
+            return;
+        }
+        let gauges = gauges.lock();
+        for (key, lookups) in windows.iter() {
+            let ratio = lookups.take_ratio();
+            // Gone if filtered since
+            if let (Some(ratio), Some(gauge)) = (ratio, gauges.get(key)) {
+                gauge.set(ratio);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hit_ratio() {
+        assert_eq!(hit_ratio(0, 0), None);
+        assert_eq!(hit_ratio(3, 0), Some(1.0));
+        assert_eq!(hit_ratio(0, 3), Some(0.0));
+        assert_eq!(hit_ratio(3, 1), Some(0.75));
+    }
+
+    #[test]
+    fn test_each_window_starts_empty() {
+        let lookups = Lookups::default();
+        lookups.hits.fetch_add(9, Ordering::Relaxed);
+        lookups.misses.fetch_add(1, Ordering::Relaxed);
+        assert_eq!(lookups.take_ratio(), Some(0.9));
+        // No lookups since: no ratio, not the previous one or 0
+        assert_eq!(lookups.take_ratio(), None);
+        lookups.misses.fetch_add(1, Ordering::Relaxed);
+        assert_eq!(lookups.take_ratio(), Some(0.0));
+    }
+}
@@ -0,0 +1,201 @@
+//! What the aggregator accepts beyond the baseline schema
+//!
+//! On connect the agent asks with `GetCapabilities`. An aggregator built
+//! before the call existed answers Unimplemented, and gets batches without
+//! any optional contents: no events, exemplars or announces. A call that
+//! fails any other way assumes everything is accepted, as before the probe.
+
+#[cfg(not(feature = "noop"))]
+use std::future::Future;
+
+#[cfg(not(feature = "noop"))]
+use crate::runtime::Transport;
+#[cfg(not(feature = "noop"))]
+use crate::telemetry::metric_sample::Value;
+#[cfg(not(feature = "noop"))]
+use crate::telemetry::{Capabilities, TelemetryBatch};
+
+/// Optional batch contents the aggregator accepts; see
+/// `Config::assume_capabilities`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ServerCapabilities {
+    /// `emit_event` events; without, they are dropped at collect and
+    /// counted in `agent_events_dropped_total`
+    pub events: bool,
+    /// Trace ids recorded with histogram samples
+    pub exemplars: bool,
+    /// Metadata announced at startup and after reconnects; without, it is
+    /// held until an aggregator accepts it
+    pub announce: bool,
+    /// Sample timestamps as offsets from a batch base time, for
+    /// `Config::delta_timestamps`; without, they stay absolute
+    pub delta_timestamps: bool,
+    /// `Agent::start_span` spans; without, each sampled span's duration
+    /// is recorded in `span_duration_ms{span, outcome}` instead
+    pub spans: bool,
+    /// Series sent by id, for `Config::intern_series`; without, every
+    /// metric carries its name and labels
+    pub series_ids: bool,
+    /// Each metric's `series_hash`, for `Config::hash_series`; without,
+    /// the aggregator hashes series itself
+    pub series_hash: bool,
+}
+
+impl ServerCapabilities {
+    /// Everything this agent can send
+    pub const ALL: Self = Self {
+        events: true,
+        exemplars: true,
+        announce: true,
+        delta_timestamps: true,
+        spans: true,
+        series_ids: true,
+        series_hash: true,
+    };
+
+    /// Only what every aggregator understands
+    pub const BASELINE: Self = Self {
+        events: false,
+        exemplars: false,
+        announce: false,
+        delta_timestamps: false,
+        spans: false,
+        series_ids: false,
+        series_hash: false,
+    };
+}
+
+#[cfg(not(feature = "noop"))]
+impl From<Capabilities> for ServerCapabilities {
+    fn from(capabilities: Capabilities) -> Self {
+        Self {
+            events: capabilities.events,
+            exemplars: capabilities.exemplars,
+            announce: capabilities.announce,
+            delta_timestamps: capabilities.delta_timestamps,
+            spans: capabilities.spans,
+            series_ids: capabilities.series_ids,
+            series_hash: capabilities.series_hash,
+        }
+    }
+}
+
+#[cfg(not(feature = "noop"))]
+impl ServerCapabilities {
+    /// Remove what the aggregator doesn't accept from `batch`, returning
+    /// the number of events removed. Spans only get here if they were
+    /// queued before the aggregator turned out not to take them.
+    pub(crate) fn strip(&self, batch: &mut TelemetryBatch) -> usize {
+        if !self.exemplars {
+            for sample in batch.metrics.iter_mut().flat_map(|m| &mut m.samples) {
+                if let Some(Value::Histogram(histogram)) = &mut sample.value {
+                    histogram.exemplars.clear();
+                }
+            }
+        }
+        if !self.spans {
+            batch.spans.clear();
+        }
+        match self.events {
+            true => 0,
+            false => std::mem::take(&mut batch.events).len(),
+        }
+    }
+}
+
+/// Ask the aggregator behind `transport`, giving up when `timeout`
+/// completes
+#[cfg(not(feature = "noop"))]
+pub(crate) async fn probe(
+    transport: &Transport,
+    timeout: impl Future<Output = ()>,
+) -> ServerCapabilities {
+    let result = tokio::select! {
+        result = transport.get_capabilities() => result,
+        _ = timeout => Err(tonic::Status::deadline_exceeded(
+            "capabilities not returned within push_timeout",
+        )),
+    };
+    from_answer(result)
+}
+
+/// What a `GetCapabilities` answer means for the batches sent
+#[cfg(not(feature = "noop"))]
+pub(crate) fn from_answer(
+    result: Result<tonic::Response<Capabilities>, tonic::Status>,
+) -> ServerCapabilities {
+    let capabilities = match result {
+        Ok(capabilities) => capabilities.into_inner().into(),
+        Err(status) if status.code() == tonic::Code::Unimplemented => ServerCapabilities::BASELINE,
+        Err(status) => {
+            tracing::warn!(error = %status, "capability probe failed, sending everything");
+            return ServerCapabilities::ALL;
+        }
+    };
+    if capabilities != ServerCapabilities::ALL {
+        let suppressed: Vec<&str> = [
+            ("events", capabilities.events),
+            ("exemplars", capabilities.exemplars),
+            ("announce", capabilities.announce),
+            ("delta_timestamps", capabilities.delta_timestamps),
+            ("spans", capabilities.spans),
+            ("series_ids", capabilities.series_ids),
+            ("series_hash", capabilities.series_hash),
+        ]
+        .into_iter()
+        .filter(|(_, accepted)| !accepted)
+        .map(|(name, _)| name)
+        .collect();
+        tracing::info!(?suppressed, "aggregator predates parts of the schema");
+    }
+    capabilities
+}
+
+#[cfg(all(test, not(feature = "noop")))]
+mod tests {
+    use super::*;
+    use crate::telemetry::{Event, Exemplar, Histogram, Metric, MetricSample, Span};
+
+    #[test]
+    fn test_strip_keeps_what_is_accepted() {
+        let histogram = Histogram {
+            bounds: vec![1.0],
+            counts: vec![1, 0],
+            exemplars: vec![Exemplar {
+                bucket_index: 0,
+                value: 0.5,
+                trace_id: vec![1; 16],
+                timestamp_ns: 1,
+            }],
+        };
+        let batch = TelemetryBatch {
+            metrics: vec![Metric {
+                name: "render_ms".to_string(),
+                samples: vec![MetricSample {
+                    value: Some(Value::Histogram(histogram)),
+                    ..Default::default()
+                }],
+                ..Default::default()
+            }],
+            events: vec![Event::default(), Event::default()],
+            spans: vec![Span::default()],
+            ..Default::default()
+        };
+
+        let mut all = batch.clone();
+        assert_eq!(ServerCapabilities::ALL.strip(&mut all), 0);
+        assert_eq!(all, batch);
+
+        let mut baseline = batch.clone();
+        assert_eq!(ServerCapabilities::BASELINE.strip(&mut baseline), 2);
+        assert!(baseline.events.is_empty());
+        assert!(baseline.spans.is_empty());
+        match &baseline.metrics[0].samples[0].value {
+            Some(Value::Histogram(histogram)) => {
+                assert!(histogram.exemplars.is_empty());
+                assert_eq!(histogram.counts, vec![1, 0]);
+            }
+            other => panic!("render_ms is {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,119 @@
+//! Estimate of the agent clock's offset from the aggregator's
+//!
+//! Every acknowledged push is one sample, NTP style: the aggregator's
+//! receive time (`Ack::received_at_ns`) against the midpoint of the agent's
+//! send and ack times, assuming request and response take equally long.
+//! Samples are smoothed so one lopsided push barely moves the estimate.
+//!
+//! Also the monotonic clock request latencies are timed by, which tests
+//! replace to make time jump.
+
+use std::sync::atomic::{AtomicBool, AtomicI64, Ordering};
+use std::time::{Duration, Instant};
+
+use crate::ClockSkew;
+
+/// Weight of each new sample is 1/SMOOTHING
+const SMOOTHING: i64 = 8;
+
+#[derive(Default)]
+pub(crate) struct ClockSync {
+    /// Agent clock minus aggregator clock
+    skew_ns: AtomicI64,
+    estimated: AtomicBool,
+}
+
+impl ClockSync {
+    /// Fold in one push, all times in Unix nanoseconds: sent and acked by
+    /// the agent's clock, received by the aggregator's. Aggregators that
+    /// don't report a receive time (0) are ignored.
+    pub(crate) fn observe(&self, sent_ns: u64, received_ns: u64, acked_ns: u64) {
+        if received_ns == 0 || sent_ns == 0 {
+            return;
+        }
+        let midpoint = sent_ns as i128 + (acked_ns as i128 - sent_ns as i128) / 2;
+        let sample = (midpoint - received_ns as i128) as i64;
+        // Only the push loop writes, so load-then-store doesn't race
+        let skew = match self.estimated.load(Ordering::Relaxed) {
+            false => sample,
+            true => {
+                let skew = self.skew_ns.load(Ordering::Relaxed);
+                skew + (sample - skew) / SMOOTHING
+            }
+        };
+        self.skew_ns.store(skew, Ordering::Relaxed);
+        self.estimated.store(true, Ordering::Release);
+    }
+
+    /// Agent clock minus aggregator clock, once a push has been acked
+    pub(crate) fn skew_ns(&self) -> Option<i64> {
+        self.estimated
+            .load(Ordering::Acquire)
+            .then(|| self.skew_ns.load(Ordering::Relaxed))
+    }
+
+    pub(crate) fn skew(&self) -> Option<ClockSkew> {
+        self.skew_ns().map(|ns| match ns {
+            0.. => ClockSkew::Ahead(Duration::from_nanos(ns as u64)),
+            _ => ClockSkew::Behind(Duration::from_nanos(ns.unsigned_abs())),
+        })
+    }
+
+    /// `timestamp_ns` by the aggregator's clock; 0 stays unset
+    pub(crate) fn correct(&self, timestamp_ns: u64) -> u64 {
+        match self.skew_ns() {
+            Some(skew) if timestamp_ns != 0 => (timestamp_ns as i64 - skew).max(0) as u64,
+            _ => timestamp_ns,
+        }
+    }
+}
+
+/// Where `RequestGuard` latencies get the time
+pub(crate) trait Clock: Send + Sync {
+    fn now(&self) -> Instant;
+}
+
+pub(crate) struct SystemClock;
+
+impl Clock for SystemClock {
+    #[inline]
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECOND: u64 = 1_000_000_000;
+
+    #[test]
+    fn test_estimate_is_smoothed() {
+        let clock = ClockSync::default();
+        assert_eq!(clock.skew(), None);
+        // Received mid-flight, 90s by an aggregator clock 90s ahead
+        clock.observe(1000 * SECOND, 1090 * SECOND + 5, 1000 * SECOND + 10);
+        assert_eq!(
+            clock.skew(),
+            Some(ClockSkew::Behind(Duration::from_secs(90)))
+        );
+
+        // A lopsided push moves the estimate by an eighth of its error
+        clock.observe(2000 * SECOND, 2090 * SECOND - 8 * 1000, 2000 * SECOND);
+        assert_eq!(clock.skew_ns(), Some(-90 * SECOND as i64 + 1000));
+        assert_eq!(clock.correct(3000 * SECOND), 3090 * SECOND - 1000);
+        assert_eq!(clock.correct(0), 0);
+    }
+
+    #[test]
+    fn test_missing_receive_time_is_ignored() {
+        let clock = ClockSync::default();
+        clock.observe(1000 * SECOND, 0, 1000 * SECOND);
+        assert_eq!(clock.skew(), None);
+        assert_eq!(clock.correct(7), 7);
+
+        clock.observe(1000 * SECOND, 999 * SECOND, 1000 * SECOND);
+        assert_eq!(clock.skew(), Some(ClockSkew::Ahead(Duration::from_secs(1))));
+    }
+}
@@ -0,0 +1,266 @@
+//! Byte encoding of batches for transports other than gRPC, the batch
+//! checksum (`Config::checksum_batches`), and timestamp encoding
+//! (`Config::timestamp_resolution`, `Config::delta_timestamps`)
+
+use std::fmt;
+
+use prost::Message;
+
+use crate::telemetry::metric_sample::Value;
+use crate::telemetry::TelemetryBatch;
+use crate::Resolution;
+
+/// Version byte prefixed to every encoded batch. Bumped when the encoding
+/// changes incompatibly.
+pub const WIRE_VERSION: u8 = 1;
+
+/// Error returned by `TelemetryBatch::from_bytes`
+#[derive(Debug)]
+pub enum DecodeError {
+    /// Input was empty, so not even the version byte is present
+    Empty,
+    /// Encoded by an agent with a different wire version
+    UnsupportedVersion(u8),
+    /// Version matched but the protobuf payload is malformed
+    Protobuf(prost::DecodeError),
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DecodeError::Empty => write!(f, "empty batch payload"),
+            DecodeError::UnsupportedVersion(v) => write!(
+                f,
+                "unsupported batch wire version {} (expected {})",
+                v, WIRE_VERSION
+            ),
+            DecodeError::Protobuf(e) => write!(f, "malformed batch: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for DecodeError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            DecodeError::Protobuf(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl TelemetryBatch {
+    /// Encode as `[WIRE_VERSION][protobuf bytes]`
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(1 + self.encoded_len());
+        buf.push(WIRE_VERSION);
+        // Encoding into a Vec cannot run out of capacity
+        self.encode(&mut buf).expect("Vec has unbounded capacity");
+        buf
+    }
+
+    /// Decode bytes produced by `to_bytes`
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, DecodeError> {
+        let (&version, payload) = bytes.split_first().ok_or(DecodeError::Empty)?;
+        if version != WIRE_VERSION {
+            return Err(DecodeError::UnsupportedVersion(version));
+        }
+        TelemetryBatch::decode(payload).map_err(DecodeError::Protobuf)
+    }
+
+    /// CRC-32C of the batch encoded without its `checksum` field, as
+    /// stamped by `Config::checksum_batches`
+    pub fn compute_checksum(&self) -> u32 {
+        let unsealed = TelemetryBatch {
+            checksum: None,
+            ..self.clone()
+        };
+        crc32c(&unsealed.encode_to_vec())
+    }
+
+    /// Whether the batch matches the checksum it carries; `None` if it
+    /// carries none
+    pub fn verify_checksum(&self) -> Option<bool> {
+        self.checksum
+            .map(|checksum| checksum == self.compute_checksum())
+    }
+
+    /// Stamp the checksum; last, once nothing else about the batch changes
+    pub(crate) fn seal(&mut self) {
+        self.checksum = Some(self.compute_checksum());
+    }
+
+    /// Cut timestamps to `resolution` and, with `delta`, make sample
+    /// timestamps offsets from the earliest of them
+    pub(crate) fn shrink_timestamps(&mut self, resolution: Resolution, delta: bool) {
+        if resolution != Resolution::Nanos {
+            for sample in self.metrics.iter_mut().flat_map(|m| &mut m.samples) {
+                sample.timestamp_ns = resolution.truncate(sample.timestamp_ns);
+                sample.window_start_ns = resolution.truncate(sample.window_start_ns);
+                if let Some(Value::Histogram(histogram)) = &mut sample.value {
+                    for exemplar in &mut histogram.exemplars {
+                        exemplar.timestamp_ns = resolution.truncate(exemplar.timestamp_ns);
+                    }
+                }
+            }
+            for event in &mut self.events {
+                event.timestamp_ns = resolution.truncate(event.timestamp_ns);
+            }
+            for span in &mut self.spans {
+                span.start_ns = resolution.truncate(span.start_ns);
+            }
+        }
+        if !delta || self.base_timestamp_ns != 0 {
+            return;
+        }
+        let samples = self.metrics.iter_mut().flat_map(|m| &mut m.samples);
+        let Some(base) = samples.map(|sample| sample.timestamp_ns).min() else {
+            return;
+        };
+        for sample in self.metrics.iter_mut().flat_map(|m| &mut m.samples) {
+            sample.timestamp_ns -= base;
+        }
+        self.base_timestamp_ns = base;
+    }
+
+    /// Turn sample timestamps sent as offsets from `base_timestamp_ns` back
+    /// into absolute ones; a no-op for batches sent without a base
+    pub fn resolve_timestamps(&mut self) {
+        let base = std::mem::take(&mut self.base_timestamp_ns);
+        if base == 0 {
+            return;
+        }
+        for sample in self.metrics.iter_mut().flat_map(|m| &mut m.samples) {
+            sample.timestamp_ns += base;
+        }
+    }
+}
+
+/// CRC-32C (Castagnoli), reflected, as in iSCSI and ext4
+pub(crate) fn crc32c(bytes: &[u8]) -> u32 {
+    !bytes.iter().fold(!0u32, |crc, &b| {
+        CRC32C_TABLE[((crc ^ b as u32) & 0xff) as usize] ^ (crc >> 8)
+    })
+}
+
+const CRC32C_TABLE: [u32; 256] = {
+    // Reversed polynomial 0x1EDC6F41
+    const POLY: u32 = 0x82f6_3b78;
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ POLY
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Agent, Config};
+
+    #[test]
+    fn test_bytes_round_trip() {
+        let agent = Agent::new(Config::default());
+        agent.inc_counter("requests_total");
+        let batch = agent.collect_now();
+
+        let bytes = batch.to_bytes();
+        assert_eq!(bytes[0], WIRE_VERSION);
+        assert_eq!(TelemetryBatch::from_bytes(&bytes).unwrap(), batch);
+    }
+
+    /// 5k series shaped like the loadgen example's: counters, gauges and
+    /// some histograms, each with a shard label
+    fn large_batch() -> TelemetryBatch {
+        let agent = Agent::new(Config::default());
+        for i in 0..5_000 {
+            let shard = format!("shard-{}", i % 16);
+            let labels = [("shard", shard.as_str())];
+            match i % 10 {
+                0 => agent.record_histogram_with(&format!("loadgen_histogram_{}", i), &labels, 1.0),
+                1..=4 => agent.set_gauge_with(&format!("loadgen_gauge_{}", i), &labels, i as f64),
+                _ => agent.inc_counter_with(&format!("loadgen_counter_{}", i), &labels),
+            }
+        }
+        agent.collect_now()
+    }
+
+    #[test]
+    fn test_delta_timestamps_shrink_batches() {
+        let batch = large_batch();
+        assert!(batch.metrics.len() >= 5_000);
+        let absolute = batch.encoded_len();
+
+        let mut delta = batch.clone();
+        delta.shrink_timestamps(Resolution::Nanos, true);
+        let reduction = 1.0 - delta.encoded_len() as f64 / absolute as f64;
+        assert!(reduction >= 0.10, "{:.1}% smaller", reduction * 100.0);
+
+        let mut resolved = TelemetryBatch::decode(delta.encode_to_vec().as_slice()).unwrap();
+        resolved.resolve_timestamps();
+        assert_eq!(resolved, batch);
+    }
+
+    #[test]
+    fn test_timestamp_resolution() {
+        let mut batch = large_batch();
+        let exact = batch.metrics[0].samples[0].timestamp_ns;
+        batch.shrink_timestamps(Resolution::Millis, false);
+        let cut = batch.metrics[0].samples[0].timestamp_ns;
+        assert_eq!(cut % 1_000_000, 0);
+        assert!(exact - cut < 1_000_000);
+        assert_eq!(batch.base_timestamp_ns, 0);
+        assert_eq!(Resolution::Micros.truncate(1_234_567), 1_234_000);
+        assert_eq!(Resolution::Nanos.truncate(1_234_567), 1_234_567);
+    }
+
+    #[test]
+    fn test_crc32c_check_value() {
+        assert_eq!(crc32c(b"123456789"), 0xe306_9283);
+        assert_eq!(crc32c(b""), 0);
+    }
+
+    #[test]
+    fn test_checksum_catches_changes() {
+        let agent = Agent::new(Config::default());
+        agent.inc_counter("requests_total");
+        let mut batch = agent.collect_now();
+        assert_eq!(batch.verify_checksum(), None);
+
+        batch.seal();
+        assert_eq!(batch.verify_checksum(), Some(true));
+        let bytes = batch.to_bytes();
+        assert_eq!(
+            TelemetryBatch::from_bytes(&bytes)
+                .unwrap()
+                .verify_checksum(),
+            Some(true)
+        );
+
+        batch.metrics[0].name = "requests_totak".to_string();
+        assert_eq!(batch.verify_checksum(), Some(false));
+    }
+
+    #[test]
+    fn test_version_guard() {
+        assert!(matches!(
+            TelemetryBatch::from_bytes(&[]),
+            Err(DecodeError::Empty)
+        ));
+        assert!(matches!(
+            TelemetryBatch::from_bytes(&[WIRE_VERSION + 1, 0]),
+            Err(DecodeError::UnsupportedVersion(v)) if v == WIRE_VERSION + 1
+        ));
+    }
+}
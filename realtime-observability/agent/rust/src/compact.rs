@@ -0,0 +1,121 @@
+//! Shrinking registry tables after label churn (`Agent::compact`)
+//!
+//! A `HashMap` never gives back buckets when entries are removed, so once
+//! tens of thousands of short-lived series are purged, their table stays
+//! at its peak size. The push loop looks at one registry per collect,
+//! round robin, and rebuilds its table when fewer than
+//! `Config::compact_threshold` of its slots are live. A rebuild holds the
+//! registry lock for one rehash of the live entries, which is cheap
+//! exactly when there are few of them; recording through a handle never
+//! takes the lock at all.
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use parking_lot::Mutex;
+
+use crate::agent::Registries;
+
+/// Tables smaller than this aren't worth rebuilding on the push loop
+const MIN_CAPACITY: usize = 64;
+
+/// Gauges, counters, histograms, sharded counters, recordable histograms
+const TABLES: usize = 5;
+
+#[derive(Default)]
+pub(crate) struct Compactor {
+    /// The table the next collect looks at
+    next: AtomicUsize,
+    /// Tables rebuilt
+    runs: AtomicU64,
+    /// Slots given back by those rebuilds
+    reclaimed: AtomicU64,
+}
+
+impl Compactor {
+    /// Look at the next table in turn, rebuilding it if fewer than
+    /// `threshold` of its slots are live; returns the slots reclaimed
+    pub(crate) fn tick(&self, registries: &Registries, threshold: f64) -> usize {
+        let table = self.next.fetch_add(1, Ordering::Relaxed) % TABLES;
+        self.compact_table(registries, table, Some(threshold))
+    }
+
+    /// Rebuild every table with spare slots; returns the slots reclaimed
+    pub(crate) fn compact_all(&self, registries: &Registries) -> usize {
+        (0..TABLES)
+            .map(|table| self.compact_table(registries, table, None))
+            .sum()
+    }
+
+    pub(crate) fn runs(&self) -> u64 {
+        self.runs.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn reclaimed(&self) -> u64 {
+        self.reclaimed.load(Ordering::Relaxed)
+    }
+
+    fn compact_table(
+        &self,
+        registries: &Registries,
+        table: usize,
+        threshold: Option<f64>,
+    ) -> usize {
+        let reclaimed = match table {
+            0 => shrink(&registries.gauges, threshold),
+            1 => shrink(&registries.counters, threshold),
+            2 => shrink(&registries.histograms, threshold),
+            3 => shrink(&registries.sharded, threshold),
+            _ => shrink(&registries.recordable, threshold),
+        };
+        if reclaimed > 0 {
+            self.runs.fetch_add(1, Ordering::Relaxed);
+            self.reclaimed
+                .fetch_add(reclaimed as u64, Ordering::Relaxed);
+        }
+        reclaimed
+    }
+}
+
+/// Shrink `table` to its live entries, unless more than `threshold` of it
+/// is live; returns the slots given back
+fn shrink<T: ?Sized>(table: &Mutex<HashMap<String, Arc<T>>>, threshold: Option<f64>) -> usize {
+    let mut table = table.lock();
+    let capacity = table.capacity();
+    if let Some(threshold) = threshold {
+        if capacity < MIN_CAPACITY || table.len() as f64 >= capacity as f64 * threshold {
+            return 0;
+        }
+    }
+    table.shrink_to_fit();
+    capacity.saturating_sub(table.capacity())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn table(len: usize, capacity: usize) -> Mutex<HashMap<String, Arc<u64>>> {
+        let mut table = HashMap::with_capacity(capacity);
+        for i in 0..len {
+            table.insert(i.to_string(), Arc::new(0));
+        }
+        Mutex::new(table)
+    }
+
+    #[test]
+    fn test_shrinks_only_below_threshold() {
+        let sparse = table(10, 1000);
+        assert!(shrink(&sparse, Some(0.25)) > 0);
+        assert!(sparse.lock().capacity() < 64);
+        assert_eq!(sparse.lock().len(), 10);
+
+        let dense = table(500, 1000);
+        assert_eq!(shrink(&dense, Some(0.25)), 0);
+        let small = table(1, 32);
+        assert_eq!(shrink(&small, Some(0.25)), 0);
+        // On demand, whatever the ratio
+        assert!(shrink(&dense, None) > 0);
+    }
+}
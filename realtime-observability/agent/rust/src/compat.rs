@@ -0,0 +1,110 @@
+//! Decoding batches from any agent version (`telemetry::compat`)
+//!
+//! Fields are only ever added to `telemetry.proto`, never renumbered or
+//! reused, so an older batch decodes with the fields it predates left at
+//! their defaults, and prost skips fields newer than this crate.
+//! `decode_any_version` adds what a reader otherwise has to know about
+//! each version: it takes `to_bytes` output or a bare protobuf message,
+//! checks the checksum, makes delta timestamps absolute again and drops
+//! the explicit `+Inf` histogram bound, so every batch comes out in one
+//! shape. `tests/wire_format.rs` pins the encoding against checked-in
+//! fixtures.
+//!
+//! Batches sent with series ids (`Metric::series_id`) depend on the
+//! definitions of earlier batches, which a single decode can't see; a
+//! receiver advertising `Capabilities::series_ids` keeps a `SeriesTable`
+//! per agent process to name their metrics.
+
+use std::collections::{BTreeMap, HashMap};
+
+use prost::Message;
+
+use crate::telemetry::metric_sample::Value;
+use crate::telemetry::TelemetryBatch;
+use crate::{DecodeError, WIRE_VERSION};
+
+/// A batch from `decode_any_version`
+#[derive(Debug, Clone, PartialEq)]
+pub struct DecodedBatch {
+    /// Version byte of `to_bytes` output; `None` for a bare protobuf
+    /// message, as carried by gRPC
+    pub wire_version: Option<u8>,
+    /// As sent; 0 from agents older than the field, whose batches mean
+    /// what schema 1 does
+    pub schema_version: u32,
+    /// Whether the batch matched its checksum; `None` if it carried none
+    pub checksum_valid: Option<bool>,
+    /// With absolute sample timestamps (`base_timestamp_ns` is 0) and
+    /// histogram bounds without a trailing `+Inf`, so every histogram has
+    /// one more count than bounds
+    pub batch: TelemetryBatch,
+}
+
+/// Decode `bytes` encoded by any version of this crate: `to_bytes`
+/// output, or the bare message. Fails on an unknown wire version or a
+/// malformed message.
+pub fn decode_any_version(bytes: &[u8]) -> Result<DecodedBatch, DecodeError> {
+    let (&first, rest) = bytes.split_first().ok_or(DecodeError::Empty)?;
+    // No protobuf message starts with a byte below 8: that would be
+    // field number 0, which is invalid
+    let (wire_version, payload) = match first {
+        WIRE_VERSION => (Some(WIRE_VERSION), rest),
+        0..=7 => return Err(DecodeError::UnsupportedVersion(first)),
+        _ => (None, bytes),
+    };
+    let mut batch = TelemetryBatch::decode(payload).map_err(DecodeError::Protobuf)?;
+    let checksum_valid = batch.verify_checksum();
+    batch.resolve_timestamps();
+    for sample in batch.metrics.iter_mut().flat_map(|m| &mut m.samples) {
+        if let Some(Value::Histogram(histogram)) = &mut sample.value {
+            if histogram.bounds.last() == Some(&f64::INFINITY) {
+                histogram.bounds.pop();
+            }
+        }
+    }
+    Ok(DecodedBatch {
+        wire_version,
+        schema_version: batch.schema_version,
+        checksum_valid,
+        batch,
+    })
+}
+
+/// The series an agent process has defined, for naming the metrics of its
+/// batches sent with series ids. Keep one per `service`, `instance` and
+/// `instance_epoch`, and pass it every batch in the order received.
+#[derive(Debug, Clone, Default)]
+pub struct SeriesTable {
+    series: HashMap<u32, (String, BTreeMap<String, String>)>,
+}
+
+impl SeriesTable {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Take in `batch`'s series definitions and give each metric sent by
+    /// id its name and labels back. Metrics whose id was never defined
+    /// are removed; returns how many. Batches without series ids pass
+    /// through unchanged.
+    pub fn resolve(&mut self, batch: &mut TelemetryBatch) -> usize {
+        for definition in batch.series_definitions.drain(..) {
+            self.series
+                .insert(definition.id, (definition.name, definition.labels));
+        }
+        let before = batch.metrics.len();
+        batch.metrics.retain_mut(|metric| {
+            if metric.series_id == 0 {
+                return true;
+            }
+            let Some((name, labels)) = self.series.get(&metric.series_id) else {
+                return false;
+            };
+            metric.name.clone_from(name);
+            metric.labels.clone_from(labels);
+            metric.series_id = 0;
+            true
+        });
+        before - batch.metrics.len()
+    }
+}
@@ -0,0 +1,420 @@
+//! Agent configuration from a TOML file (`Config::from_file`)
+//!
+//! Keys are `Config` field names, with durations in whole milliseconds
+//! under the field name plus `_ms`. The file is applied on top of a base
+//! config: the defaults for `from_file`, the config in effect for a reload.
+//! Unknown keys are rejected, so a typo doesn't silently keep a default.
+//! A `profile` key applies `Config::profile` defaults first, wherever it
+//! appears, so the other keys in the file win over it.
+
+use std::fmt;
+use std::io;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::time::Duration;
+
+use toml_edit::{Document, Item};
+
+use crate::{Config, MetricFilter, Profile, Resolution};
+
+/// Returned by `Config::from_file`
+#[derive(Debug)]
+pub enum ConfigFileError {
+    Io(io::Error),
+    /// The file is not valid TOML
+    Parse(String),
+    /// A key is unknown, or its value has the wrong type or is out of range
+    Invalid {
+        key: String,
+        reason: String,
+    },
+}
+
+impl fmt::Display for ConfigFileError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Io(e) => write!(f, "reading config file: {}", e),
+            Self::Parse(message) => write!(f, "config file is not valid TOML: {}", message),
+            Self::Invalid { key, reason } => write!(f, "config key {:?}: {}", key, reason),
+        }
+    }
+}
+
+impl std::error::Error for ConfigFileError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl Config {
+    /// Read a TOML config file. Keys left out keep their defaults.
+    ///
+    /// ```toml
+    /// profile = "prod"
+    /// aggregator_addr = "http://aggregator:9000"
+    /// service_name = "checkout"
+    /// service_version = "2.3.1"
+    /// instance_id = "checkout-0"
+    /// push_interval_ms = 1000
+    /// min_push_interval_ms = 5
+    /// max_collect_budget = 0.2
+    /// push_timeout_ms = 5000
+    /// connect_timeout_ms = 5000
+    /// handshake_timeout_ms = 5000
+    /// flush_timeout_ms = 2000
+    /// spool_dir = "/var/spool/telemetry"
+    /// allow_remote_config = true
+    /// verbose_push = false
+    /// lazy_connect = false
+    /// dedicated_thread = true
+    /// stdout_fallback_after_ms = 30000
+    /// bytes_per_hour = 50000000
+    /// drop_report_interval_ms = 60000
+    /// checksum_batches = false
+    /// validate_before_send = false
+    /// compact_threshold = 0.25
+    /// timestamp_resolution = "millis"
+    /// delta_timestamps = true
+    /// intern_series = true
+    /// hash_series = false
+    /// cache_hit_ratio = true
+    /// emit_counter_rates = true
+    /// pull_listener = "127.0.0.1:9464"
+    /// pull_listener_public = false
+    /// retry_budget_ratio = 0.2
+    /// retry_budget_reserve = 0.1
+    /// group_batches = true
+    /// local_stats = true
+    /// local_stats_window_ms = 60000
+    /// track_state_transitions = true
+    /// incremental_mode = true
+    /// resync_interval_ms = 30000
+    /// max_events_per_batch = 100
+    /// span_sample_rate = 0.01
+    /// max_spans_per_batch = 256
+    /// max_error_types = 100
+    /// max_self_metric_fraction = 0.5
+    /// max_plausible_latency_ms = 600000
+    /// sanitize_names = true
+    ///
+    /// [metadata]
+    /// region = "eu-west-1"
+    ///
+    /// [metric_filter]
+    /// allow = ["http_*", "db_*"]
+    /// deny = ["*_debug"]
+    /// ```
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Config, ConfigFileError> {
+        let text = std::fs::read_to_string(path).map_err(ConfigFileError::Io)?;
+        merge(&Config::default(), &text)
+    }
+}
+
+/// `base` with the settings in TOML `text` applied
+pub(crate) fn merge(base: &Config, text: &str) -> Result<Config, ConfigFileError> {
+    let document = Document::parse(text).map_err(|e| ConfigFileError::Parse(e.to_string()))?;
+    let root = document
+        .as_item()
+        .as_table()
+        .expect("a parsed document is a table");
+
+    let mut config = base.clone();
+    if let Some(item) = root.get("profile") {
+        profile("profile", item)?.apply(&mut config);
+    }
+    for (key, item) in root.iter() {
+        match key {
+            "profile" => {}
+            "aggregator_addr" => config.aggregator_addr = string(key, item)?,
+            "service_name" => config.service_name = string(key, item)?,
+            "service_version" => config.service_version = string(key, item)?,
+            "instance_id" => config.instance_id = string(key, item)?,
+            "push_interval_ms" => config.push_interval = millis(key, item)?,
+            "min_push_interval_ms" => config.min_push_interval = millis(key, item)?,
+            "max_collect_budget" => config.max_collect_budget = Some(fraction(key, item)?),
+            "push_timeout_ms" => config.push_timeout = millis(key, item)?,
+            "connect_timeout_ms" => config.connect_timeout = millis(key, item)?,
+            "handshake_timeout_ms" => config.handshake_timeout = millis(key, item)?,
+            "flush_timeout_ms" => config.flush_timeout = millis(key, item)?,
+            "spool_dir" => config.spool_dir = Some(string(key, item)?.into()),
+            "allow_remote_config" => config.allow_remote_config = boolean(key, item)?,
+            "verbose_push" => config.verbose_push = boolean(key, item)?,
+            "lazy_connect" => config.lazy_connect = boolean(key, item)?,
+            "dedicated_thread" => config.dedicated_thread = boolean(key, item)?,
+            "stdout_fallback_after_ms" => config.stdout_fallback_after = Some(millis(key, item)?),
+            "bytes_per_hour" => config.bytes_per_hour = Some(count(key, item)? as u64),
+            "drop_report_interval_ms" => config.drop_report_interval = Some(millis(key, item)?),
+            "checksum_batches" => config.checksum_batches = boolean(key, item)?,
+            "validate_before_send" => config.validate_before_send = boolean(key, item)?,
+            "compact_threshold" => config.compact_threshold = Some(fraction(key, item)?),
+            "timestamp_resolution" => config.timestamp_resolution = resolution(key, item)?,
+            "delta_timestamps" => config.delta_timestamps = boolean(key, item)?,
+            "intern_series" => config.intern_series = boolean(key, item)?,
+            "hash_series" => config.hash_series = boolean(key, item)?,
+            "cache_hit_ratio" => config.cache_hit_ratio = boolean(key, item)?,
+            "emit_counter_rates" => config.emit_counter_rates = boolean(key, item)?,
+            "pull_listener" => config.pull_listener = Some(socket_addr(key, item)?),
+            "pull_listener_public" => config.pull_listener_public = boolean(key, item)?,
+            "retry_budget_ratio" => config.retry_budget_ratio = fraction(key, item)?,
+            "retry_budget_reserve" => config.retry_budget_reserve = fraction(key, item)?,
+            "group_batches" => config.group_batches = boolean(key, item)?,
+            "local_stats" => config.local_stats = boolean(key, item)?,
+            "local_stats_window_ms" => config.local_stats_window = millis(key, item)?,
+            "track_state_transitions" => config.track_state_transitions = boolean(key, item)?,
+            "incremental_mode" => config.incremental_mode = boolean(key, item)?,
+            "resync_interval_ms" => config.resync_interval = millis(key, item)?,
+            "max_events_per_batch" => config.max_events_per_batch = count(key, item)?,
+            "span_sample_rate" => config.span_sample_rate = fraction(key, item)?,
+            "max_spans_per_batch" => config.max_spans_per_batch = count(key, item)?,
+            "max_error_types" => config.max_error_types = count(key, item)?,
+            "max_plausible_latency_ms" => config.max_plausible_latency = Some(millis(key, item)?),
+            "sanitize_names" => config.sanitize_names = boolean(key, item)?,
+            "max_self_metric_fraction" => {
+                config.max_self_metric_fraction = Some(fraction(key, item)?)
+            }
+            "metadata" => config.metadata = metadata(key, item)?,
+            "metric_filter" => config.metric_filter = Some(filter(key, item)?),
+            _ => return Err(invalid(key, "unknown key")),
+        }
+    }
+    if config.push_interval < config.min_push_interval {
+        return Err(invalid(
+            "push_interval_ms",
+            format!(
+                "must be at least min_push_interval_ms ({})",
+                config.min_push_interval.as_millis()
+            ),
+        ));
+    }
+    Ok(config)
+}
+
+fn invalid(key: &str, reason: impl Into<String>) -> ConfigFileError {
+    ConfigFileError::Invalid {
+        key: key.to_string(),
+        reason: reason.into(),
+    }
+}
+
+fn string(key: &str, item: &Item) -> Result<String, ConfigFileError> {
+    item.as_str()
+        .map(str::to_string)
+        .ok_or_else(|| invalid(key, "expected a string"))
+}
+
+fn boolean(key: &str, item: &Item) -> Result<bool, ConfigFileError> {
+    item.as_bool()
+        .ok_or_else(|| invalid(key, "expected true or false"))
+}
+
+fn count(key: &str, item: &Item) -> Result<usize, ConfigFileError> {
+    let value = item
+        .as_integer()
+        .ok_or_else(|| invalid(key, "expected an integer"))?;
+    usize::try_from(value).map_err(|_| invalid(key, "must not be negative"))
+}
+
+fn millis(key: &str, item: &Item) -> Result<Duration, ConfigFileError> {
+    match count(key, item)? {
+        0 => Err(invalid(key, "must be at least 1")),
+        ms => Ok(Duration::from_millis(ms as u64)),
+    }
+}
+
+/// A share in (0, 1]; integers are taken too, so `1` works
+fn fraction(key: &str, item: &Item) -> Result<f64, ConfigFileError> {
+    let value = item
+        .as_float()
+        .or_else(|| item.as_integer().map(|n| n as f64))
+        .ok_or_else(|| invalid(key, "expected a number"))?;
+    match value > 0.0 && value <= 1.0 {
+        true => Ok(value),
+        false => Err(invalid(key, "must be above 0 and at most 1")),
+    }
+}
+
+fn socket_addr(key: &str, item: &Item) -> Result<SocketAddr, ConfigFileError> {
+    string(key, item)?
+        .parse()
+        .map_err(|_| invalid(key, "expected an address such as \"127.0.0.1:9464\""))
+}
+
+fn resolution(key: &str, item: &Item) -> Result<Resolution, ConfigFileError> {
+    let name = string(key, item)?;
+    [Resolution::Nanos, Resolution::Micros, Resolution::Millis]
+        .into_iter()
+        .find(|resolution| resolution.as_str() == name)
+        .ok_or_else(|| invalid(key, "expected \"nanos\", \"micros\" or \"millis\""))
+}
+
+fn profile(key: &str, item: &Item) -> Result<Profile, ConfigFileError> {
+    let name = string(key, item)?;
+    [Profile::Dev, Profile::Prod, Profile::Test]
+        .into_iter()
+        .find(|profile| profile.as_str() == name)
+        .ok_or_else(|| invalid(key, "expected \"dev\", \"prod\" or \"test\""))
+}
+
+fn strings(key: &str, item: &Item) -> Result<Vec<String>, ConfigFileError> {
+    let array = item
+        .as_array()
+        .ok_or_else(|| invalid(key, "expected an array of strings"))?;
+    array
+        .iter()
+        .map(|value| {
+            value
+                .as_str()
+                .map(str::to_string)
+                .ok_or_else(|| invalid(key, "expected an array of strings"))
+        })
+        .collect()
+}
+
+fn metadata(
+    key: &str,
+    item: &Item,
+) -> Result<std::collections::HashMap<String, String>, ConfigFileError> {
+    let table = item
+        .as_table_like()
+        .ok_or_else(|| invalid(key, "expected a table"))?;
+    table
+        .iter()
+        .map(|(field, value)| {
+            let value = value
+                .as_str()
+                .ok_or_else(|| invalid(&format!("{}.{}", key, field), "expected a string"))?;
+            Ok((field.to_string(), value.to_string()))
+        })
+        .collect()
+}
+
+fn filter(key: &str, item: &Item) -> Result<MetricFilter, ConfigFileError> {
+    let table = item
+        .as_table_like()
+        .ok_or_else(|| invalid(key, "expected a table"))?;
+    let mut allow = Vec::new();
+    let mut deny = Vec::new();
+    for (field, value) in table.iter() {
+        let path = format!("{}.{}", key, field);
+        match field {
+            "allow" => allow = strings(&path, value)?,
+            "deny" => deny = strings(&path, value)?,
+            _ => return Err(invalid(&path, "unknown key")),
+        }
+    }
+    MetricFilter::from_strings(allow, deny).map_err(|e| invalid(key, e.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_keys_apply_over_the_base() {
+        let base = Config {
+            service_name: "checkout".to_string(),
+            ..Default::default()
+        };
+        let config = merge(
+            &base,
+            r#"
+            aggregator_addr = "http://aggregator:9000"
+            push_interval_ms = 1500
+            verbose_push = true
+            max_collect_budget = 0.5
+            timestamp_resolution = "millis"
+            retry_budget_reserve = 0.05
+            pull_listener = "127.0.0.1:9464"
+
+            [metadata]
+            region = "eu-west-1"
+
+            [metric_filter]
+            deny = ["*_debug"]
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.aggregator_addr, "http://aggregator:9000");
+        assert_eq!(config.service_name, "checkout");
+        assert_eq!(config.push_interval, Duration::from_millis(1500));
+        assert!(config.verbose_push);
+        assert_eq!(config.max_collect_budget, Some(0.5));
+        assert_eq!(config.timestamp_resolution, Resolution::Millis);
+        assert_eq!(config.retry_budget_reserve, 0.05);
+        assert_eq!(config.pull_listener, Some(([127, 0, 0, 1], 9464).into()));
+        assert_eq!(config.metadata["region"], "eu-west-1");
+        let filter = config.metric_filter.unwrap();
+        assert!(filter.allows("http_requests"));
+        assert!(!filter.allows("cache_debug"));
+    }
+
+    #[test]
+    fn test_keys_win_over_the_profile() {
+        let config = merge(
+            &Config::default(),
+            r#"
+            push_interval_ms = 250
+            profile = "dev"
+            "#,
+        )
+        .unwrap();
+        assert!(config.lazy_connect);
+        assert!(config.verbose_push);
+        assert_eq!(config.push_interval, Duration::from_millis(250));
+        assert_eq!(config.stdout_fallback_after, Some(Duration::from_secs(30)));
+
+        let config = merge(&config, "profile = \"prod\"").unwrap();
+        assert!(!config.lazy_connect);
+        assert_eq!(config.push_interval, Config::default().push_interval);
+        assert_eq!(config.stdout_fallback_after, None);
+    }
+
+    #[test]
+    fn test_bad_keys_are_rejected() {
+        let reason = |text: &str| match merge(&Config::default(), text) {
+            Err(ConfigFileError::Invalid { key, reason }) => format!("{}: {}", key, reason),
+            Err(e) => panic!("unexpected error {}", e),
+            Ok(_) => panic!("{:?} was accepted", text),
+        };
+        assert_eq!(
+            reason("push_intervl_ms = 10"),
+            "push_intervl_ms: unknown key"
+        );
+        assert_eq!(
+            reason("push_interval_ms = \"1s\""),
+            "push_interval_ms: expected an integer"
+        );
+        assert_eq!(
+            reason("push_interval_ms = 0"),
+            "push_interval_ms: must be at least 1"
+        );
+        assert_eq!(
+            reason("push_interval_ms = 1"),
+            "push_interval_ms: must be at least min_push_interval_ms (5)"
+        );
+        assert_eq!(
+            reason("max_collect_budget = 1.5"),
+            "max_collect_budget: must be above 0 and at most 1"
+        );
+        assert_eq!(
+            reason("pull_listener = \"localhost\""),
+            "pull_listener: expected an address such as \"127.0.0.1:9464\""
+        );
+        assert_eq!(
+            reason("[metadata]\nshard = 3"),
+            "metadata.shard: expected a string"
+        );
+        assert!(reason("[metric_filter]\nallow = [\"\"]").contains("pattern is empty"));
+        assert_eq!(
+            reason("profile = \"staging\""),
+            "profile: expected \"dev\", \"prod\" or \"test\""
+        );
+        assert!(matches!(
+            merge(&Config::default(), "service_name = "),
+            Err(ConfigFileError::Parse(_))
+        ));
+    }
+}
@@ -0,0 +1,59 @@
+//! Cumulative counters that increment per epoch
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+use crate::epoch::{self, Epoch};
+use crate::switches::{CachedSwitch, Switched};
+
+/// Increments land in the pending slot of the epoch they pin; collection
+/// folds the ended epoch's slot into the cumulative total it reports.
+#[derive(Default)]
+pub(crate) struct Counter {
+    epoch: Option<Arc<Epoch>>,
+    pending: [AtomicU64; 2],
+    /// Everything collected so far
+    collected: AtomicU64,
+    enabled: CachedSwitch,
+}
+
+impl Switched for Counter {
+    fn switch(&self) -> &CachedSwitch {
+        &self.enabled
+    }
+}
+
+impl Counter {
+    pub(crate) fn new(epoch: Option<Arc<Epoch>>) -> Self {
+        Self {
+            epoch,
+            ..Default::default()
+        }
+    }
+
+    #[inline]
+    pub(crate) fn add(&self, n: u64) {
+        let pin = epoch::pin(self.epoch.as_deref());
+        self.pending[pin.slot()].fetch_add(n, Ordering::Relaxed);
+    }
+
+    /// Add to an epoch the caller has already cut, for counts the collector
+    /// derives while draining it
+    pub(crate) fn add_to_slot(&self, slot: usize, n: u64) {
+        self.pending[slot].fetch_add(n, Ordering::Relaxed);
+    }
+
+    /// Total including increments not collected yet
+    pub(crate) fn value(&self) -> u64 {
+        self.collected.load(Ordering::Relaxed)
+            + self.pending[0].load(Ordering::Relaxed)
+            + self.pending[1].load(Ordering::Relaxed)
+    }
+
+    /// Fold one epoch's increments into the total; returns the total and
+    /// the increments
+    pub(crate) fn collect_slot(&self, slot: usize) -> (u64, u64) {
+        let n = self.pending[slot].swap(0, Ordering::Relaxed);
+        (self.collected.fetch_add(n, Ordering::Relaxed) + n, n)
+    }
+}
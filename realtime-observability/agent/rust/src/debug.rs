@@ -0,0 +1,152 @@
+//! Metrics that exist only in debug builds (`debug_counter!`,
+//! `debug_histogram!`, `Agent::debug_scope`)
+//!
+//! With `debug_assertions` or the `debug-metrics` feature on, the macros
+//! expand to the `Agent` call they stand for. Otherwise they expand to
+//! nothing: their arguments are never evaluated, so neither the metric
+//! name nor the work computing a value reaches the binary. Which applies
+//! is decided when this crate is compiled, so it follows the profile the
+//! agent is built with, not the calling crate's. Work done only to feed a
+//! debug metric belongs inside the macro call; done before it, it still
+//! runs in release, and its result is warned about as unused.
+//!
+//! ```
+//! use telemetry_agent::{debug_counter, debug_histogram, Agent, Config};
+//!
+//! let agent = Agent::new(Config::default());
+//! debug_counter!(agent, "cache_probes_total");
+//! debug_counter!(agent, "cache_probed_bytes_total", 4096);
+//! debug_histogram!(agent, "cache_chain_len", 3.0);
+//! ```
+
+use crate::{Agent, MetricName};
+
+/// Count one, or `n`, in counter `name`, as `Agent::inc_counter` and
+/// `Agent::add_counter` would; nothing, arguments unevaluated, in release
+/// builds without the `debug-metrics` feature. Works on a `ScopedAgent`
+/// too.
+#[cfg(any(debug_assertions, feature = "debug-metrics"))]
+#[macro_export]
+macro_rules! debug_counter {
+    ($agent:expr, $name:expr $(,)?) => {
+        $agent.inc_counter($name)
+    };
+    ($agent:expr, $name:expr, $n:expr $(,)?) => {
+        $agent.add_counter($name, $n)
+    };
+}
+
+/// Count one, or `n`, in counter `name`, as `Agent::inc_counter` and
+/// `Agent::add_counter` would; nothing, arguments unevaluated, in release
+/// builds without the `debug-metrics` feature. Works on a `ScopedAgent`
+/// too.
+#[cfg(not(any(debug_assertions, feature = "debug-metrics")))]
+#[macro_export]
+macro_rules! debug_counter {
+    ($agent:expr, $name:expr $(,)?) => {
+        ()
+    };
+    ($agent:expr, $name:expr, $n:expr $(,)?) => {
+        ()
+    };
+}
+
+/// Record `value` in histogram `name`, as `Agent::record_histogram`
+/// would; nothing, arguments unevaluated, in release builds without the
+/// `debug-metrics` feature
+#[cfg(any(debug_assertions, feature = "debug-metrics"))]
+#[macro_export]
+macro_rules! debug_histogram {
+    ($agent:expr, $name:expr, $value:expr $(,)?) => {
+        $agent.record_histogram($name, $value)
+    };
+}
+
+/// Record `value` in histogram `name`, as `Agent::record_histogram`
+/// would; nothing, arguments unevaluated, in release builds without the
+/// `debug-metrics` feature
+#[cfg(not(any(debug_assertions, feature = "debug-metrics")))]
+#[macro_export]
+macro_rules! debug_histogram {
+    ($agent:expr, $name:expr, $value:expr $(,)?) => {
+        ()
+    };
+}
+
+/// The agent's recording calls for debug-only metrics, from
+/// `Agent::debug_scope`
+///
+/// Unlike the macros, these are functions: their arguments are evaluated
+/// in every build, but release builds without the `debug-metrics` feature
+/// record nothing and the calls inline away.
+#[derive(Clone, Copy)]
+pub struct ScopedAgent<'a> {
+    #[cfg(any(debug_assertions, feature = "debug-metrics"))]
+    agent: &'a Agent,
+    #[cfg(not(any(debug_assertions, feature = "debug-metrics")))]
+    agent: std::marker::PhantomData<&'a Agent>,
+}
+
+impl Agent {
+    /// Recording calls that only record in debug builds or with the
+    /// `debug-metrics` feature; see `ScopedAgent`
+    #[inline(always)]
+    pub fn debug_scope(&self) -> ScopedAgent<'_> {
+        ScopedAgent {
+            #[cfg(any(debug_assertions, feature = "debug-metrics"))]
+            agent: self,
+            #[cfg(not(any(debug_assertions, feature = "debug-metrics")))]
+            agent: std::marker::PhantomData,
+        }
+    }
+}
+
+#[cfg(any(debug_assertions, feature = "debug-metrics"))]
+impl ScopedAgent<'_> {
+    /// Whether calls record, to skip work that only feeds them
+    #[inline(always)]
+    pub const fn is_enabled(&self) -> bool {
+        true
+    }
+
+    #[inline(always)]
+    pub fn inc_counter(&self, name: &(impl MetricName + ?Sized)) {
+        self.agent.inc_counter(name);
+    }
+
+    #[inline(always)]
+    pub fn add_counter(&self, name: &(impl MetricName + ?Sized), n: u64) {
+        self.agent.add_counter(name, n);
+    }
+
+    #[inline(always)]
+    pub fn set_gauge(&self, name: &(impl MetricName + ?Sized), value: f64) {
+        self.agent.set_gauge(name, value);
+    }
+
+    #[inline(always)]
+    pub fn record_histogram(&self, name: &(impl MetricName + ?Sized), value: f64) {
+        self.agent.record_histogram(name, value);
+    }
+}
+
+#[cfg(not(any(debug_assertions, feature = "debug-metrics")))]
+impl ScopedAgent<'_> {
+    /// Whether calls record, to skip work that only feeds them
+    #[inline(always)]
+    pub const fn is_enabled(&self) -> bool {
+        false
+    }
+
+    #[inline(always)]
+    pub fn inc_counter(&self, _name: &(impl MetricName + ?Sized)) {}
+
+    #[inline(always)]
+    pub fn add_counter(&self, _name: &(impl MetricName + ?Sized), _n: u64) {}
+
+    #[inline(always)]
+    pub fn set_gauge(&self, _name: &(impl MetricName + ?Sized), _value: f64) {}
+
+    #[inline(always)]
+    pub fn record_histogram(&self, _name: &(impl MetricName + ?Sized), _value: f64) {}
+}
@@ -0,0 +1,338 @@
+//! Counters describing the agent's own push pipeline
+
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::Duration;
+
+use parking_lot::Mutex;
+
+use crate::{AgentError, ErrorInfo, PushErrorKind};
+
+/// Push pipeline counters shared with the push loop
+#[derive(Default)]
+pub(crate) struct PushStats {
+    pub(crate) batches_sent: AtomicU64,
+    pub(crate) bytes_sent: AtomicU64,
+    pub(crate) batches_dropped: AtomicU64,
+    pub(crate) push_interval_ms: AtomicU64,
+    /// Successful pushes that followed a failed one
+    reconnects: AtomicU64,
+    /// The latest push failed
+    disconnected: AtomicBool,
+    last_error: Mutex<Option<AgentError>>,
+    /// Address the push loop switched to last, if it did
+    endpoint: Mutex<Option<String>>,
+    endpoint_switches: AtomicU64,
+    /// Whole tokens left in the push loop's retry budget
+    retry_tokens: AtomicU64,
+    retries: AtomicU64,
+    retries_denied: AtomicU64,
+    /// Interval of the `Agent::burst_mode` going on; 0 without one
+    burst_interval_ms: AtomicU64,
+    /// Batches `Config::validate_before_send` found invalid
+    invalid_batches: AtomicU64,
+    /// Backoff the push loop is waiting out; 0 without one
+    backoff_ms: AtomicU64,
+    /// The push loop ended on an error before `stop()`
+    stopped: AtomicBool,
+}
+
+impl PushStats {
+    pub(crate) fn sent(&self, bytes: usize) {
+        self.batches_sent.fetch_add(1, Ordering::Relaxed);
+        self.bytes_sent.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub(crate) fn dropped(&self) {
+        self.batches_dropped.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// A push failed; the next one to succeed starts a new generation
+    pub(crate) fn disconnected(&self) {
+        self.disconnected.store(true, Ordering::Relaxed);
+    }
+
+    /// A connect or push failed
+    pub(crate) fn failed(&self, kind: PushErrorKind, err: &dyn std::fmt::Display) {
+        *self.last_error.lock() = Some(AgentError {
+            kind,
+            message: err.to_string(),
+        });
+    }
+
+    pub(crate) fn last_error(&self) -> Option<AgentError> {
+        self.last_error.lock().clone()
+    }
+
+    /// A push succeeded
+    pub(crate) fn connected(&self) {
+        if self.disconnected.swap(false, Ordering::Relaxed) {
+            self.reconnects.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// `TelemetryBatch::connection_generation` for batches collected now
+    pub(crate) fn connection_generation(&self) -> u64 {
+        self.reconnects.load(Ordering::Relaxed) + 1
+    }
+
+    /// The push loop now sends to `addr`
+    pub(crate) fn switched_endpoint(&self, addr: String) {
+        *self.endpoint.lock() = Some(addr);
+        self.endpoint_switches.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn endpoint(&self) -> Option<String> {
+        self.endpoint.lock().clone()
+    }
+
+    pub(crate) fn endpoint_switches(&self) -> u64 {
+        self.endpoint_switches.load(Ordering::Relaxed)
+    }
+
+    /// The push loop's retry budget now; returns the retries made and
+    /// denied since the last call
+    pub(crate) fn update_retry_budget(&self, tokens: f64, retries: u64, denied: u64) -> (u64, u64) {
+        self.retry_tokens.store(tokens as u64, Ordering::Relaxed);
+        (
+            retries - self.retries.swap(retries, Ordering::Relaxed),
+            denied - self.retries_denied.swap(denied, Ordering::Relaxed),
+        )
+    }
+
+    pub(crate) fn retry_tokens(&self) -> u64 {
+        self.retry_tokens.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn retries(&self) -> u64 {
+        self.retries.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn retries_denied(&self) -> u64 {
+        self.retries_denied.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn set_burst_interval(&self, interval: Option<Duration>) {
+        let ms = interval.map_or(0, |interval| interval.as_millis().max(1) as u64);
+        self.burst_interval_ms.store(ms, Ordering::Relaxed);
+    }
+
+    pub(crate) fn burst_interval(&self) -> Option<Duration> {
+        match self.burst_interval_ms.load(Ordering::Relaxed) {
+            0 => None,
+            ms => Some(Duration::from_millis(ms)),
+        }
+    }
+
+    pub(crate) fn invalid_batch(&self) {
+        self.invalid_batches.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn invalid_batches(&self) -> u64 {
+        self.invalid_batches.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn set_backoff(&self, backoff: Option<Duration>) {
+        let ms = backoff.map_or(0, |backoff| backoff.as_millis().max(1) as u64);
+        self.backoff_ms.store(ms, Ordering::Relaxed);
+    }
+
+    pub(crate) fn backoff(&self) -> Option<Duration> {
+        match self.backoff_ms.load(Ordering::Relaxed) {
+            0 => None,
+            ms => Some(Duration::from_millis(ms)),
+        }
+    }
+
+    /// The push loop gave up for good, short of `stop()`
+    pub(crate) fn push_stopped(&self) {
+        self.stopped.store(true, Ordering::Relaxed);
+    }
+
+    pub(crate) fn is_push_stopped(&self) -> bool {
+        self.stopped.load(Ordering::Relaxed)
+    }
+
+    /// The interval the push loop ticks at; zero before `start()`
+    pub(crate) fn push_interval(&self) -> Duration {
+        Duration::from_millis(self.push_interval_ms.load(Ordering::Relaxed))
+    }
+
+    pub(crate) fn set_push_interval(&self, interval: Duration) {
+        self.push_interval_ms
+            .store(interval.as_millis() as u64, Ordering::Relaxed);
+    }
+}
+
+/// Point-in-time view of the agent's internals, from `Agent::diagnostics`
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Diagnostics {
+    /// Batches acknowledged by the aggregator
+    pub batches_sent: u64,
+    /// Encoded protobuf bytes of acknowledged batches
+    pub bytes_sent: u64,
+    /// Batches lost to failed pushes (there is no retry buffer)
+    pub batches_dropped: u64,
+    /// Interval the push loop ticks at, after remote directives and
+    /// `Config::auto_relax_interval`; zero until `start()`, and for agents
+    /// on a `TransportPool`
+    pub push_interval: Duration,
+    /// Stamped on every batch; random per process
+    pub instance_epoch: u64,
+    /// Stamped on batches collected now: 1, plus one for every push that
+    /// succeeded after a failed one
+    pub connection_generation: u64,
+    pub gauge_series: usize,
+    pub counter_series: usize,
+    pub histogram_series: usize,
+    /// Latest error per type from `record_error_detailed`
+    pub last_errors: Vec<ErrorInfo>,
+    /// The latest failed connect or push, kept after later successes. Its
+    /// kind tells a TCP connect that timed out (`ConnectTimeout`) from an
+    /// aggregator that accepted the connection but never answered
+    /// (`HandshakeTimeout`).
+    pub last_push_error: Option<AgentError>,
+    /// Where batches go after `Agent::set_endpoint` or a reloaded
+    /// `aggregator_addr`; `None` while still `Config::aggregator_addr`
+    pub endpoint: Option<String>,
+    /// Switches of the push loop to another aggregator
+    pub endpoint_switches: u64,
+    /// Registry tables rebuilt by compaction (`Config::compact_threshold`,
+    /// `Agent::compact`)
+    pub compactions: u64,
+    /// Table slots those rebuilds gave back
+    pub reclaimed_slots: u64,
+    /// Whole tokens in the retry budget (`Config::retry_budget_ratio`);
+    /// zero means batches wait while the aggregator keeps failing
+    pub retry_tokens: u64,
+    /// Pushes made right after a failed one
+    pub retries: u64,
+    /// Times a batch waited for want of a retry token
+    pub retries_denied: u64,
+    /// Interval requested by the `Agent::burst_mode` going on, which
+    /// reverts by itself; `push_interval` is the interval in effect
+    pub burst_interval: Option<Duration>,
+    /// Sub-batches sent and failed per metric group, with
+    /// `Config::group_batches`; in group order
+    pub groups: Vec<GroupDiagnostics>,
+    /// Request latencies left out of their histograms for exceeding
+    /// `Config::max_plausible_latency`, since the agent was created
+    pub latency_outliers: u64,
+    /// Batches sent although they broke an invariant of
+    /// `telemetry::validate`, with `Config::validate_before_send` in a
+    /// release build; a debug build panics instead
+    pub invalid_batches: u64,
+    /// Wait before the next push after the latest failure, while it lasts.
+    /// Minutes rather than seconds after the aggregator refused the
+    /// credentials (`Unauthenticated`, `PermissionDenied`).
+    pub push_backoff: Option<Duration>,
+    /// The push loop ended on an error no push can recover from, so
+    /// nothing is sent until the agent is started again
+    pub push_stopped: bool,
+}
+
+/// One metric group's pushes, from `Diagnostics::groups`
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct GroupDiagnostics {
+    pub group: String,
+    /// Sub-batches acknowledged
+    pub sent: u64,
+    /// Sub-batches that failed or weren't answered in time
+    pub failed: u64,
+}
+
+/// Offset of this host's clock from the aggregator's, from
+/// `Agent::estimated_clock_skew`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ClockSkew {
+    /// This host's clock reads later than the aggregator's
+    Ahead(Duration),
+    /// This host's clock reads earlier than the aggregator's
+    Behind(Duration),
+}
+
+/// Estimated bytes held by the agent, from `Agent::memory_usage`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MemoryUsage {
+    /// Registered series: keys, registry tables and metric storage
+    pub registry_bytes: usize,
+    /// Collected batches waiting for the sender
+    pub buffer_bytes: usize,
+    /// Events queued for the next batch
+    pub events_bytes: usize,
+}
+
+impl MemoryUsage {
+    pub fn total(&self) -> usize {
+        self.registry_bytes + self.buffer_bytes + self.events_bytes
+    }
+}
+
+/// Outcome of a `run_scoped` job's pushes
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct JobReport {
+    /// Whether the final flush was acknowledged
+    pub delivered: bool,
+    /// Metrics in acknowledged batches, mid-run flushes included
+    pub metrics_sent: usize,
+    /// Why the final flush failed, after retries
+    pub error: Option<AgentError>,
+}
+
+/// What `Agent::stop` flushed, with lifetime totals as of the push loop's
+/// exit
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ShutdownReport {
+    /// Metrics in the batch collected at shutdown
+    pub final_flush_metrics: usize,
+    /// Whether that batch was acknowledged; also true if there was nothing
+    /// left to send
+    pub final_flush_delivered: bool,
+    pub batches_dropped: u64,
+    /// Encoded protobuf bytes of acknowledged batches
+    pub bytes_sent: u64,
+    /// Since `start()`, or since creation for agents on a `TransportPool`
+    pub uptime: Duration,
+    /// Whether the aggregator acknowledged a batch marked `draining`, from
+    /// `Agent::drain` or the final flush
+    pub drain_acknowledged: bool,
+    /// Pushes acknowledged once shutdown began, the final flush's included
+    pub shutdown_delivered: u64,
+    /// Batches written to `Config::spool_dir` instead, at the deadline
+    pub shutdown_spooled: u64,
+    /// Batches lost once shutdown began: queued or in flight at the
+    /// deadline with no spool, or failed for good
+    pub shutdown_dropped: u64,
+    /// Where the last of the batches went
+    pub shutdown_path: ShutdownPath,
+}
+
+/// How a shutdown ended, from `ShutdownReport::shutdown_path`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ShutdownPath {
+    /// Everything was delivered, or there was nothing to send
+    #[default]
+    Delivered,
+    /// What couldn't be delivered by the deadline was spooled to disk
+    Spooled,
+    /// Some batches were lost
+    Dropped,
+}
+
+/// Aggregate view of a `TransportPool`, from `TransportPool::diagnostics`
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PoolDiagnostics {
+    /// Agent batches acknowledged, summed over all agents
+    pub batches_sent: u64,
+    pub bytes_sent: u64,
+    pub batches_dropped: u64,
+    /// Registered agents, in registration order
+    pub agents: Vec<PoolAgentDiagnostics>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PoolAgentDiagnostics {
+    pub service: String,
+    pub instance: String,
+    pub batches_sent: u64,
+    pub batches_dropped: u64,
+}
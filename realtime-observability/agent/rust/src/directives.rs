@@ -0,0 +1,84 @@
+//! Runtime directives pushed by the aggregator in `Ack` responses
+
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::Duration;
+
+use crate::telemetry::AgentDirectives;
+
+/// Lower bound for a remotely requested push interval
+pub const MIN_REMOTE_INTERVAL: Duration = Duration::from_millis(10);
+/// Upper bound for a remotely requested push interval
+pub const MAX_REMOTE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Directive values currently in effect, shared between the push loop and
+/// the recording API
+pub(crate) struct RemoteState {
+    push_interval_ms: AtomicU64,
+    /// Keep one histogram record out of every `sample_every`
+    sample_every: AtomicU64,
+    sample_tick: AtomicU64,
+    paused: AtomicBool,
+}
+
+impl RemoteState {
+    pub(crate) fn new(push_interval: Duration) -> Self {
+        Self {
+            push_interval_ms: AtomicU64::new(push_interval.as_millis() as u64),
+            sample_every: AtomicU64::new(1),
+            sample_tick: AtomicU64::new(0),
+            paused: AtomicBool::new(false),
+        }
+    }
+
+    /// Apply directives on top of the local defaults, returning the push
+    /// interval now in effect
+    pub(crate) fn apply(
+        &self,
+        directives: &AgentDirectives,
+        default_interval: Duration,
+    ) -> Duration {
+        let interval = if directives.push_interval_ms == 0 {
+            default_interval
+        } else {
+            Duration::from_millis(directives.push_interval_ms)
+                .clamp(MIN_REMOTE_INTERVAL, MAX_REMOTE_INTERVAL)
+        };
+
+        let sample_rate = if directives.sample_rate > 0.0 && directives.sample_rate <= 1.0 {
+            directives.sample_rate
+        } else {
+            1.0
+        };
+
+        self.push_interval_ms
+            .store(interval.as_millis() as u64, Ordering::Relaxed);
+        self.sample_every
+            .store((1.0 / sample_rate).round() as u64, Ordering::Relaxed);
+        self.paused.store(directives.paused, Ordering::Relaxed);
+
+        interval
+    }
+
+    /// Whether the next histogram record should be kept
+    #[inline]
+    pub(crate) fn sample(&self) -> bool {
+        let every = self.sample_every.load(Ordering::Relaxed);
+        every <= 1
+            || self
+                .sample_tick
+                .fetch_add(1, Ordering::Relaxed)
+                .is_multiple_of(every)
+    }
+
+    pub(crate) fn paused(&self) -> bool {
+        self.paused.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn push_interval_ms(&self) -> u64 {
+        self.push_interval_ms.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn sample_rate(&self) -> f64 {
+        1.0 / self.sample_every.load(Ordering::Relaxed).max(1) as f64
+    }
+}
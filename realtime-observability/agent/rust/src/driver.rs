@@ -0,0 +1,622 @@
+//! The push loop's decisions, apart from clocks and sockets
+//!
+//! `PushDriver` is told what happened (a batch was collected, a push
+//! finished, a backoff ran out, shutdown began, the flush timeout passed)
+//! and answers with `Action`s for the caller to carry out. The agent's push
+//! loop wires it to timers and the transport; tests feed it events one at a
+//! time.
+//!
+//! One push is in flight at a time. Batches collected meanwhile wait in a
+//! bounded queue, and a batch collected while the queue is full is dropped.
+//! A failed push is not retried, but delays the next one by a backoff that
+//! doubles with each consecutive failure. A batch the aggregator rejected
+//! (`InvalidArgument`, `Unimplemented`) is dropped and the loop goes on; a
+//! push it refused to authorize (`Unauthenticated`, `PermissionDenied`)
+//! waits out a much longer backoff, in case the credentials are fixed
+//! meanwhile. Any other non-retryable failure stops the loop. A batch can
+//! be put back in the queue, as when part of a grouped
+//! push failed (see `groups`). With a `RetryBudget`, pushes after a
+//! failure also need a token, and batches wait in the queue for one;
+//! shutdown drains regardless. Shutdown with a deadline retries failed
+//! batches too, until the caller's timer calls `on_flush_timeout`.
+
+use std::collections::VecDeque;
+use std::time::Duration;
+
+use crate::retry_budget::RetryBudget;
+use crate::PushErrorKind;
+
+/// Wait after the first failure in a row; doubled after every further one
+pub(crate) const INITIAL_BACKOFF: Duration = Duration::from_millis(100);
+pub(crate) const MAX_BACKOFF: Duration = Duration::from_secs(10);
+/// Wait after the first refused authorization in a row; doubled likewise
+pub(crate) const AUTH_BACKOFF: Duration = Duration::from_secs(30);
+pub(crate) const MAX_AUTH_BACKOFF: Duration = Duration::from_secs(300);
+
+/// What the loop should do next
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum Action<B> {
+    /// Push this batch now
+    Send(B),
+    /// The batch waits behind the push in flight or the backoff
+    Buffer,
+    /// The batch is lost: the queue was full, the loop is stopping, or
+    /// the flush timeout passed
+    Drop(B),
+    /// Wait this long, then call `on_backoff_done`
+    Backoff(Duration),
+    /// End the loop; nothing is queued
+    Stop,
+}
+
+pub(crate) struct PushDriver<B> {
+    queue: VecDeque<B>,
+    capacity: usize,
+    sending: bool,
+    backing_off: bool,
+    /// Consecutive failed pushes
+    failures: u32,
+    shutting_down: bool,
+    stopped: bool,
+    budget: Option<RetryBudget>,
+}
+
+impl<B> PushDriver<B> {
+    /// A driver queueing up to `capacity` batches behind the one in flight
+    pub(crate) fn new(capacity: usize) -> Self {
+        Self {
+            queue: VecDeque::with_capacity(capacity),
+            capacity,
+            sending: false,
+            backing_off: false,
+            failures: 0,
+            shutting_down: false,
+            stopped: false,
+            budget: None,
+        }
+    }
+
+    /// Limit pushes after a failure to what `budget` allows
+    pub(crate) fn with_retry_budget(mut self, budget: RetryBudget) -> Self {
+        self.budget = Some(budget);
+        self
+    }
+
+    pub(crate) fn retry_budget(&self) -> Option<&RetryBudget> {
+        self.budget.as_ref()
+    }
+
+    /// A batch was collected
+    pub(crate) fn on_tick(&mut self, batch: B) -> Vec<Action<B>> {
+        if self.stopped {
+            return vec![Action::Drop(batch)];
+        }
+        if let Some(budget) = &mut self.budget {
+            budget.on_batch();
+        }
+        if !self.sending && !self.backing_off && self.may_send() {
+            self.sending = true;
+            // Batches held for a retry token go first
+            let batch = match self.queue.pop_front() {
+                Some(oldest) => {
+                    self.queue.push_back(batch);
+                    oldest
+                }
+                None => batch,
+            };
+            return vec![Action::Send(batch)];
+        }
+        if self.queue.len() < self.capacity {
+            self.queue.push_back(batch);
+            return vec![Action::Buffer];
+        }
+        vec![Action::Drop(batch)]
+    }
+
+    /// A batch from the push in flight goes back in the queue, before
+    /// `on_push_result` picks what to send next
+    pub(crate) fn requeue(&mut self, batch: B) -> Vec<Action<B>> {
+        if self.stopped || self.queue.len() >= self.capacity {
+            return vec![Action::Drop(batch)];
+        }
+        self.queue.push_back(batch);
+        vec![Action::Buffer]
+    }
+
+    /// The batch of the push in flight, which failed, is to be sent again
+    /// before anything queued, as during shutdown with a deadline; call
+    /// before `on_push_result`. It had its place as the push in flight, so
+    /// it is not dropped for a full queue.
+    pub(crate) fn retry(&mut self, batch: B) -> Vec<Action<B>> {
+        if self.stopped {
+            return vec![Action::Drop(batch)];
+        }
+        self.queue.push_front(batch);
+        vec![Action::Buffer]
+    }
+
+    /// The push in flight finished
+    pub(crate) fn on_push_result(&mut self, result: Result<(), PushErrorKind>) -> Vec<Action<B>> {
+        self.sending = false;
+        match result {
+            Ok(()) => {
+                self.failures = 0;
+                if let Some(budget) = &mut self.budget {
+                    budget.on_success();
+                }
+                self.next()
+            }
+            // The aggregator answered, so the next batch may well get through
+            Err(PushErrorKind::InvalidArgument | PushErrorKind::Unimplemented) => self.next(),
+            Err(PushErrorKind::Unauthenticated | PushErrorKind::PermissionDenied) => {
+                self.failures += 1;
+                // Nothing queued would get through before the flush timeout
+                if self.shutting_down {
+                    return self.stop();
+                }
+                self.backing_off = true;
+                vec![Action::Backoff(self.auth_backoff())]
+            }
+            Err(kind) if !kind.is_retryable() => self.stop(),
+            Err(_) => {
+                self.failures += 1;
+                if self.shutting_down && self.queue.is_empty() {
+                    return self.stop();
+                }
+                self.backing_off = true;
+                vec![Action::Backoff(self.backoff())]
+            }
+        }
+    }
+
+    /// The backoff from `Action::Backoff` ran out
+    pub(crate) fn on_backoff_done(&mut self) -> Vec<Action<B>> {
+        self.backing_off = false;
+        self.next()
+    }
+
+    /// Shutdown began, with the batch collected for it if there was one.
+    /// Queued batches are still sent; no more ticks follow.
+    pub(crate) fn on_shutdown(&mut self, final_batch: Option<B>) -> Vec<Action<B>> {
+        self.shutting_down = true;
+        let mut actions = match final_batch {
+            Some(batch) => self.on_tick(batch),
+            None => Vec::new(),
+        };
+        if !self.sending && !self.backing_off {
+            // Batches held for a retry token, if any, go now
+            actions.extend(self.next());
+        }
+        actions
+    }
+
+    /// The flush timeout passed during shutdown: give up on everything
+    /// queued. The push in flight, if any, is the caller's to abandon.
+    pub(crate) fn on_flush_timeout(&mut self) -> Vec<Action<B>> {
+        self.sending = false;
+        self.stop()
+    }
+
+    /// Take the oldest queued batch, to shed memory
+    pub(crate) fn evict_oldest(&mut self) -> Option<B> {
+        self.queue.pop_front()
+    }
+
+    #[cfg(test)]
+    pub(crate) fn queued(&self) -> usize {
+        self.queue.len()
+    }
+
+    /// Delay before the next push after the latest failure
+    fn backoff(&self) -> Duration {
+        let doublings = self.failures.saturating_sub(1).min(16);
+        INITIAL_BACKOFF
+            .saturating_mul(1 << doublings)
+            .min(MAX_BACKOFF)
+    }
+
+    /// Delay before the next push after the latest refused authorization
+    fn auth_backoff(&self) -> Duration {
+        let doublings = self.failures.saturating_sub(1).min(16);
+        AUTH_BACKOFF
+            .saturating_mul(1 << doublings)
+            .min(MAX_AUTH_BACKOFF)
+    }
+
+    fn next(&mut self) -> Vec<Action<B>> {
+        if self.queue.is_empty() {
+            return if self.shutting_down {
+                self.stop()
+            } else {
+                Vec::new()
+            };
+        }
+        if !self.may_send() {
+            return Vec::new();
+        }
+        self.sending = true;
+        let batch = self.queue.pop_front().expect("checked above");
+        vec![Action::Send(batch)]
+    }
+
+    /// Whether a push may start now, spending a retry token if the last
+    /// one failed
+    fn may_send(&mut self) -> bool {
+        if self.failures == 0 || self.shutting_down {
+            return true;
+        }
+        self.budget.as_mut().is_none_or(RetryBudget::try_retry)
+    }
+
+    fn stop(&mut self) -> Vec<Action<B>> {
+        self.stopped = true;
+        self.backing_off = false;
+        let mut actions: Vec<_> = self.queue.drain(..).map(Action::Drop).collect();
+        actions.push(Action::Stop);
+        actions
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use Action::*;
+
+    const UNAVAILABLE: Result<(), PushErrorKind> = Err(PushErrorKind::Unavailable);
+
+    #[test]
+    fn test_sends_one_at_a_time() {
+        let mut driver = PushDriver::new(2);
+        assert_eq!(driver.on_tick(1), vec![Send(1)]);
+        assert_eq!(driver.on_tick(2), vec![Buffer]);
+        assert_eq!(driver.on_tick(3), vec![Buffer]);
+        assert_eq!(driver.on_push_result(Ok(())), vec![Send(2)]);
+        assert_eq!(driver.on_push_result(Ok(())), vec![Send(3)]);
+        assert_eq!(driver.on_push_result(Ok(())), vec![]);
+        assert_eq!(driver.on_tick(4), vec![Send(4)]);
+    }
+
+    #[test]
+    fn test_buffer_overflow_during_backoff() {
+        let mut driver = PushDriver::new(2);
+        driver.on_tick(1);
+        assert_eq!(
+            driver.on_push_result(UNAVAILABLE),
+            vec![Backoff(INITIAL_BACKOFF)]
+        );
+
+        // Nothing is sent while backing off; the newest batch is the one lost
+        assert_eq!(driver.on_tick(2), vec![Buffer]);
+        assert_eq!(driver.on_tick(3), vec![Buffer]);
+        assert_eq!(driver.on_tick(4), vec![Drop(4)]);
+        assert_eq!(driver.on_backoff_done(), vec![Send(2)]);
+        assert_eq!(driver.queued(), 1);
+    }
+
+    #[test]
+    fn test_backoff_doubles_until_success() {
+        let mut driver = PushDriver::new(4);
+        let mut backoffs = Vec::new();
+        driver.on_tick(0);
+        for batch in 1..10 {
+            match &driver.on_push_result(UNAVAILABLE)[..] {
+                [Backoff(wait)] => backoffs.push(wait.as_millis()),
+                other => panic!("expected a backoff, got {:?}", other),
+            }
+            driver.on_tick(batch);
+            assert_eq!(driver.on_backoff_done(), vec![Send(batch)]);
+        }
+        assert_eq!(
+            backoffs,
+            [100, 200, 400, 800, 1600, 3200, 6400, 10_000, 10_000]
+        );
+
+        // Success resets the streak
+        assert_eq!(driver.on_push_result(Ok(())), vec![]);
+        driver.on_tick(10);
+        assert_eq!(
+            driver.on_push_result(UNAVAILABLE),
+            vec![Backoff(INITIAL_BACKOFF)]
+        );
+    }
+
+    #[test]
+    fn test_rejected_batch_is_dropped_and_pushing_goes_on() {
+        let mut driver = PushDriver::new(4);
+        driver.on_tick(1);
+        driver.on_tick(2);
+        driver.on_tick(3);
+        // Batch 1 is gone; the next is sent at once, without a backoff
+        assert_eq!(
+            driver.on_push_result(Err(PushErrorKind::InvalidArgument)),
+            vec![Send(2)]
+        );
+        assert_eq!(
+            driver.on_push_result(Err(PushErrorKind::Unimplemented)),
+            vec![Send(3)]
+        );
+        assert_eq!(driver.on_push_result(Ok(())), vec![]);
+        assert_eq!(driver.on_tick(4), vec![Send(4)]);
+    }
+
+    #[test]
+    fn test_refused_authorization_backs_off_long() {
+        let mut driver = PushDriver::new(2);
+        let mut backoffs = Vec::new();
+        driver.on_tick(0);
+        for batch in 1..7 {
+            let refused = match batch % 2 {
+                0 => PushErrorKind::Unauthenticated,
+                _ => PushErrorKind::PermissionDenied,
+            };
+            match &driver.on_push_result(Err(refused))[..] {
+                [Backoff(wait)] => backoffs.push(wait.as_secs()),
+                other => panic!("expected a backoff, got {:?}", other),
+            }
+            // Batches wait meanwhile, as for any backoff
+            assert_eq!(driver.on_tick(batch), vec![Buffer]);
+            assert_eq!(driver.on_backoff_done(), vec![Send(batch)]);
+        }
+        assert_eq!(backoffs, [30, 60, 120, 240, 300, 300]);
+
+        // Credentials fixed: back to the usual backoff
+        assert_eq!(driver.on_push_result(Ok(())), vec![]);
+        driver.on_tick(7);
+        assert_eq!(
+            driver.on_push_result(UNAVAILABLE),
+            vec![Backoff(INITIAL_BACKOFF)]
+        );
+    }
+
+    #[test]
+    fn test_refused_authorization_during_shutdown_stops() {
+        let mut driver = PushDriver::new(4);
+        driver.on_tick(1);
+        assert_eq!(driver.on_shutdown(Some(2)), vec![Buffer]);
+        assert_eq!(
+            driver.on_push_result(Err(PushErrorKind::Unauthenticated)),
+            vec![Drop(2), Stop]
+        );
+    }
+
+    #[test]
+    fn test_other_non_retryable_failure_stops() {
+        let mut driver = PushDriver::new(4);
+        driver.on_tick(1);
+        driver.on_tick(2);
+        driver.on_tick(3);
+        assert_eq!(
+            driver.on_push_result(Err(PushErrorKind::NotStarted)),
+            vec![Drop(2), Drop(3), Stop]
+        );
+        assert_eq!(driver.on_tick(4), vec![Drop(4)]);
+    }
+
+    #[test]
+    fn test_shutdown_drains_the_queue() {
+        let mut driver = PushDriver::new(4);
+        driver.on_tick(1);
+        driver.on_tick(2);
+        assert_eq!(driver.on_shutdown(Some(3)), vec![Buffer]);
+        assert_eq!(driver.on_push_result(Ok(())), vec![Send(2)]);
+        assert_eq!(driver.on_push_result(Ok(())), vec![Send(3)]);
+        assert_eq!(driver.on_push_result(Ok(())), vec![Stop]);
+    }
+
+    #[test]
+    fn test_idle_shutdown_stops_at_once() {
+        let mut driver = PushDriver::<u32>::new(4);
+        assert_eq!(driver.on_shutdown(None), vec![Stop]);
+
+        let mut driver = PushDriver::new(4);
+        assert_eq!(driver.on_shutdown(Some(1)), vec![Send(1)]);
+        assert_eq!(driver.on_push_result(Ok(())), vec![Stop]);
+    }
+
+    #[test]
+    fn test_outage_mid_flush() {
+        let mut driver = PushDriver::new(4);
+        driver.on_tick(1);
+        driver.on_tick(2);
+        assert_eq!(driver.on_shutdown(Some(3)), vec![Buffer]);
+
+        // Every push fails; each failure backs off before the next batch
+        assert_eq!(
+            driver.on_push_result(UNAVAILABLE),
+            vec![Backoff(INITIAL_BACKOFF)]
+        );
+        assert_eq!(driver.on_backoff_done(), vec![Send(2)]);
+        assert_eq!(
+            driver.on_push_result(UNAVAILABLE),
+            vec![Backoff(INITIAL_BACKOFF * 2)]
+        );
+        // The flush timeout passes mid-backoff
+        assert_eq!(driver.on_flush_timeout(), vec![Drop(3), Stop]);
+    }
+
+    #[test]
+    fn test_outage_ends_at_the_last_batch() {
+        let mut driver = PushDriver::new(4);
+        driver.on_tick(1);
+        assert_eq!(driver.on_shutdown(None), vec![]);
+        // No retry buffer: a failed last batch has nothing left to wait for
+        assert_eq!(driver.on_push_result(UNAVAILABLE), vec![Stop]);
+    }
+
+    #[test]
+    fn test_reconnect_during_shutdown() {
+        let mut driver = PushDriver::new(4);
+        driver.on_tick(1);
+        assert_eq!(
+            driver.on_push_result(UNAVAILABLE),
+            vec![Backoff(INITIAL_BACKOFF)]
+        );
+        driver.on_tick(2);
+
+        // Shutdown lands mid-backoff; the connection comes back for the drain
+        assert_eq!(driver.on_shutdown(Some(3)), vec![Buffer]);
+        assert_eq!(driver.on_backoff_done(), vec![Send(2)]);
+        assert_eq!(driver.on_push_result(Ok(())), vec![Send(3)]);
+        assert_eq!(driver.on_push_result(Ok(())), vec![Stop]);
+    }
+
+    /// Drive a shutdown with a `grace` deadline against an endpoint that
+    /// fails until `recovers_at`, advancing the clock by each backoff;
+    /// returns the batches delivered and when, and those dropped
+    fn shutdown_against(
+        recovers_at: Duration,
+        grace: Duration,
+    ) -> (Vec<(u32, Duration)>, Vec<u32>) {
+        let mut driver = PushDriver::new(4);
+        let mut now = Duration::ZERO;
+        let mut in_flight = None;
+        let mut delivered = Vec::new();
+        let mut dropped = Vec::new();
+        let mut actions = driver.on_tick(1);
+        actions.extend(driver.on_shutdown(Some(2)));
+        loop {
+            assert!(!actions.is_empty() || in_flight.is_some(), "stalled");
+            let mut next = Vec::new();
+            for action in actions {
+                match action {
+                    Send(batch) => in_flight = Some(batch),
+                    Buffer => {}
+                    Drop(batch) => dropped.push(batch),
+                    Backoff(wait) if now + wait >= grace => {
+                        now = grace;
+                        next.extend(driver.on_flush_timeout());
+                    }
+                    Backoff(wait) => {
+                        now += wait;
+                        next.extend(driver.on_backoff_done());
+                    }
+                    Stop => return (delivered, dropped),
+                }
+            }
+            if let Some(batch) = in_flight.take() {
+                if now >= recovers_at {
+                    delivered.push((batch, now));
+                    next.extend(driver.on_push_result(Ok(())));
+                } else {
+                    next.extend(driver.retry(batch));
+                    next.extend(driver.on_push_result(UNAVAILABLE));
+                }
+            }
+            actions = next;
+        }
+    }
+
+    #[test]
+    fn test_shutdown_retries_until_the_endpoint_recovers() {
+        let grace = Duration::from_secs(30);
+        let (delivered, dropped) = shutdown_against(Duration::from_secs(5), grace);
+        // Backoffs of 0.1s, doubling, add up to 6.3s before the first
+        // attempt past 5s; both batches go then, in order
+        let recovered = Duration::from_millis(6_300);
+        assert_eq!(delivered, [(1, recovered), (2, recovered)]);
+        assert!(dropped.is_empty());
+    }
+
+    #[test]
+    fn test_shutdown_gives_up_at_the_deadline() {
+        let (delivered, dropped) = shutdown_against(Duration::MAX, Duration::from_secs(30));
+        assert!(delivered.is_empty());
+        assert_eq!(dropped, [1, 2]);
+    }
+
+    #[test]
+    fn test_requeued_batch_goes_next() {
+        let mut driver = PushDriver::new(2);
+        driver.on_tick(1);
+        // A failed part of batch 1 comes back while it is still in flight
+        assert_eq!(driver.requeue(10), vec![Buffer]);
+        assert_eq!(driver.on_tick(2), vec![Buffer]);
+        assert_eq!(driver.requeue(11), vec![Drop(11)]);
+        assert_eq!(driver.on_push_result(Ok(())), vec![Send(10)]);
+        assert_eq!(driver.on_push_result(Ok(())), vec![Send(2)]);
+    }
+
+    /// Push every batch into an aggregator that is down, backoffs running
+    /// out at once; returns how many pushes were made
+    fn pushes_during_outage(driver: &mut PushDriver<u32>, ticks: u32) -> u32 {
+        let mut pushes = 0;
+        for batch in 0..ticks {
+            let mut actions = driver.on_tick(batch);
+            while let Some(action) = actions.pop() {
+                match action {
+                    Send(_) => {
+                        pushes += 1;
+                        actions.extend(driver.on_push_result(UNAVAILABLE));
+                    }
+                    Backoff(_) => actions.extend(driver.on_backoff_done()),
+                    _ => {}
+                }
+            }
+        }
+        pushes
+    }
+
+    #[test]
+    fn test_retries_converge_to_the_reserve() {
+        let mut unlimited = PushDriver::new(4);
+        assert_eq!(pushes_during_outage(&mut unlimited, 1000), 1000);
+
+        // One token per 16 batches, so the sums below are exact
+        let mut driver = PushDriver::new(4).with_retry_budget(RetryBudget::new(0.2, 0.0625));
+        let first = pushes_during_outage(&mut driver, 1000);
+        // The first push, then the bucket and whatever the reserve added
+        assert_eq!(first, 1 + crate::retry_budget::MAX_TOKENS as u32 + 999 / 16);
+        let steady = pushes_during_outage(&mut driver, 1000);
+        assert!(steady.abs_diff(1000 / 16) <= 1, "{} pushes", steady);
+
+        let budget = driver.retry_budget().unwrap();
+        assert_eq!(budget.retries() as u32, first + steady - 1);
+        assert!(budget.denied() > 1800);
+    }
+
+    #[test]
+    fn test_batches_wait_for_a_retry_token() {
+        let mut driver = PushDriver::new(2).with_retry_budget(RetryBudget::new(0.5, 0.5));
+        driver.on_tick(0);
+        for _ in 0..crate::retry_budget::MAX_TOKENS as usize {
+            driver.budget.as_mut().unwrap().try_retry();
+        }
+        assert_eq!(
+            driver.on_push_result(UNAVAILABLE),
+            vec![Backoff(INITIAL_BACKOFF)]
+        );
+        assert_eq!(driver.on_tick(1), vec![Buffer]);
+        // Half a token from that batch, and none to spare
+        assert_eq!(driver.on_backoff_done(), vec![]);
+        assert_eq!(driver.on_tick(2), vec![Send(1)]);
+        assert_eq!(driver.queued(), 1);
+
+        // A success needs no token, and refills the bucket
+        assert_eq!(driver.on_push_result(Ok(())), vec![Send(2)]);
+    }
+
+    #[test]
+    fn test_shutdown_skips_the_retry_budget() {
+        let mut driver = PushDriver::new(4).with_retry_budget(RetryBudget::new(0.2, 0.01));
+        driver.on_tick(0);
+        for _ in 0..crate::retry_budget::MAX_TOKENS as usize {
+            driver.budget.as_mut().unwrap().try_retry();
+        }
+        driver.on_push_result(UNAVAILABLE);
+        driver.on_backoff_done();
+        assert_eq!(driver.on_tick(1), vec![Buffer]);
+        assert_eq!(driver.on_shutdown(None), vec![Send(1)]);
+        assert_eq!(driver.on_push_result(UNAVAILABLE), vec![Stop]);
+    }
+
+    #[test]
+    fn test_evicts_oldest_first() {
+        let mut driver = PushDriver::new(4);
+        driver.on_tick(1);
+        driver.on_tick(2);
+        driver.on_tick(3);
+        assert_eq!(driver.evict_oldest(), Some(2));
+        assert_eq!(driver.on_push_result(Ok(())), vec![Send(3)]);
+        assert_eq!(driver.evict_oldest(), None);
+    }
+}
@@ -0,0 +1,203 @@
+//! Which metrics lost data, and why (`Agent::drop_report`)
+//!
+//! Every record, registration, event or sample the agent throws away is
+//! counted against the metric it was meant for. The log keeps at most
+//! `TRACKED_METRICS` metrics: one dropping for the first time while the log
+//! is full takes the place of the one with the fewest drops, so a flood of
+//! distinct names costs a bounded table and the noisiest metrics stay in it.
+
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use parking_lot::Mutex;
+
+/// Metrics whose drops are kept
+const TRACKED_METRICS: usize = 128;
+
+/// Why data was dropped, the `reason` label of `agent_drops_total`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum DropReason {
+    SampledOut,
+    QueueDropped,
+    Filtered,
+    Truncated,
+    TypeConflict,
+}
+
+impl DropReason {
+    pub(crate) const ALL: [DropReason; 5] = [
+        DropReason::SampledOut,
+        DropReason::QueueDropped,
+        DropReason::Filtered,
+        DropReason::Truncated,
+        DropReason::TypeConflict,
+    ];
+
+    pub(crate) fn as_str(&self) -> &'static str {
+        match self {
+            DropReason::SampledOut => "sampled_out",
+            DropReason::QueueDropped => "queue_dropped",
+            DropReason::Filtered => "filtered",
+            DropReason::Truncated => "truncated",
+            DropReason::TypeConflict => "type_conflict",
+        }
+    }
+}
+
+/// Drops counted against one metric since the agent started, or since it
+/// last made room in the log for it
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DropStats {
+    /// Metric name, or event name for dropped events
+    pub metric: String,
+    /// Histogram records skipped by the remote `sample_rate` directive
+    pub sampled_out: u64,
+    /// Events pushed out of the full event queue, and samples in batches
+    /// dropped or evicted from the send queue
+    pub queue_dropped: u64,
+    /// Registrations and records refused by `Config::metric_filter`
+    pub filtered: u64,
+    /// Names and label sets cut to the `Config` limits; the data is kept,
+    /// but in a series other than the one asked for
+    pub truncated: u64,
+    /// Records of a name as another metric type than its first use
+    pub type_conflict: u64,
+}
+
+impl DropStats {
+    pub fn total(&self) -> u64 {
+        self.sampled_out + self.queue_dropped + self.filtered + self.truncated + self.type_conflict
+    }
+
+    pub(crate) fn count(&self, reason: DropReason) -> u64 {
+        match reason {
+            DropReason::SampledOut => self.sampled_out,
+            DropReason::QueueDropped => self.queue_dropped,
+            DropReason::Filtered => self.filtered,
+            DropReason::Truncated => self.truncated,
+            DropReason::TypeConflict => self.type_conflict,
+        }
+    }
+
+    fn count_mut(&mut self, reason: DropReason) -> &mut u64 {
+        match reason {
+            DropReason::SampledOut => &mut self.sampled_out,
+            DropReason::QueueDropped => &mut self.queue_dropped,
+            DropReason::Filtered => &mut self.filtered,
+            DropReason::Truncated => &mut self.truncated,
+            DropReason::TypeConflict => &mut self.type_conflict,
+        }
+    }
+}
+
+#[derive(Default)]
+pub(crate) struct DropLog {
+    metrics: Mutex<HashMap<String, DropStats>>,
+    /// When `agent_drops_total` was last sent
+    reported_at: Mutex<Option<Instant>>,
+}
+
+impl DropLog {
+    /// Count `n` drops of `metric` for `reason`
+    pub(crate) fn record(&self, metric: &str, reason: DropReason, n: u64) {
+        if n == 0 {
+            return;
+        }
+        let mut metrics = self.metrics.lock();
+        if let Some(stats) = metrics.get_mut(metric) {
+            *stats.count_mut(reason) += n;
+            return;
+        }
+        if metrics.len() >= TRACKED_METRICS {
+            let quietest = metrics
+                .values()
+                .min_by_key(|stats| stats.total())
+                .map(|stats| stats.metric.clone());
+            if let Some(quietest) = quietest {
+                metrics.remove(&quietest);
+            }
+        }
+        let mut stats = DropStats {
+            metric: metric.to_string(),
+            ..Default::default()
+        };
+        *stats.count_mut(reason) = n;
+        metrics.insert(metric.to_string(), stats);
+    }
+
+    /// Every tracked metric, most drops first
+    pub(crate) fn report(&self) -> Vec<DropStats> {
+        let mut report: Vec<DropStats> = self.metrics.lock().values().cloned().collect();
+        report.sort_by(|a, b| {
+            b.total()
+                .cmp(&a.total())
+                .then_with(|| a.metric.cmp(&b.metric))
+        });
+        report
+    }
+
+    /// Whether `agent_drops_total` is due, at most once per `interval`;
+    /// marks it sent if so
+    pub(crate) fn report_due(&self, interval: Duration, now: Instant) -> bool {
+        let mut reported_at = self.reported_at.lock();
+        if reported_at.is_some_and(|at| now.saturating_duration_since(at) < interval) {
+            return false;
+        }
+        *reported_at = Some(now);
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_counts_per_metric_and_reason() {
+        let log = DropLog::default();
+        log.record("db_query_ms", DropReason::SampledOut, 3);
+        log.record("db_query_ms", DropReason::Filtered, 1);
+        log.record("cache_hits", DropReason::Truncated, 5);
+        log.record("cache_hits", DropReason::QueueDropped, 0);
+        assert_eq!(
+            log.report(),
+            [
+                DropStats {
+                    metric: "cache_hits".to_string(),
+                    truncated: 5,
+                    ..Default::default()
+                },
+                DropStats {
+                    metric: "db_query_ms".to_string(),
+                    sampled_out: 3,
+                    filtered: 1,
+                    ..Default::default()
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_keeps_the_noisiest() {
+        let log = DropLog::default();
+        log.record("noisy", DropReason::QueueDropped, 1_000);
+        for i in 0..TRACKED_METRICS * 2 {
+            log.record(&format!("quiet_{}", i), DropReason::Filtered, 1);
+        }
+        let report = log.report();
+        assert_eq!(report.len(), TRACKED_METRICS);
+        assert_eq!(report[0].metric, "noisy");
+        assert_eq!(report[0].queue_dropped, 1_000);
+    }
+
+    #[test]
+    fn test_report_due_once_per_interval() {
+        let log = DropLog::default();
+        let start = Instant::now();
+        let minute = Duration::from_secs(60);
+        assert!(log.report_due(minute, start));
+        assert!(!log.report_due(minute, start + minute / 2));
+        assert!(log.report_due(minute, start + minute));
+        assert!(!log.report_due(minute, start + minute));
+    }
+}
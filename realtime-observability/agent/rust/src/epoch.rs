@@ -0,0 +1,185 @@
+//! Collection epochs: one consistent cut across an agent's metrics
+//!
+//! Counters, histograms and windowed gauges keep two accumulators, one per
+//! epoch parity. A write pins the agent's current epoch for the duration of
+//! its atomic update and lands in that epoch's accumulator. Collection
+//! advances the epoch, waits until no write still holds the old one, and
+//! only then drains the old accumulators. Every write that finished before
+//! the advance is in the batch; none that pinned the new epoch is, whichever
+//! registry it went to.
+//!
+//! The cut is per write, not per request. A request that increments a
+//! counter and then records a histogram pins twice and can straddle the
+//! advance, its increment in one batch and its sample in the next. Summed
+//! over batches the pair agrees exactly; within one batch it is off by at
+//! most the pairs in flight at the advance, one per recording thread.
+//!
+//! Pins are counted per thread shard, like `ShardedCounter`, so recording
+//! threads don't contend on one cache line.
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use crossbeam::utils::CachePadded;
+use parking_lot::{Mutex, MutexGuard};
+
+use crate::sharded::{shard_index, SHARDS};
+
+/// An agent's epoch counter and its pinned writers
+pub(crate) struct Epoch {
+    current: AtomicUsize,
+    /// Writers holding each parity, by thread shard
+    pinned: Box<[CachePadded<[AtomicUsize; 2]>]>,
+    /// Held from advance to drain, so a second collector cannot flip back
+    /// to a parity still being drained
+    advancing: Mutex<()>,
+}
+
+impl Epoch {
+    pub(crate) fn new() -> Self {
+        Self {
+            current: AtomicUsize::new(0),
+            pinned: (0..SHARDS)
+                .map(|_| CachePadded::new([AtomicUsize::new(0), AtomicUsize::new(0)]))
+                .collect(),
+            advancing: Mutex::new(()),
+        }
+    }
+
+    /// Hold the current epoch until the pin is dropped
+    #[inline]
+    fn pin(&self) -> Pin<'_> {
+        let shard = shard_index();
+        loop {
+            let current = self.current.load(Ordering::SeqCst);
+            let slot = current & 1;
+            self.pinned[shard][slot].fetch_add(1, Ordering::SeqCst);
+            // A collector that advanced before our increment may have seen
+            // this shard idle already; retry in the new epoch
+            if self.current.load(Ordering::SeqCst) == current {
+                return Pin {
+                    epoch: Some(self),
+                    shard,
+                    slot,
+                };
+            }
+            self.pinned[shard][slot].fetch_sub(1, Ordering::Release);
+        }
+    }
+
+    /// Start a new epoch and wait out every write still pinning the old
+    /// one. Its accumulators are the collector's until the `Cut` drops.
+    pub(crate) fn advance(&self) -> Cut<'_> {
+        let guard = self.advancing.lock();
+        let slot = self.current.fetch_add(1, Ordering::SeqCst) & 1;
+        // Pins are held across one atomic update, so this is short
+        while self
+            .pinned
+            .iter()
+            .any(|shard| shard[slot].load(Ordering::SeqCst) != 0)
+        {
+            std::thread::yield_now();
+        }
+        Cut {
+            slot,
+            _guard: guard,
+        }
+    }
+}
+
+impl Default for Epoch {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A write in progress; see `pin`
+pub(crate) struct Pin<'a> {
+    epoch: Option<&'a Epoch>,
+    shard: usize,
+    slot: usize,
+}
+
+impl Pin<'_> {
+    /// Accumulator index the write goes to
+    #[inline]
+    pub(crate) fn slot(&self) -> usize {
+        self.slot
+    }
+}
+
+impl Drop for Pin<'_> {
+    #[inline]
+    fn drop(&mut self) {
+        if let Some(epoch) = self.epoch {
+            // Publishes the write to the collector waiting in `advance`
+            epoch.pinned[self.shard][self.slot].fetch_sub(1, Ordering::Release);
+        }
+    }
+}
+
+/// Pin `epoch` for one write. Metrics outside an agent have no epoch and
+/// always use the first accumulator.
+#[inline]
+pub(crate) fn pin(epoch: Option<&Epoch>) -> Pin<'_> {
+    match epoch {
+        Some(epoch) => epoch.pin(),
+        None => Pin {
+            epoch: None,
+            shard: 0,
+            slot: 0,
+        },
+    }
+}
+
+/// The previous epoch, quiesced and ready to drain
+pub(crate) struct Cut<'a> {
+    slot: usize,
+    _guard: MutexGuard<'a, ()>,
+}
+
+impl Cut<'_> {
+    /// Accumulator index of the epoch that ended
+    pub(crate) fn slot(&self) -> usize {
+        self.slot
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicU64;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_advance_waits_for_pinned_writes() {
+        let epoch = Arc::new(Epoch::new());
+        let slots = Arc::new([AtomicU64::new(0), AtomicU64::new(0)]);
+        let writers: Vec<_> = (0..8)
+            .map(|_| {
+                let (epoch, slots) = (epoch.clone(), slots.clone());
+                std::thread::spawn(move || {
+                    for _ in 0..20_000 {
+                        let pin = epoch.pin();
+                        slots[pin.slot()].fetch_add(1, Ordering::Relaxed);
+                    }
+                })
+            })
+            .collect();
+
+        let mut drained = 0;
+        while writers.iter().any(|w| !w.is_finished()) {
+            let cut = epoch.advance();
+            drained += slots[cut.slot()].swap(0, Ordering::Relaxed);
+            // Nothing may land in the drained slot until the next advance
+            assert_eq!(slots[cut.slot()].load(Ordering::Relaxed), 0);
+        }
+        for writer in writers {
+            writer.join().unwrap();
+        }
+        for _ in 0..2 {
+            let cut = epoch.advance();
+            drained += slots[cut.slot()].swap(0, Ordering::Relaxed);
+        }
+        assert_eq!(drained, 8 * 20_000);
+    }
+}
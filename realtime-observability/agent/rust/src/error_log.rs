@@ -0,0 +1,95 @@
+//! Last-error capture for `record_error_detailed`
+
+use std::collections::HashMap;
+use std::time::SystemTime;
+
+use parking_lot::Mutex;
+
+/// Distinct error types kept; further types are still counted but their
+/// messages are not stored
+const MAX_ERROR_TYPES: usize = 128;
+
+/// Most recent error of one type
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ErrorInfo {
+    pub error_type: String,
+    pub message: String,
+    pub timestamp: SystemTime,
+}
+
+#[derive(Default)]
+pub(crate) struct ErrorLog {
+    last: Mutex<HashMap<String, ErrorInfo>>,
+    /// Samples waiting for the next batch, at most `cap` per interval
+    pending: Mutex<Vec<ErrorInfo>>,
+}
+
+impl ErrorLog {
+    pub(crate) fn record(&self, error_type: &str, message: &str, limit: usize, sample_cap: usize) {
+        let info = ErrorInfo {
+            error_type: error_type.to_string(),
+            message: truncate(message, limit).to_string(),
+            timestamp: SystemTime::now(),
+        };
+
+        {
+            let mut last = self.last.lock();
+            if last.len() < MAX_ERROR_TYPES || last.contains_key(error_type) {
+                last.insert(info.error_type.clone(), info.clone());
+            }
+        }
+
+        let mut pending = self.pending.lock();
+        if pending.len() < sample_cap {
+            pending.push(info);
+        }
+    }
+
+    /// Latest error per type, ordered by type
+    pub(crate) fn last_errors(&self) -> Vec<ErrorInfo> {
+        let mut errors: Vec<ErrorInfo> = self.last.lock().values().cloned().collect();
+        errors.sort_by(|a, b| a.error_type.cmp(&b.error_type));
+        errors
+    }
+
+    pub(crate) fn take_pending(&self) -> Vec<ErrorInfo> {
+        std::mem::take(&mut *self.pending.lock())
+    }
+}
+
+/// Cut `s` to at most `limit` bytes without splitting a character
+pub(crate) fn truncate(s: &str, limit: usize) -> &str {
+    if s.len() <= limit {
+        return s;
+    }
+    let mut end = limit;
+    while !s.is_char_boundary(end) {
+        end -= 1;
+    }
+    &s[..end]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_truncate_on_char_boundary() {
+        assert_eq!(truncate("héllo", 2), "h");
+        assert_eq!(truncate("héllo", 3), "hé");
+        assert_eq!(truncate("hi", 10), "hi");
+    }
+
+    #[test]
+    fn test_bounded_slots_and_samples() {
+        let log = ErrorLog::default();
+        for i in 0..10 {
+            log.record("timeout", &format!("attempt {}", i), 64, 3);
+        }
+        let last = log.last_errors();
+        assert_eq!(last.len(), 1);
+        assert_eq!(last[0].message, "attempt 9");
+        assert_eq!(log.take_pending().len(), 3);
+        assert!(log.take_pending().is_empty());
+    }
+}
@@ -0,0 +1,99 @@
+//! The bounded set of types `record_error` counts under
+//!
+//! Error types often come from error messages or enum names, so an
+//! instance can produce them without bound. Each type is normalized and
+//! counted as `errors_total{type}`; once `Config::max_error_types` are
+//! known, new ones are counted as `type="__other"` and a warning is logged
+//! once, so the sum over types is always the number of errors recorded.
+
+use std::collections::BTreeSet;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use parking_lot::Mutex;
+
+/// Type that errors beyond `Config::max_error_types` are counted under
+pub(crate) const OTHER_ERROR_TYPE: &str = "__other";
+
+pub(crate) struct ErrorTypes {
+    known: Mutex<BTreeSet<String>>,
+    cap: usize,
+    warned: AtomicBool,
+}
+
+impl ErrorTypes {
+    pub(crate) fn new(cap: usize) -> Self {
+        Self {
+            known: Mutex::new(BTreeSet::new()),
+            cap,
+            warned: AtomicBool::new(false),
+        }
+    }
+
+    /// The type `raw` is counted under: normalized, or `__other` if it is
+    /// new and the cap is reached
+    pub(crate) fn resolve(&self, raw: &str) -> String {
+        let normalized = normalize(raw);
+        let mut known = self.known.lock();
+        if known.contains(&normalized) {
+            return normalized;
+        }
+        if known.len() < self.cap {
+            known.insert(normalized.clone());
+            return normalized;
+        }
+        drop(known);
+        if !self.warned.swap(true, Ordering::Relaxed) {
+            tracing::warn!(
+                cap = self.cap,
+                error_type = %normalized,
+                "too many error types, counting new ones as __other"
+            );
+        }
+        OTHER_ERROR_TYPE.to_string()
+    }
+
+    /// Types counted so far, sorted, without `__other`
+    pub(crate) fn list(&self) -> Vec<String> {
+        self.known.lock().iter().cloned().collect()
+    }
+}
+
+/// Lowercase and trimmed, with anything but `[a-z0-9_]` replaced by `_`;
+/// `unknown` if nothing is left
+fn normalize(raw: &str) -> String {
+    let trimmed = raw.trim();
+    if trimmed.is_empty() {
+        return "unknown".to_string();
+    }
+    trimmed
+        .chars()
+        .map(|c| match c.to_ascii_lowercase() {
+            c @ ('a'..='z' | '0'..='9' | '_') => c,
+            _ => '_',
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_types_are_normalized() {
+        assert_eq!(normalize("  Timeout "), "timeout");
+        assert_eq!(normalize("DB.Connection-Reset"), "db_connection_reset");
+        assert_eq!(normalize("naïve"), "na_ve");
+        assert_eq!(normalize(" \t"), "unknown");
+    }
+
+    #[test]
+    fn test_new_types_collapse_past_the_cap() {
+        let types = ErrorTypes::new(2);
+        assert_eq!(types.resolve("A"), "a");
+        assert_eq!(types.resolve("b"), "b");
+        assert_eq!(types.resolve("c"), OTHER_ERROR_TYPE);
+        // Known types keep their own series after the cap
+        assert_eq!(types.resolve(" a"), "a");
+        assert_eq!(types.list(), ["a", "b"]);
+    }
+}
@@ -0,0 +1,133 @@
+//! Discrete events queued for the next batch
+//!
+//! The queue holds at most `Config::max_events_per_batch` events; beyond
+//! that the oldest are dropped. Events from a failed push go back to the
+//! front of the queue so they ride with the next one. The queue's length
+//! is mirrored in an atomic so its fill level reads without the lock.
+
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use parking_lot::Mutex;
+
+use crate::memory::event_bytes;
+use crate::telemetry::Event;
+
+pub(crate) struct EventQueue {
+    events: Mutex<VecDeque<Event>>,
+    cap: usize,
+    /// `events.len()`, updated under the lock
+    depth: AtomicUsize,
+}
+
+impl EventQueue {
+    pub(crate) fn new(cap: usize) -> Self {
+        Self {
+            events: Mutex::new(VecDeque::new()),
+            cap,
+            depth: AtomicUsize::new(0),
+        }
+    }
+
+    /// Queue `event`, returning the events dropped to make room
+    pub(crate) fn push(&self, event: Event) -> Vec<Event> {
+        let mut events = self.events.lock();
+        events.push_back(event);
+        self.trim(&mut events)
+    }
+
+    /// Put events from a failed push back ahead of newer ones, returning
+    /// the events dropped to stay within the cap
+    pub(crate) fn requeue(&self, failed: Vec<Event>) -> Vec<Event> {
+        if failed.is_empty() {
+            return Vec::new();
+        }
+        let mut events = self.events.lock();
+        for event in failed.into_iter().rev() {
+            events.push_front(event);
+        }
+        self.trim(&mut events)
+    }
+
+    /// Everything queued, oldest first
+    pub(crate) fn drain(&self) -> Vec<Event> {
+        let mut events = self.events.lock();
+        self.depth.store(0, Ordering::Relaxed);
+        events.drain(..).collect()
+    }
+
+    /// Events queued now
+    pub(crate) fn depth(&self) -> usize {
+        self.depth.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn capacity(&self) -> usize {
+        self.cap
+    }
+
+    /// How full the queue is, from 0.0 to 1.0. A queue with no room is
+    /// always full.
+    pub(crate) fn pressure(&self) -> f32 {
+        match self.cap {
+            0 => 1.0,
+            cap => (self.depth() as f32 / cap as f32).min(1.0),
+        }
+    }
+
+    /// Estimated bytes held by queued events
+    pub(crate) fn bytes(&self) -> usize {
+        let events = self.events.lock();
+        events.capacity() * std::mem::size_of::<Event>()
+            + events.iter().map(event_bytes).sum::<usize>()
+    }
+
+    fn trim(&self, events: &mut VecDeque<Event>) -> Vec<Event> {
+        let excess = events.len().saturating_sub(self.cap);
+        let dropped = events.drain(..excess).collect();
+        self.depth.store(events.len(), Ordering::Relaxed);
+        dropped
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event(name: &str) -> Event {
+        Event {
+            name: name.to_string(),
+            ..Default::default()
+        }
+    }
+
+    fn names(events: &[Event]) -> Vec<&str> {
+        events.iter().map(|e| e.name.as_str()).collect()
+    }
+
+    #[test]
+    fn test_drops_oldest() {
+        let queue = EventQueue::new(2);
+        assert!(queue.push(event("a")).is_empty());
+        assert!(queue.push(event("b")).is_empty());
+        assert_eq!(names(&queue.push(event("c"))), vec!["a"]);
+        assert_eq!(queue.pressure(), 1.0);
+        assert_eq!(names(&queue.drain()), vec!["b", "c"]);
+        assert!(queue.drain().is_empty());
+        assert_eq!(queue.pressure(), 0.0);
+    }
+
+    #[test]
+    fn test_requeue_keeps_order() {
+        let queue = EventQueue::new(3);
+        queue.push(event("c"));
+        assert!(queue.requeue(vec![event("a"), event("b")]).is_empty());
+        assert_eq!(names(&queue.drain()), vec!["a", "b", "c"]);
+
+        queue.push(event("d"));
+        assert_eq!(
+            names(&queue.requeue(vec![event("a"), event("b"), event("c")])),
+            vec!["a"]
+        );
+        assert_eq!(names(&queue.drain()), vec!["b", "c", "d"]);
+    }
+}
@@ -0,0 +1,125 @@
+//! Summarized logging of push failures
+//!
+//! During an outage the push loop fails every tick. Instead of one line per
+//! failure, the first failure is logged immediately, then at most one
+//! summary per `Config::error_log_interval`, and a single recovery line once
+//! a push succeeds again.
+
+use std::fmt::Display;
+use std::time::{Duration, Instant};
+
+/// Tracks an ongoing outage for the push loop
+pub(crate) struct FailureLog {
+    interval: Duration,
+    outage: Option<Outage>,
+}
+
+struct Outage {
+    started: Instant,
+    last_logged: Instant,
+    /// Failures since the last line was written
+    suppressed: u64,
+    /// Batches lost over the whole outage
+    dropped: u64,
+    last_error: String,
+}
+
+impl FailureLog {
+    pub(crate) fn new(interval: Duration) -> Self {
+        Self {
+            interval,
+            outage: None,
+        }
+    }
+
+    pub(crate) fn on_failure(&mut self, err: &dyn Display, now: Instant) {
+        match &mut self.outage {
+            None => {
+                tracing::warn!(error = %err, "failed to push metrics");
+                self.outage = Some(Outage {
+                    started: now,
+                    last_logged: now,
+                    suppressed: 0,
+                    dropped: 1,
+                    last_error: err.to_string(),
+                });
+            }
+            Some(outage) => {
+                outage.suppressed += 1;
+                outage.dropped += 1;
+                outage.last_error = err.to_string();
+
+                if now.duration_since(outage.last_logged) >= self.interval {
+                    tracing::warn!(
+                        suppressed = outage.suppressed,
+                        outage_secs = now.duration_since(outage.started).as_secs(),
+                        last_error = %outage.last_error,
+                        "metrics push still failing"
+                    );
+                    outage.last_logged = now;
+                    outage.suppressed = 0;
+                }
+            }
+        }
+    }
+
+    pub(crate) fn on_success(&mut self, now: Instant) {
+        if let Some(outage) = self.outage.take() {
+            tracing::info!(
+                outage_ms = now.duration_since(outage.started).as_millis() as u64,
+                dropped_batches = outage.dropped,
+                "metrics push recovered"
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use std::sync::{Arc, Mutex};
+
+    #[derive(Clone, Default)]
+    struct Capture(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Capture {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_failures_are_summarized() {
+        let capture = Capture::default();
+        let writer = capture.clone();
+        let subscriber = tracing_subscriber::fmt()
+            .with_writer(move || writer.clone())
+            .with_ansi(false)
+            .finish();
+
+        tracing::subscriber::with_default(subscriber, || {
+            let mut log = FailureLog::new(Duration::from_secs(30));
+            let start = Instant::now();
+
+            // 20ms ticks for 61s of outage: 3050 failures
+            for i in 0..3050u64 {
+                log.on_failure(&"connection refused", start + Duration::from_millis(i * 20));
+            }
+            log.on_success(start + Duration::from_secs(61));
+        });
+
+        let output = String::from_utf8(capture.0.lock().unwrap().clone()).unwrap();
+        let lines: Vec<&str> = output.lines().collect();
+        assert_eq!(lines.len(), 4, "{}", output);
+        assert!(lines[0].contains("failed to push metrics"));
+        assert!(lines[1].contains("suppressed=1500"));
+        assert!(lines[2].contains("suppressed=1500"));
+        assert!(lines[3].contains("dropped_batches=3050"));
+    }
+}
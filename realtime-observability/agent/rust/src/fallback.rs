@@ -0,0 +1,145 @@
+//! Writing batches to stdout while the aggregator is unreachable
+//! (`Config::stdout_fallback_after`)
+//!
+//! Pushes carry on as usual; once they have failed for the configured
+//! time without a success in between, every batch that then fails or is
+//! dropped from the send queue is written to stdout instead of being lost.
+//! The first successful push switches it off again.
+
+use std::io::{self, Write};
+use std::time::{Duration, Instant};
+
+use crate::telemetry::TelemetryBatch;
+
+pub(crate) struct StdoutFallback {
+    after: Duration,
+    /// Start of the current run of failed pushes
+    failing_since: Option<Instant>,
+    active: bool,
+}
+
+impl StdoutFallback {
+    pub(crate) fn new(after: Duration) -> Self {
+        Self {
+            after,
+            failing_since: None,
+            active: false,
+        }
+    }
+
+    /// Whether batches that don't reach the aggregator go to stdout
+    pub(crate) fn active(&self) -> bool {
+        self.active
+    }
+
+    /// A push finished at `now`
+    pub(crate) fn on_push(&mut self, delivered: bool, now: Instant) {
+        if delivered {
+            if self.active {
+                tracing::info!("aggregator reachable again, no longer writing batches to stdout");
+            }
+            self.failing_since = None;
+            self.active = false;
+            return;
+        }
+        let since = *self.failing_since.get_or_insert(now);
+        if !self.active && now.duration_since(since) >= self.after {
+            self.active = true;
+            tracing::warn!(
+                after = ?self.after,
+                "aggregator unreachable, writing batches to stdout"
+            );
+        }
+    }
+}
+
+/// One line per metric, as its `Display`, and per event
+pub(crate) fn write_batch(out: &mut impl Write, batch: &TelemetryBatch) -> io::Result<()> {
+    for metric in &batch.metrics {
+        writeln!(out, "{} {}", batch.service, metric)?;
+    }
+    for event in &batch.events {
+        let attributes: Vec<String> = event
+            .attributes
+            .iter()
+            .map(|(key, value)| format!("{}={:?}", key, value))
+            .collect();
+        writeln!(
+            out,
+            "{} event {}{{{}}}",
+            batch.service,
+            event.name,
+            attributes.join(",")
+        )?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::telemetry::metric_sample::Value;
+    use crate::telemetry::{Event, Histogram, Metric, MetricSample};
+
+    #[test]
+    fn test_activates_after_failing_for_long_enough() {
+        let start = Instant::now();
+        let mut fallback = StdoutFallback::new(Duration::from_secs(30));
+        fallback.on_push(false, start);
+        fallback.on_push(false, start + Duration::from_secs(29));
+        assert!(!fallback.active());
+        fallback.on_push(false, start + Duration::from_secs(30));
+        assert!(fallback.active());
+
+        // A success starts the next run of failures from scratch
+        fallback.on_push(true, start + Duration::from_secs(31));
+        assert!(!fallback.active());
+        fallback.on_push(false, start + Duration::from_secs(40));
+        fallback.on_push(false, start + Duration::from_secs(60));
+        assert!(!fallback.active());
+    }
+
+    #[test]
+    fn test_batch_lines() {
+        let batch = TelemetryBatch {
+            service: "checkout".to_string(),
+            metrics: vec![
+                Metric {
+                    name: "requests_total".to_string(),
+                    labels: [("route".to_string(), "/a".to_string())].into(),
+                    samples: vec![MetricSample {
+                        value: Some(Value::Counter(3)),
+                        ..Default::default()
+                    }],
+                    series_id: 0,
+                    series_hash: 0,
+                },
+                Metric {
+                    name: "latency_ms".to_string(),
+                    samples: vec![MetricSample {
+                        value: Some(Value::Histogram(Histogram {
+                            bounds: vec![10.0],
+                            counts: vec![4, 1],
+                            exemplars: Vec::new(),
+                        })),
+                        ..Default::default()
+                    }],
+                    ..Default::default()
+                },
+            ],
+            events: vec![Event {
+                name: "deploy".to_string(),
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        let mut out = Vec::new();
+        write_batch(&mut out, &batch).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "checkout requests_total{route=\"/a\"} 3\n\
+             checkout latency_ms count=5 [<=10:4 +Inf:1]\n\
+             checkout event deploy{}\n"
+        );
+    }
+}
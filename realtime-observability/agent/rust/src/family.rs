@@ -0,0 +1,270 @@
+//! Metric families: one name, a fixed label schema, cached handles
+//!
+//! `with()` hashes the label values in place and only builds a series key
+//! the first time a combination is seen; later calls are a read lock and a
+//! slice compare.
+
+use std::collections::hash_map::RandomState;
+use std::collections::HashMap;
+use std::fmt;
+use std::hash::BuildHasher;
+use std::sync::Arc;
+
+use parking_lot::RwLock;
+use smallvec::SmallVec;
+
+use crate::counter::Counter;
+use crate::gauge::Gauge;
+use crate::switches::Switch;
+use crate::Histogram;
+
+type LabelValues = SmallVec<[Box<str>; 4]>;
+/// Handles whose label values share a hash
+type Bucket<H> = SmallVec<[(LabelValues, H); 1]>;
+/// Builds the handle for a label set, in schema order
+type Create<H> = Box<dyn Fn(&[(&str, &str)]) -> H + Send + Sync>;
+
+/// Series sharing a name and label schema, from `Agent::counter_family`,
+/// `Agent::gauge_family` or `Agent::histogram_family`
+pub struct Family<H> {
+    inner: Arc<FamilyInner<H>>,
+}
+
+pub type CounterFamily = Family<CounterHandle>;
+pub type GaugeFamily = Family<GaugeHandle>;
+pub type HistogramFamily = Family<HistogramHandle>;
+
+struct FamilyInner<H> {
+    name: String,
+    label_names: Arc<[String]>,
+    hasher: RandomState,
+    /// Keyed by the hash of the label values; collisions share a bucket
+    handles: RwLock<HashMap<u64, Bucket<H>>>,
+    create: Create<H>,
+}
+
+impl<H> Clone for Family<H> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+impl<H: Clone> Family<H> {
+    pub(crate) fn new(
+        name: &str,
+        label_names: Arc<[String]>,
+        create: impl Fn(&[(&str, &str)]) -> H + Send + Sync + 'static,
+    ) -> Self {
+        Self {
+            inner: Arc::new(FamilyInner {
+                name: name.to_string(),
+                label_names,
+                hasher: RandomState::new(),
+                handles: RwLock::new(HashMap::new()),
+                create: Box::new(create),
+            }),
+        }
+    }
+
+    /// Handle for the series with these label values, in schema order
+    ///
+    /// # Panics
+    ///
+    /// If `values` does not have one entry per declared label.
+    pub fn with(&self, values: &[&str]) -> H {
+        let inner = &*self.inner;
+        assert_eq!(
+            values.len(),
+            inner.label_names.len(),
+            "family {:?} takes labels {:?}",
+            inner.name,
+            inner.label_names
+        );
+
+        let hash = inner.hasher.hash_one(values);
+
+        if let Some(handle) = inner
+            .handles
+            .read()
+            .get(&hash)
+            .and_then(|b| find(b, values))
+        {
+            return handle;
+        }
+
+        let mut handles = inner.handles.write();
+        let bucket = handles.entry(hash).or_default();
+        if let Some(handle) = find(bucket, values) {
+            return handle;
+        }
+        let labels: SmallVec<[(&str, &str); 4]> = inner
+            .label_names
+            .iter()
+            .map(String::as_str)
+            .zip(values.iter().copied())
+            .collect();
+        let handle = (inner.create)(&labels);
+        bucket.push((
+            values.iter().map(|v| Box::from(*v)).collect(),
+            handle.clone(),
+        ));
+        handle
+    }
+
+    pub fn label_names(&self) -> &[String] {
+        &self.inner.label_names
+    }
+}
+
+fn find<H: Clone>(bucket: &[(LabelValues, H)], values: &[&str]) -> Option<H> {
+    bucket
+        .iter()
+        .find(|(cached, _)| cached.iter().map(|v| &**v).eq(values.iter().copied()))
+        .map(|(_, handle)| handle.clone())
+}
+
+/// Returned when a family is declared again with a different label schema
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LabelSchemaMismatch {
+    pub name: String,
+    pub registered: Vec<String>,
+    pub requested: Vec<String>,
+}
+
+impl fmt::Display for LabelSchemaMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "family {:?} is registered with labels {:?} but was requested with {:?}",
+            self.name, self.registered, self.requested
+        )
+    }
+}
+
+impl std::error::Error for LabelSchemaMismatch {}
+
+/// One counter series of a `CounterFamily`
+#[derive(Clone)]
+pub struct CounterHandle {
+    pub(crate) counter: Arc<Counter>,
+    pub(crate) enabled: Switch,
+}
+
+impl CounterHandle {
+    #[inline]
+    pub fn inc(&self) {
+        self.add(1);
+    }
+
+    #[inline]
+    pub fn add(&self, n: u64) {
+        if !self.enabled.is_on() {
+            return;
+        }
+        self.counter.add(n);
+    }
+
+    pub fn get(&self) -> u64 {
+        self.counter.value()
+    }
+}
+
+/// One gauge series of a `GaugeFamily`
+#[derive(Clone)]
+pub struct GaugeHandle {
+    pub(crate) gauge: Arc<Gauge>,
+    pub(crate) enabled: Switch,
+}
+
+impl GaugeHandle {
+    #[inline]
+    pub fn set(&self, value: f64) {
+        if !self.enabled.is_on() {
+            return;
+        }
+        self.gauge.set(value);
+    }
+
+    /// Atomically add `delta`, returning the new value, or 0 while the
+    /// metric is disabled
+    #[inline]
+    pub fn add(&self, delta: f64) -> f64 {
+        if !self.enabled.is_on() {
+            return 0.0;
+        }
+        self.gauge.add(delta)
+    }
+
+    #[inline]
+    pub fn sub(&self, delta: f64) -> f64 {
+        self.add(-delta)
+    }
+}
+
+/// One histogram series of a `HistogramFamily`
+#[derive(Clone)]
+pub struct HistogramHandle {
+    pub(crate) hist: Arc<Histogram>,
+    pub(crate) enabled: Switch,
+}
+
+impl HistogramHandle {
+    #[inline]
+    pub fn record(&self, value: f64) {
+        if !self.enabled.is_on() {
+            return;
+        }
+        self.hist.record(value);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[test]
+    fn test_with_caches_handles() {
+        let created = Arc::new(AtomicUsize::new(0));
+        let keys = Arc::new(parking_lot::Mutex::new(Vec::new()));
+        let family = {
+            let created = created.clone();
+            let keys = keys.clone();
+            Family::new(
+                "http_requests",
+                Arc::from(vec!["method".to_string(), "status".to_string()]),
+                move |labels| {
+                    created.fetch_add(1, Ordering::Relaxed);
+                    keys.lock()
+                        .push(crate::series::encode("http_requests", labels));
+                    CounterHandle {
+                        counter: Arc::default(),
+                        enabled: Switch::default(),
+                    }
+                },
+            )
+        };
+
+        family.with(&["GET", "200"]).inc();
+        family.with(&["GET", "200"]).inc();
+        family.with(&["POST", "200"]).inc();
+
+        assert_eq!(family.with(&["GET", "200"]).get(), 2);
+        assert_eq!(created.load(Ordering::Relaxed), 2);
+        assert_eq!(keys.lock()[0], "http_requests{method=GET,status=200}");
+    }
+
+    #[test]
+    #[should_panic(expected = "takes labels")]
+    fn test_with_rejects_wrong_arity() {
+        let family = Family::new("jobs", Arc::from(vec!["queue".to_string()]), |_| {
+            GaugeHandle {
+                gauge: Arc::new(Gauge::new(Default::default())),
+                enabled: Switch::default(),
+            }
+        });
+        family.with(&["a", "b"]);
+    }
+}
@@ -0,0 +1,206 @@
+//! Allow/deny lists for metric names
+//!
+//! Patterns are globs over metric names: `*` matches any run of characters
+//! and `?` exactly one. A name is kept if it matches some allow pattern (or
+//! the allow list is empty) and no deny pattern; deny wins when both match.
+//!
+//! The agent keeps its filter in a `SharedFilter` so `Agent::watch_config`
+//! can replace it while running. Until a filter is set, checking a name
+//! costs one relaxed load.
+
+use std::fmt;
+#[cfg(not(feature = "noop"))]
+use std::sync::atomic::{AtomicBool, Ordering};
+
+#[cfg(not(feature = "noop"))]
+use parking_lot::RwLock;
+
+/// Metric name filter for `Config::metric_filter`
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct MetricFilter {
+    allow: Vec<String>,
+    deny: Vec<String>,
+}
+
+/// Returned by `MetricFilter::from_strings` for a malformed pattern
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InvalidPattern {
+    pub pattern: String,
+    pub reason: &'static str,
+}
+
+impl fmt::Display for InvalidPattern {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "invalid metric pattern {:?}: {}",
+            self.pattern, self.reason
+        )
+    }
+}
+
+impl std::error::Error for InvalidPattern {}
+
+impl MetricFilter {
+    /// Build a filter, rejecting empty patterns and characters that can't
+    /// occur in a metric name
+    pub fn from_strings<A, D>(allow: A, deny: D) -> Result<Self, InvalidPattern>
+    where
+        A: IntoIterator,
+        A::Item: Into<String>,
+        D: IntoIterator,
+        D::Item: Into<String>,
+    {
+        let validate = |pattern: String| {
+            if pattern.is_empty() {
+                return Err(InvalidPattern {
+                    pattern,
+                    reason: "pattern is empty",
+                });
+            }
+            let valid = pattern
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | ':' | '.' | '*' | '?'));
+            if !valid {
+                return Err(InvalidPattern {
+                    pattern,
+                    reason: "only letters, digits, '_', ':', '.', '*' and '?' are allowed",
+                });
+            }
+            Ok(pattern)
+        };
+
+        Ok(Self {
+            allow: allow
+                .into_iter()
+                .map(|p| validate(p.into()))
+                .collect::<Result<_, _>>()?,
+            deny: deny
+                .into_iter()
+                .map(|p| validate(p.into()))
+                .collect::<Result<_, _>>()?,
+        })
+    }
+
+    /// Whether metric `name` may be registered and sent
+    pub fn allows(&self, name: &str) -> bool {
+        let allowed = self.allow.is_empty() || self.allow.iter().any(|p| glob_match(p, name));
+        allowed && !self.deny.iter().any(|p| glob_match(p, name))
+    }
+}
+
+/// The filter in effect, shared by the recording API and the collector
+#[cfg(not(feature = "noop"))]
+pub(crate) struct SharedFilter {
+    set: AtomicBool,
+    filter: RwLock<Option<MetricFilter>>,
+}
+
+#[cfg(not(feature = "noop"))]
+impl SharedFilter {
+    pub(crate) fn new(filter: Option<MetricFilter>) -> Self {
+        Self {
+            set: AtomicBool::new(filter.is_some()),
+            filter: RwLock::new(filter),
+        }
+    }
+
+    #[cfg(feature = "toml")]
+    pub(crate) fn replace(&self, filter: Option<MetricFilter>) {
+        let mut slot = self.filter.write();
+        self.set.store(filter.is_some(), Ordering::Relaxed);
+        *slot = filter;
+    }
+
+    pub(crate) fn is_set(&self) -> bool {
+        self.set.load(Ordering::Relaxed)
+    }
+
+    /// Whether metric `name` is filtered out; `agent_*` self-metrics never
+    /// are
+    pub(crate) fn rejects(&self, name: &str) -> bool {
+        if !self.is_set() || name.starts_with("agent_") {
+            return false;
+        }
+        self.filter
+            .read()
+            .as_ref()
+            .is_some_and(|filter| !filter.allows(name))
+    }
+}
+
+/// Iterative glob match with single-star backtracking
+fn glob_match(pattern: &str, name: &str) -> bool {
+    let (pattern, name) = (pattern.as_bytes(), name.as_bytes());
+    let (mut p, mut n) = (0, 0);
+    // Position after the last `*` and the name position it matched up to
+    let mut star: Option<(usize, usize)> = None;
+
+    while n < name.len() {
+        match pattern.get(p) {
+            Some(b'*') => {
+                star = Some((p + 1, n));
+                p += 1;
+            }
+            Some(&c) if c == b'?' || c == name[n] => {
+                p += 1;
+                n += 1;
+            }
+            _ => match star {
+                // Let the last star absorb one more character
+                Some((after, matched)) => {
+                    p = after;
+                    n = matched + 1;
+                    star = Some((after, matched + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == b'*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_glob() {
+        assert!(glob_match("*", ""));
+        assert!(glob_match("tokio_*", "tokio_tasks"));
+        assert!(!glob_match("tokio_*", "app_tokio_tasks"));
+        assert!(glob_match("*_ms", "render_ms"));
+        assert!(glob_match("a*b*c", "aXXbYYbc"));
+        assert!(glob_match("req?", "req1"));
+        assert!(!glob_match("req?", "req"));
+        assert!(glob_match("exact", "exact"));
+        assert!(!glob_match("exact", "exactly"));
+    }
+
+    #[test]
+    fn test_deny_wins_over_allow() {
+        let filter = MetricFilter::from_strings(["*"], ["tokio_*", "debug_*"]).unwrap();
+        assert!(filter.allows("requests_total"));
+        assert!(!filter.allows("tokio_tasks"));
+        assert!(!filter.allows("debug_queue"));
+
+        let filter = MetricFilter::from_strings(["debug_queue"], ["debug_*"]).unwrap();
+        assert!(!filter.allows("debug_queue"));
+        assert!(!filter.allows("requests_total"));
+    }
+
+    #[test]
+    fn test_empty_allow_keeps_everything_not_denied() {
+        let filter = MetricFilter::from_strings(Vec::<String>::new(), ["debug_*"]).unwrap();
+        assert!(filter.allows("requests_total"));
+        assert!(!filter.allows("debug_queue"));
+    }
+
+    #[test]
+    fn test_invalid_patterns() {
+        let err = MetricFilter::from_strings([""], Vec::<String>::new()).unwrap_err();
+        assert_eq!(err.reason, "pattern is empty");
+        let err = MetricFilter::from_strings(["*"], ["http{route}"]).unwrap_err();
+        assert_eq!(err.pattern, "http{route}");
+    }
+}
@@ -0,0 +1,188 @@
+//! Histograms with their buckets inline (`FixedHistogram`), and the trait
+//! the agent collects any histogram through (`RecordableHistogram`)
+//!
+//! `Histogram` keeps its buckets and exemplar slots behind boxes sized at
+//! runtime, so a record reads the box's pointer before it can search the
+//! bounds. `FixedHistogram<N>` fixes the bucket count at compile time and
+//! keeps the buckets in the struct itself, with no exemplars or
+//! reconfiguration; bucketing is the same, value for value.
+
+use std::sync::atomic::Ordering;
+
+use crate::sync::AtomicU64;
+use crate::{telemetry, Histogram};
+
+/// A histogram `Agent::register_recordable_histogram` can push
+///
+/// Each push takes `snapshot_and_reset` of every registered histogram.
+/// Unlike the agent's own histograms, these aren't cut together with the
+/// rest of the batch: a record racing the collection lands in this push or
+/// the next, but is counted once.
+pub trait RecordableHistogram: Send + Sync {
+    fn record(&self, value: f64);
+
+    /// Upper bounds, and the counts since the last call with the overflow
+    /// count last, resetting the counts
+    fn snapshot_and_reset(&self) -> (Vec<f64>, Vec<u64>);
+}
+
+impl RecordableHistogram for Histogram {
+    #[inline]
+    fn record(&self, value: f64) {
+        Histogram::record(self, value);
+    }
+
+    fn snapshot_and_reset(&self) -> (Vec<f64>, Vec<u64>) {
+        Histogram::snapshot_and_reset(self)
+    }
+}
+
+/// Histogram with `N` bounds stored inline, for record paths that can't
+/// afford an indirection
+///
+/// Buckets follow `Histogram`: `counts[i]` holds values in
+/// `(bounds[i-1], bounds[i]]`, and the overflow bucket after them holds
+/// values above the last bound and NaN.
+///
+/// ```
+/// use telemetry_agent::FixedHistogram;
+///
+/// let hist = FixedHistogram::new([1.0, 5.0, 10.0]);
+/// hist.record(3.0);
+/// hist.record(12.0);
+/// assert_eq!(hist.snapshot_and_reset().1, vec![0, 1, 0, 1]);
+/// ```
+pub struct FixedHistogram<const N: usize> {
+    buckets: [FixedBucket; N],
+    overflow: AtomicU64,
+}
+
+/// Upper bound and count side by side, as in `Histogram`, but with no
+/// epochs to count apart
+struct FixedBucket {
+    bound: f64,
+    count: AtomicU64,
+}
+
+impl<const N: usize> FixedHistogram<N> {
+    /// A histogram with these upper bounds, sorted ascending
+    pub fn new(bounds: [f64; N]) -> Self {
+        debug_assert!(bounds.windows(2).all(|w| w[0] <= w[1]));
+        Self {
+            buckets: std::array::from_fn(|i| FixedBucket {
+                bound: bounds[i],
+                count: AtomicU64::new(0),
+            }),
+            overflow: AtomicU64::new(0),
+        }
+    }
+
+    /// Index of the first bucket whose bound is >= value, or `N` for the
+    /// overflow bucket (including NaN)
+    #[inline]
+    #[allow(clippy::neg_cmp_op_on_partial_ord)]
+    fn bucket_index(&self, value: f64) -> usize {
+        self.buckets.partition_point(|b| !(value <= b.bound))
+    }
+
+    #[inline]
+    pub fn record(&self, value: f64) {
+        // Relaxed is enough for exactly-once counting; see `sync`
+        match self.buckets.get(self.bucket_index(value)) {
+            Some(bucket) => bucket.count.fetch_add(1, Ordering::Relaxed),
+            None => self.overflow.fetch_add(1, Ordering::Relaxed),
+        };
+    }
+
+    /// Values recorded above the last bound since the last reset
+    pub fn overflow_count(&self) -> u64 {
+        self.overflow.load(Ordering::Relaxed)
+    }
+
+    pub fn bounds(&self) -> [f64; N] {
+        std::array::from_fn(|i| self.buckets[i].bound)
+    }
+
+    pub fn snapshot_and_reset(&self) -> (Vec<f64>, Vec<u64>) {
+        let counts = self
+            .buckets
+            .iter()
+            .map(|b| &b.count)
+            .chain(std::iter::once(&self.overflow))
+            .map(|c| c.swap(0, Ordering::Relaxed))
+            .collect();
+        (self.bounds().to_vec(), counts)
+    }
+}
+
+impl<const N: usize> RecordableHistogram for FixedHistogram<N> {
+    #[inline]
+    fn record(&self, value: f64) {
+        FixedHistogram::record(self, value);
+    }
+
+    fn snapshot_and_reset(&self) -> (Vec<f64>, Vec<u64>) {
+        FixedHistogram::snapshot_and_reset(self)
+    }
+}
+
+/// The counts so far, without resetting them
+impl<const N: usize> From<&FixedHistogram<N>> for telemetry::Histogram {
+    fn from(hist: &FixedHistogram<N>) -> Self {
+        telemetry::Histogram {
+            bounds: hist.bounds().to_vec(),
+            counts: hist
+                .buckets
+                .iter()
+                .map(|b| &b.count)
+                .chain(std::iter::once(&hist.overflow))
+                .map(|c| c.load(Ordering::Relaxed))
+                .collect(),
+            exemplars: Vec::new(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::DEFAULT_BOUNDS;
+
+    #[test]
+    fn test_buckets_like_histogram() {
+        let fixed = FixedHistogram::new(DEFAULT_BOUNDS);
+        let boxed = Histogram::with_bounds(&DEFAULT_BOUNDS);
+        let mut values = vec![
+            f64::NEG_INFINITY,
+            -1.0,
+            0.0,
+            f64::INFINITY,
+            f64::NAN,
+            f64::MAX,
+        ];
+        for &bound in &DEFAULT_BOUNDS {
+            values.extend([bound, bound.next_down(), bound.next_up()]);
+        }
+        values.extend((0..2_000).map(|i| i as f64 * 6.1));
+        for &value in &values {
+            fixed.record(value);
+            boxed.record(value);
+        }
+        assert_eq!(fixed.overflow_count(), boxed.overflow_count());
+        let proto = telemetry::Histogram::from(&fixed);
+        let snapshot = fixed.snapshot_and_reset();
+        assert_eq!(snapshot, boxed.snapshot_and_reset());
+        assert_eq!((proto.bounds, proto.counts), snapshot);
+        assert_eq!(
+            fixed.snapshot_and_reset().1,
+            vec![0; DEFAULT_BOUNDS.len() + 1]
+        );
+    }
+
+    #[test]
+    fn test_no_bounds() {
+        let hist = FixedHistogram::new([]);
+        hist.record(1.0);
+        assert_eq!(hist.snapshot_and_reset(), (vec![], vec![1]));
+    }
+}
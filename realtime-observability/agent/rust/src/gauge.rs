@@ -0,0 +1,235 @@
+//! Gauges with per-push-window aggregation
+
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+
+use crate::epoch::{self, Epoch};
+use crate::switches::{CachedSwitch, Switched};
+use crate::sync::{AtomicU64, AtomicU8};
+
+/// How repeated `set_gauge` calls within one push window are combined
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum GaugeAggregation {
+    /// Keep the most recent value; it persists across windows
+    #[default]
+    Last,
+    Max,
+    Min,
+    Mean,
+    Sum,
+}
+
+impl GaugeAggregation {
+    fn from_u8(v: u8) -> Self {
+        match v {
+            1 => GaugeAggregation::Max,
+            2 => GaugeAggregation::Min,
+            3 => GaugeAggregation::Mean,
+            4 => GaugeAggregation::Sum,
+            _ => GaugeAggregation::Last,
+        }
+    }
+
+    fn as_u8(self) -> u8 {
+        match self {
+            GaugeAggregation::Last => 0,
+            GaugeAggregation::Max => 1,
+            GaugeAggregation::Min => 2,
+            GaugeAggregation::Mean => 3,
+            GaugeAggregation::Sum => 4,
+        }
+    }
+}
+
+/// Lock-free gauge accumulator. Values are stored as `f64` bits.
+///
+/// Windowed modes accumulate per epoch parity (see `epoch`); `Last` keeps
+/// one value in the first slot, which persists across windows and so is
+/// read as of the collect rather than the cut.
+pub(crate) struct Gauge {
+    mode: AtomicU8,
+    epoch: Option<Arc<Epoch>>,
+    value: [AtomicU64; 2],
+    /// Sets since the slot was last taken
+    count: [AtomicU64; 2],
+    enabled: CachedSwitch,
+}
+
+impl Switched for Gauge {
+    fn switch(&self) -> &CachedSwitch {
+        &self.enabled
+    }
+}
+
+impl Gauge {
+    pub(crate) fn new(mode: GaugeAggregation) -> Self {
+        let identity = Self::identity(mode).to_bits();
+        Self {
+            mode: AtomicU8::new(mode.as_u8()),
+            epoch: None,
+            value: [AtomicU64::new(identity), AtomicU64::new(identity)],
+            count: [AtomicU64::new(0), AtomicU64::new(0)],
+            enabled: CachedSwitch::default(),
+        }
+    }
+
+    /// Accumulate windows per epoch of `epoch`
+    pub(crate) fn in_epoch(mut self, epoch: Option<Arc<Epoch>>) -> Self {
+        self.epoch = epoch;
+        self
+    }
+
+    pub(crate) fn mode(&self) -> GaugeAggregation {
+        GaugeAggregation::from_u8(self.mode.load(Ordering::Relaxed))
+    }
+
+    pub(crate) fn set_mode(&self, mode: GaugeAggregation) {
+        self.mode.store(mode.as_u8(), Ordering::Relaxed);
+        for (value, count) in self.value.iter().zip(&self.count) {
+            value.store(Self::identity(mode).to_bits(), Ordering::Relaxed);
+            count.store(0, Ordering::Relaxed);
+        }
+    }
+
+    fn identity(mode: GaugeAggregation) -> f64 {
+        match mode {
+            GaugeAggregation::Max => f64::NEG_INFINITY,
+            GaugeAggregation::Min => f64::INFINITY,
+            GaugeAggregation::Last | GaugeAggregation::Mean | GaugeAggregation::Sum => 0.0,
+        }
+    }
+
+    #[inline]
+    pub(crate) fn set(&self, value: f64) {
+        let mode = self.mode();
+        if mode == GaugeAggregation::Last {
+            self.value[0].store(value.to_bits(), Ordering::Relaxed);
+            self.count[0].fetch_add(1, Ordering::Release);
+            return;
+        }
+        let pin = epoch::pin(self.epoch.as_deref());
+        let slot = pin.slot();
+        match mode {
+            GaugeAggregation::Max => {
+                self.update(slot, |cur| cur.max(value));
+            }
+            GaugeAggregation::Min => {
+                self.update(slot, |cur| cur.min(value));
+            }
+            _ => {
+                self.update(slot, |cur| cur + value);
+            }
+        }
+        // Publishes the value update to a `take` that sees this count
+        self.count[slot].fetch_add(1, Ordering::Release);
+    }
+
+    /// Atomically add `delta` to the stored value and return the result.
+    /// A later `set` overwrites whatever has accumulated. Meant for `Last`
+    /// gauges; in windowed modes it adds to the window's accumulator.
+    #[inline]
+    pub(crate) fn add(&self, delta: f64) -> f64 {
+        let pin =
+            (self.mode() != GaugeAggregation::Last).then(|| epoch::pin(self.epoch.as_deref()));
+        let slot = pin.as_ref().map_or(0, |pin| pin.slot());
+        let previous = self.update(slot, |cur| cur + delta);
+        self.count[slot].fetch_add(1, Ordering::Release);
+        previous + delta
+    }
+
+    /// Apply `f` to one slot with a CAS loop, returning the value it
+    /// replaced
+    fn update(&self, slot: usize, f: impl Fn(f64) -> f64) -> f64 {
+        let previous = self.value[slot]
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |bits| {
+                Some(f(f64::from_bits(bits)).to_bits())
+            })
+            .unwrap_or_else(|bits| bits);
+        f64::from_bits(previous)
+    }
+
+    /// Current value of a `Last` gauge without resetting
+    pub(crate) fn peek(&self) -> f64 {
+        f64::from_bits(self.value[0].load(Ordering::Relaxed))
+    }
+
+    /// Aggregate for the window that just ended, resetting the accumulator.
+    /// Only for gauges outside an agent, whose windows are all in the
+    /// first slot; agents take the slot of their `Cut`.
+    #[cfg(any(test, loom))]
+    pub(crate) fn take(&self) -> Option<f64> {
+        self.take_slot(0)
+    }
+
+    /// Like `take_slot`, but `None` also for a `Last` gauge not set since
+    /// it was last taken
+    pub(crate) fn take_slot_if_set(&self, slot: usize) -> Option<f64> {
+        if self.mode() == GaugeAggregation::Last {
+            let sets = self.count[0].swap(0, Ordering::Acquire);
+            return (sets > 0).then(|| self.peek());
+        }
+        self.take_slot(slot)
+    }
+
+    /// Aggregate of one epoch's window, resetting it. Returns `None` for
+    /// windowed modes that saw no values.
+    pub(crate) fn take_slot(&self, slot: usize) -> Option<f64> {
+        let mode = self.mode();
+        if mode == GaugeAggregation::Last {
+            // Only marks whether it was set since, for `take_slot_if_set`
+            self.count[0].swap(0, Ordering::Acquire);
+            return Some(self.peek());
+        }
+
+        let count = self.count[slot].swap(0, Ordering::Acquire);
+        if count == 0 {
+            // A set racing with this take may have updated the value
+            // already; leave it for the window its count lands in
+            return None;
+        }
+        let value = f64::from_bits(
+            self.value[slot].swap(Self::identity(mode).to_bits(), Ordering::Relaxed),
+        );
+        match mode {
+            GaugeAggregation::Mean => Some(value / count as f64),
+            _ => Some(value),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_balanced_add_sub_ends_at_zero() {
+        let gauge = Arc::new(Gauge::new(GaugeAggregation::Last));
+        let threads: Vec<_> = (0..16)
+            .map(|_| {
+                let gauge = gauge.clone();
+                std::thread::spawn(move || {
+                    for _ in 0..10_000 {
+                        gauge.add(1.0);
+                        gauge.add(-1.0);
+                    }
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        assert_eq!(gauge.take(), Some(0.0));
+    }
+
+    #[test]
+    fn test_set_overwrites_accumulated() {
+        let gauge = Gauge::new(GaugeAggregation::Last);
+        assert_eq!(gauge.add(2.0), 2.0);
+        assert_eq!(gauge.add(3.0), 5.0);
+        gauge.set(1.0);
+        assert_eq!(gauge.add(-0.5), 0.5);
+        // Last-write-wins persists across windows
+        assert_eq!(gauge.take(), Some(0.5));
+        assert_eq!(gauge.take(), Some(0.5));
+    }
+}
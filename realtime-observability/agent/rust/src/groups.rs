@@ -0,0 +1,349 @@
+//! Pushing each batch as one sub-batch per metric group
+//! (`Config::group_batches`, `Agent::assign_group`)
+//!
+//! A metric's group is the part of its name before the first `_`
+//! (`http_requests_total` is in `http`) unless assigned another. Each
+//! group's sub-batch is its own push, one after another, so an aggregator
+//! rejecting one group, say for a histogram family too large to accept,
+//! fails only that group. Events, the announce and a batch without metrics
+//! ride with the first group.
+//!
+//! The push as a whole fails only if every group did. Otherwise the
+//! groups that failed are counted and reported, and those that failed with
+//! a retryable error go back into the send queue once, as batches of their
+//! own; a group that keeps failing is left to the next collect.
+
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
+use std::time::Duration;
+
+use parking_lot::{Mutex, RwLock};
+use tonic::{Response, Status};
+
+use crate::agent::PushCall;
+use crate::runtime::{BoxFuture, Transport};
+use crate::telemetry::{Ack, TelemetryBatch};
+use crate::{GroupDiagnostics, PushErrorKind};
+
+#[derive(Default)]
+pub(crate) struct BatchGroups {
+    /// From `Agent::assign_group`, by metric name
+    assigned: RwLock<HashMap<String, String>>,
+    /// Sub-batches acknowledged and failed, by group
+    counts: Mutex<BTreeMap<String, (u64, u64)>>,
+}
+
+impl BatchGroups {
+    pub(crate) fn assign(&self, name: &str, group: &str) {
+        self.assigned
+            .write()
+            .insert(name.to_string(), group.to_string());
+    }
+
+    fn group_of<'a>(&'a self, assigned: &'a HashMap<String, String>, name: &'a str) -> &'a str {
+        match assigned.get(name) {
+            Some(group) => group,
+            None => name.split('_').next().unwrap_or(name),
+        }
+    }
+
+    /// `batch` as one batch per group, in group order; the first keeps
+    /// everything but the other groups' metrics
+    pub(crate) fn split(&self, mut batch: TelemetryBatch) -> Vec<(String, TelemetryBatch)> {
+        let metrics = std::mem::take(&mut batch.metrics);
+        if metrics.is_empty() {
+            return vec![(String::new(), batch)];
+        }
+        let mut groups: BTreeMap<String, Vec<_>> = BTreeMap::new();
+        {
+            let assigned = self.assigned.read();
+            for metric in metrics {
+                let group = self.group_of(&assigned, &metric.name);
+                match groups.get_mut(group) {
+                    Some(metrics) => metrics.push(metric),
+                    None => {
+                        groups.insert(group.to_string(), vec![metric]);
+                    }
+                }
+            }
+        }
+        let mut first = Some(batch);
+        let template = first.as_ref().map(|batch| TelemetryBatch {
+            events: Vec::new(),
+            spans: Vec::new(),
+            announce: None,
+            ..batch.clone()
+        });
+        groups
+            .into_iter()
+            .map(|(group, metrics)| {
+                let batch = first.take().unwrap_or_else(|| template.clone().unwrap());
+                (group, TelemetryBatch { metrics, ..batch })
+            })
+            .collect()
+    }
+
+    pub(crate) fn diagnostics(&self) -> Vec<GroupDiagnostics> {
+        self.counts
+            .lock()
+            .iter()
+            .map(|(group, &(sent, failed))| GroupDiagnostics {
+                group: group.clone(),
+                sent,
+                failed,
+            })
+            .collect()
+    }
+
+    fn count(&self, group: &str, ok: bool) {
+        // The metric-less batch of a quiet interval isn't a group
+        if group.is_empty() {
+            return;
+        }
+        let mut counts = self.counts.lock();
+        let (sent, failed) = counts.entry(group.to_string()).or_default();
+        match ok {
+            true => *sent += 1,
+            false => *failed += 1,
+        }
+    }
+}
+
+/// One group's sub-batch, as collected and as sent
+pub(crate) struct GroupBatch {
+    pub(crate) group: String,
+    /// Kept to go back into the send queue if the push fails
+    pub(crate) collected: Option<TelemetryBatch>,
+    pub(crate) finished: TelemetryBatch,
+}
+
+/// How the groups of one push fared
+#[derive(Default)]
+pub(crate) struct GroupOutcome {
+    /// Collected sub-batches of groups not answered yet
+    pending: Vec<(String, Option<TelemetryBatch>)>,
+    /// Groups acknowledged
+    sent: Vec<String>,
+    /// The first acknowledgement
+    ack: Option<Response<Ack>>,
+    failed: Vec<FailedGroup>,
+    /// The first group, with the events, failed
+    pub(crate) events_lost: bool,
+}
+
+pub(crate) struct FailedGroup {
+    pub(crate) group: String,
+    pub(crate) collected: Option<TelemetryBatch>,
+    pub(crate) status: Status,
+}
+
+impl GroupOutcome {
+    fn answered(&mut self, group: &str, result: Result<Response<Ack>, Status>) {
+        let at = self
+            .pending
+            .iter()
+            .position(|(pending, _)| pending == group);
+        let collected = at.and_then(|at| self.pending.remove(at).1);
+        match result {
+            Ok(ack) => {
+                self.sent.push(group.to_string());
+                self.ack.get_or_insert(ack);
+            }
+            Err(status) => self.fail(group.to_string(), collected, status),
+        }
+    }
+
+    fn fail(&mut self, group: String, collected: Option<TelemetryBatch>, status: Status) {
+        self.events_lost |= self.failed.is_empty() && self.ack.is_none();
+        self.failed.push(FailedGroup {
+            group,
+            collected,
+            status,
+        });
+    }
+
+    /// Count every group's outcome; returns the groups that failed,
+    /// leaving out their sub-batches if the whole push failed or the
+    /// error won't go away on a retry
+    pub(crate) fn settle(mut self, groups: &BatchGroups, push_ok: bool) -> Vec<FailedGroup> {
+        for group in &self.sent {
+            groups.count(group, true);
+        }
+        for failed in &mut self.failed {
+            groups.count(&failed.group, false);
+            if !push_ok || !PushErrorKind::from_status(&failed.status).is_retryable() {
+                failed.collected = None;
+            }
+        }
+        self.failed
+    }
+}
+
+/// Push `batches` one group after another over `transport`, the announce
+/// leading the first, within `timeout`. The outcome is shared with the
+/// returned call, which records each group's answer as it comes.
+pub(crate) fn push_groups(
+    transport: Transport,
+    announce: Option<TelemetryBatch>,
+    batches: Vec<GroupBatch>,
+    timeout: BoxFuture,
+    push_timeout: Duration,
+) -> (PushCall, Arc<Mutex<GroupOutcome>>) {
+    let outcome = Arc::new(Mutex::new(GroupOutcome {
+        pending: batches
+            .iter()
+            .map(|batch| (batch.group.clone(), None))
+            .collect(),
+        ..Default::default()
+    }));
+    let mut sends = Vec::with_capacity(batches.len());
+    {
+        let mut shared = outcome.lock();
+        for (at, batch) in batches.into_iter().enumerate() {
+            shared.pending[at].1 = batch.collected;
+            sends.push((batch.group, batch.finished));
+        }
+    }
+
+    let shared = outcome.clone();
+    let call = Box::pin(async move {
+        let pushes = async {
+            let mut announce = announce;
+            for (group, batch) in sends {
+                let announce = announce.take();
+                let stream = async_stream::stream! {
+                    if let Some(announce) = announce {
+                        yield announce;
+                    }
+                    yield batch;
+                };
+                let result = transport.push(stream).await;
+                shared.lock().answered(&group, result);
+            }
+        };
+        tokio::select! {
+            _ = pushes => {}
+            _ = timeout => {
+                let mut shared = shared.lock();
+                for (group, collected) in std::mem::take(&mut shared.pending) {
+                    let status = Status::deadline_exceeded(format!(
+                        "push not acknowledged within {:?}",
+                        push_timeout
+                    ));
+                    shared.fail(group, collected, status);
+                }
+            }
+        }
+        // The first acknowledgement, or the first error if every group failed
+        let mut shared = shared.lock();
+        match shared.ack.take() {
+            Some(ack) => Ok(ack),
+            None => Err(shared.failed[0].status.clone()),
+        }
+    });
+    (call, outcome)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::telemetry::{Event, Metric};
+
+    fn metric(name: &str) -> Metric {
+        Metric {
+            name: name.to_string(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_split_by_prefix_and_assignment() {
+        let groups = BatchGroups::default();
+        groups.assign("latency_giant", "giant");
+        let batch = TelemetryBatch {
+            service: "checkout".to_string(),
+            metrics: vec![
+                metric("http_requests_total"),
+                metric("latency_giant"),
+                metric("db_queries_total"),
+                metric("http_errors_total"),
+                metric("uptime"),
+            ],
+            events: vec![Event::default()],
+            ..Default::default()
+        };
+        let split = groups.split(batch);
+        let names: Vec<(&str, Vec<&str>)> = split
+            .iter()
+            .map(|(group, batch)| {
+                let metrics = batch.metrics.iter().map(|m| m.name.as_str()).collect();
+                (group.as_str(), metrics)
+            })
+            .collect();
+        assert_eq!(
+            names,
+            [
+                ("db", vec!["db_queries_total"]),
+                ("giant", vec!["latency_giant"]),
+                ("http", vec!["http_requests_total", "http_errors_total"]),
+                ("uptime", vec!["uptime"]),
+            ]
+        );
+        assert!(split.iter().all(|(_, batch)| batch.service == "checkout"));
+        assert_eq!(split[0].1.events.len(), 1);
+        assert!(split[1..].iter().all(|(_, batch)| batch.events.is_empty()));
+    }
+
+    #[test]
+    fn test_batch_without_metrics_goes_whole() {
+        let groups = BatchGroups::default();
+        let batch = TelemetryBatch {
+            events: vec![Event::default()],
+            ..Default::default()
+        };
+        let split = groups.split(batch);
+        assert_eq!(split.len(), 1);
+        assert_eq!(split[0].0, "");
+        groups.count("", true);
+        assert!(groups.diagnostics().is_empty());
+    }
+
+    #[test]
+    fn test_settle_keeps_only_retryable_partial_failures() {
+        let groups = BatchGroups::default();
+        let mut outcome = GroupOutcome {
+            pending: vec![
+                ("db".to_string(), Some(TelemetryBatch::default())),
+                ("giant".to_string(), Some(TelemetryBatch::default())),
+                ("http".to_string(), Some(TelemetryBatch::default())),
+            ],
+            ..Default::default()
+        };
+        outcome.answered("db", Err(Status::unavailable("busy")));
+        assert!(outcome.events_lost);
+        outcome.answered("giant", Err(Status::invalid_argument("too large")));
+        outcome.answered("http", Ok(Response::new(Ack::default())));
+        assert!(outcome.ack.is_some());
+
+        let failed = outcome.settle(&groups, true);
+        let retried: Vec<&str> = failed
+            .iter()
+            .filter(|failed| failed.collected.is_some())
+            .map(|failed| failed.group.as_str())
+            .collect();
+        assert_eq!(retried, ["db"]);
+        let counted: Vec<(String, u64, u64)> = groups
+            .diagnostics()
+            .into_iter()
+            .map(|group| (group.group, group.sent, group.failed))
+            .collect();
+        assert_eq!(
+            counted,
+            [
+                ("db".to_string(), 0, 1),
+                ("giant".to_string(), 0, 1),
+                ("http".to_string(), 1, 0),
+            ]
+        );
+    }
+}
@@ -0,0 +1,154 @@
+//! Raw histogram records for offline analysis (`Agent::tap_histogram`)
+//!
+//! Buckets can't say when a value was recorded or what it was exactly.
+//! While a `HistogramTap` is alive, every `record_histogram` and
+//! `record_histogram_with` of its name also pushes `(timestamp_ns, value)`
+//! into the tap's bounded queue; when it is full the oldest record is
+//! dropped and counted. With no tap on any name, the record path pays one
+//! relaxed load; while any tap is alive, every record looks its name up.
+//! Handles from `histogram_family`, `histogram_ms` and the like are not
+//! tapped.
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crossbeam::queue::ArrayQueue;
+use parking_lot::RwLock;
+
+struct Ring {
+    records: ArrayQueue<(u64, f64)>,
+    dropped: AtomicU64,
+}
+
+#[derive(Default)]
+pub(crate) struct HistogramTaps {
+    /// Whether `taps` has any entry, checked before taking the lock
+    any: AtomicBool,
+    taps: RwLock<HashMap<String, Vec<Arc<Ring>>>>,
+}
+
+impl HistogramTaps {
+    /// Hand `value` to the taps on `name`, if any
+    #[inline]
+    pub(crate) fn record(&self, name: &str, value: f64) {
+        if !self.any.load(Ordering::Relaxed) {
+            return;
+        }
+        self.record_slow(name, value);
+    }
+
+    #[cold]
+    fn record_slow(&self, name: &str, value: f64) {
+        let taps = self.taps.read();
+        let Some(rings) = taps.get(name) else {
+            return;
+        };
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos() as u64;
+        for ring in rings {
+            if ring.records.force_push((now, value)).is_some() {
+                ring.dropped.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+
+    fn add(&self, name: &str, ring: Arc<Ring>) {
+        let mut taps = self.taps.write();
+        taps.entry(name.to_string()).or_default().push(ring);
+        self.any.store(true, Ordering::Relaxed);
+    }
+
+    fn remove(&self, name: &str, ring: &Arc<Ring>) {
+        let mut taps = self.taps.write();
+        if let Some(rings) = taps.get_mut(name) {
+            rings.retain(|r| !Arc::ptr_eq(r, ring));
+            if rings.is_empty() {
+                taps.remove(name);
+            }
+        }
+        self.any.store(!taps.is_empty(), Ordering::Relaxed);
+    }
+}
+
+/// Raw records of one histogram, from `Agent::tap_histogram`; recording
+/// stops when it is dropped
+pub struct HistogramTap {
+    name: String,
+    ring: Arc<Ring>,
+    taps: Arc<HistogramTaps>,
+}
+
+impl HistogramTap {
+    pub(crate) fn new(taps: Arc<HistogramTaps>, name: &str, capacity: usize) -> Self {
+        let ring = Arc::new(Ring {
+            records: ArrayQueue::new(capacity.max(1)),
+            dropped: AtomicU64::new(0),
+        });
+        taps.add(name, ring.clone());
+        Self {
+            name: name.to_string(),
+            ring,
+            taps,
+        }
+    }
+
+    /// `(timestamp_ns, value)` recorded since the last drain, oldest first
+    pub fn drain(&self) -> Vec<(u64, f64)> {
+        let mut records = Vec::with_capacity(self.ring.records.len());
+        while let Some(record) = self.ring.records.pop() {
+            records.push(record);
+        }
+        records
+    }
+
+    /// Records dropped, oldest first, for want of room
+    pub fn dropped(&self) -> u64 {
+        self.ring.dropped.load(Ordering::Relaxed)
+    }
+}
+
+impl Drop for HistogramTap {
+    fn drop(&mut self) {
+        self.taps.remove(&self.name, &self.ring);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_full_taps_drop_the_oldest() {
+        let taps = Arc::new(HistogramTaps::default());
+        let tap = HistogramTap::new(taps.clone(), "latency_ms", 3);
+        for value in 1..=5 {
+            taps.record("latency_ms", value as f64);
+        }
+        taps.record("other_ms", 9.0);
+        let records = tap.drain();
+        let values: Vec<f64> = records.iter().map(|&(_, v)| v).collect();
+        assert_eq!(values, [3.0, 4.0, 5.0]);
+        assert!(records.windows(2).all(|w| w[0].0 <= w[1].0));
+        assert_eq!(tap.dropped(), 2);
+        assert!(tap.drain().is_empty());
+    }
+
+    #[test]
+    fn test_dropping_the_last_tap_turns_recording_off() {
+        let taps = Arc::new(HistogramTaps::default());
+        let first = HistogramTap::new(taps.clone(), "latency_ms", 8);
+        let second = HistogramTap::new(taps.clone(), "latency_ms", 8);
+        taps.record("latency_ms", 1.0);
+        drop(first);
+        taps.record("latency_ms", 2.0);
+        assert!(taps.any.load(Ordering::Relaxed));
+        assert_eq!(second.drain().len(), 2);
+        drop(second);
+        assert!(!taps.any.load(Ordering::Relaxed));
+        assert!(taps.taps.read().is_empty());
+    }
+}
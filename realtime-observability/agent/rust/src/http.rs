@@ -0,0 +1,262 @@
+//! HTTP POST fallback transport, for networks whose proxies mangle gRPC
+//!
+//! Selected by an `aggregator_addr` of the form
+//! `http+post://collector:8080/v1/telemetry`: each batch is POSTed to the
+//! URL with `+post` removed, encoded as protobuf with content type
+//! `application/x-protobuf`, along with `Config::http_headers`. A 2xx
+//! response is an ack, and its body, if protobuf, is decoded as the `Ack`.
+//! Any other status fails the push like the matching gRPC status would.
+//!
+//! Like the gRPC transport, this speaks plain HTTP; TLS is left to a
+//! sidecar or proxy. Schema negotiation needs gRPC and is skipped, and a
+//! `TransportPool` always pushes over gRPC.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use hyper::client::HttpConnector;
+use hyper::header::{HeaderName, HeaderValue, CONTENT_TYPE};
+use hyper::{Body, Client, HeaderMap, Method, Request, StatusCode, Uri};
+use prost::Message;
+use tonic::codegen::tokio_stream::{Stream, StreamExt};
+use tonic::Status;
+
+use crate::telemetry::{Ack, TelemetryBatch};
+
+pub(crate) const SCHEME: &str = "http+post://";
+pub(crate) const PROTOBUF: &str = "application/x-protobuf";
+
+/// POSTs batches to one URL
+#[derive(Clone)]
+pub(crate) struct HttpExporter {
+    client: Client<HttpConnector>,
+    url: Uri,
+    headers: Arc<HeaderMap>,
+}
+
+impl HttpExporter {
+    /// `url` must already be a valid URI once `+post` is removed
+    pub(crate) fn new(addr: &str, headers: &HashMap<String, String>) -> Self {
+        let url = addr
+            .replacen(SCHEME, "http://", 1)
+            .parse()
+            .expect("aggregator_addr is validated before connecting");
+        let mut header_map = HeaderMap::new();
+        for (name, value) in headers {
+            match (
+                HeaderName::try_from(name.as_str()),
+                HeaderValue::try_from(value.as_str()),
+            ) {
+                (Ok(name), Ok(value)) => {
+                    header_map.insert(name, value);
+                }
+                _ => tracing::warn!(header = %name, "ignoring invalid HTTP header"),
+            }
+        }
+        Self {
+            client: Client::new(),
+            url,
+            headers: Arc::new(header_map),
+        }
+    }
+
+    /// POST every batch of `stream` in turn, returning the last ack
+    pub(crate) async fn push(
+        &self,
+        stream: impl Stream<Item = TelemetryBatch>,
+    ) -> Result<Ack, Status> {
+        let mut stream = std::pin::pin!(stream);
+        let mut ack = Ack::default();
+        while let Some(batch) = stream.next().await {
+            ack = self.post(&batch).await?;
+        }
+        Ok(ack)
+    }
+
+    async fn post(&self, batch: &TelemetryBatch) -> Result<Ack, Status> {
+        let mut request = Request::builder()
+            .method(Method::POST)
+            .uri(self.url.clone())
+            .header(CONTENT_TYPE, PROTOBUF)
+            .body(Body::from(batch.encode_to_vec()))
+            .expect("request parts are valid");
+        request
+            .headers_mut()
+            .extend(self.headers.iter().map(|(k, v)| (k.clone(), v.clone())));
+
+        let response = self.client.request(request).await.map_err(|e| {
+            let mut status = Status::unavailable(e.to_string());
+            status.set_source(Arc::new(e));
+            status
+        })?;
+        let code = response.status();
+        let is_protobuf = response
+            .headers()
+            .get(CONTENT_TYPE)
+            .is_some_and(|v| v.as_bytes() == PROTOBUF.as_bytes());
+        let body = hyper::body::to_bytes(response.into_body())
+            .await
+            .map_err(|e| Status::unavailable(format!("reading response: {}", e)))?;
+
+        if !code.is_success() {
+            let message = format!("HTTP {}: {}", code, String::from_utf8_lossy(&body));
+            return Err(Status::new(status_code(code), message));
+        }
+        if !is_protobuf || body.is_empty() {
+            return Ok(Ack::default());
+        }
+        Ack::decode(body).map_err(|e| Status::internal(format!("undecodable ack: {}", e)))
+    }
+}
+
+/// The gRPC code an HTTP status stands for, per the gRPC HTTP mapping
+fn status_code(status: StatusCode) -> tonic::Code {
+    use tonic::Code;
+
+    match status {
+        StatusCode::BAD_REQUEST | StatusCode::PAYLOAD_TOO_LARGE => Code::InvalidArgument,
+        StatusCode::UNAUTHORIZED => Code::Unauthenticated,
+        StatusCode::FORBIDDEN => Code::PermissionDenied,
+        StatusCode::NOT_FOUND | StatusCode::METHOD_NOT_ALLOWED | StatusCode::NOT_IMPLEMENTED => {
+            Code::Unimplemented
+        }
+        StatusCode::TOO_MANY_REQUESTS => Code::ResourceExhausted,
+        StatusCode::REQUEST_TIMEOUT | StatusCode::GATEWAY_TIMEOUT => Code::DeadlineExceeded,
+        StatusCode::BAD_GATEWAY | StatusCode::SERVICE_UNAVAILABLE => Code::Unavailable,
+        status if status.is_server_error() => Code::Internal,
+        _ => Code::Unknown,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::convert::Infallible;
+    use std::net::SocketAddr;
+    use std::time::Duration;
+
+    use hyper::service::{make_service_fn, service_fn};
+    use hyper::{Response, Server};
+    use parking_lot::Mutex;
+
+    use crate::telemetry::{metric_sample, Metric, MetricSample};
+    use crate::{Agent, Config, PushErrorKind};
+
+    /// Requests received as (headers, decoded body)
+    type Received = Arc<Mutex<Vec<(HeaderMap, TelemetryBatch)>>>;
+
+    /// Answers every POST with `status`, recording what it was sent
+    async fn serve(status: StatusCode) -> (SocketAddr, Received) {
+        let received: Received = Arc::default();
+        let make_service = {
+            let received = received.clone();
+            make_service_fn(move |_| {
+                let received = received.clone();
+                async move {
+                    Ok::<_, Infallible>(service_fn(move |request: Request<Body>| {
+                        let received = received.clone();
+                        async move {
+                            assert_eq!(request.headers()[CONTENT_TYPE], PROTOBUF);
+                            let headers = request.headers().clone();
+                            let body = hyper::body::to_bytes(request.into_body()).await.unwrap();
+                            let batch = TelemetryBatch::decode(body).unwrap();
+                            received.lock().push((headers, batch));
+                            let response = Response::builder()
+                                .status(status)
+                                .header(CONTENT_TYPE, PROTOBUF)
+                                .body(Body::from(
+                                    Ack {
+                                        ok: true,
+                                        ..Default::default()
+                                    }
+                                    .encode_to_vec(),
+                                ))
+                                .unwrap();
+                            Ok::<_, Infallible>(response)
+                        }
+                    }))
+                }
+            })
+        };
+        let server = Server::bind(&"127.0.0.1:0".parse().unwrap()).serve(make_service);
+        let addr = server.local_addr();
+        tokio::spawn(server);
+        (addr, received)
+    }
+
+    fn batch() -> TelemetryBatch {
+        TelemetryBatch {
+            service: "checkout".to_string(),
+            metrics: vec![Metric {
+                name: "jobs_total".to_string(),
+                samples: vec![MetricSample {
+                    value: Some(metric_sample::Value::Counter(3)),
+                    ..Default::default()
+                }],
+                ..Default::default()
+            }],
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_posts_batches_with_headers() {
+        let (addr, received) = serve(StatusCode::OK).await;
+        let exporter = HttpExporter::new(
+            &format!("http+post://{}/v1/telemetry", addr),
+            &[("authorization".to_string(), "Bearer k3y".to_string())].into(),
+        );
+
+        let ack = exporter
+            .push(tonic::codegen::tokio_stream::iter([batch(), batch()]))
+            .await
+            .unwrap();
+        assert!(ack.ok);
+
+        let received = received.lock();
+        assert_eq!(received.len(), 2);
+        assert_eq!(received[0].0["authorization"], "Bearer k3y");
+        assert_eq!(received[0].1, batch());
+    }
+
+    #[tokio::test]
+    async fn test_error_statuses_fail_the_push() {
+        for (status, kind) in [
+            (StatusCode::UNAUTHORIZED, PushErrorKind::Unauthenticated),
+            (StatusCode::SERVICE_UNAVAILABLE, PushErrorKind::Unavailable),
+            (
+                StatusCode::TOO_MANY_REQUESTS,
+                PushErrorKind::ResourceExhausted,
+            ),
+        ] {
+            let (addr, _) = serve(status).await;
+            let exporter = HttpExporter::new(&format!("http+post://{}/", addr), &HashMap::new());
+            let err = exporter
+                .push(tonic::codegen::tokio_stream::iter([batch()]))
+                .await
+                .unwrap_err();
+            assert_eq!(PushErrorKind::from_status(&err), kind, "{}", status);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_agent_pushes_over_http() {
+        let (addr, received) = serve(StatusCode::OK).await;
+        let mut agent = Agent::new(Config {
+            aggregator_addr: format!("http+post://{}/v1/telemetry", addr),
+            push_interval: Duration::from_millis(10),
+            negotiate_schema: true,
+            ..Default::default()
+        });
+        agent.start().await.unwrap();
+        agent.add_counter("jobs_total", 3);
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let report = agent.stop().await.unwrap();
+
+        assert!(report.bytes_sent > 0);
+        assert!(received
+            .lock()
+            .iter()
+            .any(|(_, b)| b.metrics.iter().any(|m| m.name == "jobs_total")));
+    }
+}
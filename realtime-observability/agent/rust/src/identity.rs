@@ -0,0 +1,160 @@
+//! Instance identity from outside the process (`Config::identity_provider`)
+//!
+//! A fleet on EC2 or GCE wants its instances named by the cloud's own
+//! instance id, and labeled with their zone, rather than by the random id
+//! `Config::instance_id` defaults to. The agent knows no cloud: a provider
+//! asks whatever it likes, once, during `start()`. An answer replaces
+//! `Config::instance_id`, and its labels are announced with
+//! `Config::metadata`, which wins over them. A provider that fails, or
+//! doesn't answer within `Config::push_timeout`, leaves the configured id.
+//!
+//! ```ignore
+//! // Needs the `reqwest` crate, and IMDSv1
+//! use telemetry_agent::{Identity, IdentityFuture, IdentityProvider};
+//!
+//! struct Ec2;
+//!
+//! impl IdentityProvider for Ec2 {
+//!     fn resolve(&self) -> IdentityFuture<'_> {
+//!         Box::pin(async {
+//!             let get = |path: &'static str| async move {
+//!                 let url = format!("http://169.254.169.254/latest/meta-data/{}", path);
+//!                 reqwest::get(url).await?.error_for_status()?.text().await
+//!             };
+//!             Ok(Identity {
+//!                 instance_id: get("instance-id").await?,
+//!                 labels: [("az".to_string(), get("placement/availability-zone").await?)].into(),
+//!             })
+//!         })
+//!     }
+//! }
+//! ```
+
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::future::Future;
+use std::pin::Pin;
+
+/// Who this instance is, from `Agent::identity`
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Identity {
+    /// Sent in every batch as `instance`. A provider answering with an
+    /// empty id leaves `Config::instance_id`.
+    pub instance_id: String,
+    /// Announced to the aggregator alongside `Config::metadata`
+    pub labels: HashMap<String, String>,
+}
+
+pub type IdentityFuture<'a> =
+    Pin<Box<dyn Future<Output = Result<Identity, Box<dyn Error + Send + Sync>>> + Send + 'a>>;
+
+/// Resolves the instance's identity for `Config::identity_provider`
+pub trait IdentityProvider: Send + Sync {
+    /// Called once, from `start()`
+    fn resolve(&self) -> IdentityFuture<'_>;
+}
+
+/// An `IdentityProvider` reading environment variables, such as those a
+/// container platform fills in from the downward API. Fails if the
+/// instance id's variable is unset; labels whose variable is unset are
+/// left out.
+///
+/// ```
+/// use std::sync::Arc;
+/// use telemetry_agent::{Config, EnvIdentityProvider};
+///
+/// let config = Config {
+///     identity_provider: Some(Arc::new(
+///         EnvIdentityProvider::new("POD_NAME")
+///             .label("node", "NODE_NAME")
+///             .label("zone", "TOPOLOGY_ZONE"),
+///     )),
+///     ..Default::default()
+/// };
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EnvIdentityProvider {
+    instance_id_var: String,
+    /// Label, then the variable holding its value
+    labels: Vec<(String, String)>,
+}
+
+impl EnvIdentityProvider {
+    /// Take the instance id from `$instance_id_var`
+    pub fn new(instance_id_var: &str) -> Self {
+        Self {
+            instance_id_var: instance_id_var.to_string(),
+            labels: Vec::new(),
+        }
+    }
+
+    /// Label the instance `label`, with the value of `$var`
+    pub fn label(mut self, label: &str, var: &str) -> Self {
+        self.labels.push((label.to_string(), var.to_string()));
+        self
+    }
+
+    fn read(&self) -> Result<Identity, UnsetVar> {
+        let instance_id = std::env::var(&self.instance_id_var)
+            .ok()
+            .filter(|id| !id.is_empty())
+            .ok_or_else(|| UnsetVar(self.instance_id_var.clone()))?;
+        let labels = self
+            .labels
+            .iter()
+            .filter_map(|(label, var)| Some((label.clone(), std::env::var(var).ok()?)))
+            .collect();
+        Ok(Identity {
+            instance_id,
+            labels,
+        })
+    }
+}
+
+impl IdentityProvider for EnvIdentityProvider {
+    fn resolve(&self) -> IdentityFuture<'_> {
+        let identity = self.read().map_err(|e| e.into());
+        Box::pin(async move { identity })
+    }
+}
+
+/// The instance id's variable of an `EnvIdentityProvider` is unset or
+/// empty
+#[derive(Debug)]
+struct UnsetVar(String);
+
+impl fmt::Display for UnsetVar {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "${} is not set", self.0)
+    }
+}
+
+impl Error for UnsetVar {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_env_provider() {
+        std::env::set_var("IDENTITY_TEST_POD", "checkout-7d9f8b-x2k4");
+        std::env::set_var("IDENTITY_TEST_ZONE", "eu-west-1b");
+        let provider = EnvIdentityProvider::new("IDENTITY_TEST_POD")
+            .label("zone", "IDENTITY_TEST_ZONE")
+            .label("node", "IDENTITY_TEST_UNSET");
+        assert_eq!(
+            provider.read().unwrap(),
+            Identity {
+                instance_id: "checkout-7d9f8b-x2k4".to_string(),
+                labels: [("zone".to_string(), "eu-west-1b".to_string())].into(),
+            }
+        );
+
+        let unset = EnvIdentityProvider::new("IDENTITY_TEST_UNSET");
+        assert_eq!(
+            unset.read().unwrap_err().to_string(),
+            "$IDENTITY_TEST_UNSET is not set"
+        );
+    }
+}
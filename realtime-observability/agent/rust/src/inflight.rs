@@ -0,0 +1,85 @@
+//! Requests in flight, and the most at once in each push interval
+//!
+//! A collect reads the current count at one instant, so a burst that
+//! starts and ends between two collects never shows in `inflight`. The
+//! high-watermark since the previous collect goes out as `inflight_max`.
+//! Raising it is a load and, only when the count is a new high, a CAS, so
+//! a request that doesn't set a record pays one uncontended read.
+
+use std::sync::atomic::{AtomicI64, Ordering};
+
+#[derive(Default)]
+pub(crate) struct Inflight {
+    current: AtomicI64,
+    max: AtomicI64,
+}
+
+impl Inflight {
+    pub(crate) fn enter(&self) {
+        let now = self.current.fetch_add(1, Ordering::Relaxed) + 1;
+        let mut max = self.max.load(Ordering::Relaxed);
+        while now > max {
+            match self
+                .max
+                .compare_exchange_weak(max, now, Ordering::Relaxed, Ordering::Relaxed)
+            {
+                Ok(_) => break,
+                Err(actual) => max = actual,
+            }
+        }
+    }
+
+    pub(crate) fn leave(&self) {
+        self.current.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn current(&self) -> i64 {
+        self.current.load(Ordering::Relaxed)
+    }
+
+    /// The most in flight since the last call, starting the next interval
+    /// from the requests still in flight
+    pub(crate) fn take_max(&self) -> i64 {
+        let current = self.current();
+        self.max.swap(current, Ordering::Relaxed).max(current)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_max_survives_until_taken() {
+        let inflight = Inflight::default();
+        for _ in 0..3 {
+            inflight.enter();
+        }
+        inflight.leave();
+        inflight.leave();
+        inflight.enter();
+        assert_eq!(inflight.current(), 2);
+        assert_eq!(inflight.take_max(), 3);
+        // Still two in flight in the next interval
+        assert_eq!(inflight.take_max(), 2);
+        inflight.leave();
+        inflight.leave();
+        assert_eq!(inflight.take_max(), 2);
+        assert_eq!(inflight.take_max(), 0);
+    }
+
+    #[test]
+    fn test_concurrent_entries_are_all_seen() {
+        let inflight = Inflight::default();
+        std::thread::scope(|scope| {
+            for _ in 0..8 {
+                scope.spawn(|| {
+                    for _ in 0..1000 {
+                        inflight.enter();
+                    }
+                });
+            }
+        });
+        assert_eq!(inflight.take_max(), 8000);
+    }
+}
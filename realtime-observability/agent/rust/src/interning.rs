@@ -0,0 +1,220 @@
+//! Series sent by id (`Config::intern_series`)
+//!
+//! With thousands of labeled series, the names and label maps repeated in
+//! every batch outweigh the samples. To aggregators that report
+//! `Capabilities::series_ids`, each series is instead given a small id,
+//! defined once in `TelemetryBatch::series_definitions`, and its metrics
+//! carry only the id after that. Ids last for the life of the process.
+//!
+//! The aggregator knows an id only if it got a batch defining it, so the
+//! agent tracks what it has defined on the current connection. A failed
+//! push or a switch of endpoint forgets all of it, and the next batch
+//! defines every series it carries again; so does every batch after
+//! `Config::resync_interval` has passed, for aggregators that lose their
+//! tables without the connection failing. Series are interned as the
+//! batch is sent: `subscribe_batches` and the stdout fallback see them
+//! in full.
+
+use std::collections::{HashMap, HashSet};
+use std::time::{Duration, Instant};
+
+use parking_lot::Mutex;
+
+use crate::series;
+use crate::telemetry::{SeriesDefinition, TelemetryBatch};
+
+/// Ids handed out before they are all dropped and numbered again from 1,
+/// so series that came and went don't accumulate
+const MAX_SERIES_IDS: usize = 1 << 16;
+
+pub(crate) struct SeriesIds {
+    /// How often every series is defined again
+    interval: Duration,
+    state: Mutex<State>,
+}
+
+#[derive(Default)]
+struct State {
+    /// By `series::encode` key
+    ids: HashMap<String, u32>,
+    /// Ids defined on the current connection
+    defined: HashSet<u32>,
+    /// When `defined` was last cleared for the interval
+    since: Option<Instant>,
+}
+
+impl SeriesIds {
+    pub(crate) fn new(interval: Duration) -> Self {
+        Self {
+            interval,
+            state: Mutex::default(),
+        }
+    }
+
+    /// Send `batch`'s metrics by id, with definitions of those the
+    /// aggregator may not have
+    pub(crate) fn intern(&self, batch: &mut TelemetryBatch, now: Instant) {
+        let mut state = self.state.lock();
+        let state = &mut *state;
+        if state
+            .since
+            .is_none_or(|since| now.duration_since(since) >= self.interval)
+        {
+            state.defined.clear();
+            state.since = Some(now);
+        }
+        // Numbered again between batches, never within one: an id defined
+        // in this batch must mean one series throughout it
+        if state.ids.len() + batch.metrics.len() > MAX_SERIES_IDS {
+            state.ids.clear();
+            state.defined.clear();
+        }
+        for metric in &mut batch.metrics {
+            let labels: Vec<(&str, &str)> = metric
+                .labels
+                .iter()
+                .map(|(k, v)| (k.as_str(), v.as_str()))
+                .collect();
+            let key = series::encode(&metric.name, &labels);
+            let next = state.ids.len() as u32 + 1;
+            let id = *state.ids.entry(key).or_insert(next);
+            let name = std::mem::take(&mut metric.name);
+            let labels = std::mem::take(&mut metric.labels);
+            if state.defined.insert(id) {
+                batch
+                    .series_definitions
+                    .push(SeriesDefinition { id, name, labels });
+            }
+            metric.series_id = id;
+        }
+    }
+
+    /// Define every series again in the next batch, as after a push that
+    /// may have lost definitions
+    pub(crate) fn forget(&self) {
+        self.state.lock().defined.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use prost::Message;
+
+    use super::*;
+    use crate::telemetry::compat::SeriesTable;
+    use crate::telemetry::metric_sample::Value;
+    use crate::telemetry::{Metric, MetricSample};
+    use crate::{Agent, Config};
+
+    const INTERVAL: Duration = Duration::from_secs(30);
+
+    fn batch(routes: &[&str]) -> TelemetryBatch {
+        TelemetryBatch {
+            metrics: routes
+                .iter()
+                .map(|route| Metric {
+                    name: "requests_total".to_string(),
+                    labels: [("route".to_string(), route.to_string())].into(),
+                    samples: vec![MetricSample {
+                        value: Some(Value::Counter(1)),
+                        ..Default::default()
+                    }],
+                    series_id: 0,
+                    series_hash: 0,
+                })
+                .collect(),
+            ..Default::default()
+        }
+    }
+
+    fn defined(batch: &TelemetryBatch) -> Vec<u32> {
+        batch.series_definitions.iter().map(|d| d.id).collect()
+    }
+
+    /// 5,000 request series labeled as a web service's would be
+    fn labeled_batch(agent: &Agent) -> TelemetryBatch {
+        for i in 0..5_000 {
+            let route = format!(
+                "/api/v2/{}/{{id}}/items",
+                ["orders", "users", "carts"][i % 3]
+            );
+            let labels = [
+                ("service", "checkout-frontend"),
+                ("route", route.as_str()),
+                ("method", ["GET", "POST", "PUT", "DELETE"][i % 4]),
+                ("status", ["200", "201", "404", "500", "503"][i % 5]),
+                ("region", ["eu-west-1", "us-east-1"][i % 2]),
+                ("pod", &format!("checkout-frontend-7d9f8b-{}", i / 60)),
+            ];
+            agent.inc_counter_with("http_server_requests_total", &labels);
+        }
+        agent.collect_now()
+    }
+
+    #[test]
+    fn test_series_ids_halve_steady_state_batches() {
+        let agent = Agent::new(Config::default());
+        let ids = SeriesIds::new(INTERVAL);
+        let now = Instant::now();
+        let mut first = labeled_batch(&agent);
+        assert!(first.metrics.len() >= 5_000);
+        let verbose_first = first.encoded_len();
+        ids.intern(&mut first, now);
+        // Defining every series costs little over sending it in full
+        assert!(first.encoded_len() < verbose_first * 11 / 10);
+
+        let batch = labeled_batch(&agent);
+        let verbose = batch.encoded_len();
+        let mut interned = batch.clone();
+        ids.intern(&mut interned, now);
+        assert!(interned.series_definitions.is_empty());
+        let reduction = 1.0 - interned.encoded_len() as f64 / verbose as f64;
+        assert!(reduction > 0.5, "{:.1}% smaller", reduction * 100.0);
+
+        let mut table = SeriesTable::new();
+        assert_eq!(table.resolve(&mut first), 0);
+        assert_eq!(table.resolve(&mut interned), 0);
+        assert_eq!(interned, batch);
+    }
+
+    #[test]
+    fn test_series_are_defined_once_per_connection() {
+        let ids = SeriesIds::new(INTERVAL);
+        let start = Instant::now();
+        let mut first = batch(&["/a", "/b"]);
+        ids.intern(&mut first, start);
+        assert_eq!(defined(&first), [1, 2]);
+        assert!(first.metrics.iter().all(|m| m.name.is_empty()));
+
+        let mut second = batch(&["/b", "/c"]);
+        ids.intern(&mut second, start);
+        assert_eq!(defined(&second), [3]);
+        assert_eq!(second.metrics[0].series_id, 2);
+
+        // A failed push may have lost any definition
+        ids.forget();
+        let mut third = batch(&["/a"]);
+        ids.intern(&mut third, start);
+        assert_eq!(defined(&third), [1]);
+
+        let mut table = SeriesTable::new();
+        for mut batch in [first, second, third] {
+            assert_eq!(table.resolve(&mut batch), 0);
+            assert!(batch.metrics.iter().all(|m| m.name == "requests_total"));
+        }
+    }
+
+    #[test]
+    fn test_series_are_defined_again_each_interval() {
+        let ids = SeriesIds::new(INTERVAL);
+        let start = Instant::now();
+        let mut first = batch(&["/a"]);
+        ids.intern(&mut first, start);
+        let mut within = batch(&["/a"]);
+        ids.intern(&mut within, start + INTERVAL / 2);
+        assert!(within.series_definitions.is_empty());
+        let mut after = batch(&["/a"]);
+        ids.intern(&mut after, start + INTERVAL);
+        assert_eq!(defined(&after), [1]);
+    }
+}
@@ -1,57 +1,550 @@
+// Under `noop` only the public surface is compiled; internal helpers shared
+// with the runtime build go unused.
+#![cfg_attr(feature = "noop", allow(dead_code))]
+
+#[cfg(all(not(feature = "runtime"), not(feature = "noop")))]
+compile_error!("telemetry-agent needs either the default `runtime` feature or `noop`");
+
+#[cfg(not(feature = "noop"))]
 pub mod telemetry {
     tonic::include_proto!("telemetry");
+
+    /// Sent in every batch as `schema_version`; bump it with any change to
+    /// what `telemetry.proto` messages mean
+    pub const SCHEMA_VERSION: u32 = 2;
+
+    /// This crate's version, sent in every batch as `agent_version`
+    pub const AGENT_VERSION: &str = env!("CARGO_PKG_VERSION");
+
+    /// Decoding batches from every version of this crate
+    pub mod compat {
+        pub use crate::compat::{decode_any_version, DecodedBatch, SeriesTable};
+    }
+
+    /// Batches rendered in full for people to read
+    pub mod pretty {
+        pub use crate::pretty::render;
+    }
+
+    /// Invariants of the batches this agent sends, for receivers to check
+    /// theirs against
+    pub mod validate {
+        pub use crate::validate::{validate_batch, Violation, MAX_TIMESTAMP_AHEAD};
+    }
+
+    pub use crate::series::series_hash;
 }
 
+/// Generated protobuf types under a stable path
+pub use telemetry as proto;
+
+#[cfg(not(feature = "noop"))]
+mod agent;
+#[cfg(not(feature = "noop"))]
+mod announce;
+#[cfg(not(feature = "noop"))]
+mod arena;
+#[cfg(all(feature = "axum", not(feature = "noop")))]
+pub mod axum;
+#[cfg(not(feature = "noop"))]
+mod budget;
+mod bulk;
+#[cfg(not(feature = "noop"))]
+mod cache;
+mod capabilities;
+#[cfg(not(feature = "noop"))]
+mod clock;
+#[cfg(not(feature = "noop"))]
+mod codec;
+#[cfg(not(feature = "noop"))]
+mod compact;
+#[cfg(not(feature = "noop"))]
+mod compat;
+#[cfg(feature = "toml")]
+mod config_file;
+#[cfg(not(feature = "noop"))]
+mod counter;
+mod debug;
+mod diagnostics;
+#[cfg(not(feature = "noop"))]
+mod directives;
+#[cfg(not(feature = "noop"))]
+mod driver;
+mod drops;
+mod epoch;
+mod error_log;
+#[cfg(not(feature = "noop"))]
+mod error_types;
+#[cfg(not(feature = "noop"))]
+mod events;
+#[cfg(not(feature = "noop"))]
+mod failure_log;
+#[cfg(not(feature = "noop"))]
+mod fallback;
+#[cfg(not(feature = "noop"))]
+mod family;
+mod filter;
+mod fixed_histogram;
+mod gauge;
+#[cfg(not(feature = "noop"))]
+mod groups;
+#[cfg(not(feature = "noop"))]
+mod histogram_tap;
+#[cfg(all(feature = "http", not(feature = "noop")))]
+mod http;
+mod identity;
+#[cfg(not(feature = "noop"))]
+mod inflight;
+#[cfg(not(feature = "noop"))]
+mod interning;
+#[cfg(not(feature = "noop"))]
+mod limits;
+#[cfg(not(feature = "noop"))]
+mod local;
+mod local_stats;
+mod manifest;
+#[cfg(not(feature = "noop"))]
+mod memory;
+mod metric_type;
+mod naming;
+#[cfg(feature = "noop")]
+mod noop;
+#[cfg(not(feature = "noop"))]
+mod pacing;
+#[cfg(not(feature = "noop"))]
+mod panic_hook;
+#[cfg(not(feature = "noop"))]
+mod pool;
+#[cfg(not(feature = "noop"))]
+mod pretty;
+#[cfg(not(feature = "noop"))]
+mod pull;
+mod push_error;
+#[cfg(not(feature = "noop"))]
+mod quantiles;
+#[cfg(not(feature = "noop"))]
+mod quarantine;
+#[cfg(not(feature = "noop"))]
+mod quota;
+#[cfg(not(feature = "noop"))]
+mod rates;
+#[cfg(not(feature = "noop"))]
+mod recorder;
+#[cfg(all(feature = "toml", not(feature = "noop")))]
+mod reload;
+#[cfg(not(feature = "noop"))]
+mod resync;
+#[cfg(not(feature = "noop"))]
+mod retry_budget;
+#[cfg(not(feature = "noop"))]
+mod runtime;
+#[cfg(not(feature = "noop"))]
+mod scoped;
+mod series;
+mod sharded;
+#[cfg(not(feature = "noop"))]
+mod shutdown_guard;
+#[cfg(all(feature = "signal", not(feature = "noop")))]
+mod signal;
+mod slo;
+#[cfg(not(feature = "noop"))]
+mod spans;
+#[cfg(not(feature = "noop"))]
+mod spool;
+mod state;
+mod state_set;
+#[cfg(all(feature = "statsd", not(feature = "noop")))]
+mod statsd;
+mod switches;
+mod sync;
+#[cfg(not(feature = "noop"))]
+mod tap;
+#[cfg(not(feature = "noop"))]
+mod totals;
+mod typed;
+mod validate;
+#[cfg(not(feature = "noop"))]
+mod window;
+mod wire_bounds;
+
+#[cfg(not(feature = "noop"))]
+pub use agent::{Agent, RequestChildGuard, RequestGuard};
+pub use bulk::BulkCollect;
+#[cfg(not(feature = "noop"))]
+pub use bulk::BulkCounterHandle;
+#[cfg(not(feature = "noop"))]
+pub use cache::CacheHandle;
+pub use capabilities::ServerCapabilities;
+#[cfg(not(feature = "noop"))]
+pub use codec::{DecodeError, WIRE_VERSION};
+#[cfg(feature = "toml")]
+pub use config_file::ConfigFileError;
+pub use debug::ScopedAgent;
+pub use diagnostics::{
+    ClockSkew, Diagnostics, GroupDiagnostics, JobReport, MemoryUsage, PoolAgentDiagnostics,
+    PoolDiagnostics, ShutdownPath, ShutdownReport,
+};
+#[cfg(not(feature = "noop"))]
+pub use directives::{MAX_REMOTE_INTERVAL, MIN_REMOTE_INTERVAL};
+pub use drops::DropStats;
+pub use error_log::ErrorInfo;
+#[cfg(not(feature = "noop"))]
+pub use family::{
+    CounterFamily, CounterHandle, Family, GaugeFamily, GaugeHandle, HistogramFamily,
+    HistogramHandle, LabelSchemaMismatch,
+};
+pub use filter::{InvalidPattern, MetricFilter};
+pub use fixed_histogram::{FixedHistogram, RecordableHistogram};
+pub use gauge::GaugeAggregation;
+#[cfg(not(feature = "noop"))]
+pub use histogram_tap::HistogramTap;
+pub use identity::{EnvIdentityProvider, Identity, IdentityFuture, IdentityProvider};
+#[cfg(not(feature = "noop"))]
+pub use local::{LocalAggregator, MetricView, SeriesValue, SeriesView};
+pub use local_stats::{LatencyStats, LocalStats};
+pub use manifest::{CounterDecl, GaugeDecl, HistogramDecl, ManifestConflict, MetricManifest};
+pub use metric_type::{MetricType, MetricTypeConflict};
+pub use naming::{normalize_name, MetricName, MetricNameLit};
+#[cfg(feature = "noop")]
+pub use noop::{
+    run_scoped, shutdown_guard, telemetry, Agent, BulkCounterHandle, CacheHandle, CounterFamily,
+    CounterHandle, DecodeError, Family, GaugeFamily, GaugeHandle, HistogramBytes, HistogramFamily,
+    HistogramHandle, HistogramMs, HistogramTap, LabelSchemaMismatch, LocalRecorder,
+    RequestChildGuard, RequestGuard, ShardedCounterHandle, ShutdownGuard, SloHandle, SpanGuard,
+    SpoolReplay, TokioSpawner, TransportPool, MAX_REMOTE_INTERVAL, MIN_REMOTE_INTERVAL,
+    WIRE_VERSION,
+};
+#[cfg(feature = "noop")]
+pub use noop::{BoxFuture, Spawner};
+#[cfg(not(feature = "noop"))]
+pub use pool::TransportPool;
+pub use push_error::{AgentError, PushErrorCallback, PushErrorKind};
+#[cfg(not(feature = "noop"))]
+pub use recorder::LocalRecorder;
+#[cfg(not(feature = "noop"))]
+pub use runtime::{BoxFuture, Spawner, TokioSpawner};
+#[cfg(not(feature = "noop"))]
+pub use scoped::run_scoped;
+#[cfg(not(feature = "noop"))]
+pub use sharded::ShardedCounterHandle;
+#[cfg(not(feature = "noop"))]
+pub use shutdown_guard::{shutdown_guard, ShutdownGuard};
+#[cfg(not(feature = "noop"))]
+pub use slo::SloHandle;
+pub use slo::SloSpec;
+#[cfg(not(feature = "noop"))]
+pub use spans::SpanGuard;
+#[cfg(not(feature = "noop"))]
+pub use spool::SpoolReplay;
+pub use state::{AgentState, HistogramState};
+pub use state_set::InvalidState;
+#[cfg(not(feature = "noop"))]
+pub use telemetry::telemetry_ingestor_server::{TelemetryIngestor, TelemetryIngestorServer};
+pub use typed::{ByteCount, Unit, UnitMismatch};
+#[cfg(not(feature = "noop"))]
+pub use typed::{HistogramBytes, HistogramMs};
+pub use wire_bounds::MisalignedWireBounds;
+
+use epoch::Epoch;
 use parking_lot::Mutex;
 use std::collections::HashMap;
-use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
-use std::sync::Arc;
-use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
-use tokio::sync::mpsc;
-use tokio::time::interval;
-use tonic::transport::Channel;
-
-use telemetry::telemetry_ingestor_client::TelemetryIngestorClient;
-use telemetry::{Histogram as HistogramProto, Metric, MetricSample, TelemetryBatch};
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, OnceLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use sync::AtomicU64;
 
 /// Default histogram bounds for latency tracking (in milliseconds)
-const DEFAULT_BOUNDS: [f64; 12] = [
+pub(crate) const DEFAULT_BOUNDS: [f64; 12] = [
     1.0, 5.0, 10.0, 25.0, 50.0, 100.0, 250.0, 500.0, 1000.0, 2500.0, 5000.0, 10000.0,
 ];
 
 /// Lock-free histogram for latency tracking
+///
+/// `counts` from `snapshot_and_reset` always has one more entry than
+/// `bounds`: `counts[i]` holds values in `(bounds[i-1], bounds[i]]` and the
+/// last entry is the overflow bucket, for values above the last bound and
+/// NaN. With `Config::explicit_inf_bound` the pushed proto appends `+Inf`
+/// to `bounds` so the two arrays line up one-to-one.
+///
+/// Counts are kept per epoch parity (see `epoch`) so an agent can drain
+/// one epoch while the next records.
 pub struct Histogram {
-    bounds: Vec<f64>,
-    counts: Vec<AtomicU64>,
+    buckets: Box<[Bucket]>,
+    overflow: [AtomicU64; 2],
+    /// The owning agent's epoch; `None` outside an agent
+    epoch: Option<Arc<Epoch>>,
+    /// One slot per bucket plus overflow, allocated by the first
+    /// `record_with_exemplar` with a trace, so histograms never given one
+    /// don't pay a mutex per bucket
+    exemplars: OnceLock<Box<[Mutex<Option<Exemplar>>]>>,
+    /// Set when the registry replaces this histogram with one of different
+    /// bounds; handles still holding this one record into the successor
+    successor: OnceLock<Arc<Histogram>>,
+    /// Its metric's switch, for the string API
+    enabled: switches::CachedSwitch,
+}
+
+impl switches::Switched for Histogram {
+    fn switch(&self) -> &switches::CachedSwitch {
+        &self.enabled
+    }
+}
+
+/// Upper bound and its counts, kept side by side so a lookup touches one
+/// cache line instead of two parallel vectors
+struct Bucket {
+    bound: f64,
+    count: [AtomicU64; 2],
+}
+
+/// Sample linking a histogram bucket to the trace that produced it
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Exemplar {
+    pub value: f64,
+    pub trace_id: u128,
+    pub timestamp_ns: u64,
 }
 
 impl Histogram {
     pub fn new() -> Self {
+        Self::with_bounds(&DEFAULT_BOUNDS)
+    }
+
+    /// Create a histogram with custom bucket bounds (must be sorted ascending)
+    pub fn with_bounds(bounds: &[f64]) -> Self {
+        debug_assert!(bounds.windows(2).all(|w| w[0] <= w[1]));
         Self {
-            bounds: DEFAULT_BOUNDS.to_vec(),
-            counts: (0..DEFAULT_BOUNDS.len() + 1)
-                .map(|_| AtomicU64::new(0))
+            buckets: bounds
+                .iter()
+                .map(|&bound| Bucket {
+                    bound,
+                    count: [AtomicU64::new(0), AtomicU64::new(0)],
+                })
                 .collect(),
+            overflow: [AtomicU64::new(0), AtomicU64::new(0)],
+            epoch: None,
+            exemplars: OnceLock::new(),
+            successor: OnceLock::new(),
+            enabled: switches::CachedSwitch::default(),
         }
     }
 
-    pub fn record(&self, value: f64) {
-        for (i, bound) in self.bounds.iter().enumerate() {
-            if value <= *bound {
-                self.counts[i].fetch_add(1, Ordering::Relaxed);
-                return;
+    /// Create a histogram with `count` exponentially growing bounds
+    pub fn exponential(start: f64, factor: f64, count: usize) -> Self {
+        Self::with_bounds(
+            &BucketSpec::Exponential {
+                start,
+                factor,
+                count,
             }
+            .bounds(),
+        )
+    }
+
+    /// Count per epoch of `epoch`
+    pub(crate) fn in_epoch(mut self, epoch: Option<Arc<Epoch>>) -> Self {
+        self.epoch = epoch;
+        self
+    }
+
+    /// Bytes allocated for buckets and exemplar slots
+    pub(crate) fn heap_bytes(&self) -> usize {
+        let exemplars = self.exemplars.get().map_or(0, |slots| slots.len());
+        self.buckets.len() * std::mem::size_of::<Bucket>()
+            + exemplars * std::mem::size_of::<Mutex<Option<Exemplar>>>()
+    }
+
+    /// Rebuild a histogram from bounds and counts (overflow count last)
+    pub(crate) fn from_parts(bounds: &[f64], counts: &[u64]) -> Self {
+        let hist = Self::with_bounds(bounds);
+        hist.add_counts(counts);
+        hist
+    }
+
+    /// Add to the counts (overflow count last)
+    pub(crate) fn add_counts(&self, counts: &[u64]) {
+        let pin = epoch::pin(self.epoch.as_deref());
+        for (count, value) in self.slot_counts(pin.slot()).zip(counts) {
+            count.fetch_add(*value, Ordering::Relaxed);
         }
-        self.counts[self.counts.len() - 1].fetch_add(1, Ordering::Relaxed);
     }
 
-    pub fn snapshot_and_reset(&self) -> (Vec<f64>, Vec<u64>) {
-        let counts: Vec<u64> = self
-            .counts
+    /// One epoch's bucket counts, overflow last
+    fn slot_counts(&self, slot: usize) -> impl Iterator<Item = &AtomicU64> {
+        self.buckets
+            .iter()
+            .map(move |b| &b.count[slot])
+            .chain(std::iter::once(&self.overflow[slot]))
+    }
+
+    /// Add counts binned by `bounds` (overflow count last), following a
+    /// successor. Counts binned by other bounds than ours go to the bucket
+    /// holding their upper bound.
+    pub(crate) fn merge_counts(&self, bounds: &[f64], counts: &[u64]) {
+        let pin = epoch::pin(self.epoch.as_deref());
+        if let Some(successor) = self.successor.get() {
+            drop(pin);
+            return successor.merge_counts(bounds, counts);
+        }
+        self.merge_into_slot(pin.slot(), bounds, counts);
+    }
+
+    /// `merge_counts` into one epoch's counts, without following a successor
+    fn merge_into_slot(&self, slot: usize, bounds: &[f64], counts: &[u64]) {
+        if self
+            .buckets
             .iter()
+            .map(|b| b.bound)
+            .eq(bounds.iter().copied())
+        {
+            for (count, value) in self.slot_counts(slot).zip(counts) {
+                count.fetch_add(*value, Ordering::Relaxed);
+            }
+            return;
+        }
+        for (i, &n) in counts.iter().enumerate().filter(|(_, &n)| n > 0) {
+            let index = bounds
+                .get(i)
+                .map_or(self.buckets.len(), |&bound| self.bucket_index(bound));
+            match self.buckets.get(index) {
+                Some(bucket) => bucket.count[slot].fetch_add(n, Ordering::Relaxed),
+                None => self.overflow[slot].fetch_add(n, Ordering::Relaxed),
+            };
+        }
+    }
+
+    pub(crate) fn bounds(&self) -> Vec<f64> {
+        self.buckets.iter().map(|b| b.bound).collect()
+    }
+
+    /// Counts of both epochs without resetting them (overflow count last)
+    pub(crate) fn counts(&self) -> Vec<u64> {
+        self.slot_counts(0)
+            .zip(self.slot_counts(1))
+            .map(|(a, b)| a.load(Ordering::Relaxed) + b.load(Ordering::Relaxed))
+            .collect()
+    }
+
+    /// Index of the first bucket whose bound is >= value, or
+    /// `buckets.len()` for the overflow bucket (including NaN)
+    #[inline]
+    #[allow(clippy::neg_cmp_op_on_partial_ord)]
+    pub(crate) fn bucket_index(&self, value: f64) -> usize {
+        self.buckets.partition_point(|b| !(value <= b.bound))
+    }
+
+    #[inline]
+    fn increment(&self, slot: usize, index: usize) {
+        // Relaxed is enough for exactly-once counting; see `sync`
+        match self.buckets.get(index) {
+            Some(bucket) => bucket.count[slot].fetch_add(1, Ordering::Relaxed),
+            None => self.overflow[slot].fetch_add(1, Ordering::Relaxed),
+        };
+    }
+
+    /// Values recorded above the last bound since the last reset
+    pub fn overflow_count(&self) -> u64 {
+        self.overflow[0].load(Ordering::Relaxed) + self.overflow[1].load(Ordering::Relaxed)
+    }
+
+    #[inline]
+    pub fn record(&self, value: f64) {
+        // Checked under the pin, so a record that misses the successor is
+        // done by the next epoch advance; see `retire`
+        let pin = epoch::pin(self.epoch.as_deref());
+        if let Some(successor) = self.successor.get() {
+            drop(pin);
+            return successor.record(value);
+        }
+        self.increment(pin.slot(), self.bucket_index(value));
+    }
+
+    /// Forward later records to `successor`. Records that checked for a
+    /// successor just before this can still land here until the epoch
+    /// advances twice; `drain_retired` then moves them over.
+    pub(crate) fn retire(&self, successor: Arc<Histogram>) {
+        let _ = self.successor.set(successor);
+    }
+
+    /// Move what records racing `retire` left here into the successor's
+    /// `slot`. Exact once the epoch has advanced since the collect that
+    /// retired this histogram.
+    pub(crate) fn drain_retired(&self, slot: usize) {
+        let Some(successor) = self.successor.get() else {
+            return;
+        };
+        let (bounds, counts, _) = self.snapshot_with_exemplars_and_reset();
+        if counts.iter().any(|&n| n > 0) {
+            successor.merge_into_slot(slot, &bounds, &counts);
+        }
+    }
+
+    /// Record `value` and keep it as the exemplar for its bucket, replacing
+    /// any earlier one this interval. A zero `trace_id` is not a valid
+    /// trace and records without an exemplar.
+    pub fn record_with_exemplar(&self, value: f64, trace_id: u128) {
+        let pin = epoch::pin(self.epoch.as_deref());
+        if let Some(successor) = self.successor.get() {
+            drop(pin);
+            return successor.record_with_exemplar(value, trace_id);
+        }
+        let index = self.bucket_index(value);
+        self.increment(pin.slot(), index);
+        drop(pin);
+        if trace_id == 0 {
+            return;
+        }
+        let slots = self
+            .exemplars
+            .get_or_init(|| (0..=self.buckets.len()).map(|_| Mutex::new(None)).collect());
+        *slots[index].lock() = Some(Exemplar {
+            value,
+            trace_id,
+            timestamp_ns: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_nanos() as u64,
+        });
+    }
+
+    pub fn snapshot_and_reset(&self) -> (Vec<f64>, Vec<u64>) {
+        let (bounds, counts, _) = self.snapshot_with_exemplars_and_reset();
+        (bounds, counts)
+    }
+
+    /// Like `snapshot_and_reset`, also taking the exemplars as
+    /// `(bucket_index, exemplar)` pairs
+    pub(crate) fn snapshot_with_exemplars_and_reset(
+        &self,
+    ) -> (Vec<f64>, Vec<u64>, Vec<(usize, Exemplar)>) {
+        let (bounds, mut counts, exemplars) = self.snapshot_slot_and_reset(0);
+        for (count, other) in counts.iter_mut().zip(self.slot_counts(1)) {
+            *count += other.swap(0, Ordering::Relaxed);
+        }
+        (bounds, counts, exemplars)
+    }
+
+    /// Like `snapshot_with_exemplars_and_reset`, for one epoch's counts.
+    /// Exemplars are not kept per epoch and all go with it.
+    pub(crate) fn snapshot_slot_and_reset(
+        &self,
+        slot: usize,
+    ) -> (Vec<f64>, Vec<u64>, Vec<(usize, Exemplar)>) {
+        let bounds = self.bounds();
+        let counts = self
+            .slot_counts(slot)
             .map(|c| c.swap(0, Ordering::Relaxed))
             .collect();
-        (self.bounds.clone(), counts)
+        let exemplars = self
+            .exemplars
+            .get()
+            .into_iter()
+            .flat_map(|slots| slots.iter().enumerate())
+            .filter_map(|(i, slot)| slot.lock().take().map(|e| (i, e)))
+            .collect();
+        (bounds, counts, exemplars)
     }
 }
 
@@ -61,13 +554,360 @@ impl Default for Histogram {
     }
 }
 
+/// Bucket layout for a histogram
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum BucketSpec {
+    /// Upper bounds, sorted ascending
+    Explicit(Vec<f64>),
+    /// `count` bounds starting at `start`, each `factor` times the previous
+    Exponential {
+        start: f64,
+        factor: f64,
+        count: usize,
+    },
+}
+
+impl BucketSpec {
+    pub fn bounds(&self) -> Vec<f64> {
+        match self {
+            BucketSpec::Explicit(bounds) => bounds.clone(),
+            BucketSpec::Exponential {
+                start,
+                factor,
+                count,
+            } => (0..*count).map(|i| start * factor.powi(i as i32)).collect(),
+        }
+    }
+}
+
+/// When a histogram's counts start afresh, chosen per histogram with
+/// `Agent::register_histogram`
+///
+/// A histogram accumulating past one push sends its totals since the
+/// window opened in every batch, marked with the window's start in
+/// `MetricSample::window_start_ns`; the batch at the deadline still carries
+/// the full window, and the next one starts empty. `into_state` carries
+/// only counts not yet collected, so a resumed agent starts new windows.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ResetPolicy {
+    /// Send each push interval's counts alone
+    #[default]
+    EveryPush,
+    /// Accumulate for at least this long, closing the window at the first
+    /// push past it
+    Every(Duration),
+    /// Accumulate for the life of the agent
+    Never,
+}
+
+/// What `Agent::set_monotonic_total` counts when a total goes down, as when
+/// the library keeping it was restarted
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum TotalReset {
+    /// The total restarted from zero: all of the new value is growth
+    #[default]
+    CountNewValue,
+    /// Count nothing, only measure growth from the new value on
+    Ignore,
+}
+
+/// Precision of the timestamps sent, for `Config::timestamp_resolution`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Resolution {
+    #[default]
+    Nanos,
+    Micros,
+    Millis,
+}
+
+impl Resolution {
+    /// Name in config files
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Resolution::Nanos => "nanos",
+            Resolution::Micros => "micros",
+            Resolution::Millis => "millis",
+        }
+    }
+
+    /// `ns` cut down to a multiple of this resolution
+    pub fn truncate(&self, ns: u64) -> u64 {
+        match self {
+            Resolution::Nanos => ns,
+            Resolution::Micros => ns - ns % 1_000,
+            Resolution::Millis => ns - ns % 1_000_000,
+        }
+    }
+}
+
+/// Changes to the gRPC endpoint before the agent connects; see
+/// `Config::endpoint_customizer`
+#[cfg(feature = "runtime")]
+pub type EndpointCustomizer =
+    Arc<dyn Fn(tonic::transport::Endpoint) -> tonic::transport::Endpoint + Send + Sync>;
+
 /// Agent configuration
 #[derive(Clone)]
 pub struct Config {
     pub aggregator_addr: String,
     pub service_name: String,
+    /// Sent in every batch as `service_version`; empty if unset
+    pub service_version: String,
     pub instance_id: String,
+    /// Asked for the instance id and labels during `start()`, in place of
+    /// `instance_id`; see `IdentityProvider`. Agents on a `TransportPool`
+    /// are never started and keep `instance_id`.
+    pub identity_provider: Option<Arc<dyn IdentityProvider>>,
     pub push_interval: Duration,
+    /// Shortest `push_interval` accepted: `start()` fails with
+    /// `InvalidArgument` below it, and a reloaded config file below it is
+    /// refused. Collecting thousands of series every millisecond would
+    /// take a whole core from the application.
+    pub min_push_interval: Duration,
+    /// Share of the push interval collecting and encoding a batch may
+    /// take. A cycle over it is counted in
+    /// `agent_collect_budget_exceeded_total`, and the next few cycles send
+    /// only series that changed: gauges set and counters incremented since
+    /// the last batch. `None` never skips anything.
+    pub max_collect_budget: Option<f64>,
+    /// Apply `AgentDirectives` returned by the aggregator (push interval,
+    /// sample rate, pause). Local values remain the defaults.
+    pub allow_remote_config: bool,
+    /// Called with the classified kind of every failed connect or push
+    pub on_push_error: Option<PushErrorCallback>,
+    /// Minimum time between summary lines while pushes keep failing
+    pub error_log_interval: Duration,
+    /// Bounds for request latency histograms without a
+    /// `configure_latency` override; `None` uses the built-in millisecond
+    /// bounds
+    pub default_latency_bounds: Option<BucketSpec>,
+    /// Also record request latency into the combined histogram, alongside
+    /// the per-outcome `{outcome="success"|"error"}` series. On by default
+    /// so dashboards reading the combined series keep working; set it to
+    /// `false` to opt out once they sum the outcomes instead, which saves
+    /// about half of what a `RequestGuard` costs on drop.
+    pub emit_combined_latency: bool,
+    /// Record a request's latency when the last of it and its
+    /// `RequestGuard::child` guards drops, rather than when it does
+    pub latency_until_children_done: bool,
+    /// Request latencies above this are not recorded: the request counts
+    /// toward `latency_outliers_total{metric}` instead, and its raw value
+    /// is logged at debug. A host that was suspended mid-request, or a
+    /// clock that jumped, would otherwise leave a sample of minutes or
+    /// hours in a millisecond histogram. `None` records everything.
+    pub max_plausible_latency: Option<Duration>,
+    /// Send recent `record_error_detailed` messages in the batch as
+    /// `error_sample` events, with `error_type` and `message` attributes
+    pub report_error_samples: bool,
+    /// Maximum error samples sent per push interval
+    pub error_samples_per_interval: usize,
+    /// Error messages are truncated to this many bytes
+    pub error_message_limit: usize,
+    /// Warn when the agent's own series, `agent_*` and those recorded from
+    /// inside the push loop, are more than this fraction of a batch: a
+    /// sign that something records every push. Off by default, since a
+    /// service with few metrics of its own sends mostly the agent's.
+    pub max_self_metric_fraction: Option<f64>,
+    /// Distinct `record_error` types counted in `errors_total{type}`;
+    /// types first seen after that are counted as `type="__other"`
+    pub max_error_types: usize,
+    /// End every pushed histogram's bounds with `+Inf`, giving bounds and
+    /// counts equal length. When off, counts carry one extra trailing
+    /// overflow entry.
+    pub explicit_inf_bound: bool,
+    /// Announced to the aggregator at startup and after reconnects, along
+    /// with `agent_version`, `os`, `arch` and `push_interval_ms`
+    pub metadata: HashMap<String, String>,
+    /// A push that has not been acknowledged after this long is cancelled
+    /// and counted as `deadline_exceeded`
+    pub push_timeout: Duration,
+    /// Give up on a TCP connection to the aggregator after this long,
+    /// counted as `connect_timeout`; also bounds reconnects
+    pub connect_timeout: Duration,
+    /// After connecting, `start()` fails if the aggregator hasn't answered
+    /// a first request within this long (`handshake_timeout`), as when a
+    /// load balancer accepts connections for a backend that is down.
+    /// Not checked with `lazy_connect`.
+    pub handshake_timeout: Duration,
+    /// How long `stop()` waits for queued and in-flight pushes before
+    /// aborting them
+    pub flush_timeout: Duration,
+    /// Where batches not pushed by the end of shutdown are written, one
+    /// checksummed spool file each, instead of dropped (see
+    /// `Agent::shutdown_with_deadline`). Read them back with
+    /// `SpoolReplay::read`.
+    pub spool_dir: Option<PathBuf>,
+    /// Stretch `push_interval` to twice the observed cycle time when
+    /// collecting and pushing a batch keeps taking longer than the interval
+    /// (see `Diagnostics::push_interval`). Either way, such overruns are
+    /// logged as a warning.
+    pub auto_relax_interval: bool,
+    /// Log every push, not just failures. With the `signal` feature,
+    /// SIGUSR2 toggles this while running.
+    pub verbose_push: bool,
+    /// Don't wait for the aggregator in `start()`: connect on the first
+    /// push, so an unreachable aggregator fails pushes rather than startup
+    pub lazy_connect: bool,
+    /// Once pushes have failed for this long, write batches that would be
+    /// lost to stdout, one line per metric, until a push succeeds again
+    pub stdout_fallback_after: Option<Duration>,
+    /// Shift pushed sample timestamps by `Agent::estimated_clock_skew`,
+    /// once there is an estimate, so they land in the aggregator's time
+    /// buckets. `collect_now` batches are never corrected.
+    pub correct_clock_skew: bool,
+    /// How long `run_scoped` keeps retrying its final flush, and each
+    /// `Agent::flush`, before giving up
+    pub final_flush_timeout: Duration,
+    /// Drive the gRPC transport on this runtime instead of the one
+    /// `start`/`start_on` is called from; for hosts on another executor
+    #[cfg(feature = "runtime")]
+    pub tokio_handle: Option<tokio::runtime::Handle>,
+    /// Applied to every gRPC endpoint the agent connects to, after it has
+    /// set `connect_timeout`, for knobs the agent doesn't expose: HTTP/2
+    /// keepalives, window sizes, TLS, a user agent. To build the whole
+    /// channel instead, see `Agent::start_with_channel`.
+    #[cfg(feature = "runtime")]
+    pub endpoint_customizer: Option<EndpointCustomizer>,
+    /// Have `start()` run the push loop and gRPC client on a
+    /// single-threaded runtime on an OS thread of its own, so pushes keep
+    /// their schedule while the application's runtime is saturated or
+    /// blocked. Replaces `tokio_handle`; `start_on` and the other ways of
+    /// starting ignore it. `stop()` joins the thread, waiting at most a
+    /// second past `flush_timeout`.
+    pub dedicated_thread: bool,
+    /// On connect, fetch the service's canonical histogram bounds from the
+    /// aggregator (`GetSchema`) and use them in place of local ones
+    pub negotiate_schema: bool,
+    /// Send only what these allow instead of asking the aggregator on
+    /// connect (`GetCapabilities`). Agents on a `TransportPool` never ask,
+    /// and send everything unless this is set.
+    pub assume_capabilities: Option<ServerCapabilities>,
+    /// Events queued by `emit_event` are capped at this many; the oldest
+    /// are dropped and counted in `agent_events_dropped_total`
+    pub max_events_per_batch: usize,
+    /// Share of root spans from `Agent::start_span` kept, with all their
+    /// descendants; 0.0 keeps none
+    pub span_sample_rate: f64,
+    /// Completed spans queued for the next batch are capped at this many;
+    /// the oldest are dropped and counted in `agent_spans_dropped_total`
+    pub max_spans_per_batch: usize,
+    /// Metrics whose names it rejects are never registered or sent; each
+    /// rejected registration counts in `agent_metrics_filtered_total`.
+    /// `agent_*` self-metrics are always kept.
+    pub metric_filter: Option<MetricFilter>,
+    /// Sent with every push to an `http+post://` address, such as
+    /// `authorization`; needs the `http` feature
+    pub http_headers: HashMap<String, String>,
+    /// Cap on the agent's estimated memory (see `Agent::memory_usage`).
+    /// Past it, new series are refused (`agent_registrations_refused_total`)
+    /// and queued batches are evicted oldest first
+    /// (`agent_batches_evicted_total`); existing series keep recording.
+    /// Batches queued in a `TransportPool` are not counted.
+    pub memory_budget_bytes: Option<usize>,
+    /// Cap on bytes sent per hour, as a token bucket that holds an hour's
+    /// worth and refills continuously. Batches the quota can't cover are
+    /// dropped (`agent_quota_dropped_batches_total`) until it refills;
+    /// `agent_quota_remaining_bytes` is still sent from a small reserve
+    /// carved out of the quota.
+    pub bytes_per_hour: Option<u64>,
+    /// Label values are cut to this many bytes, ending in `…`; each label
+    /// set that needed cutting counts in `agent_truncated_labels_total`
+    pub max_label_value_len: usize,
+    /// Labels past this many are dropped, keeping the first in key order
+    /// (also counted in `agent_truncated_labels_total`)
+    pub max_label_count_per_metric: usize,
+    /// Metric names are cut to this many bytes, ending in `…`; each cut
+    /// counts in `agent_truncated_names_total`
+    pub max_metric_name_len: usize,
+    /// Rename metrics whose names aren't snake_case with `normalize_name`,
+    /// so `requestCount` is recorded as `request_count`; each rename counts
+    /// in `agent_sanitized_names_total`. Names from `name!` are always
+    /// valid and pass through untouched.
+    pub sanitize_names: bool,
+    /// How `Agent::set_monotonic_total` treats a total that went down
+    pub total_reset: TotalReset,
+    /// Send the metrics with the most drops in `Agent::drop_report` as
+    /// `agent_drops_total{metric, reason}` counters, at most this often
+    pub drop_report_interval: Option<Duration>,
+    /// Stamp every batch with a CRC-32C of its encoding
+    /// (`TelemetryBatch::checksum`), for aggregators that check batches
+    /// end to end; `LocalAggregator` rejects a batch that doesn't match
+    pub checksum_batches: bool,
+    /// Check every batch against `telemetry::validate::validate_batch` as
+    /// it is sent. A debug build panics on a batch that breaks an
+    /// invariant; a release build sends it and counts it in
+    /// `Diagnostics::invalid_batches`. On by default in debug builds only.
+    pub validate_before_send: bool,
+    /// Rebuild a registry's table once fewer than this share of its slots
+    /// hold live series, as after a burst of short-lived label sets is
+    /// purged; the push loop checks one registry per collect. `None`
+    /// leaves it to `Agent::compact`.
+    pub compact_threshold: Option<f64>,
+    /// Precision of sample, event and exemplar timestamps sent. They stay
+    /// in nanoseconds, ending in zeros, which compresses better; the
+    /// aggregator's clock-skew estimate still uses exact send times.
+    pub timestamp_resolution: Resolution,
+    /// Send sample timestamps as offsets from a batch base time
+    /// (`TelemetryBatch::base_timestamp_ns`) to aggregators that accept it.
+    /// Samples collected together share a timestamp, so their offset is
+    /// zero and takes no bytes at all.
+    pub delta_timestamps: bool,
+    /// Send each series' name and labels once per connection, and only
+    /// a small id with its samples after that, to aggregators that accept
+    /// it (`Capabilities::series_ids`). Every series is defined again at
+    /// `resync_interval`. Agents on a `TransportPool` send every series in
+    /// full.
+    pub intern_series: bool,
+    /// Send each metric's `series_hash`, the hash of its name and labels
+    /// that aggregators sharding by series would otherwise compute, to
+    /// aggregators that accept it (`Capabilities::series_hash`). Hashed as
+    /// each batch is sent; see `telemetry::series_hash` for the algorithm.
+    pub hash_series: bool,
+    /// Send a `cache_hit_ratio{cache}` gauge for every `Agent::cache`:
+    /// hits over lookups since the previous push, in this instance only.
+    /// A push window without lookups sends no ratio.
+    pub cache_hit_ratio: bool,
+    /// Send a `{name}_per_second` gauge with each counter, for aggregators
+    /// without a rate function: its growth since the previous collect
+    /// over the time measured between the two. A counter's first collect
+    /// sends no rate. Sharded and bulk counters get none.
+    pub emit_counter_rates: bool,
+    /// Serve `AgentSnapshot.QuerySnapshot` on this address from `start()`,
+    /// for aggregators that pull a fresh snapshot on demand rather than
+    /// wait for the next push; see `Agent::pull_listener_addr`. Port 0
+    /// picks a free port. `start()` refuses an address other than
+    /// loopback unless `pull_listener_public` is set.
+    pub pull_listener: Option<SocketAddr>,
+    /// Let `pull_listener` bind to addresses other than loopback. The
+    /// snapshot is served to anyone who can reach it, without
+    /// authentication.
+    pub pull_listener_public: bool,
+    /// Tokens for pushes after a failed one that each successful push
+    /// earns (0.2: one retry per five successes). The push loop starts
+    /// with a few; without one, batches wait in the send queue instead.
+    pub retry_budget_ratio: f64,
+    /// Tokens each collected batch earns whatever the pushes do, so a
+    /// long outage is still retried at this share of the push rate
+    /// (0.1: every tenth push interval). 1.0 retries on every tick.
+    pub retry_budget_reserve: f64,
+    /// Push each batch as one sub-batch per metric group, by name prefix
+    /// or `Agent::assign_group`, so a group the aggregator rejects fails
+    /// alone; see `Diagnostics::groups`
+    pub group_batches: bool,
+    /// Keep the last `local_stats_window` of latency histograms in-process
+    /// for `Agent::local_stats`; see that for the memory it takes
+    pub local_stats: bool,
+    pub local_stats_window: Duration,
+    /// Count each `Agent::set_state` change of state in
+    /// `<name>_transitions_total{from, to}`
+    pub track_state_transitions: bool,
+    /// Send only the `Last` gauges set and counters moved since the
+    /// previous batch, plus every series in a `full_resync` batch each
+    /// `resync_interval` and after each reconnect. Histograms are deltas
+    /// either way; sharded counters are always sent.
+    pub incremental_mode: bool,
+    pub resync_interval: Duration,
 }
 
 impl Default for Config {
@@ -75,245 +915,230 @@ impl Default for Config {
         Self {
             aggregator_addr: "http://localhost:9000".to_string(),
             service_name: "default".to_string(),
+            service_version: String::new(),
             instance_id: generate_instance_id(),
+            identity_provider: None,
             push_interval: Duration::from_millis(20),
+            min_push_interval: Duration::from_millis(5),
+            max_collect_budget: Some(0.2),
+            allow_remote_config: false,
+            on_push_error: None,
+            error_log_interval: Duration::from_secs(30),
+            default_latency_bounds: None,
+            emit_combined_latency: true,
+            latency_until_children_done: false,
+            max_plausible_latency: None,
+            report_error_samples: false,
+            error_samples_per_interval: 5,
+            error_message_limit: 256,
+            max_self_metric_fraction: None,
+            max_error_types: 100,
+            explicit_inf_bound: true,
+            metadata: HashMap::new(),
+            push_timeout: Duration::from_secs(5),
+            connect_timeout: Duration::from_secs(5),
+            handshake_timeout: Duration::from_secs(5),
+            flush_timeout: Duration::from_secs(2),
+            spool_dir: None,
+            auto_relax_interval: false,
+            verbose_push: false,
+            lazy_connect: false,
+            stdout_fallback_after: None,
+            correct_clock_skew: false,
+            final_flush_timeout: Duration::from_secs(10),
+            #[cfg(feature = "runtime")]
+            tokio_handle: None,
+            #[cfg(feature = "runtime")]
+            endpoint_customizer: None,
+            dedicated_thread: false,
+            negotiate_schema: false,
+            assume_capabilities: None,
+            max_events_per_batch: 100,
+            span_sample_rate: 0.01,
+            max_spans_per_batch: 256,
+            metric_filter: None,
+            http_headers: HashMap::new(),
+            memory_budget_bytes: None,
+            bytes_per_hour: None,
+            max_label_value_len: 256,
+            max_label_count_per_metric: 16,
+            max_metric_name_len: 256,
+            sanitize_names: false,
+            total_reset: TotalReset::default(),
+            drop_report_interval: None,
+            checksum_batches: false,
+            validate_before_send: cfg!(debug_assertions),
+            compact_threshold: Some(0.25),
+            timestamp_resolution: Resolution::default(),
+            delta_timestamps: false,
+            intern_series: false,
+            hash_series: false,
+            cache_hit_ratio: false,
+            emit_counter_rates: false,
+            pull_listener: None,
+            pull_listener_public: false,
+            retry_budget_ratio: 0.2,
+            retry_budget_reserve: 0.1,
+            group_batches: false,
+            local_stats: false,
+            local_stats_window: Duration::from_secs(60),
+            track_state_transitions: false,
+            incremental_mode: false,
+            resync_interval: Duration::from_secs(30),
         }
     }
 }
 
-/// Telemetry agent for collecting and pushing metrics
-pub struct Agent {
-    config: Config,
-    gauges: Arc<Mutex<HashMap<String, f64>>>,
-    counters: Arc<Mutex<HashMap<String, AtomicU64>>>,
-    histograms: Arc<Mutex<HashMap<String, Arc<Histogram>>>>,
-    inflight: Arc<AtomicI64>,
-    shutdown_tx: Option<mpsc::Sender<()>>,
-}
-
-impl Agent {
-    pub fn new(config: Config) -> Self {
-        Self {
-            config,
-            gauges: Arc::new(Mutex::new(HashMap::new())),
-            counters: Arc::new(Mutex::new(HashMap::new())),
-            histograms: Arc::new(Mutex::new(HashMap::new())),
-            inflight: Arc::new(AtomicI64::new(0)),
-            shutdown_tx: None,
-        }
-    }
+/// Push interval `Profile::Test` sets; in practice only `stop()` and
+/// `flush()` push
+const MANUAL_FLUSH_INTERVAL: Duration = Duration::from_secs(24 * 3600);
 
-    /// Connect and start the agent
-    pub async fn start(&mut self) -> Result<(), Box<dyn std::error::Error>> {
-        let channel = Channel::from_shared(self.config.aggregator_addr.clone())?
-            .connect()
-            .await?;
-
-        let client = TelemetryIngestorClient::new(channel);
-        let (shutdown_tx, mut shutdown_rx) = mpsc::channel(1);
-        self.shutdown_tx = Some(shutdown_tx);
-
-        let config = self.config.clone();
-        let gauges = self.gauges.clone();
-        let counters = self.counters.clone();
-        let histograms = self.histograms.clone();
-        let inflight = self.inflight.clone();
-
-        tokio::spawn(async move {
-            let mut interval = interval(config.push_interval);
-            let mut client = client;
-
-            loop {
-                tokio::select! {
-                    _ = interval.tick() => {
-                        let batch = collect_metrics(
-                            &config,
-                            &gauges,
-                            &counters,
-                            &histograms,
-                            &inflight,
-                        );
-
-                        if !batch.metrics.is_empty() {
-                            let stream = async_stream::stream! {
-                                yield batch;
-                            };
-
-                            if let Err(e) = client.stream_telemetry(stream).await {
-                                eprintln!("Failed to send metrics: {}", e);
-                            }
-                        }
-                    }
-                    _ = shutdown_rx.recv() => {
-                        break;
-                    }
-                }
-            }
-        });
-
-        Ok(())
-    }
+/// Defaults for where the agent runs, for `Config::profile`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Profile {
+    /// Starts without an aggregator (`lazy_connect`), pushes every second,
+    /// logs every push, and writes batches to stdout once the aggregator
+    /// has been unreachable for 30s
+    Dev,
+    /// `Config::default()`: connects on start, pushes every 20ms and logs
+    /// only failures
+    Prod,
+    /// Starts without an aggregator and pushes only on `stop()`, or on
+    /// `flush()` inside `run_scoped`; point `aggregator_addr` at a
+    /// `LocalAggregator` to inspect what was sent
+    Test,
+}
 
-    /// Stop the agent
-    pub async fn stop(&mut self) {
-        if let Some(tx) = self.shutdown_tx.take() {
-            let _ = tx.send(()).await;
+impl Profile {
+    /// Name in config files
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Profile::Dev => "dev",
+            Profile::Prod => "prod",
+            Profile::Test => "test",
         }
     }
 
-    /// Set a gauge metric value
-    pub fn set_gauge(&self, name: &str, value: f64) {
-        let mut gauges = self.gauges.lock();
-        gauges.insert(name.to_string(), value);
-    }
-
-    /// Increment a counter
-    pub fn inc_counter(&self, name: &str) {
-        let mut counters = self.counters.lock();
-        counters
-            .entry(name.to_string())
-            .or_insert_with(|| AtomicU64::new(0))
-            .fetch_add(1, Ordering::Relaxed);
-    }
-
-    /// Record a histogram value
-    pub fn record_histogram(&self, name: &str, value: f64) {
-        let hist = {
-            let mut histograms = self.histograms.lock();
-            histograms
-                .entry(name.to_string())
-                .or_insert_with(|| Arc::new(Histogram::new()))
-                .clone()
+    /// Set the fields this profile covers
+    pub(crate) fn apply(self, config: &mut Config) {
+        let defaults = Config::default();
+        let (lazy_connect, push_interval, verbose_push, stdout_fallback_after) = match self {
+            Profile::Dev => (
+                true,
+                Duration::from_secs(1),
+                true,
+                Some(Duration::from_secs(30)),
+            ),
+            Profile::Prod => (
+                defaults.lazy_connect,
+                defaults.push_interval,
+                defaults.verbose_push,
+                defaults.stdout_fallback_after,
+            ),
+            Profile::Test => (true, MANUAL_FLUSH_INTERVAL, false, None),
         };
-        hist.record(value);
-    }
-
-    /// Track a request (returns guard that records latency on drop)
-    pub fn track_request(&self) -> RequestGuard {
-        self.inflight.fetch_add(1, Ordering::Relaxed);
-        RequestGuard {
-            start: Instant::now(),
-            inflight: self.inflight.clone(),
-            histograms: self.histograms.clone(),
-        }
+        config.lazy_connect = lazy_connect;
+        config.push_interval = push_interval;
+        config.verbose_push = verbose_push;
+        config.stdout_fallback_after = stdout_fallback_after;
     }
+}
 
-    /// Record an error
-    pub fn record_error(&self, error_type: &str) {
-        self.inc_counter(&format!("errors_{}", error_type));
-        self.inc_counter("errors_total");
+impl Config {
+    /// Defaults for `profile`. Fields set alongside it win:
+    ///
+    /// ```
+    /// use std::time::Duration;
+    /// use telemetry_agent::{Config, Profile};
+    ///
+    /// let config = Config {
+    ///     push_interval: Duration::from_millis(250),
+    ///     ..Config::profile(Profile::Dev)
+    /// };
+    /// assert!(config.lazy_connect);
+    /// assert_eq!(config.push_interval, Duration::from_millis(250));
+    /// ```
+    pub fn profile(profile: Profile) -> Self {
+        let mut config = Config::default();
+        profile.apply(&mut config);
+        config
     }
 }
 
-/// Guard that records latency when dropped
-pub struct RequestGuard {
-    start: Instant,
-    inflight: Arc<AtomicI64>,
-    histograms: Arc<Mutex<HashMap<String, Arc<Histogram>>>>,
+/// `TransportPool` settings; these replace the per-agent push interval and
+/// timeouts of pooled agents
+#[derive(Debug, Clone)]
+pub struct PoolConfig {
+    pub push_interval: Duration,
+    /// A stream of batches not acknowledged after this long is cancelled
+    pub push_timeout: Duration,
+    /// How long `TransportPool::shutdown` waits for the final pushes
+    pub flush_timeout: Duration,
+    /// Minimum time between summary lines while pushes keep failing
+    pub error_log_interval: Duration,
 }
 
-impl Drop for RequestGuard {
-    fn drop(&mut self) {
-        self.inflight.fetch_sub(1, Ordering::Relaxed);
-        let latency = self.start.elapsed().as_secs_f64() * 1000.0;
-
-        let hist = {
-            let mut histograms = self.histograms.lock();
-            histograms
-                .entry("latency".to_string())
-                .or_insert_with(|| Arc::new(Histogram::new()))
-                .clone()
-        };
-        hist.record(latency);
+impl Default for PoolConfig {
+    fn default() -> Self {
+        Self {
+            push_interval: Duration::from_millis(20),
+            push_timeout: Duration::from_secs(5),
+            flush_timeout: Duration::from_secs(2),
+            error_log_interval: Duration::from_secs(30),
+        }
     }
 }
 
-fn collect_metrics(
-    config: &Config,
-    gauges: &Arc<Mutex<HashMap<String, f64>>>,
-    counters: &Arc<Mutex<HashMap<String, AtomicU64>>>,
-    histograms: &Arc<Mutex<HashMap<String, Arc<Histogram>>>>,
-    inflight: &Arc<AtomicI64>,
-) -> TelemetryBatch {
-    let now = SystemTime::now()
-        .duration_since(UNIX_EPOCH)
-        .unwrap()
-        .as_nanos() as u64;
-
-    let mut metrics = Vec::new();
-
-    // Collect gauges
-    {
-        let gauges = gauges.lock();
-        for (name, value) in gauges.iter() {
-            metrics.push(Metric {
-                name: name.clone(),
-                labels: HashMap::new(),
-                samples: vec![MetricSample {
-                    timestamp_ns: now,
-                    value: Some(telemetry::metric_sample::Value::Gauge(*value)),
-                }],
-            });
-        }
-    }
+/// Result of a tracked request, used to split latency histograms
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Outcome {
+    #[default]
+    Success,
+    Error,
+}
 
-    // Collect counters
-    {
-        let counters = counters.lock();
-        for (name, counter) in counters.iter() {
-            metrics.push(Metric {
-                name: name.clone(),
-                labels: HashMap::new(),
-                samples: vec![MetricSample {
-                    timestamp_ns: now,
-                    value: Some(telemetry::metric_sample::Value::Counter(
-                        counter.load(Ordering::Relaxed),
-                    )),
-                }],
-            });
+impl Outcome {
+    /// Value of the `outcome` label
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Outcome::Success => "success",
+            Outcome::Error => "error",
         }
     }
+}
 
-    // Collect histograms
-    {
-        let histograms = histograms.lock();
-        for (name, hist) in histograms.iter() {
-            let (bounds, counts) = hist.snapshot_and_reset();
-            metrics.push(Metric {
-                name: name.clone(),
-                labels: HashMap::new(),
-                samples: vec![MetricSample {
-                    timestamp_ns: now,
-                    value: Some(telemetry::metric_sample::Value::Histogram(HistogramProto {
-                        bounds,
-                        counts,
-                    })),
-                }],
-            });
-        }
-    }
+/// Severity of an event from `emit_event`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Severity {
+    Debug,
+    #[default]
+    Info,
+    Warn,
+    Error,
+}
 
-    // Add inflight gauge
-    metrics.push(Metric {
-        name: "inflight".to_string(),
-        labels: HashMap::new(),
-        samples: vec![MetricSample {
-            timestamp_ns: now,
-            value: Some(telemetry::metric_sample::Value::Gauge(
-                inflight.load(Ordering::Relaxed) as f64,
-            )),
-        }],
-    });
+/// `TelemetryBatch::instance_epoch`, drawn once per process
+#[cfg(not(feature = "noop"))]
+pub(crate) fn instance_epoch() -> u64 {
+    use std::collections::hash_map::RandomState;
+    use std::hash::{BuildHasher, Hasher};
 
-    TelemetryBatch {
-        service: config.service_name.clone(),
-        instance: config.instance_id.clone(),
-        metrics,
-    }
+    static EPOCH: OnceLock<u64> = OnceLock::new();
+    *EPOCH.get_or_init(|| {
+        let mut hasher = RandomState::new().build_hasher();
+        hasher.write_u128(
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_nanos(),
+        );
+        hasher.write_u32(std::process::id());
+        // 0 means unset on the wire
+        hasher.finish().max(1)
+    })
 }
 
 fn generate_instance_id() -> String {
-    use std::time::SystemTime;
     let nanos = SystemTime::now()
         .duration_since(UNIX_EPOCH)
         .unwrap()
@@ -336,4 +1161,103 @@ mod tests {
         assert!(!bounds.is_empty());
         assert!(counts.iter().sum::<u64>() == 3);
     }
+
+    #[test]
+    fn test_overflow_count() {
+        let hist = Histogram::with_bounds(&[1.0, 2.0]);
+        hist.record(2.0);
+        hist.record(3.0);
+        hist.record(f64::NAN);
+        assert_eq!(hist.overflow_count(), 2);
+
+        let (bounds, counts) = hist.snapshot_and_reset();
+        assert_eq!(counts.len(), bounds.len() + 1);
+        assert_eq!(counts, vec![0, 1, 2]);
+        assert_eq!(hist.overflow_count(), 0);
+    }
+
+    #[test]
+    fn test_exemplars() {
+        let hist = Histogram::new();
+        hist.record_with_exemplar(3.0, 1);
+        hist.record_with_exemplar(4.0, 2);
+        hist.record_with_exemplar(20_000.0, 3);
+        hist.record_with_exemplar(7.0, 0);
+
+        let (_, counts, exemplars) = hist.snapshot_with_exemplars_and_reset();
+        assert_eq!(counts.iter().sum::<u64>(), 4);
+        let ids: Vec<(usize, u128)> = exemplars.iter().map(|(i, e)| (*i, e.trace_id)).collect();
+        assert_eq!(ids, vec![(1, 2), (DEFAULT_BOUNDS.len(), 3)]);
+        assert_eq!(exemplars[0].1.value, 4.0);
+
+        hist.record_with_exemplar(3.0, 9);
+        hist.snapshot_and_reset();
+        assert!(hist.snapshot_with_exemplars_and_reset().2.is_empty());
+    }
+
+    #[test]
+    fn test_exemplar_slots_are_allocated_on_first_use() {
+        let hist = Histogram::new();
+        let buckets_only = hist.heap_bytes();
+        hist.record(3.0);
+        // A zero trace id carries no exemplar either
+        hist.record_with_exemplar(3.0, 0);
+        assert_eq!(hist.heap_bytes(), buckets_only);
+        assert!(hist.snapshot_with_exemplars_and_reset().2.is_empty());
+
+        hist.record_with_exemplar(3.0, 7);
+        assert!(hist.heap_bytes() > buckets_only);
+        assert_eq!(hist.snapshot_with_exemplars_and_reset().2[0].1.trace_id, 7);
+    }
+
+    #[test]
+    fn test_record_racing_retire_is_drained_into_the_successor() {
+        let epoch = Arc::new(Epoch::new());
+        let old = Histogram::with_bounds(&[1.0, 2.0]).in_epoch(Some(epoch.clone()));
+        let new = Arc::new(Histogram::with_bounds(&[2.0]).in_epoch(Some(epoch.clone())));
+        drop(epoch.advance());
+
+        // A record that found no successor, stalled before incrementing
+        let pin = epoch::pin(Some(&epoch));
+        assert!(old.successor.get().is_none());
+        // The collect switching it
+        old.retire(new.clone());
+        assert_eq!(old.snapshot_and_reset().1, vec![0, 0, 0]);
+        old.increment(pin.slot(), 0);
+        drop(pin);
+
+        // The next collect finds it
+        let cut = epoch.advance();
+        old.drain_retired(cut.slot());
+        assert_eq!(new.snapshot_slot_and_reset(cut.slot()).1, vec![1, 0]);
+        assert_eq!(old.counts(), vec![0, 0, 0]);
+    }
+
+    fn linear_index(bounds: &[f64], value: f64) -> usize {
+        bounds
+            .iter()
+            .position(|bound| value <= *bound)
+            .unwrap_or(bounds.len())
+    }
+
+    #[test]
+    fn test_histogram_exact_bounds() {
+        let hist = Histogram::new();
+        for (i, bound) in DEFAULT_BOUNDS.iter().enumerate() {
+            assert_eq!(hist.bucket_index(*bound), i);
+        }
+        assert_eq!(hist.bucket_index(f64::NAN), DEFAULT_BOUNDS.len());
+        assert_eq!(hist.bucket_index(f64::INFINITY), DEFAULT_BOUNDS.len());
+    }
+
+    proptest::proptest! {
+        #[test]
+        fn prop_binary_search_matches_linear(value in proptest::num::f64::ANY, pick in 0usize..160) {
+            let hist = Histogram::exponential(0.01, 1.1, 160);
+            let (bounds, _) = hist.snapshot_and_reset();
+
+            proptest::prop_assert_eq!(hist.bucket_index(value), linear_index(&bounds, value));
+            proptest::prop_assert_eq!(hist.bucket_index(bounds[pick]), linear_index(&bounds, bounds[pick]));
+        }
+    }
 }
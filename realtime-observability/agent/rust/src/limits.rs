@@ -0,0 +1,211 @@
+//! Caps on metric names and label sets
+//!
+//! Applied wherever a name or label set becomes a series key, so one runaway
+//! value, such as a SQL query passed as a label, costs a bounded key instead
+//! of megabytes per batch. Cutting depends only on the input: the same
+//! oversized name or label set always maps to the same series.
+//!
+//! Label values and names over their limit keep their leading bytes and end
+//! in `…`; past `max_label_count_per_metric`, labels are kept in key order
+//! and the rest dropped.
+
+use std::borrow::Cow;
+
+use crate::agent::{inc_counter_in, CounterRegistry};
+use crate::drops::{DropLog, DropReason};
+use crate::error_log::truncate;
+use crate::naming::{self, normalize_name, MetricName};
+use crate::{name, series, Config};
+
+/// Ends every cut name or label value
+pub(crate) const MARKER: &str = "…";
+
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Limits {
+    name_len: usize,
+    label_value_len: usize,
+    label_count: usize,
+    sanitize: bool,
+}
+
+impl Limits {
+    pub(crate) fn new(config: &Config) -> Self {
+        Self {
+            name_len: config.max_metric_name_len,
+            label_value_len: config.max_label_value_len,
+            label_count: config.max_label_count_per_metric,
+            sanitize: config.sanitize_names,
+        }
+    }
+
+    /// `name` within `max_metric_name_len`, counting a cut in
+    /// `agent_truncated_names_total` and against the cut name in `drops`;
+    /// normalized first with `sanitize_names` unless it is a `name!` literal
+    pub(crate) fn name<'a, N: MetricName + ?Sized>(
+        &self,
+        counters: &CounterRegistry,
+        drops: &DropLog,
+        name: &'a N,
+    ) -> Cow<'a, str> {
+        let name = match name.literal() {
+            Some(literal) => literal.as_str(),
+            None if self.sanitize && !naming::is_valid(name.as_name()) => {
+                inc_counter_in(counters, name!("agent_sanitized_names_total"));
+                let sanitized = normalize_name(name.as_name());
+                return Cow::Owned(self.name(counters, drops, sanitized.as_str()).into_owned());
+            }
+            None => name.as_name(),
+        };
+        let name = cut(name, self.name_len);
+        if let Cow::Owned(cut) = &name {
+            inc_counter_in(counters, name!("agent_truncated_names_total"));
+            drops.record(cut, DropReason::Truncated, 1);
+        }
+        name
+    }
+
+    /// Series key for `name` and `labels` with the label limits applied,
+    /// counting a label set that needed them in
+    /// `agent_truncated_labels_total` and against `name` in `drops`
+    pub(crate) fn key(
+        &self,
+        counters: &CounterRegistry,
+        drops: &DropLog,
+        name: &str,
+        labels: &[(&str, &str)],
+    ) -> String {
+        let within = labels.len() <= self.label_count
+            && labels.iter().all(|(_, v)| v.len() <= self.label_value_len);
+        if within {
+            return series::encode(name, labels);
+        }
+        inc_counter_in(counters, name!("agent_truncated_labels_total"));
+        drops.record(name, DropReason::Truncated, 1);
+
+        let mut sorted: Vec<&(&str, &str)> = labels.iter().collect();
+        sorted.sort_by_key(|(k, _)| *k);
+        sorted.truncate(self.label_count);
+        let values: Vec<Cow<str>> = sorted
+            .iter()
+            .map(|(_, v)| cut(v, self.label_value_len))
+            .collect();
+        let kept: Vec<(&str, &str)> = sorted
+            .iter()
+            .zip(&values)
+            .map(|((k, _), v)| (*k, &**v))
+            .collect();
+        series::encode(name, &kept)
+    }
+}
+
+/// `s` cut to at most `limit` bytes, marker included, on a character
+/// boundary
+fn cut(s: &str, limit: usize) -> Cow<'_, str> {
+    if s.len() <= limit {
+        return Cow::Borrowed(s);
+    }
+    let mut cut = truncate(s, limit.saturating_sub(MARKER.len())).to_string();
+    cut.push_str(MARKER);
+    Cow::Owned(cut)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    fn limits() -> Limits {
+        Limits {
+            name_len: 8,
+            label_value_len: 8,
+            label_count: 2,
+            sanitize: false,
+        }
+    }
+
+    #[test]
+    fn test_sanitized_names_are_cut_too() {
+        let counters: CounterRegistry = Arc::default();
+        let drops = DropLog::default();
+        let limits = Limits {
+            sanitize: true,
+            ..limits()
+        };
+        assert_eq!(limits.name(&counters, &drops, "jobs"), "jobs");
+        assert_eq!(limits.name(&counters, &drops, "jobId"), "job_id");
+        assert_eq!(limits.name(&counters, &drops, "requestCount"), "reque…");
+        assert_eq!(counters.lock()["agent_sanitized_names_total"].value(), 2);
+        assert_eq!(counters.lock()["agent_truncated_names_total"].value(), 1);
+    }
+
+    #[test]
+    fn test_literals_are_not_checked_again() {
+        let counters: CounterRegistry = Arc::default();
+        let drops = DropLog::default();
+        let limits = Limits {
+            sanitize: true,
+            ..limits()
+        };
+        let literal = name!("jobs");
+        assert_eq!(literal.literal(), Some(*literal));
+        assert_eq!((&literal).literal(), Some(*literal));
+        assert_eq!("jobs".literal(), None);
+        assert!(matches!(
+            limits.name(&counters, &drops, literal),
+            Cow::Borrowed("jobs")
+        ));
+        // Still cut to the limit
+        assert_eq!(
+            limits.name(&counters, &drops, name!("requests_total")),
+            "reque…"
+        );
+        assert!(!counters.lock().contains_key("agent_sanitized_names_total"));
+    }
+
+    #[test]
+    fn test_cuts_are_stable() {
+        let counters: CounterRegistry = Arc::default();
+        let drops = DropLog::default();
+        let query = "SELECT * FROM orders WHERE id = 42";
+
+        let key = limits().key(&counters, &drops, "db_ms", &[("query", query)]);
+        assert_eq!(key, "db_ms{query=SELEC…}");
+        assert_eq!(
+            limits().key(&counters, &drops, "db_ms", &[("query", query)]),
+            key
+        );
+        assert_eq!(counters.lock()["agent_truncated_labels_total"].value(), 2);
+
+        // Multi-byte characters are never split
+        assert_eq!(cut("ééééé", 8), "éé…");
+        assert_eq!(limits().name(&counters, &drops, "requests_total"), "reque…");
+        assert_eq!(limits().name(&counters, &drops, "up"), "up");
+        assert_eq!(counters.lock()["agent_truncated_names_total"].value(), 1);
+        let truncated: Vec<_> = drops
+            .report()
+            .into_iter()
+            .map(|stats| (stats.metric, stats.truncated))
+            .collect();
+        assert_eq!(
+            truncated,
+            [("db_ms".to_string(), 2), ("reque…".to_string(), 1)]
+        );
+    }
+
+    #[test]
+    fn test_extra_labels_dropped_by_key_order() {
+        let counters: CounterRegistry = Arc::default();
+        let drops = DropLog::default();
+        let labels = [("c", "3"), ("a", "1"), ("d", "4"), ("b", "2")];
+        let mut reordered = labels;
+        reordered.reverse();
+
+        let key = limits().key(&counters, &drops, "hits", &labels);
+        assert_eq!(key, "hits{a=1,b=2}");
+        assert_eq!(limits().key(&counters, &drops, "hits", &reordered), key);
+        assert_eq!(
+            limits().key(&counters, &drops, "hits", &[("a", "1"), ("b", "2")]),
+            key
+        );
+    }
+}
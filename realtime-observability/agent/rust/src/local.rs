@@ -0,0 +1,359 @@
+//! In-process aggregator for tests, dev dashboards and edge buffering
+//!
+//! Implements the generated `TelemetryIngestor` service, so agents push to
+//! it exactly as they would to the real aggregator:
+//!
+//! ```no_run
+//! use telemetry_agent::{LocalAggregator, TelemetryIngestorServer};
+//!
+//! # async fn run() -> Result<(), Box<dyn std::error::Error>> {
+//! let aggregator = LocalAggregator::new();
+//! tonic::transport::Server::builder()
+//!     .add_service(TelemetryIngestorServer::new(aggregator.clone()))
+//!     .serve("127.0.0.1:9000".parse()?)
+//!     .await?;
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! Series from every agent share one namespace, keyed by name and labels.
+//! Metrics sent by series id are named from a `SeriesTable` per agent
+//! process; those whose definition was lost are ignored.
+//! Gauges keep the last value, counters the latest cumulative total, and
+//! histogram deltas are summed. Histograms sent as window totals (see
+//! `ResetPolicy`) add what grew since the last push of the same window.
+
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use parking_lot::Mutex;
+use tonic::{Request, Response, Status, Streaming};
+
+use crate::telemetry::compat::SeriesTable;
+use crate::telemetry::metric_sample::Value;
+use crate::telemetry::telemetry_ingestor_server::TelemetryIngestor;
+use crate::telemetry::{
+    Ack, Capabilities, CapabilitiesRequest, Schema, SchemaRequest, TelemetryBatch,
+};
+use crate::{series, Histogram};
+
+/// Accumulates pushed batches; clones share state
+#[derive(Clone, Default)]
+pub struct LocalAggregator {
+    series: Arc<Mutex<HashMap<String, Series>>>,
+    windows: Arc<Mutex<HashMap<String, WindowTotals>>>,
+    tables: Arc<Mutex<HashMap<Sender, SeriesTable>>>,
+}
+
+/// An agent process: service, instance and instance epoch
+type Sender = (String, String, u64);
+
+/// Last window start and totals received for a series
+type WindowTotals = (u64, Vec<u64>);
+
+enum Series {
+    Gauge(f64),
+    Counter(u64),
+    Histogram(Histogram),
+}
+
+/// Every series of one metric, from `LocalAggregator::query`
+#[derive(Debug, Clone, PartialEq)]
+pub struct MetricView {
+    pub name: String,
+    /// Ordered by labels
+    pub series: Vec<SeriesView>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SeriesView {
+    pub labels: BTreeMap<String, String>,
+    pub value: SeriesValue,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum SeriesValue {
+    Gauge(f64),
+    Counter(u64),
+    /// Counts have one more entry than bounds; the last is the overflow
+    /// bucket
+    Histogram {
+        bounds: Vec<f64>,
+        counts: Vec<u64>,
+    },
+}
+
+impl LocalAggregator {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Fold one batch into the accumulated state
+    pub fn ingest(&self, batch: &TelemetryBatch) {
+        let mut state = self.series.lock();
+        let mut windows = self.windows.lock();
+        for metric in &batch.metrics {
+            let labels: Vec<(&str, &str)> = metric
+                .labels
+                .iter()
+                .map(|(k, v)| (k.as_str(), v.as_str()))
+                .collect();
+            let key = series::encode(&metric.name, &labels);
+
+            for sample in &metric.samples {
+                match &sample.value {
+                    Some(Value::Gauge(value)) => {
+                        state.insert(key.clone(), Series::Gauge(*value));
+                    }
+                    Some(Value::Counter(value)) => {
+                        state.insert(key.clone(), Series::Counter(*value));
+                    }
+                    Some(Value::Histogram(hist)) => {
+                        // Drop an explicit +Inf bound; `Histogram` keeps
+                        // overflow implicitly
+                        let mut bounds = hist.bounds.as_slice();
+                        if bounds.len() == hist.counts.len()
+                            && bounds.last() == Some(&f64::INFINITY)
+                        {
+                            bounds = &bounds[..bounds.len() - 1];
+                        }
+                        let counts = match sample.window_start_ns {
+                            0 => hist.counts.clone(),
+                            start => window_delta(&mut windows, &key, start, &hist.counts),
+                        };
+                        match state.get(&key) {
+                            Some(Series::Histogram(existing)) if existing.bounds() == bounds => {
+                                existing.add_counts(&counts);
+                            }
+                            _ => {
+                                state.insert(
+                                    key.clone(),
+                                    Series::Histogram(Histogram::from_parts(bounds, &counts)),
+                                );
+                            }
+                        }
+                    }
+                    None => {}
+                }
+            }
+        }
+    }
+
+    /// Current value of every series named `name`
+    pub fn query(&self, name: &str) -> MetricView {
+        let state = self.series.lock();
+        let mut series: Vec<SeriesView> = state
+            .iter()
+            .filter(|(key, _)| series::name(key) == name)
+            .map(|(key, value)| SeriesView {
+                labels: series::decode(key).1,
+                value: match value {
+                    Series::Gauge(v) => SeriesValue::Gauge(*v),
+                    Series::Counter(v) => SeriesValue::Counter(*v),
+                    Series::Histogram(hist) => SeriesValue::Histogram {
+                        bounds: hist.bounds(),
+                        counts: hist.counts(),
+                    },
+                },
+            })
+            .collect();
+        series.sort_by(|a, b| a.labels.cmp(&b.labels));
+
+        MetricView {
+            name: name.to_string(),
+            series,
+        }
+    }
+
+    /// Names of all metrics seen so far, sorted
+    pub fn metric_names(&self) -> Vec<String> {
+        let mut names: Vec<String> = self
+            .series
+            .lock()
+            .keys()
+            .map(|key| series::name(key).to_string())
+            .collect();
+        names.sort();
+        names.dedup();
+        names
+    }
+}
+
+/// What `totals` for the window starting at `start` add over the last
+/// totals received for it; all of them for a new window
+fn window_delta(
+    windows: &mut HashMap<String, WindowTotals>,
+    key: &str,
+    start: u64,
+    totals: &[u64],
+) -> Vec<u64> {
+    let delta = match windows.get(key) {
+        Some((last_start, last)) if *last_start == start && last.len() == totals.len() => totals
+            .iter()
+            .zip(last)
+            .map(|(total, last)| total.saturating_sub(*last))
+            .collect(),
+        _ => totals.to_vec(),
+    };
+    windows.insert(key.to_string(), (start, totals.to_vec()));
+    delta
+}
+
+#[tonic::async_trait]
+impl TelemetryIngestor for LocalAggregator {
+    async fn stream_telemetry(
+        &self,
+        request: Request<Streaming<TelemetryBatch>>,
+    ) -> Result<Response<Ack>, Status> {
+        let mut stream = request.into_inner();
+        let mut received_at_ns = 0;
+        while let Some(mut batch) = stream.message().await? {
+            if received_at_ns == 0 {
+                received_at_ns = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap()
+                    .as_nanos() as u64;
+            }
+            if batch.verify_checksum() == Some(false) {
+                return Err(Status::data_loss("batch checksum mismatch"));
+            }
+            self.tables
+                .lock()
+                .entry((
+                    batch.service.clone(),
+                    batch.instance.clone(),
+                    batch.instance_epoch,
+                ))
+                .or_default()
+                .resolve(&mut batch);
+            self.ingest(&batch);
+        }
+        Ok(Response::new(Ack {
+            ok: true,
+            directives: None,
+            received_at_ns,
+        }))
+    }
+
+    /// Agents keep their local bounds
+    async fn get_schema(
+        &self,
+        _request: Request<SchemaRequest>,
+    ) -> Result<Response<Schema>, Status> {
+        Err(Status::unimplemented("LocalAggregator has no schema"))
+    }
+
+    /// Everything an agent sends is ingested or ignored
+    async fn get_capabilities(
+        &self,
+        _request: Request<CapabilitiesRequest>,
+    ) -> Result<Response<Capabilities>, Status> {
+        Ok(Response::new(Capabilities {
+            events: true,
+            exemplars: true,
+            announce: true,
+            delta_timestamps: true,
+            spans: true,
+            series_ids: true,
+            series_hash: true,
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::telemetry::{Histogram as HistogramProto, Metric, MetricSample};
+
+    fn batch(name: &str, labels: &[(&str, &str)], value: Value) -> TelemetryBatch {
+        TelemetryBatch {
+            metrics: vec![Metric {
+                name: name.to_string(),
+                labels: labels
+                    .iter()
+                    .map(|(k, v)| (k.to_string(), v.to_string()))
+                    .collect(),
+                samples: vec![MetricSample {
+                    timestamp_ns: 0,
+                    window_start_ns: 0,
+                    value: Some(value),
+                }],
+                series_id: 0,
+                series_hash: 0,
+            }],
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_histogram_deltas_are_summed() {
+        let aggregator = LocalAggregator::new();
+        let delta = || {
+            Value::Histogram(HistogramProto {
+                bounds: vec![1.0, 10.0, f64::INFINITY],
+                counts: vec![1, 2, 3],
+                exemplars: Vec::new(),
+            })
+        };
+        aggregator.ingest(&batch("latency", &[], delta()));
+        aggregator.ingest(&batch("latency", &[], delta()));
+
+        assert_eq!(
+            aggregator.query("latency").series[0].value,
+            SeriesValue::Histogram {
+                bounds: vec![1.0, 10.0],
+                counts: vec![2, 4, 6],
+            }
+        );
+    }
+
+    #[test]
+    fn test_window_totals_are_not_double_counted() {
+        let aggregator = LocalAggregator::new();
+        let totals = |window_start_ns, counts: Vec<u64>| TelemetryBatch {
+            metrics: vec![Metric {
+                name: "session_s".to_string(),
+                samples: vec![MetricSample {
+                    timestamp_ns: 0,
+                    window_start_ns,
+                    value: Some(Value::Histogram(HistogramProto {
+                        bounds: vec![60.0],
+                        counts,
+                        exemplars: Vec::new(),
+                    })),
+                }],
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        aggregator.ingest(&totals(7, vec![1, 0]));
+        aggregator.ingest(&totals(7, vec![2, 1]));
+        // A new window starts from zero
+        aggregator.ingest(&totals(9, vec![1, 0]));
+
+        assert_eq!(
+            aggregator.query("session_s").series[0].value,
+            SeriesValue::Histogram {
+                bounds: vec![60.0],
+                counts: vec![3, 1],
+            }
+        );
+    }
+
+    #[test]
+    fn test_query_by_name() {
+        let aggregator = LocalAggregator::new();
+        aggregator.ingest(&batch("hits", &[("route", "/b")], Value::Counter(5)));
+        aggregator.ingest(&batch("hits", &[("route", "/a")], Value::Counter(2)));
+        aggregator.ingest(&batch("hits", &[("route", "/a")], Value::Counter(3)));
+        aggregator.ingest(&batch("up", &[], Value::Gauge(1.0)));
+
+        let hits = aggregator.query("hits");
+        assert_eq!(hits.series.len(), 2);
+        assert_eq!(hits.series[0].labels["route"], "/a");
+        assert_eq!(hits.series[0].value, SeriesValue::Counter(3));
+        assert_eq!(aggregator.metric_names(), vec!["hits", "up"]);
+        assert!(aggregator.query("missing").series.is_empty());
+    }
+}
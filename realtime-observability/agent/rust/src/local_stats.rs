@@ -0,0 +1,315 @@
+//! Rolling latency statistics kept in-process (`Agent::local_stats`)
+//!
+//! With `Config::local_stats`, every collect also hands its latency
+//! histogram deltas (the `{outcome}` series of `track_request` and
+//! `start_timer`) to a ring covering the last `Config::local_stats_window`.
+//! Quantiles, request rates and error ratios are computed from the ring on
+//! demand, so a small deployment can serve `/stats` without an aggregator.
+//!
+//! The ring holds at most `SLOTS` slots, each covering at least a
+//! `SLOTS`th of the window; collects closer together than that add up in
+//! one slot. Memory is therefore bounded by `SLOTS` × latency histograms ×
+//! buckets per histogram: about 8 KB per histogram with the default 15
+//! bounds. Figures are as of the last collect, up to one push interval
+//! old, and quantiles are interpolated within buckets, so they are only as
+//! fine as the bounds.
+
+#[cfg(not(feature = "noop"))]
+use std::collections::{BTreeMap, HashMap, VecDeque};
+#[cfg(not(feature = "noop"))]
+use std::time::{Duration, Instant};
+
+#[cfg(not(feature = "noop"))]
+use parking_lot::Mutex;
+
+/// Slots in the ring at most
+#[cfg(not(feature = "noop"))]
+pub(crate) const SLOTS: u32 = 60;
+
+/// Rolling view of the agent's latency histograms, from
+/// `Agent::local_stats`
+#[derive(Debug, Clone, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct LocalStats {
+    /// Seconds the figures cover: the window, or less since the agent
+    /// started collecting
+    pub window_secs: f64,
+    /// Requests tracked by `track_request` guards now
+    pub inflight: i64,
+    /// By histogram name
+    pub latencies: Vec<LatencyStats>,
+}
+
+/// One latency histogram over the window, both outcomes together
+#[derive(Debug, Clone, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct LatencyStats {
+    pub name: String,
+    pub requests: u64,
+    /// Requests marked failed
+    pub errors: u64,
+    /// Requests per second
+    pub rate: f64,
+    /// Errors over requests; 0 without requests
+    pub error_ratio: f64,
+    /// Milliseconds; `None` without requests
+    pub p50_ms: Option<f64>,
+    pub p95_ms: Option<f64>,
+    pub p99_ms: Option<f64>,
+}
+
+/// One collect's counts for a latency series: histogram name, whether it
+/// is the error outcome, bounds, and counts with the overflow last
+#[cfg(not(feature = "noop"))]
+pub(crate) type LatencyDelta = (String, bool, Vec<f64>, Vec<u64>);
+
+#[cfg(not(feature = "noop"))]
+pub(crate) struct LatencyWindow {
+    window: Duration,
+    ring: Mutex<Ring>,
+}
+
+#[cfg(not(feature = "noop"))]
+#[derive(Default)]
+struct Ring {
+    /// End of the last collect, where the next slot starts
+    last_collect: Option<Instant>,
+    slots: VecDeque<Slot>,
+}
+
+#[cfg(not(feature = "noop"))]
+struct Slot {
+    start: Instant,
+    end: Instant,
+    histograms: HashMap<String, Counts>,
+}
+
+#[cfg(not(feature = "noop"))]
+#[derive(Clone)]
+struct Counts {
+    bounds: Vec<f64>,
+    /// Both outcomes, overflow last
+    counts: Vec<u64>,
+    errors: u64,
+}
+
+#[cfg(not(feature = "noop"))]
+impl Counts {
+    fn add(&mut self, counts: &[u64], error: bool) {
+        for (sum, n) in self.counts.iter_mut().zip(counts) {
+            *sum += n;
+        }
+        if error {
+            self.errors += counts.iter().sum::<u64>();
+        }
+    }
+}
+
+#[cfg(not(feature = "noop"))]
+impl LatencyWindow {
+    pub(crate) fn new(window: Duration) -> Self {
+        Self {
+            window,
+            ring: Mutex::default(),
+        }
+    }
+
+    /// Add what one collect, ending `now`, took from the latency histograms
+    pub(crate) fn record(&self, now: Instant, deltas: Vec<LatencyDelta>) {
+        let mut ring = self.ring.lock();
+        let start = ring.last_collect.replace(now).unwrap_or(now);
+        let granularity = self.window / SLOTS;
+        let fresh = match ring.slots.back() {
+            Some(slot) => now.duration_since(slot.start) >= granularity,
+            None => true,
+        };
+        if fresh {
+            ring.slots.push_back(Slot {
+                start,
+                end: now,
+                histograms: HashMap::new(),
+            });
+        }
+        let slot = ring.slots.back_mut().expect("pushed above");
+        slot.end = now;
+        for (name, error, bounds, counts) in deltas {
+            let sums = slot.histograms.entry(name).or_insert_with(|| Counts {
+                bounds: bounds.clone(),
+                counts: vec![0; counts.len()],
+                errors: 0,
+            });
+            // Reconfigured bounds: the slot keeps the latest only
+            if sums.bounds != bounds {
+                *sums = Counts {
+                    bounds,
+                    counts: vec![0; counts.len()],
+                    errors: 0,
+                };
+            }
+            sums.add(&counts, error);
+        }
+        while ring.slots.len() > SLOTS as usize + 1
+            || ring
+                .slots
+                .front()
+                .is_some_and(|slot| slot.end + self.window < now)
+        {
+            ring.slots.pop_front();
+        }
+    }
+
+    /// Statistics over the slots that end within the window before `now`
+    pub(crate) fn stats(&self, now: Instant) -> (Duration, Vec<LatencyStats>) {
+        let ring = self.ring.lock();
+        let live: Vec<&Slot> = ring
+            .slots
+            .iter()
+            .filter(|slot| slot.end + self.window >= now)
+            .collect();
+        let (Some(first), Some(last)) = (live.first(), live.last()) else {
+            return (Duration::ZERO, Vec::new());
+        };
+        let span = last.end.duration_since(first.start);
+        // Newest first, so older slots with other bounds are left out
+        let mut merged: BTreeMap<&str, Counts> = BTreeMap::new();
+        for slot in live.iter().rev() {
+            for (name, counts) in &slot.histograms {
+                match merged.get_mut(name.as_str()) {
+                    Some(sums) if sums.bounds == counts.bounds => {
+                        sums.add(&counts.counts, false);
+                        sums.errors += counts.errors;
+                    }
+                    Some(_) => {}
+                    None => {
+                        merged.insert(name, counts.clone());
+                    }
+                }
+            }
+        }
+        let stats = merged
+            .into_iter()
+            .map(|(name, counts)| latency_stats(name, &counts, span))
+            .collect();
+        (span, stats)
+    }
+}
+
+#[cfg(not(feature = "noop"))]
+fn latency_stats(name: &str, counts: &Counts, span: Duration) -> LatencyStats {
+    let requests: u64 = counts.counts.iter().sum();
+    let quantile = |q| quantile(&counts.bounds, &counts.counts, q);
+    LatencyStats {
+        name: name.to_string(),
+        requests,
+        errors: counts.errors,
+        rate: match span.is_zero() {
+            true => 0.0,
+            false => requests as f64 / span.as_secs_f64(),
+        },
+        error_ratio: match requests {
+            0 => 0.0,
+            _ => counts.errors as f64 / requests as f64,
+        },
+        p50_ms: quantile(0.5),
+        p95_ms: quantile(0.95),
+        p99_ms: quantile(0.99),
+    }
+}
+
+/// The `q` quantile, interpolated linearly within its bucket; the last
+/// bound if it falls in the overflow bucket
+#[cfg(not(feature = "noop"))]
+pub(crate) fn quantile(bounds: &[f64], counts: &[u64], q: f64) -> Option<f64> {
+    let total: u64 = counts.iter().sum();
+    if total == 0 {
+        return None;
+    }
+    let rank = q * total as f64;
+    let mut below = 0;
+    for (i, &n) in counts.iter().enumerate() {
+        if n > 0 && (below + n) as f64 >= rank {
+            let Some(&upper) = bounds.get(i) else {
+                return bounds.last().copied();
+            };
+            let lower = match i {
+                0 => 0.0,
+                _ => bounds[i - 1],
+            };
+            return Some(lower + (upper - lower) * (rank - below as f64) / n as f64);
+        }
+        below += n;
+    }
+    bounds.last().copied()
+}
+
+#[cfg(all(test, not(feature = "noop")))]
+mod tests {
+    use super::*;
+
+    const BOUNDS: [f64; 3] = [10.0, 20.0, 40.0];
+
+    fn delta(name: &str, error: bool, counts: [u64; 4]) -> LatencyDelta {
+        (name.to_string(), error, BOUNDS.to_vec(), counts.to_vec())
+    }
+
+    #[test]
+    fn test_quantile_interpolates_within_buckets() {
+        let counts = [0, 10, 10, 0];
+        assert_eq!(quantile(&BOUNDS, &counts, 0.5), Some(20.0));
+        assert_eq!(quantile(&BOUNDS, &counts, 0.25), Some(15.0));
+        assert_eq!(quantile(&BOUNDS, &counts, 0.75), Some(30.0));
+        // In the overflow bucket: the last bound is all there is to say
+        assert_eq!(quantile(&BOUNDS, &[0, 0, 0, 5], 0.99), Some(40.0));
+        assert_eq!(quantile(&BOUNDS, &[0; 4], 0.5), None);
+    }
+
+    #[test]
+    fn test_window_rolls_and_merges_outcomes() {
+        let window = LatencyWindow::new(Duration::from_secs(60));
+        let start = Instant::now();
+        window.record(start, Vec::new());
+        window.record(
+            start + Duration::from_secs(10),
+            vec![
+                delta("checkout", false, [4, 4, 0, 0]),
+                delta("checkout", true, [0, 0, 2, 0]),
+            ],
+        );
+        window.record(
+            start + Duration::from_secs(20),
+            vec![delta("checkout", false, [6, 4, 0, 0])],
+        );
+
+        let (span, stats) = window.stats(start + Duration::from_secs(20));
+        assert_eq!(span, Duration::from_secs(20));
+        let checkout = &stats[0];
+        assert_eq!((checkout.requests, checkout.errors), (20, 2));
+        assert_eq!(checkout.rate, 1.0);
+        assert_eq!(checkout.error_ratio, 0.1);
+        assert_eq!(checkout.p50_ms, Some(10.0));
+        assert_eq!(checkout.p95_ms, Some(30.0));
+
+        // A minute on, only the last collect's slot is left
+        let (_, stats) = window.stats(start + Duration::from_secs(75));
+        assert_eq!(stats[0].requests, 10);
+        let (span, stats) = window.stats(start + Duration::from_secs(90));
+        assert_eq!(span, Duration::ZERO);
+        assert!(stats.is_empty());
+    }
+
+    #[test]
+    fn test_ring_is_bounded() {
+        let window = LatencyWindow::new(Duration::from_secs(60));
+        let start = Instant::now();
+        // A collect every 20ms for two minutes
+        for tick in 0..6000 {
+            let now = start + Duration::from_millis(20 * tick);
+            window.record(now, vec![delta("fast", false, [1, 0, 0, 0])]);
+        }
+        assert!(window.ring.lock().slots.len() <= SLOTS as usize + 1);
+        let (span, stats) = window.stats(start + Duration::from_millis(20 * 5999));
+        // A minute, give or take a slot
+        assert!(span <= Duration::from_secs(62), "{:?}", span);
+        assert!(stats[0].requests <= 62 * 50, "{}", stats[0].requests);
+    }
+}
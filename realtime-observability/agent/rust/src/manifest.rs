@@ -0,0 +1,174 @@
+//! Metrics declared up front, for `Agent::register_manifest`
+//!
+//! A metric otherwise exists from its first record, so a dashboard has
+//! nothing to show for a counter that hasn't moved since the service
+//! started. Registering a manifest creates every declared series at once:
+//! counters at zero, gauges at their initial value and histograms empty
+//! with their declared buckets, all in the first batch. Each name's type is
+//! fixed as declared, and later records of another type are refused as for
+//! any metric (`MetricTypeConflict`).
+//!
+//! With the `serde` feature a manifest deserializes from any format serde
+//! reads, such as this JSON:
+//!
+//! ```json
+//! {
+//!   "counters": [{ "name": "orders_total", "description": "Orders placed" }],
+//!   "gauges": [{ "name": "queue_depth", "initial": 0.0 }],
+//!   "histograms": [{
+//!     "name": "payment_ms",
+//!     "unit": "Milliseconds",
+//!     "buckets": { "Explicit": [5.0, 50.0, 500.0] }
+//!   }]
+//! }
+//! ```
+//!
+//! Descriptions are announced to the aggregator as `description.{name}`
+//! metadata fields.
+
+use std::fmt;
+
+use crate::{BucketSpec, MetricTypeConflict, Unit, UnitMismatch};
+
+/// Counters, gauges and histograms to create at startup
+#[derive(Debug, Clone, Default, PartialEq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(default, deny_unknown_fields)
+)]
+pub struct MetricManifest {
+    pub counters: Vec<CounterDecl>,
+    pub gauges: Vec<GaugeDecl>,
+    pub histograms: Vec<HistogramDecl>,
+}
+
+impl MetricManifest {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Declare a counter, starting at zero
+    pub fn counter(mut self, name: &str, description: &str) -> Self {
+        self.counters.push(CounterDecl {
+            name: name.to_string(),
+            description: description.to_string(),
+        });
+        self
+    }
+
+    /// Declare a gauge, set to `initial` until first set
+    pub fn gauge(mut self, name: &str, initial: f64, description: &str) -> Self {
+        self.gauges.push(GaugeDecl {
+            name: name.to_string(),
+            initial,
+            description: description.to_string(),
+        });
+        self
+    }
+
+    /// Declare a histogram
+    pub fn histogram(mut self, histogram: HistogramDecl) -> Self {
+        self.histograms.push(histogram);
+        self
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(deny_unknown_fields)
+)]
+pub struct CounterDecl {
+    pub name: String,
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub description: String,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(deny_unknown_fields)
+)]
+pub struct GaugeDecl {
+    pub name: String,
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub initial: f64,
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub description: String,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(deny_unknown_fields)
+)]
+pub struct HistogramDecl {
+    pub name: String,
+    /// `None` for the unit's bounds, or the agent's default without a unit
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub buckets: Option<BucketSpec>,
+    /// Sent as a `unit` label, as for `Agent::histogram_ms`
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub unit: Option<Unit>,
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub description: String,
+}
+
+impl HistogramDecl {
+    pub fn new(name: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            ..Default::default()
+        }
+    }
+
+    pub fn buckets(mut self, buckets: BucketSpec) -> Self {
+        self.buckets = Some(buckets);
+        self
+    }
+
+    pub fn unit(mut self, unit: Unit) -> Self {
+        self.unit = Some(unit);
+        self
+    }
+
+    pub fn description(mut self, description: &str) -> Self {
+        self.description = description.to_string();
+        self
+    }
+}
+
+/// A declaration `Agent::register_manifest` could not register, because
+/// its name is already a metric of another type or unit
+#[derive(Debug, Clone, PartialEq)]
+pub enum ManifestConflict {
+    Type(MetricTypeConflict),
+    Unit(UnitMismatch),
+}
+
+impl fmt::Display for ManifestConflict {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ManifestConflict::Type(conflict) => conflict.fmt(f),
+            ManifestConflict::Unit(mismatch) => mismatch.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for ManifestConflict {}
+
+impl From<MetricTypeConflict> for ManifestConflict {
+    fn from(conflict: MetricTypeConflict) -> Self {
+        ManifestConflict::Type(conflict)
+    }
+}
+
+impl From<UnitMismatch> for ManifestConflict {
+    fn from(mismatch: UnitMismatch) -> Self {
+        ManifestConflict::Unit(mismatch)
+    }
+}
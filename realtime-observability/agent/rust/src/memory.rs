@@ -0,0 +1,402 @@
+//! Memory accounting and `Config::memory_budget_bytes`
+//!
+//! Estimates cover what the agent allocates itself: registry tables, series
+//! keys and metric storage, the table of metric types, queued events, and
+//! collected batches waiting for the sender. They ignore allocator overhead
+//! and round nothing up, so they run somewhat low; the tests below hold them within 2x of what a
+//! counting allocator sees.
+//!
+//! Registry bytes are recounted at every collect while a budget is set and
+//! estimated in between, so a burst of registrations within one push
+//! interval can overshoot the budget by the estimation error.
+
+use std::collections::{BTreeMap, HashMap};
+use std::mem::size_of;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use parking_lot::Mutex;
+
+use crate::agent::{inc_counter_in, CounterRegistry, Registries};
+use crate::bulk::{BulkCollect, BulkCounter};
+use crate::counter::Counter;
+use crate::gauge::Gauge;
+use crate::sharded::ShardedCounter;
+use crate::telemetry::{
+    metric_sample, Event, Exemplar as ExemplarProto, Metric, MetricSample, Span, TelemetryBatch,
+};
+use crate::{name, Histogram, MemoryUsage, DEFAULT_BOUNDS};
+
+/// Reference counts in front of every `Arc` allocation
+const ARC_HEADER: usize = 2 * size_of::<usize>();
+
+/// Leaf nodes of a `BTreeMap<String, String>` hold up to 11 entries
+const BTREE_LEAF: usize = 11 * 2 * size_of::<String>() + 16;
+
+/// Heap bytes behind one `Arc` of a metric
+pub(crate) trait Footprint {
+    fn footprint(&self) -> usize;
+
+    /// Estimate for a series not created yet
+    fn new_footprint() -> usize;
+}
+
+impl Footprint for Counter {
+    fn footprint(&self) -> usize {
+        Self::new_footprint()
+    }
+
+    fn new_footprint() -> usize {
+        ARC_HEADER + size_of::<Counter>()
+    }
+}
+
+impl Footprint for Gauge {
+    fn footprint(&self) -> usize {
+        Self::new_footprint()
+    }
+
+    fn new_footprint() -> usize {
+        ARC_HEADER + size_of::<Gauge>()
+    }
+}
+
+impl Footprint for Histogram {
+    fn footprint(&self) -> usize {
+        ARC_HEADER + size_of::<Histogram>() + self.heap_bytes()
+    }
+
+    fn new_footprint() -> usize {
+        // Custom bounds are usually about as long as the defaults
+        Histogram::with_bounds(&DEFAULT_BOUNDS).footprint()
+    }
+}
+
+impl Footprint for BulkCounter {
+    fn footprint(&self) -> usize {
+        ARC_HEADER + size_of::<BulkCounter>() + self.heap_bytes()
+    }
+
+    /// Without its slots, which registration doesn't know the number of
+    fn new_footprint() -> usize {
+        BulkCounter::new("", 0, BulkCollect::All).footprint()
+    }
+}
+
+impl Footprint for ShardedCounter {
+    fn footprint(&self) -> usize {
+        ARC_HEADER + size_of::<ShardedCounter>() + self.heap_bytes()
+    }
+
+    fn new_footprint() -> usize {
+        ShardedCounter::new(0).footprint()
+    }
+}
+
+/// Running totals shared by the API, collector and sender
+pub(crate) struct MemoryAccount {
+    budget: Option<usize>,
+    /// Where refusals and evictions are counted
+    counters: CounterRegistry,
+    registry_bytes: AtomicUsize,
+    buffer_bytes: AtomicUsize,
+    events_bytes: AtomicUsize,
+}
+
+impl MemoryAccount {
+    pub(crate) fn new(budget: Option<usize>, counters: CounterRegistry) -> Self {
+        Self {
+            budget,
+            counters,
+            registry_bytes: AtomicUsize::new(0),
+            buffer_bytes: AtomicUsize::new(0),
+            events_bytes: AtomicUsize::new(0),
+        }
+    }
+
+    /// Whether series `key` may be created in `registry`. Existing series
+    /// always may; a refused one counts in
+    /// `agent_registrations_refused_total`.
+    pub(crate) fn has_room<T: Footprint>(
+        &self,
+        registry: &Mutex<HashMap<String, Arc<T>>>,
+        key: &str,
+    ) -> bool {
+        let Some(budget) = self.budget else {
+            return true;
+        };
+        let growth = {
+            let registry = registry.lock();
+            if registry.contains_key(key) {
+                return true;
+            }
+            // A full table doubles on the next insert
+            let capacity = registry.capacity();
+            match registry.len() == capacity {
+                true => {
+                    table_bytes::<(String, Arc<T>)>((capacity * 2).max(3))
+                        - table_bytes::<(String, Arc<T>)>(capacity)
+                }
+                false => 0,
+            }
+        };
+        // The slot itself is already counted in the table
+        let bytes = key.len() + T::new_footprint() + growth;
+        if self.used() + bytes > budget {
+            inc_counter_in(&self.counters, name!("agent_registrations_refused_total"));
+            return false;
+        }
+        self.registry_bytes.fetch_add(bytes, Ordering::Relaxed);
+        true
+    }
+
+    /// A table entry of `bytes` was made outside `has_room`, such as a
+    /// metric name's type
+    pub(crate) fn registered(&self, bytes: usize) {
+        if self.budget.is_some() {
+            self.registry_bytes.fetch_add(bytes, Ordering::Relaxed);
+        }
+    }
+
+    /// Whether the total is past the budget
+    pub(crate) fn over_budget(&self) -> bool {
+        self.budget.is_some_and(|budget| self.used() > budget)
+    }
+
+    /// A batch of `bytes` joined the send queue
+    pub(crate) fn buffered(&self, bytes: usize) {
+        self.buffer_bytes.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    /// A batch of `bytes` left the send queue, sent or evicted
+    pub(crate) fn unbuffered(&self, bytes: usize) {
+        self.buffer_bytes.fetch_sub(bytes, Ordering::Relaxed);
+    }
+
+    /// A queued batch was dropped to get back under budget
+    pub(crate) fn evicted(&self, bytes: usize) {
+        self.unbuffered(bytes);
+        inc_counter_in(&self.counters, name!("agent_batches_evicted_total"));
+    }
+
+    /// Recount everything and store the registry and event totals for
+    /// later budget checks
+    pub(crate) fn measure(&self, registries: &Registries) -> MemoryUsage {
+        let usage = MemoryUsage {
+            registry_bytes: map_bytes(&registries.gauges)
+                + map_bytes(&registries.counters)
+                + map_bytes(&registries.histograms)
+                + map_bytes(&registries.sharded)
+                + map_bytes(&registries.bulk)
+                + registries.types.bytes(),
+            buffer_bytes: self.buffer_bytes.load(Ordering::Relaxed),
+            events_bytes: registries.events.bytes(),
+        };
+        self.registry_bytes
+            .store(usage.registry_bytes, Ordering::Relaxed);
+        self.events_bytes
+            .store(usage.events_bytes, Ordering::Relaxed);
+        usage
+    }
+
+    /// Recount at collect time, only when a budget needs the numbers
+    pub(crate) fn remeasure(&self, registries: &Registries) {
+        if self.budget.is_some() {
+            self.measure(registries);
+        }
+    }
+
+    fn used(&self) -> usize {
+        self.registry_bytes.load(Ordering::Relaxed)
+            + self.buffer_bytes.load(Ordering::Relaxed)
+            + self.events_bytes.load(Ordering::Relaxed)
+    }
+}
+
+/// Hash table, keys and metrics of one registry
+fn map_bytes<T: Footprint>(registry: &Mutex<HashMap<String, Arc<T>>>) -> usize {
+    let registry = registry.lock();
+    table_bytes::<(String, Arc<T>)>(registry.capacity())
+        + registry
+            .iter()
+            .map(|(key, metric)| key.capacity() + metric.footprint())
+            .sum::<usize>()
+}
+
+/// A hashbrown table: a power-of-two bucket count at 7/8 load, one slot
+/// and one control byte per bucket
+pub(crate) fn table_bytes<T>(capacity: usize) -> usize {
+    if capacity == 0 {
+        return 0;
+    }
+    let buckets = (capacity * 8 / 7).next_power_of_two();
+    buckets * (size_of::<T>() + 1)
+}
+
+fn labels_bytes(labels: &BTreeMap<String, String>) -> usize {
+    labels.len().div_ceil(11) * BTREE_LEAF
+        + labels
+            .iter()
+            .map(|(k, v)| k.capacity() + v.capacity())
+            .sum::<usize>()
+}
+
+/// Heap bytes of a decoded event, excluding its own slot
+pub(crate) fn event_bytes(event: &Event) -> usize {
+    event.name.capacity() + labels_bytes(&event.attributes)
+}
+
+/// Heap bytes of a decoded batch plus the batch itself
+pub(crate) fn batch_bytes(batch: &TelemetryBatch) -> usize {
+    let metrics: usize = batch
+        .metrics
+        .iter()
+        .map(|metric| {
+            let samples: usize = metric
+                .samples
+                .iter()
+                .map(|sample| match &sample.value {
+                    Some(metric_sample::Value::Histogram(hist)) => {
+                        hist.bounds.capacity() * size_of::<f64>()
+                            + hist.counts.capacity() * size_of::<u64>()
+                            + hist.exemplars.capacity() * size_of::<ExemplarProto>()
+                            + hist
+                                .exemplars
+                                .iter()
+                                .map(|e| e.trace_id.capacity())
+                                .sum::<usize>()
+                    }
+                    _ => 0,
+                })
+                .sum();
+            metric.name.capacity()
+                + labels_bytes(&metric.labels)
+                + metric.samples.capacity() * size_of::<MetricSample>()
+                + samples
+        })
+        .sum();
+    size_of::<TelemetryBatch>()
+        + batch.service.capacity()
+        + batch.instance.capacity()
+        + batch.metrics.capacity() * size_of::<Metric>()
+        + metrics
+        + batch.events.capacity() * size_of::<Event>()
+        + batch.events.iter().map(event_bytes).sum::<usize>()
+        + batch.spans.capacity() * size_of::<Span>()
+        + batch
+            .spans
+            .iter()
+            .map(|span| {
+                span.trace_id.capacity() + span.name.capacity() + labels_bytes(&span.attributes)
+            })
+            .sum::<usize>()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::alloc::{GlobalAlloc, Layout, System};
+    use std::cell::Cell;
+
+    use super::*;
+    use crate::{Agent, Config, Severity};
+
+    /// Counts bytes requested on the current thread, net of frees
+    struct Counting;
+
+    thread_local! {
+        static ALLOCATED: Cell<isize> = const { Cell::new(0) };
+    }
+
+    unsafe impl GlobalAlloc for Counting {
+        unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+            let _ = ALLOCATED.try_with(|n| n.set(n.get() + layout.size() as isize));
+            System.alloc(layout)
+        }
+
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+            let _ = ALLOCATED.try_with(|n| n.set(n.get() - layout.size() as isize));
+            System.dealloc(ptr, layout)
+        }
+    }
+
+    #[global_allocator]
+    static COUNTING: Counting = Counting;
+
+    fn allocated_by<T>(f: impl FnOnce() -> T) -> (T, usize) {
+        let before = ALLOCATED.with(Cell::get);
+        let result = f();
+        (result, (ALLOCATED.with(Cell::get) - before) as usize)
+    }
+
+    fn assert_within_2x(estimate: usize, real: usize) {
+        assert!(
+            estimate * 2 >= real && estimate <= real * 2,
+            "estimated {} bytes, allocated {}",
+            estimate,
+            real
+        );
+    }
+
+    #[test]
+    fn test_registry_estimate() {
+        let agent = Agent::new(Config::default());
+        let ((), real) = allocated_by(|| {
+            for i in 0..2000 {
+                agent.inc_counter_with("requests", &[("route", &format!("/users/{}", i))]);
+            }
+            for i in 0..500 {
+                agent.set_gauge(&format!("queue_depth_{}", i), 1.0);
+            }
+            for i in 0..200 {
+                agent.record_histogram_with("latency", &[("shard", &i.to_string())], 1.0);
+            }
+            agent.sharded_counter("hot");
+        });
+        assert_within_2x(agent.memory_usage().registry_bytes, real);
+    }
+
+    #[test]
+    fn test_batch_estimate() {
+        let agent = Agent::new(Config::default());
+        for i in 0..300 {
+            let route = format!("/users/{}", i);
+            agent.inc_counter_with("requests", &[("route", &route), ("method", "GET")]);
+            agent.record_histogram_with("latency", &[("route", &route)], 3.0);
+        }
+        let (batch, real) = allocated_by(|| agent.collect_now());
+        assert_within_2x(batch_bytes(&batch), real);
+    }
+
+    #[test]
+    fn test_events_estimate() {
+        let agent = Agent::new(Config::default());
+        let ((), real) = allocated_by(|| {
+            for i in 0..100 {
+                let version = format!("1.{}", i);
+                agent.emit_event("deploy", Severity::Info, &[("version", &version)]);
+            }
+        });
+        assert_within_2x(agent.memory_usage().events_bytes, real);
+    }
+
+    #[test]
+    fn test_budget_refuses_new_series() {
+        let agent = Agent::new(Config {
+            memory_budget_bytes: Some(32 * 1024),
+            ..Default::default()
+        });
+        agent.inc_counter("existing");
+        for i in 0..1000 {
+            agent.inc_counter(&format!("requests_{}", i));
+        }
+        agent.inc_counter("existing");
+
+        let counters = agent.counters.lock();
+        assert!(counters.len() < 1000);
+        assert_eq!(counters["existing"].value(), 2);
+        assert!(counters["agent_registrations_refused_total"].value() > 0);
+        drop(counters);
+        // Self-metrics are never refused and may grow the table past it
+        assert!(agent.memory_usage().total() < 2 * 32 * 1024);
+    }
+}
@@ -0,0 +1,206 @@
+//! One metric type per name
+//!
+//! The first gauge, counter or histogram recorded under a name fixes its
+//! type. A record of another type under that name is refused: the `try_`
+//! recording methods return `MetricTypeConflict`, the others drop the
+//! sample, count it in `drop_report` and log the first conflict per name.
+//! Without this a batch could carry two metrics of one name with different
+//! sample types, and the aggregator rejects such a batch whole.
+
+use std::fmt;
+
+#[cfg(not(feature = "noop"))]
+use std::collections::HashMap;
+#[cfg(not(feature = "noop"))]
+use std::mem::size_of;
+#[cfg(not(feature = "noop"))]
+use std::sync::atomic::{AtomicBool, Ordering};
+#[cfg(not(feature = "noop"))]
+use std::sync::Arc;
+
+#[cfg(not(feature = "noop"))]
+use parking_lot::RwLock;
+
+#[cfg(not(feature = "noop"))]
+use crate::drops::{DropLog, DropReason};
+#[cfg(not(feature = "noop"))]
+use crate::memory::{table_bytes, MemoryAccount};
+
+/// Sample type a metric name is fixed to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum MetricType {
+    Gauge,
+    Counter,
+    Histogram,
+}
+
+impl MetricType {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            MetricType::Gauge => "gauge",
+            MetricType::Counter => "counter",
+            MetricType::Histogram => "histogram",
+        }
+    }
+}
+
+/// Returned when a name is recorded as a different type than its first use
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MetricTypeConflict {
+    pub name: String,
+    pub registered: MetricType,
+    pub requested: MetricType,
+}
+
+impl fmt::Display for MetricTypeConflict {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "metric {:?} is a {} but was recorded as a {}",
+            self.name,
+            self.registered.as_str(),
+            self.requested.as_str()
+        )
+    }
+}
+
+impl std::error::Error for MetricTypeConflict {}
+
+#[cfg(not(feature = "noop"))]
+pub(crate) struct MetricTypes {
+    types: RwLock<HashMap<String, Fixed>>,
+    /// Where refused records are counted
+    drops: Arc<DropLog>,
+    memory: Arc<MemoryAccount>,
+}
+
+#[cfg(not(feature = "noop"))]
+struct Fixed {
+    kind: MetricType,
+    /// Whether a conflict was logged
+    warned: AtomicBool,
+}
+
+#[cfg(not(feature = "noop"))]
+impl MetricTypes {
+    pub(crate) fn new(drops: Arc<DropLog>, memory: Arc<MemoryAccount>) -> Self {
+        Self {
+            types: RwLock::default(),
+            drops,
+            memory,
+        }
+    }
+
+    /// Fix `name` as `requested` if it is new, or check it already is,
+    /// counting a conflict against `name` in `drop_report`. Callers check
+    /// once per series, when creating it.
+    pub(crate) fn check(
+        &self,
+        name: &str,
+        requested: MetricType,
+    ) -> Result<(), MetricTypeConflict> {
+        let known = self.types.read().get(name).map(|fixed| fixed.kind);
+        let registered = match known {
+            Some(registered) => registered,
+            None => {
+                let mut types = self.types.write();
+                let fixed = types.entry(name.to_string()).or_insert_with(|| {
+                    self.memory
+                        .registered(name.len() + size_of::<(String, Fixed)>());
+                    Fixed {
+                        kind: requested,
+                        warned: AtomicBool::new(false),
+                    }
+                });
+                fixed.kind
+            }
+        };
+        if registered == requested {
+            return Ok(());
+        }
+        self.drops.record(name, DropReason::TypeConflict, 1);
+        Err(MetricTypeConflict {
+            name: name.to_string(),
+            registered,
+            requested,
+        })
+    }
+
+    /// `check`, logging the first conflict for `name`
+    pub(crate) fn claim(&self, name: &str, kind: MetricType) -> bool {
+        match self.check(name, kind) {
+            Ok(()) => true,
+            Err(conflict) => {
+                self.warn_once(&conflict);
+                false
+            }
+        }
+    }
+
+    pub(crate) fn get(&self, name: &str) -> Option<MetricType> {
+        self.types.read().get(name).map(|fixed| fixed.kind)
+    }
+
+    /// Log `conflict` unless its name already had one logged
+    pub(crate) fn warn_once(&self, conflict: &MetricTypeConflict) {
+        let types = self.types.read();
+        let Some(fixed) = types.get(&conflict.name) else {
+            return;
+        };
+        if fixed.warned.swap(true, Ordering::Relaxed) {
+            return;
+        }
+        tracing::warn!(
+            metric = %conflict.name,
+            registered = conflict.registered.as_str(),
+            requested = conflict.requested.as_str(),
+            "metric recorded as another type than its first use, dropping the sample"
+        );
+    }
+
+    /// Heap bytes of the table, for `MemoryAccount::measure`
+    pub(crate) fn bytes(&self) -> usize {
+        let types = self.types.read();
+        table_bytes::<(String, Fixed)>(types.capacity())
+            + types.keys().map(String::capacity).sum::<usize>()
+    }
+}
+
+#[cfg(all(test, not(feature = "noop")))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_first_use_fixes_the_type() {
+        let types = MetricTypes::new(
+            Arc::default(),
+            Arc::new(MemoryAccount::new(None, Arc::default())),
+        );
+        assert_eq!(types.get("jobs"), None);
+        assert!(types.check("jobs", MetricType::Counter).is_ok());
+        assert!(types.check("jobs", MetricType::Counter).is_ok());
+        assert_eq!(
+            types.check("jobs", MetricType::Gauge),
+            Err(MetricTypeConflict {
+                name: "jobs".to_string(),
+                registered: MetricType::Counter,
+                requested: MetricType::Gauge,
+            })
+        );
+        assert_eq!(types.get("jobs"), Some(MetricType::Counter));
+    }
+
+    #[test]
+    fn test_table_counts_against_the_budget() {
+        let memory = Arc::new(MemoryAccount::new(Some(2048), Arc::default()));
+        let types = MetricTypes::new(Arc::default(), memory.clone());
+        for i in 0..100 {
+            types
+                .check(&format!("metric_{:03}", i), MetricType::Gauge)
+                .unwrap();
+        }
+        assert!(types.bytes() > 100 * "metric_000".len());
+        assert!(memory.over_budget());
+    }
+}
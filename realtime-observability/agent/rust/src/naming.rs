@@ -0,0 +1,277 @@
+//! Metric naming rules: `name!` checks literals at compile time,
+//! `normalize_name` fixes names at runtime (`Config::sanitize_names`)
+//!
+//! A metric name is snake_case: a lowercase ASCII letter, then lowercase
+//! letters, digits and single underscores, not ending in an underscore.
+//! The agent accepts any name unless `Config::sanitize_names` is set, so
+//! `requestCount` and `request_count` are otherwise two metrics.
+//!
+//! ```
+//! use telemetry_agent::{name, Agent, Config};
+//!
+//! let agent = Agent::new(Config::default());
+//! agent.inc_counter(name!("request_count"));
+//! ```
+//!
+//! Agent methods take any `MetricName`: a `name!` literal goes in as
+//! checked, so `sanitize_names` doesn't check it again on every call.
+//!
+//! A literal that breaks the rules fails to compile, with the rule it
+//! breaks:
+//!
+//! ```compile_fail
+//! let _ = telemetry_agent::name!("requestCount");
+//! ```
+//!
+//! ```compile_fail
+//! let _ = telemetry_agent::name!("request_count_");
+//! ```
+//!
+//! ```compile_fail
+//! let _ = telemetry_agent::name!("http.requests");
+//! ```
+
+use std::borrow::Cow;
+use std::fmt;
+use std::ops::Deref;
+use std::sync::Arc;
+
+/// A metric name checked against the naming rules, from `name!`;
+/// dereferences to `&str`, so it goes wherever a name does
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct MetricNameLit(&'static str);
+
+impl MetricNameLit {
+    /// # Panics
+    ///
+    /// If `name` breaks the naming rules, which in `name!` is an error at
+    /// compile time.
+    pub const fn new(name: &'static str) -> Self {
+        if let Err(rule) = check(name) {
+            panic!("{}", rule);
+        }
+        Self(name)
+    }
+
+    pub const fn as_str(&self) -> &'static str {
+        self.0
+    }
+}
+
+impl Deref for MetricNameLit {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        self.0
+    }
+}
+
+impl AsRef<str> for MetricNameLit {
+    fn as_ref(&self) -> &str {
+        self.0
+    }
+}
+
+impl fmt::Display for MetricNameLit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.0)
+    }
+}
+
+/// A metric name as agent methods take it: a string, checked against the
+/// rules at runtime with `Config::sanitize_names`, or a `name!` literal,
+/// checked once at compile time
+pub trait MetricName {
+    fn as_name(&self) -> &str;
+
+    /// This name as a checked literal, if it is one
+    fn literal(&self) -> Option<MetricNameLit> {
+        None
+    }
+}
+
+impl MetricName for MetricNameLit {
+    fn as_name(&self) -> &str {
+        self.0
+    }
+
+    fn literal(&self) -> Option<MetricNameLit> {
+        Some(*self)
+    }
+}
+
+impl MetricName for str {
+    fn as_name(&self) -> &str {
+        self
+    }
+}
+
+impl MetricName for String {
+    fn as_name(&self) -> &str {
+        self
+    }
+}
+
+impl MetricName for Cow<'_, str> {
+    fn as_name(&self) -> &str {
+        self
+    }
+}
+
+impl MetricName for Arc<str> {
+    fn as_name(&self) -> &str {
+        self
+    }
+}
+
+impl<T: MetricName + ?Sized> MetricName for &T {
+    fn as_name(&self) -> &str {
+        (**self).as_name()
+    }
+
+    fn literal(&self) -> Option<MetricNameLit> {
+        (**self).literal()
+    }
+}
+
+/// A metric name literal checked at compile time: `name!("request_count")`
+/// is a `&'static MetricNameLit`, and `name!("requestCount")` an error
+#[macro_export]
+macro_rules! name {
+    ($name:literal) => {{
+        const NAME: &'static $crate::MetricNameLit = &$crate::MetricNameLit::new($name);
+        NAME
+    }};
+}
+
+/// The rule `name` breaks, if any
+pub(crate) const fn check(name: &str) -> Result<(), &'static str> {
+    let bytes = name.as_bytes();
+    if bytes.is_empty() {
+        return Err("metric name is empty");
+    }
+    if !bytes[0].is_ascii_lowercase() {
+        return Err("metric names start with a lowercase letter");
+    }
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'a'..=b'z' | b'0'..=b'9' => {}
+            b'_' if i + 1 == bytes.len() => return Err("metric names don't end in `_`"),
+            b'_' if bytes[i + 1] == b'_' => return Err("metric names don't contain `__`"),
+            b'_' => {}
+            b'A'..=b'Z' => {
+                return Err("metric names are snake_case: `_` between words, no uppercase")
+            }
+            _ => return Err("metric names contain only `a-z`, `0-9` and `_`"),
+        }
+        i += 1;
+    }
+    Ok(())
+}
+
+/// Whether `name` follows the naming rules
+pub(crate) fn is_valid(name: &str) -> bool {
+    check(name).is_ok()
+}
+
+/// `name` in snake_case: words split at case changes and at anything but
+/// letters and digits, lowercased and joined by single `_`. Valid names
+/// come back unchanged; `requestCount`, `HTTPRequests` and
+/// `http.requests` become `request_count`, `http_requests` and
+/// `http_requests`. A name left starting with a digit gets `metric_` in
+/// front, and one left empty is `unnamed`.
+pub fn normalize_name(name: &str) -> String {
+    let chars: Vec<char> = name.chars().collect();
+    let mut out = String::with_capacity(name.len() + 4);
+    let separate = |out: &mut String| {
+        if !out.is_empty() && !out.ends_with('_') {
+            out.push('_');
+        }
+    };
+    for (i, &c) in chars.iter().enumerate() {
+        if c.is_ascii_uppercase() {
+            let prev = i.checked_sub(1).map(|p| chars[p]);
+            let next = chars.get(i + 1);
+            let starts_word = match prev {
+                Some(p) if p.is_ascii_lowercase() || p.is_ascii_digit() => true,
+                // The last capital of an acronym starts the next word
+                Some(p) if p.is_ascii_uppercase() => next.is_some_and(|n| n.is_ascii_lowercase()),
+                _ => false,
+            };
+            if starts_word {
+                separate(&mut out);
+            }
+            out.push(c.to_ascii_lowercase());
+        } else if c.is_ascii_lowercase() || c.is_ascii_digit() {
+            out.push(c);
+        } else {
+            separate(&mut out);
+        }
+    }
+    while out.ends_with('_') {
+        out.pop();
+    }
+    match out.chars().next() {
+        None => "unnamed".to_string(),
+        Some(c) if c.is_ascii_digit() => format!("metric_{}", out),
+        Some(_) => out,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rules() {
+        for valid in ["request_count", "p99_latency_ms", "a", "http2_streams"] {
+            assert_eq!(check(valid), Ok(()), "{}", valid);
+        }
+        for invalid in [
+            "",
+            "requestCount",
+            "_private",
+            "2xx_total",
+            "request__count",
+            "request_count_",
+            "http.requests",
+            "latency-ms",
+        ] {
+            assert!(check(invalid).is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn test_names_are_normalized() {
+        for (raw, normalized) in [
+            ("request_count", "request_count"),
+            ("requestCount", "request_count"),
+            ("HTTPRequests", "http_requests"),
+            ("http.server.duration", "http_server_duration"),
+            ("p99Latency", "p99_latency"),
+            ("__queue--depth__", "queue_depth"),
+            ("2xx", "metric_2xx"),
+            ("", "unnamed"),
+            ("日本", "unnamed"),
+        ] {
+            assert_eq!(normalize_name(raw), normalized, "{:?}", raw);
+        }
+    }
+
+    #[test]
+    fn test_literals_check_at_compile_time() {
+        let name: &'static MetricNameLit = crate::name!("request_count");
+        assert_eq!(&**name, "request_count");
+        assert_eq!(name.to_string(), "request_count");
+    }
+
+    proptest::proptest! {
+        #[test]
+        fn prop_normalized_names_are_valid(raw in "\\PC{0,24}") {
+            let normalized = normalize_name(&raw);
+            proptest::prop_assert!(is_valid(&normalized), "{:?} -> {:?}", raw, normalized);
+            proptest::prop_assert_eq!(normalize_name(&normalized), normalized);
+        }
+    }
+}
@@ -0,0 +1,1315 @@
+//! API-compatible stubs compiled under the `noop` feature
+//!
+//! Every public type and method of the runtime agent exists here with the
+//! same signature and an empty body, so downstream code builds unchanged
+//! with telemetry compiled out. Nothing here depends on tokio, tonic or
+//! prost. Items whose signatures name tonic types
+//! (`PushErrorKind::from_status`, `PushErrorKind::from_transport_error`,
+//! the ingestor server and `LocalAggregator`), `Agent::subscribe_batches`
+//! and the `axum`/`statsd` integrations are not available;
+//! `Config::tokio_handle`, `Config::endpoint_customizer`,
+//! `Agent::start_with_channel`, `Agent::start_with_connector` and
+//! `TokioSpawner::new` exist only if `runtime` is enabled as well.
+
+use std::fmt;
+use std::future::Future;
+use std::marker::PhantomData;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::{
+    AgentError, AgentState, BucketSpec, BulkCollect, ByteCount, ClockSkew, Config, Diagnostics,
+    DropStats, ErrorInfo, GaugeAggregation, Identity, InvalidState, JobReport, LocalStats,
+    ManifestConflict, MemoryUsage, MetricManifest, MetricName, MetricType, MetricTypeConflict,
+    MisalignedWireBounds, Outcome, PoolConfig, PoolDiagnostics, RecordableHistogram, ResetPolicy,
+    Severity, ShutdownReport, SloSpec, UnitMismatch,
+};
+
+pub const WIRE_VERSION: u8 = 1;
+pub const MIN_REMOTE_INTERVAL: Duration = Duration::from_millis(10);
+pub const MAX_REMOTE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Plain mirrors of the generated protobuf types
+pub mod telemetry {
+    use std::collections::BTreeMap;
+
+    pub const SCHEMA_VERSION: u32 = 2;
+    pub const AGENT_VERSION: &str = env!("CARGO_PKG_VERSION");
+
+    #[derive(Debug, Clone, PartialEq, Default)]
+    pub struct TelemetryBatch {
+        pub service: String,
+        pub instance: String,
+        pub metrics: Vec<Metric>,
+        pub announce: Option<Announce>,
+        pub events: Vec<Event>,
+        pub sent_at_ns: u64,
+        pub agent_version: String,
+        pub service_version: String,
+        pub schema_version: u32,
+        pub instance_epoch: u64,
+        pub connection_generation: u64,
+        pub draining: bool,
+        pub checksum: Option<u32>,
+        pub base_timestamp_ns: u64,
+        pub full_resync: bool,
+        pub spans: Vec<Span>,
+        pub series_definitions: Vec<SeriesDefinition>,
+    }
+
+    #[derive(Debug, Clone, PartialEq, Default)]
+    pub struct SeriesDefinition {
+        pub id: u32,
+        pub name: String,
+        pub labels: BTreeMap<String, String>,
+    }
+
+    #[derive(Debug, Clone, PartialEq, Default)]
+    pub struct Span {
+        pub trace_id: Vec<u8>,
+        pub span_id: u64,
+        pub parent_span_id: u64,
+        pub name: String,
+        pub start_ns: u64,
+        pub duration_ns: u64,
+        pub status: i32,
+        pub attributes: BTreeMap<String, String>,
+    }
+
+    #[derive(Debug, Clone, PartialEq, Default)]
+    pub struct Event {
+        pub timestamp_ns: u64,
+        pub name: String,
+        pub severity: i32,
+        pub attributes: BTreeMap<String, String>,
+    }
+
+    #[derive(Debug, Clone, PartialEq, Default)]
+    pub struct Announce {
+        pub metadata: BTreeMap<String, String>,
+    }
+
+    #[derive(Debug, Clone, PartialEq, Default)]
+    pub struct Metric {
+        pub name: String,
+        pub labels: BTreeMap<String, String>,
+        pub samples: Vec<MetricSample>,
+        pub series_id: u32,
+        pub series_hash: u64,
+    }
+
+    #[derive(Debug, Clone, PartialEq, Default)]
+    pub struct MetricSample {
+        pub timestamp_ns: u64,
+        pub value: Option<metric_sample::Value>,
+        pub window_start_ns: u64,
+    }
+
+    pub mod metric_sample {
+        #[derive(Debug, Clone, PartialEq)]
+        pub enum Value {
+            Gauge(f64),
+            Counter(u64),
+            Histogram(super::Histogram),
+        }
+    }
+
+    #[derive(Debug, Clone, PartialEq, Default)]
+    pub struct Histogram {
+        pub bounds: Vec<f64>,
+        pub counts: Vec<u64>,
+        pub exemplars: Vec<Exemplar>,
+    }
+
+    #[derive(Debug, Clone, PartialEq, Default)]
+    pub struct Exemplar {
+        pub bucket_index: u32,
+        pub value: f64,
+        pub trace_id: Vec<u8>,
+        pub timestamp_ns: u64,
+    }
+
+    #[derive(Debug, Clone, PartialEq, Default)]
+    pub struct Ack {
+        pub ok: bool,
+        pub directives: Option<AgentDirectives>,
+        pub received_at_ns: u64,
+    }
+
+    #[derive(Debug, Clone, PartialEq, Default)]
+    pub struct AgentDirectives {
+        pub push_interval_ms: u64,
+        pub sample_rate: f64,
+        pub paused: bool,
+    }
+
+    #[derive(Debug, Clone, PartialEq, Default)]
+    pub struct SchemaRequest {
+        pub service: String,
+    }
+
+    #[derive(Debug, Clone, PartialEq, Default)]
+    pub struct Schema {
+        pub histograms: BTreeMap<String, HistogramBounds>,
+    }
+
+    #[derive(Debug, Clone, PartialEq, Default)]
+    pub struct HistogramBounds {
+        pub bounds: Vec<f64>,
+    }
+
+    #[derive(Debug, Clone, PartialEq, Default)]
+    pub struct SnapshotRequest {}
+
+    #[derive(Debug, Clone, PartialEq, Default)]
+    pub struct CapabilitiesRequest {}
+
+    #[derive(Debug, Clone, PartialEq, Default)]
+    pub struct Capabilities {
+        pub events: bool,
+        pub exemplars: bool,
+        pub announce: bool,
+        pub delta_timestamps: bool,
+        pub spans: bool,
+        pub series_ids: bool,
+        pub series_hash: bool,
+    }
+
+    pub mod compat {
+        use super::TelemetryBatch;
+        use crate::DecodeError;
+
+        #[derive(Debug, Clone, PartialEq)]
+        pub struct DecodedBatch {
+            pub wire_version: Option<u8>,
+            pub schema_version: u32,
+            pub checksum_valid: Option<bool>,
+            pub batch: TelemetryBatch,
+        }
+
+        #[inline(always)]
+        pub fn decode_any_version(_bytes: &[u8]) -> Result<DecodedBatch, DecodeError> {
+            Err(DecodeError::Empty)
+        }
+
+        #[derive(Debug, Clone, Default)]
+        pub struct SeriesTable;
+
+        impl SeriesTable {
+            #[inline(always)]
+            pub fn new() -> Self {
+                Self
+            }
+
+            #[inline(always)]
+            pub fn resolve(&mut self, _batch: &mut TelemetryBatch) -> usize {
+                0
+            }
+        }
+    }
+
+    pub mod pretty {
+        #[inline(always)]
+        pub fn render(_batch: &super::TelemetryBatch) -> String {
+            String::new()
+        }
+    }
+
+    pub mod validate {
+        pub use crate::validate::{Violation, MAX_TIMESTAMP_AHEAD};
+
+        #[inline(always)]
+        pub fn validate_batch(_batch: &super::TelemetryBatch) -> Vec<Violation> {
+            Vec::new()
+        }
+    }
+
+    pub use crate::series::series_hash;
+}
+
+use telemetry::{Metric, TelemetryBatch};
+
+/// Error returned by `TelemetryBatch::from_bytes`
+#[derive(Debug)]
+pub enum DecodeError {
+    Empty,
+    UnsupportedVersion(u8),
+    /// Carries the prost error in runtime builds; never produced here
+    Protobuf(String),
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "telemetry is compiled out")
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+impl fmt::Display for TelemetryBatch {
+    fn fmt(&self, _f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        Ok(())
+    }
+}
+
+impl fmt::Display for Metric {
+    fn fmt(&self, _f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        Ok(())
+    }
+}
+
+impl TelemetryBatch {
+    #[inline(always)]
+    pub fn to_bytes(&self) -> Vec<u8> {
+        Vec::new()
+    }
+
+    #[inline(always)]
+    pub fn from_bytes(_bytes: &[u8]) -> Result<Self, DecodeError> {
+        Err(DecodeError::Empty)
+    }
+
+    #[inline(always)]
+    pub fn compute_checksum(&self) -> u32 {
+        0
+    }
+
+    #[inline(always)]
+    pub fn verify_checksum(&self) -> Option<bool> {
+        None
+    }
+
+    #[inline(always)]
+    pub fn resolve_timestamps(&mut self) {}
+}
+
+/// Batches read back from a spool file
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct SpoolReplay {
+    pub batches: Vec<TelemetryBatch>,
+    pub corrupt: usize,
+    pub truncated: bool,
+}
+
+impl SpoolReplay {
+    #[inline(always)]
+    pub fn read(_path: &std::path::Path) -> std::io::Result<Self> {
+        Ok(Self::default())
+    }
+
+    #[inline(always)]
+    pub fn parse(_bytes: &[u8]) -> Self {
+        Self::default()
+    }
+}
+
+/// Stub agent; records nothing and never connects
+pub struct Agent {
+    _private: (),
+}
+
+const _: () = assert!(std::mem::size_of::<Agent>() == 0);
+
+impl Agent {
+    #[inline(always)]
+    pub fn new(_config: Config) -> Self {
+        Self { _private: () }
+    }
+
+    #[inline(always)]
+    pub fn new_with_transport(config: Config, _pool: TransportPool) -> Self {
+        Self::new(config)
+    }
+
+    #[inline(always)]
+    pub async fn start(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        Ok(())
+    }
+
+    #[inline(always)]
+    pub async fn start_on(
+        &mut self,
+        _spawner: impl Spawner,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        Ok(())
+    }
+
+    #[cfg(feature = "runtime")]
+    #[inline(always)]
+    pub async fn start_with_channel(
+        &mut self,
+        _channel: tonic::transport::Channel,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        Ok(())
+    }
+
+    #[cfg(feature = "runtime")]
+    #[inline(always)]
+    pub async fn start_with_connector<F, Fut>(
+        &mut self,
+        _connector: F,
+    ) -> Result<(), Box<dyn std::error::Error>>
+    where
+        F: Fn() -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<tonic::transport::Channel, tonic::transport::Error>>
+            + Send
+            + 'static,
+    {
+        Ok(())
+    }
+
+    #[inline(always)]
+    pub fn collect_now(&self) -> TelemetryBatch {
+        TelemetryBatch::default()
+    }
+
+    #[inline(always)]
+    pub fn recycle(&self, _batch: TelemetryBatch) {}
+
+    #[inline(always)]
+    pub fn pull_listener_addr(&self) -> Option<std::net::SocketAddr> {
+        None
+    }
+
+    #[inline(always)]
+    pub fn instance_id(&self) -> &str {
+        ""
+    }
+
+    #[inline(always)]
+    pub fn identity(&self) -> Identity {
+        Identity::default()
+    }
+
+    #[inline(always)]
+    pub fn diagnostics(&self) -> Diagnostics {
+        Diagnostics::default()
+    }
+
+    #[inline(always)]
+    pub fn estimated_clock_skew(&self) -> Option<ClockSkew> {
+        None
+    }
+
+    #[inline(always)]
+    pub fn queue_pressure(&self) -> f32 {
+        0.0
+    }
+
+    #[inline(always)]
+    pub fn memory_usage(&self) -> MemoryUsage {
+        MemoryUsage::default()
+    }
+
+    pub fn compact(&self) -> usize {
+        0
+    }
+
+    /// Nothing is pushed, so the final flush always succeeds
+    #[inline(always)]
+    pub async fn stop(&mut self) -> Result<ShutdownReport, AgentError> {
+        Ok(ShutdownReport {
+            final_flush_delivered: true,
+            ..Default::default()
+        })
+    }
+
+    #[inline(always)]
+    pub async fn shutdown_with_deadline(
+        &mut self,
+        _deadline: Instant,
+    ) -> Result<ShutdownReport, AgentError> {
+        self.stop().await
+    }
+
+    #[inline(always)]
+    pub async fn flush(&self) -> Result<usize, AgentError> {
+        Ok(0)
+    }
+
+    #[inline(always)]
+    pub fn flush_blocking(&self, _timeout: Duration) -> Result<usize, AgentError> {
+        Ok(0)
+    }
+
+    #[inline(always)]
+    pub fn drain(&self) {}
+
+    #[inline(always)]
+    pub fn set_endpoint(&self, _addr: String) -> Result<(), AgentError> {
+        Ok(())
+    }
+
+    #[inline(always)]
+    pub fn set_push_interval(&self, _interval: Duration) -> Result<(), AgentError> {
+        Ok(())
+    }
+
+    #[inline(always)]
+    pub fn burst_mode(&self, _duration: Duration, _interval: Duration) -> Result<(), AgentError> {
+        Ok(())
+    }
+
+    #[inline(always)]
+    pub fn set_announce_field(&self, _key: &str, _value: &str) {}
+
+    #[inline(always)]
+    pub fn install_panic_hook(&self) {}
+
+    #[cfg(feature = "toml")]
+    #[inline(always)]
+    pub fn watch_config(
+        &self,
+        _path: impl Into<std::path::PathBuf>,
+        _poll_interval: Duration,
+    ) -> Result<(), crate::ConfigFileError> {
+        Ok(())
+    }
+
+    #[inline(always)]
+    pub fn emit_event(&self, _name: &str, _severity: Severity, _attributes: &[(&str, &str)]) {}
+
+    #[inline(always)]
+    pub fn set_gauge(&self, _name: &(impl MetricName + ?Sized), _value: f64) {}
+
+    #[inline(always)]
+    pub fn try_set_gauge(
+        &self,
+        _name: &(impl MetricName + ?Sized),
+        _value: f64,
+    ) -> Result<(), MetricTypeConflict> {
+        Ok(())
+    }
+
+    #[inline(always)]
+    pub fn register_gauge(
+        &self,
+        _name: &(impl MetricName + ?Sized),
+        _aggregation: GaugeAggregation,
+    ) {
+    }
+
+    pub fn register_histogram(&self, _name: &(impl MetricName + ?Sized), _policy: ResetPolicy) {}
+
+    #[inline(always)]
+    pub fn register_recordable_histogram(
+        &self,
+        _name: &(impl MetricName + ?Sized),
+        _histogram: Arc<dyn RecordableHistogram>,
+    ) {
+    }
+
+    #[inline(always)]
+    pub fn set_gauge_with(
+        &self,
+        _name: &(impl MetricName + ?Sized),
+        _labels: &[(&str, &str)],
+        _value: f64,
+    ) {
+    }
+
+    #[inline(always)]
+    pub fn try_set_gauge_with(
+        &self,
+        _name: &(impl MetricName + ?Sized),
+        _labels: &[(&str, &str)],
+        _value: f64,
+    ) -> Result<(), MetricTypeConflict> {
+        Ok(())
+    }
+
+    #[inline(always)]
+    pub fn add_gauge(&self, _name: &(impl MetricName + ?Sized), _delta: f64) -> f64 {
+        0.0
+    }
+
+    #[inline(always)]
+    pub fn try_add_gauge(
+        &self,
+        _name: &(impl MetricName + ?Sized),
+        _delta: f64,
+    ) -> Result<f64, MetricTypeConflict> {
+        Ok(0.0)
+    }
+
+    #[inline(always)]
+    pub fn sub_gauge(&self, _name: &(impl MetricName + ?Sized), _delta: f64) -> f64 {
+        0.0
+    }
+
+    #[inline(always)]
+    pub fn try_sub_gauge(
+        &self,
+        _name: &(impl MetricName + ?Sized),
+        _delta: f64,
+    ) -> Result<f64, MetricTypeConflict> {
+        Ok(0.0)
+    }
+
+    #[inline(always)]
+    pub fn add_gauge_with(
+        &self,
+        _name: &(impl MetricName + ?Sized),
+        _labels: &[(&str, &str)],
+        _delta: f64,
+    ) -> f64 {
+        0.0
+    }
+
+    #[inline(always)]
+    pub fn try_add_gauge_with(
+        &self,
+        _name: &(impl MetricName + ?Sized),
+        _labels: &[(&str, &str)],
+        _delta: f64,
+    ) -> Result<f64, MetricTypeConflict> {
+        Ok(0.0)
+    }
+
+    #[inline(always)]
+    pub fn sub_gauge_with(
+        &self,
+        _name: &(impl MetricName + ?Sized),
+        _labels: &[(&str, &str)],
+        _delta: f64,
+    ) -> f64 {
+        0.0
+    }
+
+    #[inline(always)]
+    pub fn try_sub_gauge_with(
+        &self,
+        _name: &(impl MetricName + ?Sized),
+        _labels: &[(&str, &str)],
+        _delta: f64,
+    ) -> Result<f64, MetricTypeConflict> {
+        Ok(0.0)
+    }
+
+    #[inline(always)]
+    pub fn inc_counter(&self, _name: &(impl MetricName + ?Sized)) {}
+
+    #[inline(always)]
+    pub fn try_inc_counter(
+        &self,
+        _name: &(impl MetricName + ?Sized),
+    ) -> Result<(), MetricTypeConflict> {
+        Ok(())
+    }
+
+    #[inline(always)]
+    pub fn add_counter(&self, _name: &(impl MetricName + ?Sized), _n: u64) {}
+
+    #[inline(always)]
+    pub fn try_add_counter(
+        &self,
+        _name: &(impl MetricName + ?Sized),
+        _n: u64,
+    ) -> Result<(), MetricTypeConflict> {
+        Ok(())
+    }
+
+    #[inline(always)]
+    pub fn set_monotonic_total(&self, _name: &(impl MetricName + ?Sized), _value: u64) {}
+
+    #[inline(always)]
+    pub fn try_set_monotonic_total(
+        &self,
+        _name: &(impl MetricName + ?Sized),
+        _value: u64,
+    ) -> Result<(), MetricTypeConflict> {
+        Ok(())
+    }
+
+    #[inline(always)]
+    pub fn inc_counter_with(&self, _name: &(impl MetricName + ?Sized), _labels: &[(&str, &str)]) {}
+
+    #[inline(always)]
+    pub fn try_inc_counter_with(
+        &self,
+        _name: &(impl MetricName + ?Sized),
+        _labels: &[(&str, &str)],
+    ) -> Result<(), MetricTypeConflict> {
+        Ok(())
+    }
+
+    #[inline(always)]
+    pub fn metric_type(&self, _name: &(impl MetricName + ?Sized)) -> Option<MetricType> {
+        None
+    }
+
+    #[inline(always)]
+    pub fn set_metric_enabled(&self, _name: &(impl MetricName + ?Sized), _enabled: bool) {}
+
+    #[inline(always)]
+    pub fn set_prefix_enabled(&self, _prefix: &str, _enabled: bool) {}
+
+    #[inline(always)]
+    pub fn assign_group(&self, _name: &(impl MetricName + ?Sized), _group: &str) {}
+
+    #[inline(always)]
+    pub fn sharded_counter(&self, _name: &(impl MetricName + ?Sized)) -> ShardedCounterHandle {
+        ShardedCounterHandle { _private: () }
+    }
+
+    #[inline(always)]
+    pub fn bulk_counter(
+        &self,
+        _name: &(impl MetricName + ?Sized),
+        _label: &str,
+        len: usize,
+        _mode: BulkCollect,
+    ) -> BulkCounterHandle {
+        BulkCounterHandle { len }
+    }
+
+    #[inline(always)]
+    pub fn counter_family(
+        &self,
+        _name: &(impl MetricName + ?Sized),
+        _label_names: &[&str],
+    ) -> Result<CounterFamily, LabelSchemaMismatch> {
+        Ok(Family::default())
+    }
+
+    #[inline(always)]
+    pub fn gauge_family(
+        &self,
+        _name: &(impl MetricName + ?Sized),
+        _label_names: &[&str],
+    ) -> Result<GaugeFamily, LabelSchemaMismatch> {
+        Ok(Family::default())
+    }
+
+    #[inline(always)]
+    pub fn histogram_family(
+        &self,
+        _name: &(impl MetricName + ?Sized),
+        _label_names: &[&str],
+    ) -> Result<HistogramFamily, LabelSchemaMismatch> {
+        Ok(Family::default())
+    }
+
+    #[inline(always)]
+    pub fn record_histogram(&self, _name: &(impl MetricName + ?Sized), _value: f64) {}
+
+    #[inline(always)]
+    pub fn try_record_histogram(
+        &self,
+        _name: &(impl MetricName + ?Sized),
+        _value: f64,
+    ) -> Result<(), MetricTypeConflict> {
+        Ok(())
+    }
+
+    #[inline(always)]
+    pub fn record_histogram_with(
+        &self,
+        _name: &(impl MetricName + ?Sized),
+        _labels: &[(&str, &str)],
+        _value: f64,
+    ) {
+    }
+
+    #[inline(always)]
+    pub fn try_record_histogram_with(
+        &self,
+        _name: &(impl MetricName + ?Sized),
+        _labels: &[(&str, &str)],
+        _value: f64,
+    ) -> Result<(), MetricTypeConflict> {
+        Ok(())
+    }
+
+    #[inline(always)]
+    pub fn local(&self) -> LocalRecorder<'_> {
+        LocalRecorder {
+            _agent: PhantomData,
+        }
+    }
+
+    #[inline(always)]
+    pub fn histogram_ms(
+        &self,
+        _name: &(impl MetricName + ?Sized),
+    ) -> Result<HistogramMs, UnitMismatch> {
+        Ok(HistogramMs { _private: () })
+    }
+
+    #[inline(always)]
+    pub fn histogram_bytes(
+        &self,
+        _name: &(impl MetricName + ?Sized),
+    ) -> Result<HistogramBytes, UnitMismatch> {
+        Ok(HistogramBytes { _private: () })
+    }
+
+    #[inline(always)]
+    pub fn slo(&self, _name: &str, _spec: SloSpec) -> SloHandle {
+        SloHandle { _private: () }
+    }
+
+    #[inline(always)]
+    pub fn cache(&self, _name: &str) -> CacheHandle {
+        CacheHandle { _private: () }
+    }
+
+    #[inline(always)]
+    pub fn configure_wire_bounds(
+        &self,
+        _name: &(impl MetricName + ?Sized),
+        _fine: BucketSpec,
+        _wire: BucketSpec,
+    ) -> Result<(), MisalignedWireBounds> {
+        Ok(())
+    }
+
+    #[inline(always)]
+    pub fn emit_quantiles(&self, _name: &(impl MetricName + ?Sized), _quantiles: &[f64]) {}
+
+    #[inline(always)]
+    pub fn register_manifest(&self, _manifest: MetricManifest) -> Result<(), ManifestConflict> {
+        Ok(())
+    }
+
+    #[inline(always)]
+    pub fn set_state(
+        &self,
+        _name: &(impl MetricName + ?Sized),
+        _state: &str,
+        _allowed: &[&str],
+    ) -> Result<(), InvalidState> {
+        Ok(())
+    }
+
+    #[inline(always)]
+    pub fn track_request(&self) -> RequestGuard {
+        RequestGuard::default()
+    }
+
+    #[inline(always)]
+    pub fn track_request_named(&self, _name: &(impl MetricName + ?Sized)) -> RequestGuard {
+        RequestGuard::default()
+    }
+
+    #[inline(always)]
+    pub fn track_request_with(
+        &self,
+        _name: &(impl MetricName + ?Sized),
+        _labels: &[(&str, &str)],
+    ) -> RequestGuard {
+        RequestGuard::default()
+    }
+
+    #[inline(always)]
+    pub fn configure_latency(&self, _name: &(impl MetricName + ?Sized), _spec: BucketSpec) {}
+
+    #[inline(always)]
+    pub fn start_timer(&self, _name: &(impl MetricName + ?Sized)) -> RequestGuard {
+        RequestGuard::default()
+    }
+
+    #[inline(always)]
+    pub fn start_timer_with(
+        &self,
+        _name: &(impl MetricName + ?Sized),
+        _labels: &[(&str, &str)],
+    ) -> RequestGuard {
+        RequestGuard::default()
+    }
+
+    #[inline(always)]
+    pub fn inflight(&self) -> i64 {
+        0
+    }
+
+    #[inline(always)]
+    pub fn start_span(&self, _name: &str) -> SpanGuard {
+        SpanGuard { _private: () }
+    }
+
+    #[inline(always)]
+    pub fn local_stats(&self) -> LocalStats {
+        LocalStats::default()
+    }
+
+    #[inline(always)]
+    pub fn record_error(&self, _error_type: &str) {}
+
+    #[inline(always)]
+    pub fn error_types(&self) -> Vec<String> {
+        Vec::new()
+    }
+
+    #[inline(always)]
+    pub fn record_error_detailed(&self, _error_type: &str, _message: &str) {}
+
+    #[inline(always)]
+    pub fn last_errors(&self) -> Vec<ErrorInfo> {
+        Vec::new()
+    }
+
+    #[inline(always)]
+    pub fn drop_report(&self) -> Vec<DropStats> {
+        Vec::new()
+    }
+
+    #[inline(always)]
+    pub fn tap_histogram(
+        &self,
+        _name: &(impl MetricName + ?Sized),
+        _capacity: usize,
+    ) -> HistogramTap {
+        HistogramTap { _private: () }
+    }
+
+    #[inline(always)]
+    pub fn into_state(self) -> AgentState {
+        AgentState::default()
+    }
+
+    #[inline(always)]
+    pub fn with_state(config: Config, _state: AgentState) -> Self {
+        Self::new(config)
+    }
+}
+
+pub type BoxFuture = Pin<Box<dyn Future<Output = ()> + Send + 'static>>;
+
+/// Same trait as the runtime build; never called
+pub trait Spawner: Send + Sync + 'static {
+    fn spawn(&self, future: BoxFuture);
+
+    fn sleep(&self, duration: Duration) -> BoxFuture;
+}
+
+/// Stub spawner
+#[derive(Clone)]
+pub struct TokioSpawner {
+    _private: (),
+}
+
+impl TokioSpawner {
+    #[inline(always)]
+    pub fn current() -> Self {
+        Self { _private: () }
+    }
+
+    #[cfg(feature = "runtime")]
+    #[inline(always)]
+    pub fn new(_handle: tokio::runtime::Handle) -> Self {
+        Self::current()
+    }
+}
+
+impl Spawner for TokioSpawner {
+    #[inline(always)]
+    fn spawn(&self, _future: BoxFuture) {}
+
+    #[inline(always)]
+    fn sleep(&self, _duration: Duration) -> BoxFuture {
+        Box::pin(async {})
+    }
+}
+
+/// Stub transport pool; never connects
+#[derive(Clone)]
+pub struct TransportPool {
+    _private: (),
+}
+
+impl TransportPool {
+    #[inline(always)]
+    pub fn new(_addr: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        Ok(Self { _private: () })
+    }
+
+    #[inline(always)]
+    pub fn with_config(
+        addr: &str,
+        _config: PoolConfig,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        Self::new(addr)
+    }
+
+    #[inline(always)]
+    pub async fn shutdown(&self) {}
+
+    #[inline(always)]
+    pub fn diagnostics(&self) -> PoolDiagnostics {
+        PoolDiagnostics::default()
+    }
+}
+
+/// Runs `job`; nothing is pushed, so delivery always succeeds
+#[inline(always)]
+pub async fn run_scoped<F, Fut>(config: Config, job: F) -> Result<JobReport, AgentError>
+where
+    F: FnOnce(Arc<Agent>) -> Fut,
+    Fut: Future<Output = ()>,
+{
+    job(Arc::new(Agent::new(config))).await;
+    Ok(JobReport {
+        delivered: true,
+        ..Default::default()
+    })
+}
+
+/// Stub guard; dropping it flushes nothing
+pub struct ShutdownGuard {
+    _private: (),
+}
+
+#[inline(always)]
+pub fn shutdown_guard(_agent: Arc<Agent>) -> ShutdownGuard {
+    ShutdownGuard { _private: () }
+}
+
+/// Stub thread-local recorder
+pub struct LocalRecorder<'a> {
+    _agent: PhantomData<&'a Agent>,
+}
+
+impl LocalRecorder<'_> {
+    #[inline(always)]
+    pub fn flush_every(self, _records: usize) -> Self {
+        self
+    }
+
+    #[inline(always)]
+    pub fn record_histogram(&mut self, _name: &str, _value: f64) {}
+
+    #[inline(always)]
+    pub fn record_histogram_with(&mut self, _name: &str, _labels: &[(&str, &str)], _value: f64) {}
+
+    #[inline(always)]
+    pub fn inc_counter(&mut self, _name: &str) {}
+
+    #[inline(always)]
+    pub fn add_counter(&mut self, _name: &str, _n: u64) {}
+
+    #[inline(always)]
+    pub fn inc_counter_with(&mut self, _name: &str, _labels: &[(&str, &str)]) {}
+
+    #[inline(always)]
+    pub fn flush(&mut self) {}
+}
+
+/// Stub request guard
+#[derive(Default)]
+pub struct RequestGuard {
+    outcome: Outcome,
+}
+
+impl RequestGuard {
+    #[inline(always)]
+    pub fn fail(&mut self) {
+        self.outcome = Outcome::Error;
+    }
+
+    #[inline(always)]
+    pub fn set_outcome(&mut self, outcome: Outcome) {
+        self.outcome = outcome;
+    }
+
+    #[inline(always)]
+    pub fn outcome(&self) -> Outcome {
+        self.outcome
+    }
+
+    /// Always zero; the stub keeps no clock
+    #[inline(always)]
+    pub fn elapsed(&self) -> Duration {
+        Duration::ZERO
+    }
+
+    #[inline(always)]
+    pub fn mark(&self, _label: &str) {}
+
+    #[inline(always)]
+    pub fn child(&self) -> RequestChildGuard {
+        RequestChildGuard { _private: () }
+    }
+
+    #[inline(always)]
+    pub fn children(&self) -> usize {
+        0
+    }
+}
+
+/// Stub child guard
+pub struct RequestChildGuard {
+    _private: (),
+}
+
+/// Stub span guard; never sampled
+#[must_use = "the span ends when the guard is dropped"]
+pub struct SpanGuard {
+    _private: (),
+}
+
+impl SpanGuard {
+    #[inline(always)]
+    pub fn child(&self, _name: &str) -> SpanGuard {
+        SpanGuard { _private: () }
+    }
+
+    #[inline(always)]
+    pub fn scope<F: Future>(&self, future: F) -> impl Future<Output = F::Output> {
+        future
+    }
+
+    #[inline(always)]
+    pub fn in_scope<R>(&self, f: impl FnOnce() -> R) -> R {
+        f()
+    }
+
+    #[inline(always)]
+    pub fn fail(&mut self) {}
+
+    #[inline(always)]
+    pub fn set_outcome(&mut self, _outcome: Outcome) {}
+
+    #[inline(always)]
+    pub fn set_attribute(&mut self, _key: &str, _value: &str) {}
+
+    #[inline(always)]
+    pub fn is_sampled(&self) -> bool {
+        false
+    }
+
+    #[inline(always)]
+    pub fn trace_id(&self) -> Option<u128> {
+        None
+    }
+
+    #[inline(always)]
+    pub fn span_id(&self) -> Option<u64> {
+        None
+    }
+}
+
+pub struct Family<H> {
+    _handle: PhantomData<H>,
+}
+
+pub type CounterFamily = Family<CounterHandle>;
+pub type GaugeFamily = Family<GaugeHandle>;
+pub type HistogramFamily = Family<HistogramHandle>;
+
+impl<H> Default for Family<H> {
+    fn default() -> Self {
+        Self {
+            _handle: PhantomData,
+        }
+    }
+}
+
+impl<H> Clone for Family<H> {
+    fn clone(&self) -> Self {
+        Self::default()
+    }
+}
+
+impl<H: Default> Family<H> {
+    #[inline(always)]
+    pub fn with(&self, _values: &[&str]) -> H {
+        H::default()
+    }
+
+    #[inline(always)]
+    pub fn label_names(&self) -> &[String] {
+        &[]
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LabelSchemaMismatch {
+    pub name: String,
+    pub registered: Vec<String>,
+    pub requested: Vec<String>,
+}
+
+impl fmt::Display for LabelSchemaMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "family {:?} has a conflicting label schema", self.name)
+    }
+}
+
+impl std::error::Error for LabelSchemaMismatch {}
+
+#[derive(Clone, Default)]
+pub struct CounterHandle {
+    _private: (),
+}
+
+impl CounterHandle {
+    #[inline(always)]
+    pub fn inc(&self) {}
+
+    #[inline(always)]
+    pub fn add(&self, _n: u64) {}
+
+    #[inline(always)]
+    pub fn get(&self) -> u64 {
+        0
+    }
+}
+
+#[derive(Clone, Default)]
+pub struct GaugeHandle {
+    _private: (),
+}
+
+impl GaugeHandle {
+    #[inline(always)]
+    pub fn set(&self, _value: f64) {}
+
+    #[inline(always)]
+    pub fn add(&self, _delta: f64) -> f64 {
+        0.0
+    }
+
+    #[inline(always)]
+    pub fn sub(&self, _delta: f64) -> f64 {
+        0.0
+    }
+}
+
+#[derive(Clone, Default)]
+pub struct HistogramHandle {
+    _private: (),
+}
+
+impl HistogramHandle {
+    #[inline(always)]
+    pub fn record(&self, _value: f64) {}
+}
+
+#[derive(Clone)]
+pub struct HistogramMs {
+    _private: (),
+}
+
+impl HistogramMs {
+    #[inline(always)]
+    pub fn record(&self, _duration: Duration) {}
+}
+
+#[derive(Clone)]
+pub struct HistogramBytes {
+    _private: (),
+}
+
+impl HistogramBytes {
+    #[inline(always)]
+    pub fn record<B: ByteCount>(&self, _bytes: B) {}
+}
+
+#[derive(Clone)]
+pub struct CacheHandle {
+    _private: (),
+}
+
+impl CacheHandle {
+    #[inline(always)]
+    pub fn hit(&self) {}
+
+    #[inline(always)]
+    pub fn miss(&self) {}
+
+    #[inline(always)]
+    pub fn eviction(&self) {}
+
+    #[inline(always)]
+    pub fn size(&self, _entries: u64) {}
+}
+
+#[derive(Clone)]
+pub struct SloHandle {
+    _private: (),
+}
+
+impl SloHandle {
+    #[inline(always)]
+    pub fn record_success(&self) {}
+
+    #[inline(always)]
+    pub fn record_failure(&self) {}
+
+    #[inline(always)]
+    pub fn record_latency(&self, _ms: f64) {}
+
+    #[inline(always)]
+    pub fn current_error_budget_remaining(&self) -> f64 {
+        1.0
+    }
+
+    #[inline(always)]
+    pub fn burn_rate(&self) -> f64 {
+        0.0
+    }
+}
+
+pub struct HistogramTap {
+    _private: (),
+}
+
+impl HistogramTap {
+    #[inline(always)]
+    pub fn drain(&self) -> Vec<(u64, f64)> {
+        Vec::new()
+    }
+
+    #[inline(always)]
+    pub fn dropped(&self) -> u64 {
+        0
+    }
+}
+
+#[derive(Clone)]
+pub struct BulkCounterHandle {
+    len: usize,
+}
+
+impl BulkCounterHandle {
+    #[inline(always)]
+    pub fn inc(&self, _idx: usize) {}
+
+    #[inline(always)]
+    pub fn add(&self, _idx: usize, _n: u64) {}
+
+    #[inline(always)]
+    pub fn get(&self, _idx: usize) -> u64 {
+        0
+    }
+
+    #[inline(always)]
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    #[inline(always)]
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+#[derive(Clone)]
+pub struct ShardedCounterHandle {
+    _private: (),
+}
+
+impl ShardedCounterHandle {
+    #[inline(always)]
+    pub fn inc(&self) {}
+
+    #[inline(always)]
+    pub fn add(&self, _n: u64) {}
+
+    #[inline(always)]
+    pub fn get(&self) -> u64 {
+        0
+    }
+}
@@ -0,0 +1,162 @@
+//! Detection of a push interval too short for the push loop's work
+//!
+//! Every collect-and-push cycle is timed against the interval in effect.
+//! Once `SLOW_CYCLES` in a row overrun it, one warning lists their
+//! durations; with `Config::auto_relax_interval` the interval is also
+//! stretched to twice the slowest of them. Without it the warning isn't
+//! repeated until a cycle fits again. The interval is never stretched
+//! below the configured (or remotely set) one, and never shrunk.
+
+use std::time::Duration;
+
+/// Consecutive overrunning cycles before acting
+pub(crate) const SLOW_CYCLES: usize = 3;
+
+/// Tracks overrunning cycles for the push loop
+pub(crate) struct Pacer {
+    relax: bool,
+    /// The current run of overrunning cycles
+    slow: Vec<Duration>,
+    /// Set once this run has been warned about, without relaxing
+    warned: bool,
+    relaxed: Option<Duration>,
+}
+
+impl Pacer {
+    pub(crate) fn new(relax: bool) -> Self {
+        Self {
+            relax,
+            slow: Vec::new(),
+            warned: false,
+            relaxed: None,
+        }
+    }
+
+    /// The interval to tick at, given the configured or remotely set one
+    pub(crate) fn interval(&self, base: Duration) -> Duration {
+        self.relaxed.map_or(base, |relaxed| relaxed.max(base))
+    }
+
+    /// Time one cycle of collecting and pushing a batch
+    pub(crate) fn on_cycle(&mut self, elapsed: Duration, base: Duration) {
+        let interval = self.interval(base);
+        if elapsed <= interval {
+            self.slow.clear();
+            self.warned = false;
+            return;
+        }
+        if self.warned {
+            return;
+        }
+        self.slow.push(elapsed);
+        if self.slow.len() < SLOW_CYCLES {
+            return;
+        }
+
+        let cycles_ms: Vec<u64> = self.slow.iter().map(|d| d.as_millis() as u64).collect();
+        let slowest = self.slow.iter().max().copied().unwrap_or_default();
+        self.slow.clear();
+        if !self.relax {
+            tracing::warn!(
+                interval_ms = interval.as_millis() as u64,
+                ?cycles_ms,
+                "push cycles overrun push_interval; lengthen it or set auto_relax_interval"
+            );
+            self.warned = true;
+            return;
+        }
+        let relaxed = (slowest * 2).max(interval);
+        tracing::warn!(
+            interval_ms = interval.as_millis() as u64,
+            relaxed_ms = relaxed.as_millis() as u64,
+            ?cycles_ms,
+            "push cycles overrun push_interval, relaxing it"
+        );
+        self.relaxed = Some(relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use std::sync::{Arc, Mutex};
+
+    #[derive(Clone, Default)]
+    struct Capture(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Capture {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// Warnings logged while running `f`
+    fn warnings(f: impl FnOnce()) -> Vec<String> {
+        let capture = Capture::default();
+        let writer = capture.clone();
+        let subscriber = tracing_subscriber::fmt()
+            .with_writer(move || writer.clone())
+            .with_ansi(false)
+            .finish();
+        tracing::subscriber::with_default(subscriber, f);
+        let output = String::from_utf8(capture.0.lock().unwrap().clone()).unwrap();
+        output.lines().map(str::to_string).collect()
+    }
+
+    const MS: Duration = Duration::from_millis(1);
+
+    #[test]
+    fn test_warns_once_per_slow_run() {
+        let lines = warnings(|| {
+            let mut pacer = Pacer::new(false);
+            for _ in 0..10 {
+                pacer.on_cycle(8 * MS, 5 * MS);
+            }
+            assert_eq!(pacer.interval(5 * MS), 5 * MS);
+            // A cycle that fits ends the run; the next run warns again
+            pacer.on_cycle(4 * MS, 5 * MS);
+            for _ in 0..SLOW_CYCLES {
+                pacer.on_cycle(9 * MS, 5 * MS);
+            }
+        });
+        assert_eq!(lines.len(), 2, "{:?}", lines);
+        assert!(lines[0].contains("interval_ms=5"));
+        assert!(lines[0].contains("cycles_ms=[8, 8, 8]"));
+    }
+
+    #[test]
+    fn test_relaxes_to_twice_the_slowest_cycle() {
+        let lines = warnings(|| {
+            let mut pacer = Pacer::new(true);
+            pacer.on_cycle(7 * MS, 5 * MS);
+            pacer.on_cycle(4 * MS, 5 * MS);
+            pacer.on_cycle(7 * MS, 5 * MS);
+            pacer.on_cycle(9 * MS, 5 * MS);
+            assert_eq!(pacer.interval(5 * MS), 5 * MS);
+            pacer.on_cycle(8 * MS, 5 * MS);
+            assert_eq!(pacer.interval(5 * MS), 18 * MS);
+
+            // Cycles within the stretched interval change nothing
+            for _ in 0..10 {
+                pacer.on_cycle(9 * MS, 5 * MS);
+            }
+            // A longer configured interval wins; a shorter one never does
+            assert_eq!(pacer.interval(30 * MS), 30 * MS);
+            assert_eq!(pacer.interval(MS), 18 * MS);
+
+            for _ in 0..SLOW_CYCLES {
+                pacer.on_cycle(20 * MS, 5 * MS);
+            }
+            assert_eq!(pacer.interval(5 * MS), 40 * MS);
+        });
+        assert_eq!(lines.len(), 2, "{:?}", lines);
+        assert!(lines[0].contains("relaxed_ms=18"));
+        assert!(lines[1].contains("relaxed_ms=40"));
+    }
+}
@@ -0,0 +1,111 @@
+//! Counting panics (`Agent::install_panic_hook`)
+//!
+//! The hook is installed once per process, on top of whatever hook was
+//! there before, which still runs after it. Each panic counts in
+//! `panics_total`, labelled with the thread's name if it has one, and its
+//! message is kept as the latest `panic` error. A panic on the main
+//! thread, which usually ends the process, also wakes the push loop and
+//! waits up to `FLUSH_WAIT` for a push to land; a push loop on a
+//! current-thread runtime driven by that same thread can't push then.
+
+use std::panic::{self, PanicHookInfo};
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Once};
+use std::time::{Duration, Instant};
+
+use parking_lot::{const_mutex, Mutex};
+use tokio::sync::Notify;
+
+use crate::agent::{add_counter_in, CounterRegistry};
+use crate::diagnostics::PushStats;
+use crate::error_log::ErrorLog;
+use crate::{series, Agent};
+
+/// Longest a panic on the main thread waits for the push it asked for
+const FLUSH_WAIT: Duration = Duration::from_millis(100);
+
+static INSTALL: Once = Once::new();
+/// The agent the hook reports to: the latest to install it
+static SINK: Mutex<Option<Arc<PanicSink>>> = const_mutex(None);
+
+impl Agent {
+    /// Count panics in `panics_total{thread}` and keep the latest panic
+    /// message in `last_errors` under `panic`, then run the panic hook
+    /// that was installed before. Installing again, from this agent or
+    /// another, doesn't stack hooks: panics count for the agent that
+    /// installed last.
+    pub fn install_panic_hook(&self) {
+        let sink = PanicSink {
+            counters: self.counters.clone(),
+            errors: self.errors.clone(),
+            stats: self.stats.clone(),
+            flush_requests: self.flush_requests.clone(),
+            message_limit: self.config.error_message_limit,
+            sample_cap: match self.config.report_error_samples {
+                true => self.config.error_samples_per_interval,
+                false => 0,
+            },
+        };
+        *SINK.lock() = Some(Arc::new(sink));
+        INSTALL.call_once(|| {
+            let previous = panic::take_hook();
+            panic::set_hook(Box::new(move |info| {
+                // Not held while reporting, in case reporting panics
+                let sink = SINK.lock().clone();
+                if let Some(sink) = sink {
+                    sink.on_panic(info);
+                }
+                previous(info);
+            }));
+        });
+    }
+}
+
+struct PanicSink {
+    counters: CounterRegistry,
+    errors: Arc<ErrorLog>,
+    stats: Arc<PushStats>,
+    flush_requests: Arc<Notify>,
+    message_limit: usize,
+    sample_cap: usize,
+}
+
+impl PanicSink {
+    fn on_panic(&self, info: &PanicHookInfo<'_>) {
+        let thread = std::thread::current();
+        let key = match thread.name() {
+            Some(name) => series::encode("panics_total", &[("thread", name)]),
+            None => "panics_total".to_string(),
+        };
+        add_counter_in(&self.counters, &key, 1);
+        self.errors
+            .record("panic", &message(info), self.message_limit, self.sample_cap);
+        if thread.name() == Some("main") {
+            self.flush();
+        }
+    }
+
+    /// Wake the push loop and wait a little for a push to succeed
+    fn flush(&self) {
+        let sent = self.stats.batches_sent.load(Ordering::Relaxed);
+        self.flush_requests.notify_one();
+        let deadline = Instant::now() + FLUSH_WAIT;
+        while self.stats.batches_sent.load(Ordering::Relaxed) == sent && Instant::now() < deadline {
+            std::thread::sleep(Duration::from_millis(1));
+        }
+    }
+}
+
+/// The panic's message and where it happened
+fn message(info: &PanicHookInfo<'_>) -> String {
+    let payload = info.payload();
+    let text = payload
+        .downcast_ref::<&str>()
+        .copied()
+        .or_else(|| payload.downcast_ref::<String>().map(String::as_str))
+        .unwrap_or("Box<dyn Any>");
+    match info.location() {
+        Some(location) => format!("{} at {}", text, location),
+        None => text.to_string(),
+    }
+}
@@ -0,0 +1,338 @@
+//! One connection and one push loop shared by many agents
+//!
+//! Every tick the pool collects each registered agent and sends all their
+//! batches, each with its own service and instance, over a single stream.
+//! Collection and sending are split exactly as in a standalone agent.
+
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use parking_lot::Mutex;
+use prost::Message;
+use tokio::sync::mpsc;
+use tokio::time::{interval, timeout};
+use tonic::transport::Channel;
+
+use crate::agent::{
+    finish_batch, record_directive_gauges, record_ms, record_queue_drop, report_push_error,
+    requeue_events, PushContext, PushTasks,
+};
+use crate::diagnostics::PushStats;
+use crate::failure_log::FailureLog;
+use crate::quarantine;
+use crate::runtime::{Spawner, Task, TokioSpawner};
+use crate::telemetry::telemetry_ingestor_client::TelemetryIngestorClient;
+use crate::telemetry::TelemetryBatch;
+use crate::{name, PoolAgentDiagnostics, PoolConfig, PoolDiagnostics, PushErrorKind};
+
+/// Ticks queued for the sender; further ticks are dropped while it is full
+const SEND_QUEUE: usize = 4;
+
+/// Shared transport for agents created with `Agent::new_with_transport`;
+/// clones share the same connection
+#[derive(Clone)]
+pub struct TransportPool {
+    inner: Arc<PoolInner>,
+}
+
+struct PoolInner {
+    config: PoolConfig,
+    members: Mutex<BTreeMap<u64, Arc<PushContext>>>,
+    next_id: AtomicU64,
+    stats: PushStats,
+    shutdown_tx: Mutex<Option<mpsc::Sender<()>>>,
+    tasks: Mutex<Option<PushTasks>>,
+}
+
+/// One agent's batch within a pooled push
+struct Outgoing {
+    member: Arc<PushContext>,
+    batch: TelemetryBatch,
+    collected_at: Instant,
+}
+
+/// Registration held by a pooled agent; dropping it unregisters the agent
+pub(crate) struct PoolMembership {
+    pool: TransportPool,
+    id: u64,
+}
+
+impl Drop for PoolMembership {
+    fn drop(&mut self) {
+        self.pool.inner.members.lock().remove(&self.id);
+    }
+}
+
+impl TransportPool {
+    /// Create a pool pushing to `addr` with default settings. Connects
+    /// lazily; must be called within a Tokio runtime.
+    pub fn new(addr: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        Self::with_config(addr, PoolConfig::default())
+    }
+
+    pub fn with_config(addr: &str, config: PoolConfig) -> Result<Self, Box<dyn std::error::Error>> {
+        let channel = Channel::from_shared(addr.to_string())?.connect_lazy();
+        let client = TelemetryIngestorClient::new(channel);
+        let (shutdown_tx, shutdown_rx) = mpsc::channel(1);
+        let (batch_tx, batch_rx) = mpsc::channel(SEND_QUEUE);
+
+        let inner = Arc::new(PoolInner {
+            config,
+            members: Mutex::new(BTreeMap::new()),
+            next_id: AtomicU64::new(0),
+            stats: PushStats::default(),
+            shutdown_tx: Mutex::new(Some(shutdown_tx)),
+            tasks: Mutex::new(None),
+        });
+        let spawner: Arc<dyn Spawner> = Arc::new(TokioSpawner::current());
+        let collector = Task::spawn(
+            &*spawner,
+            quarantine::scope(run_pool_collector(inner.clone(), batch_tx, shutdown_rx)),
+        );
+        let sender = Task::spawn(
+            &*spawner,
+            quarantine::scope(run_pool_sender(inner.clone(), client, batch_rx)),
+        );
+        *inner.tasks.lock() = Some(PushTasks {
+            collector,
+            sender,
+            spawner,
+        });
+
+        Ok(Self { inner })
+    }
+
+    pub(crate) fn register(&self, member: PushContext) -> PoolMembership {
+        let id = self.inner.next_id.fetch_add(1, Ordering::Relaxed);
+        self.inner.members.lock().insert(id, Arc::new(member));
+        PoolMembership {
+            pool: self.clone(),
+            id,
+        }
+    }
+
+    /// Push a final batch for every registered agent, waiting up to
+    /// `flush_timeout`, and stop the pool's tasks
+    pub async fn shutdown(&self) {
+        let shutdown_tx = self.inner.shutdown_tx.lock().take();
+        if let Some(tx) = shutdown_tx {
+            let _ = tx.send(()).await;
+        }
+        let tasks = self.inner.tasks.lock().take();
+        if let Some(tasks) = tasks {
+            tasks.finish(self.inner.config.flush_timeout).await;
+        }
+    }
+
+    /// Totals across all agents plus per-agent push counts
+    pub fn diagnostics(&self) -> PoolDiagnostics {
+        let agents = self
+            .inner
+            .members
+            .lock()
+            .values()
+            .map(|member| PoolAgentDiagnostics {
+                service: member.config.service_name.clone(),
+                instance: member.config.instance_id.clone(),
+                batches_sent: member.stats.batches_sent.load(Ordering::Relaxed),
+                batches_dropped: member.stats.batches_dropped.load(Ordering::Relaxed),
+            })
+            .collect();
+        let stats = &self.inner.stats;
+        PoolDiagnostics {
+            batches_sent: stats.batches_sent.load(Ordering::Relaxed),
+            bytes_sent: stats.bytes_sent.load(Ordering::Relaxed),
+            batches_dropped: stats.batches_dropped.load(Ordering::Relaxed),
+            agents,
+        }
+    }
+}
+
+/// One batch per registered agent with something to send
+fn collect_members(inner: &PoolInner) -> Vec<Outgoing> {
+    // Collect outside the members lock so registration never waits on it
+    let members: Vec<Arc<PushContext>> = inner.members.lock().values().cloned().collect();
+    members
+        .into_iter()
+        .filter_map(|member| {
+            let batch = member.next_batch()?;
+            Some(Outgoing {
+                member,
+                batch,
+                collected_at: Instant::now(),
+            })
+        })
+        .collect()
+}
+
+/// Count batches that were not delivered; their events wait for the next
+/// push
+fn drop_all(inner: &PoolInner, outgoing: &[Outgoing]) {
+    for o in outgoing {
+        o.member.stats.dropped();
+        inner.stats.dropped();
+        requeue_events(&o.member.registries, o.batch.events.clone());
+    }
+}
+
+/// `drop_all` for batches that found the send queue full
+fn drop_queued(inner: &PoolInner, outgoing: &[Outgoing]) {
+    for o in outgoing {
+        record_queue_drop(&o.member.registries, &o.batch);
+    }
+    drop_all(inner, outgoing);
+}
+
+async fn run_pool_collector(
+    inner: Arc<PoolInner>,
+    batch_tx: mpsc::Sender<Vec<Outgoing>>,
+    mut shutdown_rx: mpsc::Receiver<()>,
+) {
+    let mut ticker = interval(inner.config.push_interval);
+
+    loop {
+        tokio::select! {
+            _ = ticker.tick() => {
+                let outgoing = collect_members(&inner);
+                if outgoing.is_empty() {
+                    continue;
+                }
+                match batch_tx.try_send(outgoing) {
+                    Ok(()) => {}
+                    Err(mpsc::error::TrySendError::Full(outgoing)) => drop_queued(&inner, &outgoing),
+                    Err(mpsc::error::TrySendError::Closed(_)) => break,
+                }
+            }
+            _ = shutdown_rx.recv() => {
+                // Final flush; never wait here, `finish` bounds the sender
+                let outgoing = collect_members(&inner);
+                if !outgoing.is_empty() {
+                    if let Err(mpsc::error::TrySendError::Full(outgoing)) = batch_tx.try_send(outgoing) {
+                        drop_queued(&inner, &outgoing);
+                    }
+                }
+                break;
+            }
+        }
+    }
+}
+
+async fn run_pool_sender(
+    inner: Arc<PoolInner>,
+    mut client: TelemetryIngestorClient<Channel>,
+    mut batch_rx: mpsc::Receiver<Vec<Outgoing>>,
+) {
+    let mut failures = FailureLog::new(inner.config.error_log_interval);
+
+    while let Some(outgoing) = batch_rx.recv().await {
+        let sent_at_ns = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_nanos() as u64;
+        let mut messages = Vec::with_capacity(outgoing.len());
+        let mut encoded_lens = Vec::with_capacity(outgoing.len());
+        for o in &outgoing {
+            let registries = &o.member.registries;
+            record_ms(
+                registries,
+                name!("agent_batch_queue_wait_ms"),
+                o.collected_at.elapsed(),
+            );
+            let mut len = 0;
+            let announce = match o.member.capabilities.lock().announce {
+                true => o.member.announcer.take_pending(&o.member.config),
+                false => None,
+            };
+            if let Some(announce) = announce {
+                o.member.charge_quota(&announce);
+                len += announce.encoded_len();
+                messages.push(announce);
+            }
+            let mut batch = TelemetryBatch {
+                sent_at_ns,
+                ..o.batch.clone()
+            };
+            let capabilities = *o.member.capabilities.lock();
+            finish_batch(
+                &o.member.config,
+                &o.member.stats,
+                capabilities,
+                None,
+                &mut batch,
+            );
+            len += batch.encoded_len();
+            messages.push(batch);
+            encoded_lens.push(len);
+        }
+        let stream = async_stream::stream! {
+            for message in messages {
+                yield message;
+            }
+        };
+
+        let push_timeout = inner.config.push_timeout;
+        let push_started = Instant::now();
+        let result = match timeout(push_timeout, client.stream_telemetry(stream)).await {
+            Ok(result) => result,
+            Err(_) => Err(tonic::Status::deadline_exceeded(format!(
+                "push not acknowledged within {:?}",
+                push_timeout
+            ))),
+        };
+        let push_elapsed = push_started.elapsed();
+        for o in &outgoing {
+            record_ms(
+                &o.member.registries,
+                name!("agent_batch_push_ms"),
+                push_elapsed,
+            );
+        }
+
+        match result {
+            Ok(response) => {
+                failures.on_success(Instant::now());
+                for (o, len) in outgoing.iter().zip(encoded_lens) {
+                    let age = o.collected_at.elapsed();
+                    record_ms(
+                        &o.member.registries,
+                        name!("agent_batch_age_on_send_ms"),
+                        age,
+                    );
+                    o.member.stats.sent(len);
+                    inner.stats.sent(len);
+                    if o.batch.draining {
+                        o.member.drain.acknowledged.store(true, Ordering::Relaxed);
+                    }
+                }
+                // The pool's interval stays fixed; pause and sampling apply
+                // per agent
+                if let Some(directives) = response.into_inner().directives {
+                    for o in outgoing
+                        .iter()
+                        .filter(|o| o.member.config.allow_remote_config)
+                    {
+                        o.member
+                            .remote
+                            .apply(&directives, o.member.config.push_interval);
+                        record_directive_gauges(&o.member.registries.gauges, &o.member.remote);
+                    }
+                }
+            }
+            Err(e) => {
+                drop_all(&inner, &outgoing);
+                let kind = PushErrorKind::from_status(&e);
+                for o in &outgoing {
+                    o.member.announcer.mark_pending();
+                    report_push_error(&o.member.config, &o.member.registries.counters, kind, &e);
+                }
+                failures.on_failure(&e, Instant::now());
+                if !kind.is_retryable() {
+                    tracing::error!(%kind, error = %e, "push error is not retryable, stopping pool");
+                    break;
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,383 @@
+//! Batches for people to read, when debugging what goes on the wire
+//!
+//! Prost's `Debug` output has everything, but a histogram there is two
+//! bare arrays. Instead, `Display` for `Metric` is one line,
+//! `name{label="value"} value`, with a histogram's count and non-empty
+//! buckets; `Display` for `TelemetryBatch` is a summary of counts by type
+//! and the largest metrics; and `telemetry::pretty::render` is the whole
+//! batch, with each histogram drawn as bars.
+
+use std::fmt::{self, Write};
+
+use prost::Message;
+
+use crate::telemetry::metric_sample::Value;
+use crate::telemetry::{Histogram, Metric, TelemetryBatch};
+
+/// Metrics listed by size in a batch's summary
+const LARGEST: usize = 5;
+
+/// Past this, the trace-level log of each collected batch leaves out the
+/// remaining metrics
+pub(crate) const LOG_MAX_BYTES: usize = 64 * 1024;
+
+/// Characters in the bar of a histogram's fullest bucket
+const BAR_WIDTH: usize = 40;
+
+/// Everything in `batch`: its identity and flags, then every metric with
+/// its samples, histograms as one bar per bucket, then its events and
+/// spans
+pub fn render(batch: &TelemetryBatch) -> String {
+    render_within(batch, usize::MAX)
+}
+
+/// `render`, leaving out the metrics past the first that take the output
+/// over `max_bytes`, for logs that a 50k-metric batch would flood
+pub(crate) fn render_within(batch: &TelemetryBatch, max_bytes: usize) -> String {
+    let mut out = String::new();
+    // Writing to a String can't fail
+    let _ = write_batch(&mut out, batch, max_bytes);
+    out
+}
+
+fn write_batch(out: &mut String, batch: &TelemetryBatch, max_bytes: usize) -> fmt::Result {
+    writeln!(out, "{}", Summary(batch))?;
+    writeln!(
+        out,
+        "agent {}, schema {}, generation {}, epoch {:x}, sent at {}ns",
+        batch.agent_version,
+        batch.schema_version,
+        batch.connection_generation,
+        batch.instance_epoch,
+        batch.sent_at_ns
+    )?;
+    if let Some(announce) = &batch.announce {
+        writeln!(out, "announce:")?;
+        for (key, value) in &announce.metadata {
+            writeln!(out, "  {} = {:?}", key, value)?;
+        }
+    }
+    for (i, metric) in batch.metrics.iter().enumerate() {
+        if out.len() > max_bytes {
+            writeln!(out, "... {} more metrics", batch.metrics.len() - i)?;
+            break;
+        }
+        write_metric(out, metric)?;
+    }
+    for event in &batch.events {
+        write!(out, "event {} at {}ns", event.name, event.timestamp_ns)?;
+        for (key, value) in &event.attributes {
+            write!(out, " {}={:?}", key, value)?;
+        }
+        writeln!(out)?;
+    }
+    for span in &batch.spans {
+        write!(
+            out,
+            "span {} {}ns {:?} trace {} id {:x}",
+            span.name,
+            span.duration_ns,
+            span.status(),
+            hex(&span.trace_id),
+            span.span_id
+        )?;
+        if span.parent_span_id != 0 {
+            write!(out, " parent {:x}", span.parent_span_id)?;
+        }
+        for (key, value) in &span.attributes {
+            write!(out, " {}={:?}", key, value)?;
+        }
+        writeln!(out)?;
+    }
+    Ok(())
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn write_metric(out: &mut String, metric: &Metric) -> fmt::Result {
+    for sample in &metric.samples {
+        write!(out, "{}", Series(metric))?;
+        match &sample.value {
+            Some(Value::Gauge(value)) => writeln!(out, " gauge {}", value)?,
+            Some(Value::Counter(value)) => writeln!(out, " counter {}", value)?,
+            Some(Value::Histogram(histogram)) => {
+                write!(
+                    out,
+                    " histogram count={}",
+                    histogram.counts.iter().sum::<u64>()
+                )?;
+                if sample.window_start_ns != 0 {
+                    write!(out, " since {}ns", sample.window_start_ns)?;
+                }
+                writeln!(out)?;
+                write_bars(out, histogram)?;
+            }
+            None => writeln!(out, " empty")?,
+        }
+    }
+    Ok(())
+}
+
+/// One line per bucket: its upper bound, count and a bar scaled to the
+/// fullest bucket
+fn write_bars(out: &mut String, histogram: &Histogram) -> fmt::Result {
+    let max = histogram.counts.iter().copied().max().unwrap_or(0).max(1);
+    for (i, &count) in histogram.counts.iter().enumerate() {
+        let bar = (count as u128 * BAR_WIDTH as u128).div_ceil(max as u128) as usize;
+        write!(
+            out,
+            "  {:>10} {:>8} {}",
+            UpperBound(histogram, i).to_string(),
+            count,
+            "#".repeat(bar)
+        )?;
+        let exemplar = histogram
+            .exemplars
+            .iter()
+            .find(|e| e.bucket_index as usize == i);
+        if let Some(exemplar) = exemplar {
+            write!(out, " (exemplar {})", exemplar.value)?;
+        }
+        writeln!(out)?;
+    }
+    Ok(())
+}
+
+/// `<=bound` of bucket `i`, `+Inf` for the overflow bucket
+struct UpperBound<'a>(&'a Histogram, usize);
+
+impl fmt::Display for UpperBound<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.0.bounds.get(self.1) {
+            Some(bound) if bound.is_finite() => write!(f, "<={}", bound),
+            _ => write!(f, "+Inf"),
+        }
+    }
+}
+
+/// `name{key="value",...}`, or the bare name without labels
+struct Series<'a>(&'a Metric);
+
+impl fmt::Display for Series<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let metric = self.0;
+        f.write_str(&metric.name)?;
+        if metric.labels.is_empty() {
+            return Ok(());
+        }
+        f.write_char('{')?;
+        for (i, (key, value)) in metric.labels.iter().enumerate() {
+            if i > 0 {
+                f.write_char(',')?;
+            }
+            write!(f, "{}={:?}", key, value)?;
+        }
+        f.write_char('}')
+    }
+}
+
+/// The first line of a batch's `Display`
+struct Summary<'a>(&'a TelemetryBatch);
+
+impl fmt::Display for Summary<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let batch = self.0;
+        let (mut gauges, mut counters, mut histograms) = (0, 0, 0);
+        for metric in &batch.metrics {
+            match metric.samples.first().and_then(|s| s.value.as_ref()) {
+                Some(Value::Gauge(_)) => gauges += 1,
+                Some(Value::Counter(_)) => counters += 1,
+                Some(Value::Histogram(_)) => histograms += 1,
+                None => {}
+            }
+        }
+        write!(
+            f,
+            "{}/{}: {} metrics ({} gauges, {} counters, {} histograms), {} events, ",
+            batch.service,
+            batch.instance,
+            batch.metrics.len(),
+            gauges,
+            counters,
+            histograms,
+            batch.events.len(),
+        )?;
+        if !batch.spans.is_empty() {
+            write!(f, "{} spans, ", batch.spans.len())?;
+        }
+        write!(f, "{} bytes", batch.encoded_len())?;
+        for (flag, set) in [
+            ("full resync", batch.full_resync),
+            ("draining", batch.draining),
+        ] {
+            if set {
+                write!(f, " [{}]", flag)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A summary: counts by type, then the largest metrics by encoded size
+impl fmt::Display for TelemetryBatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", Summary(self))?;
+        let mut sizes: Vec<(usize, &Metric)> = self
+            .metrics
+            .iter()
+            .map(|metric| (metric.encoded_len(), metric))
+            .collect();
+        // Largest first, then by name for a stable order
+        sizes.sort_by(|a, b| b.0.cmp(&a.0).then_with(|| a.1.name.cmp(&b.1.name)));
+        for (bytes, metric) in sizes.into_iter().take(LARGEST) {
+            write!(f, "\n  {:>8} bytes  {}", bytes, Series(metric))?;
+        }
+        Ok(())
+    }
+}
+
+/// `name{key="value"} value`, with a histogram's count and its non-empty
+/// buckets as `bound:count`
+impl fmt::Display for Metric {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", Series(self))?;
+        for sample in &self.samples {
+            match &sample.value {
+                Some(Value::Gauge(value)) => write!(f, " {}", value)?,
+                Some(Value::Counter(value)) => write!(f, " {}", value)?,
+                Some(Value::Histogram(histogram)) => {
+                    write!(f, " count={} [", histogram.counts.iter().sum::<u64>())?;
+                    let buckets = histogram.counts.iter().enumerate().filter(|(_, &n)| n > 0);
+                    for (i, (bucket, count)) in buckets.enumerate() {
+                        if i > 0 {
+                            f.write_char(' ')?;
+                        }
+                        write!(f, "{}:{}", UpperBound(histogram, bucket), count)?;
+                    }
+                    f.write_char(']')?;
+                }
+                None => {}
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::telemetry::{Event, MetricSample};
+
+    fn metric(name: &str, labels: &[(&str, &str)], value: Value) -> Metric {
+        Metric {
+            name: name.to_string(),
+            labels: labels
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect(),
+            samples: vec![MetricSample {
+                value: Some(value),
+                ..Default::default()
+            }],
+            series_id: 0,
+            series_hash: 0,
+        }
+    }
+
+    fn batch() -> TelemetryBatch {
+        TelemetryBatch {
+            service: "checkout".to_string(),
+            instance: "host-1".to_string(),
+            metrics: vec![
+                metric(
+                    "latency_ms",
+                    &[("outcome", "success")],
+                    Value::Histogram(Histogram {
+                        bounds: vec![1.0, 5.0, f64::INFINITY],
+                        counts: vec![2, 8, 0],
+                        exemplars: Vec::new(),
+                    }),
+                ),
+                metric("queue_depth", &[], Value::Gauge(4.5)),
+                metric("requests_total", &[("route", "/a")], Value::Counter(3)),
+            ],
+            events: vec![Event {
+                name: "deploy".to_string(),
+                timestamp_ns: 7,
+                attributes: [("version".to_string(), "1.4.2".to_string())].into(),
+                ..Default::default()
+            }],
+            agent_version: "0.1.0".to_string(),
+            schema_version: 2,
+            connection_generation: 1,
+            full_resync: true,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_metric_lines() {
+        let batch = batch();
+        let lines: Vec<String> = batch.metrics.iter().map(|m| m.to_string()).collect();
+        assert_eq!(
+            lines,
+            [
+                "latency_ms{outcome=\"success\"} count=10 [<=1:2 <=5:8]",
+                "queue_depth 4.5",
+                "requests_total{route=\"/a\"} 3",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_batch_summary() {
+        let summary = batch().to_string();
+        let lines: Vec<&str> = summary.lines().collect();
+        assert_eq!(
+            lines[0],
+            format!(
+                "checkout/host-1: 3 metrics (1 gauges, 1 counters, 1 histograms), \
+                 1 events, {} bytes [full resync]",
+                batch().encoded_len()
+            )
+        );
+        // Largest first: the histogram, then the labeled counter
+        assert_eq!(lines.len(), 4);
+        assert!(lines[1].ends_with("bytes  latency_ms{outcome=\"success\"}"));
+        assert!(lines[2].ends_with("bytes  requests_total{route=\"/a\"}"));
+        assert!(lines[3].ends_with("bytes  queue_depth"));
+    }
+
+    #[test]
+    fn test_render() {
+        let rendered = render(&batch());
+        let body: Vec<&str> = rendered.lines().skip(1).collect();
+        assert_eq!(
+            body,
+            [
+                "agent 0.1.0, schema 2, generation 1, epoch 0, sent at 0ns",
+                "latency_ms{outcome=\"success\"} histogram count=10",
+                "         <=1        2 ##########",
+                "         <=5        8 ########################################",
+                "        +Inf        0 ",
+                "queue_depth gauge 4.5",
+                "requests_total{route=\"/a\"} counter 3",
+                "event deploy at 7ns version=\"1.4.2\"",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_render_within_leaves_out_what_doesnt_fit() {
+        let mut batch = batch();
+        let gauge = metric("queue_depth", &[], Value::Gauge(1.0));
+        batch.metrics = vec![gauge; 50_000];
+        let rendered = render_within(&batch, 1024);
+        assert!(rendered.len() < 2048);
+        assert!(rendered.contains("more metrics"));
+        // Events still go in
+        assert!(rendered.ends_with("event deploy at 7ns version=\"1.4.2\"\n"));
+    }
+}
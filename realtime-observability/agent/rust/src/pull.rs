@@ -0,0 +1,263 @@
+//! Snapshots pulled by the aggregator from `Config::pull_listener`
+//!
+//! The aggregator queries `AgentSnapshot.QuerySnapshot` when it wants the
+//! state of an instance now rather than at its next push. The answer is
+//! read the way the SIGUSR1 dump reads the registries, so nothing is
+//! reset and the next pushed batch is the same as without the query:
+//! counters carry their totals including what isn't pushed yet, `Last`
+//! gauges their value, and histograms the counts recorded since the last
+//! push, in the bounds they were recorded in. Windowed gauges have no
+//! value until their window closes and are left out, as are events and
+//! spans, which only the push loop drains.
+
+use std::io;
+use std::net::SocketAddr;
+
+use tonic::transport::server::TcpIncoming;
+use tonic::{Request, Response, Status};
+
+use crate::agent::{empty_batch, Registries};
+use crate::telemetry::agent_snapshot_server::{AgentSnapshot, AgentSnapshotServer};
+use crate::telemetry::{
+    self, Histogram as HistogramProto, Metric, MetricSample, SnapshotRequest, TelemetryBatch,
+};
+use crate::{series, Agent, Config, GaugeAggregation};
+
+/// What a push would carry if it reset nothing, as of now
+pub(crate) fn snapshot_of(config: &Config, registries: &Registries) -> TelemetryBatch {
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_nanos() as u64;
+    let metric = |key: &str, value| {
+        let (name, labels) = series::decode(key);
+        Metric {
+            name,
+            labels,
+            samples: vec![MetricSample {
+                timestamp_ns: now,
+                window_start_ns: 0,
+                value: Some(value),
+            }],
+            series_id: 0,
+            series_hash: 0,
+        }
+    };
+
+    let mut metrics = Vec::new();
+    for (key, gauge) in registries.gauges.lock().iter() {
+        if gauge.mode() == GaugeAggregation::Last {
+            let value = telemetry::metric_sample::Value::Gauge(gauge.peek());
+            metrics.push(metric(key, value));
+        }
+    }
+    {
+        let units = registries.units.lock();
+        for (key, hist) in registries.histograms.lock().iter() {
+            let mut bounds = hist.bounds();
+            if config.explicit_inf_bound {
+                bounds.push(f64::INFINITY);
+            }
+            let value = telemetry::metric_sample::Value::Histogram(HistogramProto {
+                bounds,
+                counts: hist.counts(),
+                exemplars: Vec::new(),
+            });
+            let mut metric = metric(key, value);
+            if let Some(unit) = units.get(series::name(key)) {
+                metric
+                    .labels
+                    .insert("unit".to_string(), unit.as_str().to_string());
+            }
+            metrics.push(metric);
+        }
+    }
+    for (key, counter) in registries.counters.lock().iter() {
+        let value = telemetry::metric_sample::Value::Counter(counter.value());
+        metrics.push(metric(key, value));
+    }
+    for (key, counter) in registries.sharded.lock().iter() {
+        let value = telemetry::metric_sample::Value::Counter(counter.sum());
+        metrics.push(metric(key, value));
+    }
+
+    metrics.retain(|m| !registries.filter.rejects(&m.name) && registries.switches.is_on(&m.name));
+    metrics.sort_by(|a, b| a.name.cmp(&b.name).then_with(|| a.labels.cmp(&b.labels)));
+    TelemetryBatch {
+        metrics,
+        sent_at_ns: now,
+        ..empty_batch(config)
+    }
+}
+
+struct SnapshotService {
+    config: Config,
+    registries: Registries,
+}
+
+#[tonic::async_trait]
+impl AgentSnapshot for SnapshotService {
+    async fn query_snapshot(
+        &self,
+        _request: Request<SnapshotRequest>,
+    ) -> Result<Response<TelemetryBatch>, Status> {
+        Ok(Response::new(snapshot_of(&self.config, &self.registries)))
+    }
+}
+
+impl Agent {
+    /// The address `Config::pull_listener` is bound to once started, with
+    /// the port the system picked if it asked for port 0
+    pub fn pull_listener_addr(&self) -> Option<SocketAddr> {
+        self.pull_addr
+    }
+
+    /// Bind `addr` and answer snapshot queries on it until `stop()`
+    pub(crate) fn serve_pull(&mut self, addr: SocketAddr) -> io::Result<()> {
+        let listener = std::net::TcpListener::bind(addr)?;
+        listener.set_nonblocking(true)?;
+        self.pull_addr = Some(listener.local_addr()?);
+        let service = AgentSnapshotServer::new(SnapshotService {
+            config: self.config.clone(),
+            registries: self.registries(),
+        });
+        let serve = async move {
+            let incoming = tokio::net::TcpListener::from_std(listener)
+                .map_err(Into::into)
+                .and_then(|listener| TcpIncoming::from_listener(listener, true, None));
+            let result = match incoming {
+                Ok(incoming) => tonic::transport::Server::builder()
+                    .add_service(service)
+                    .serve_with_incoming(incoming)
+                    .await
+                    .map_err(Into::into),
+                Err(e) => Err(e),
+            };
+            if let Err(e) = result {
+                tracing::warn!(error = %e, "pull listener failed");
+            }
+        };
+        let task = match &self.config.tokio_handle {
+            Some(handle) => handle.spawn(serve),
+            None => tokio::spawn(serve),
+        };
+        if let Some(previous) = self.pull_task.lock().replace(task) {
+            previous.abort();
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::telemetry::agent_snapshot_client::AgentSnapshotClient;
+    use crate::{AgentError, PushErrorKind};
+
+    fn value<'a>(batch: &'a TelemetryBatch, name: &str) -> &'a telemetry::metric_sample::Value {
+        let metric = batch.metrics.iter().find(|m| m.name == name).unwrap();
+        metric.samples[0].value.as_ref().unwrap()
+    }
+
+    fn histogram_total(batch: &TelemetryBatch, name: &str) -> u64 {
+        match value(batch, name) {
+            telemetry::metric_sample::Value::Histogram(hist) => hist.counts.iter().sum(),
+            other => panic!("{} is {:?}", name, other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_snapshot_matches_the_registries_and_resets_nothing() {
+        let mut agent = Agent::new(Config {
+            push_interval: Duration::from_secs(3600),
+            lazy_connect: true,
+            pull_listener: Some("127.0.0.1:0".parse().unwrap()),
+            ..Default::default()
+        });
+        agent.add_counter("requests_total", 3);
+        agent.set_gauge_with("queue_depth", &[("queue", "orders")], 7.0);
+        agent.register_gauge("peak_depth", GaugeAggregation::Max);
+        agent.set_gauge_with("peak_depth", &[], 9.0);
+        agent.record_histogram_with("latency_ms", &[], 12.0);
+        agent.record_histogram_with("latency_ms", &[], 40.0);
+        agent.start().await.unwrap();
+        let addr = agent.pull_listener_addr().unwrap();
+        assert_ne!(addr.port(), 0);
+
+        let mut client = AgentSnapshotClient::connect(format!("http://{}", addr))
+            .await
+            .unwrap();
+        let snapshot = client
+            .query_snapshot(SnapshotRequest {})
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(snapshot.instance, agent.instance_id());
+        assert_eq!(
+            value(&snapshot, "requests_total"),
+            &telemetry::metric_sample::Value::Counter(3)
+        );
+        let depth = snapshot
+            .metrics
+            .iter()
+            .find(|m| m.name == "queue_depth")
+            .unwrap();
+        assert_eq!(depth.labels["queue"], "orders");
+        assert_eq!(
+            depth.samples[0].value,
+            Some(telemetry::metric_sample::Value::Gauge(7.0))
+        );
+        // Its window hasn't closed
+        assert!(!snapshot.metrics.iter().any(|m| m.name == "peak_depth"));
+        assert_eq!(histogram_total(&snapshot, "latency_ms"), 2);
+
+        // Querying again sees the same, and so does the next collect
+        let again = client
+            .query_snapshot(SnapshotRequest {})
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(histogram_total(&again, "latency_ms"), 2);
+        let batch = agent.collect_now();
+        assert_eq!(histogram_total(&batch, "latency_ms"), 2);
+        assert_eq!(
+            value(&batch, "requests_total"),
+            &telemetry::metric_sample::Value::Counter(3)
+        );
+        assert_eq!(
+            value(&batch, "peak_depth"),
+            &telemetry::metric_sample::Value::Gauge(9.0)
+        );
+
+        // No new connections once stopped
+        agent.stop().await.ok();
+        tokio::task::yield_now().await;
+        let connect = AgentSnapshotClient::connect(format!("http://{}", addr)).await;
+        assert!(connect.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_pull_listener_is_loopback_only_unless_public() {
+        let config = Config {
+            lazy_connect: true,
+            pull_listener: Some("0.0.0.0:0".parse().unwrap()),
+            ..Default::default()
+        };
+        let mut agent = Agent::new(config.clone());
+        let err = agent.start().await.unwrap_err();
+        let err = err.downcast_ref::<AgentError>().unwrap();
+        assert_eq!(err.kind, PushErrorKind::InvalidArgument);
+        assert!(err.message.contains("pull_listener_public"));
+        assert!(agent.pull_listener_addr().is_none());
+
+        let mut agent = Agent::new(Config {
+            pull_listener_public: true,
+            ..config
+        });
+        agent.start().await.unwrap();
+        assert!(agent.pull_listener_addr().unwrap().ip().is_unspecified());
+        agent.stop().await.ok();
+    }
+}
@@ -0,0 +1,268 @@
+//! Classification of push failures
+//!
+//! Every failed push increments `agent_push_errors_total` and
+//! `agent_push_errors_<kind>`:
+//!
+//! | Kind                  | Source                                          | Retryable |
+//! |-----------------------|-------------------------------------------------|-----------|
+//! | `dns`                 | address lookup failed                           | yes       |
+//! | `connection_refused`  | TCP connection refused / reset                  | yes       |
+//! | `tls`                 | TLS handshake or certificate failure            | yes       |
+//! | `connect_timeout`     | no TCP connection in `Config::connect_timeout`  | yes       |
+//! | `handshake_timeout`   | connected, no answer in `Config::handshake_timeout` | yes   |
+//! | `deadline_exceeded`   | `DeadlineExceeded`, `Cancelled`, I/O timeout    | yes       |
+//! | `unavailable`         | `Unavailable` without a more specific cause     | yes       |
+//! | `resource_exhausted`  | `ResourceExhausted` (server-side throttling)    | yes       |
+//! | `unauthenticated`     | `Unauthenticated`                               | no        |
+//! | `permission_denied`   | `PermissionDenied`                              | no        |
+//! | `invalid_argument`    | `InvalidArgument`, `FailedPrecondition`, `OutOfRange` | no  |
+//! | `unimplemented`       | `Unimplemented`                                 | no        |
+//! | `internal`            | `Internal`, `DataLoss`                          | yes       |
+//! | `other`               | any other status or transport error             | yes       |
+//!
+//! `not_started` is never counted: it is the kind of the error `Agent::stop`
+//! returns for an agent that isn't running.
+
+use std::error::Error;
+use std::io;
+use std::sync::Arc;
+
+#[cfg(not(feature = "noop"))]
+use tonic::Code;
+
+/// Category of a failed push
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum PushErrorKind {
+    Dns,
+    ConnectionRefused,
+    Tls,
+    ConnectTimeout,
+    HandshakeTimeout,
+    DeadlineExceeded,
+    Unavailable,
+    ResourceExhausted,
+    Unauthenticated,
+    PermissionDenied,
+    InvalidArgument,
+    Unimplemented,
+    Internal,
+    Other,
+    /// `Agent::stop` on an agent that was never started or already stopped
+    NotStarted,
+}
+
+/// Callback invoked for every failed push or connect attempt
+pub type PushErrorCallback = Arc<dyn Fn(PushErrorKind, &(dyn Error + 'static)) + Send + Sync>;
+
+impl PushErrorKind {
+    /// Suffix used for the `agent_push_errors_<kind>` counter
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            PushErrorKind::Dns => "dns",
+            PushErrorKind::ConnectionRefused => "connection_refused",
+            PushErrorKind::Tls => "tls",
+            PushErrorKind::ConnectTimeout => "connect_timeout",
+            PushErrorKind::HandshakeTimeout => "handshake_timeout",
+            PushErrorKind::DeadlineExceeded => "deadline_exceeded",
+            PushErrorKind::Unavailable => "unavailable",
+            PushErrorKind::ResourceExhausted => "resource_exhausted",
+            PushErrorKind::Unauthenticated => "unauthenticated",
+            PushErrorKind::PermissionDenied => "permission_denied",
+            PushErrorKind::InvalidArgument => "invalid_argument",
+            PushErrorKind::Unimplemented => "unimplemented",
+            PushErrorKind::Internal => "internal",
+            PushErrorKind::Other => "other",
+            PushErrorKind::NotStarted => "not_started",
+        }
+    }
+
+    /// Whether pushing again can succeed without a configuration change
+    pub fn is_retryable(&self) -> bool {
+        !matches!(
+            self,
+            PushErrorKind::Unauthenticated
+                | PushErrorKind::PermissionDenied
+                | PushErrorKind::InvalidArgument
+                | PushErrorKind::Unimplemented
+                | PushErrorKind::NotStarted
+        )
+    }
+
+    /// Classify a status returned by `stream_telemetry`
+    #[cfg(not(feature = "noop"))]
+    pub fn from_status(status: &tonic::Status) -> Self {
+        if let Some(kind) = status.source().and_then(classify_chain) {
+            return kind;
+        }
+        match status.code() {
+            Code::DeadlineExceeded | Code::Cancelled => PushErrorKind::DeadlineExceeded,
+            Code::Unavailable => PushErrorKind::Unavailable,
+            Code::ResourceExhausted => PushErrorKind::ResourceExhausted,
+            Code::Unauthenticated => PushErrorKind::Unauthenticated,
+            Code::PermissionDenied => PushErrorKind::PermissionDenied,
+            Code::InvalidArgument | Code::FailedPrecondition | Code::OutOfRange => {
+                PushErrorKind::InvalidArgument
+            }
+            Code::Unimplemented => PushErrorKind::Unimplemented,
+            Code::Internal | Code::DataLoss => PushErrorKind::Internal,
+            _ => PushErrorKind::Other,
+        }
+    }
+
+    /// Classify an error raised while establishing the channel
+    #[cfg(not(feature = "noop"))]
+    pub fn from_transport_error(err: &tonic::transport::Error) -> Self {
+        classify_chain(err).unwrap_or(PushErrorKind::Other)
+    }
+}
+
+impl std::fmt::Display for PushErrorKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// A push that could not be delivered, from `run_scoped` or `Agent::flush`,
+/// or a `stop()` of an agent that isn't running
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AgentError {
+    pub kind: PushErrorKind,
+    pub message: String,
+}
+
+impl std::fmt::Display for AgentError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "push failed ({}): {}", self.kind, self.message)
+    }
+}
+
+impl Error for AgentError {}
+
+/// Walk an error's source chain looking for a network-level cause.
+/// hyper and rustls do not expose typed kinds, so DNS and TLS failures are
+/// recognised by message.
+fn classify_chain(err: &(dyn Error + 'static)) -> Option<PushErrorKind> {
+    // Under hyper's "tcp connect error", a timeout is `connect_timeout`
+    let mut connecting = false;
+    let mut current = Some(err);
+    while let Some(err) = current {
+        if let Some(io_err) = err.downcast_ref::<io::Error>() {
+            match io_err.kind() {
+                io::ErrorKind::ConnectionRefused
+                | io::ErrorKind::ConnectionReset
+                | io::ErrorKind::ConnectionAborted => {
+                    return Some(PushErrorKind::ConnectionRefused)
+                }
+                io::ErrorKind::TimedOut if connecting => {
+                    return Some(PushErrorKind::ConnectTimeout)
+                }
+                io::ErrorKind::TimedOut => return Some(PushErrorKind::DeadlineExceeded),
+                _ => {}
+            }
+        }
+
+        let message = err.to_string().to_lowercase();
+        connecting |= message.contains("tcp connect error");
+        if message.contains("dns error") || message.contains("failed to lookup address") {
+            return Some(PushErrorKind::Dns);
+        }
+        if message.contains("tls") || message.contains("certificate") {
+            return Some(PushErrorKind::Tls);
+        }
+
+        current = err.source();
+    }
+    None
+}
+
+#[cfg(all(test, not(feature = "noop")))]
+mod tests {
+    use super::*;
+    use tonic::Status;
+
+    #[test]
+    fn test_status_codes() {
+        let cases = [
+            (
+                Status::deadline_exceeded("slow"),
+                PushErrorKind::DeadlineExceeded,
+            ),
+            (Status::unavailable("down"), PushErrorKind::Unavailable),
+            (
+                Status::unauthenticated("bad key"),
+                PushErrorKind::Unauthenticated,
+            ),
+            (
+                Status::permission_denied("no"),
+                PushErrorKind::PermissionDenied,
+            ),
+            (
+                Status::resource_exhausted("throttled"),
+                PushErrorKind::ResourceExhausted,
+            ),
+            (
+                Status::invalid_argument("bad batch"),
+                PushErrorKind::InvalidArgument,
+            ),
+            (
+                Status::unimplemented("old server"),
+                PushErrorKind::Unimplemented,
+            ),
+            (Status::internal("boom"), PushErrorKind::Internal),
+            (Status::unknown("?"), PushErrorKind::Other),
+        ];
+        for (status, kind) in cases {
+            assert_eq!(PushErrorKind::from_status(&status), kind, "{:?}", status);
+        }
+    }
+
+    #[test]
+    fn test_source_chain() {
+        let refused = Status::from_error(Box::new(io::Error::new(
+            io::ErrorKind::ConnectionRefused,
+            "connection refused",
+        )));
+        assert_eq!(
+            PushErrorKind::from_status(&refused),
+            PushErrorKind::ConnectionRefused
+        );
+
+        let dns = Status::from_error(Box::new(io::Error::other(
+            "dns error: failed to lookup address information",
+        )));
+        assert_eq!(PushErrorKind::from_status(&dns), PushErrorKind::Dns);
+
+        let timed_out = || io::Error::new(io::ErrorKind::TimedOut, "deadline has elapsed");
+        assert_eq!(
+            PushErrorKind::from_status(&Status::from_error(Box::new(timed_out()))),
+            PushErrorKind::DeadlineExceeded
+        );
+        let connect = Status::from_error(Box::new(ConnectError(timed_out())));
+        assert_eq!(
+            PushErrorKind::from_status(&connect),
+            PushErrorKind::ConnectTimeout
+        );
+    }
+
+    /// Shaped like hyper's connector error
+    #[derive(Debug)]
+    struct ConnectError(io::Error);
+
+    impl std::fmt::Display for ConnectError {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            f.write_str("tcp connect error")
+        }
+    }
+
+    impl Error for ConnectError {
+        fn source(&self) -> Option<&(dyn Error + 'static)> {
+            Some(&self.0)
+        }
+    }
+
+    #[test]
+    fn test_retryable() {
+        assert!(!PushErrorKind::Unauthenticated.is_retryable());
+        assert!(PushErrorKind::ConnectionRefused.is_retryable());
+    }
+}
@@ -0,0 +1,245 @@
+//! Quantile gauges derived from histograms (`Agent::emit_quantiles`)
+//!
+//! Some aggregators query gauges far more easily than histograms, so for
+//! the histograms asked for, each collect also sends chosen quantiles of
+//! the counts it sends, as gauges `{name}_quantile` labeled with the
+//! histogram's labels and `quantile`. They are interpolated within buckets
+//! as in `local_stats`, from the histogram's own bounds before any
+//! `configure_wire_bounds` folding, and cover the histogram's window under
+//! a `ResetPolicy` other than `EveryPush`. A series with nothing recorded
+//! in its window sends no quantile gauges rather than NaN. The gauges'
+//! name is fixed as a gauge in `MetricTypes` when they are asked for.
+
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
+
+use parking_lot::Mutex;
+
+use crate::local_stats;
+use crate::telemetry::{self, Metric, MetricSample};
+
+/// Quantiles to send by histogram name
+#[derive(Default)]
+pub(crate) struct QuantileGauges {
+    quantiles: Mutex<HashMap<String, Arc<[f64]>>>,
+}
+
+impl QuantileGauges {
+    /// Send `quantiles` of histogram `name`, or stop if there are none
+    pub(crate) fn set(&self, name: &str, quantiles: Vec<f64>) {
+        let mut by_name = self.quantiles.lock();
+        match quantiles.is_empty() {
+            true => by_name.remove(name),
+            false => by_name.insert(name.to_string(), quantiles.into()),
+        };
+    }
+
+    /// All quantiles to send, for one collect
+    pub(crate) fn snapshot(&self) -> HashMap<String, Arc<[f64]>> {
+        self.quantiles.lock().clone()
+    }
+}
+
+/// The quantile gauges of one histogram series, given the bounds and
+/// counts (overflow last) it is sent with
+pub(crate) fn gauges(
+    name: &str,
+    labels: &BTreeMap<String, String>,
+    bounds: &[f64],
+    counts: &[u64],
+    quantiles: &[f64],
+    now: u64,
+) -> Vec<Metric> {
+    quantiles
+        .iter()
+        .filter_map(|&q| {
+            let value = local_stats::quantile(bounds, counts, q)?;
+            let mut labels = labels.clone();
+            labels.insert("quantile".to_string(), q.to_string());
+            Some(Metric {
+                name: format!("{}_quantile", name),
+                labels,
+                samples: vec![MetricSample {
+                    timestamp_ns: now,
+                    window_start_ns: 0,
+                    value: Some(telemetry::metric_sample::Value::Gauge(value)),
+                }],
+                series_id: 0,
+                series_hash: 0,
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::telemetry::TelemetryBatch;
+    use crate::{Agent, BucketSpec, Config, MetricType, ResetPolicy};
+
+    /// Bounds every `width` from `width` to `max`
+    fn linear(width: f64, max: f64) -> BucketSpec {
+        let n = (max / width) as usize;
+        BucketSpec::Explicit((1..=n).map(|i| i as f64 * width).collect())
+    }
+
+    fn quantile_gauges(batch: &TelemetryBatch, name: &str) -> Vec<(String, f64)> {
+        batch
+            .metrics
+            .iter()
+            .filter(|m| m.name == name)
+            .map(|m| match m.samples[0].value {
+                Some(crate::telemetry::metric_sample::Value::Gauge(value)) => {
+                    (m.labels["quantile"].clone(), value)
+                }
+                ref other => panic!("{} is {:?}", name, other),
+            })
+            .collect()
+    }
+
+    fn assert_near(actual: f64, expected: f64, tolerance: f64) {
+        assert!(
+            (actual - expected).abs() <= tolerance,
+            "{} is not within {} of {}",
+            actual,
+            tolerance,
+            expected
+        );
+    }
+
+    #[test]
+    fn test_uniform_quantiles_are_exact_to_a_bucket() {
+        let agent = Agent::new(Config::default());
+        agent.configure_latency("latency_ms", linear(10.0, 1000.0));
+        agent.emit_quantiles("latency_ms", &[0.5, 0.9, 0.99]);
+        for i in 0..10_000 {
+            agent.record_histogram("latency_ms", (i as f64 + 0.5) / 10.0);
+        }
+        let batch = agent.collect_now();
+        // The histogram itself still goes out
+        assert!(batch.metrics.iter().any(|m| m.name == "latency_ms"));
+        let quantiles = quantile_gauges(&batch, "latency_ms_quantile");
+        let expected = [("0.5", 500.0), ("0.9", 900.0), ("0.99", 990.0)];
+        assert_eq!(quantiles.len(), expected.len());
+        for ((label, value), (q, expected)) in quantiles.iter().zip(expected) {
+            assert_eq!(label, q);
+            // Uniform within every bucket, so interpolation is exact
+            assert_near(*value, expected, 1e-9);
+        }
+    }
+
+    #[test]
+    fn test_exponential_quantiles_are_within_a_bucket() {
+        let agent = Agent::new(Config::default());
+        agent.configure_latency(
+            "latency_ms",
+            BucketSpec::Exponential {
+                start: 1.0,
+                factor: 1.25,
+                count: 40,
+            },
+        );
+        agent.emit_quantiles("latency_ms", &[0.5, 0.99]);
+        // Mean 100ms, at evenly spaced points of the inverse CDF
+        let n = 20_000;
+        for i in 0..n {
+            let u = (i as f64 + 0.5) / n as f64;
+            agent.record_histogram("latency_ms", -100.0 * (1.0 - u).ln());
+        }
+        let quantiles = quantile_gauges(&agent.collect_now(), "latency_ms_quantile");
+        for ((_, value), q) in quantiles.iter().zip([0.5f64, 0.99]) {
+            let exact = -100.0 * (1.0 - q).ln();
+            // Off by at most the width of the bucket it falls in
+            assert_near(*value, exact, exact * 0.25);
+        }
+        assert_eq!(quantiles.len(), 2);
+    }
+
+    #[test]
+    fn test_quantiles_are_labeled_per_series_and_omitted_when_empty() {
+        let agent = Agent::new(Config::default());
+        agent.configure_latency("latency_ms", linear(10.0, 100.0));
+        agent.emit_quantiles("latency_ms", &[0.5]);
+        // Registers the unit, with an unlabeled series left empty
+        agent.histogram_ms("latency_ms").unwrap();
+        agent.record_histogram_with("latency_ms", &[("route", "/a")], 15.0);
+        agent.record_histogram_with("latency_ms", &[("route", "/b")], 75.0);
+        let batch = agent.collect_now();
+        let gauges: Vec<_> = batch
+            .metrics
+            .iter()
+            .filter(|m| m.name == "latency_ms_quantile")
+            .collect();
+        assert_eq!(gauges.len(), 2);
+        assert_eq!(gauges[0].labels["route"], "/a");
+        assert_eq!(gauges[0].labels["unit"], "ms");
+        assert_eq!(gauges[1].labels["quantile"], "0.5");
+        assert_eq!(
+            gauges[1].samples[0].value,
+            Some(crate::telemetry::metric_sample::Value::Gauge(75.0))
+        );
+
+        // Nothing recorded since: no NaN gauges
+        let batch = agent.collect_now();
+        assert!(quantile_gauges(&batch, "latency_ms_quantile").is_empty());
+
+        // Stopped with no quantiles
+        agent.emit_quantiles("latency_ms", &[]);
+        agent.record_histogram_with("latency_ms", &[("route", "/a")], 15.0);
+        let batch = agent.collect_now();
+        assert!(quantile_gauges(&batch, "latency_ms_quantile").is_empty());
+    }
+
+    #[test]
+    fn test_quantiles_all_out_of_range_keep_those_set_before() {
+        let agent = Agent::new(Config::default());
+        agent.configure_latency("latency_ms", linear(10.0, 100.0));
+        agent.emit_quantiles("latency_ms", &[0.5]);
+        agent.emit_quantiles("latency_ms", &[50.0, 99.0]);
+        agent.record_histogram("latency_ms", 15.0);
+        let quantiles = quantile_gauges(&agent.collect_now(), "latency_ms_quantile");
+        assert_eq!(quantiles.len(), 1);
+        assert_eq!(quantiles[0].0, "0.5");
+    }
+
+    #[test]
+    fn test_quantile_gauges_are_typed() {
+        let agent = Agent::new(Config::default());
+        agent.emit_quantiles("latency_ms", &[0.5]);
+        assert_eq!(
+            agent.metric_type("latency_ms_quantile"),
+            Some(MetricType::Gauge)
+        );
+        assert!(agent.try_inc_counter("latency_ms_quantile").is_err());
+
+        // Taken by a counter first: no gauges under its name
+        agent.inc_counter("db_ms_quantile");
+        agent.emit_quantiles("db_ms", &[0.5]);
+        agent.record_histogram("db_ms", 15.0);
+        let batch = agent.collect_now();
+        let counters: Vec<_> = batch
+            .metrics
+            .iter()
+            .filter(|m| m.name == "db_ms_quantile")
+            .collect();
+        assert_eq!(counters.len(), 1);
+        assert!(matches!(
+            counters[0].samples[0].value,
+            Some(crate::telemetry::metric_sample::Value::Counter(_))
+        ));
+    }
+
+    #[test]
+    fn test_quantiles_cover_the_whole_window() {
+        let agent = Agent::new(Config::default());
+        agent.configure_latency("latency_ms", linear(10.0, 100.0));
+        agent.register_histogram("latency_ms", ResetPolicy::Never);
+        agent.emit_quantiles("latency_ms", &[0.5]);
+        agent.record_histogram("latency_ms", 15.0);
+        agent.collect_now();
+        agent.record_histogram("latency_ms", 95.0);
+        agent.record_histogram("latency_ms", 95.0);
+        let quantiles = quantile_gauges(&agent.collect_now(), "latency_ms_quantile");
+        // Median of all three, not of this push's two
+        assert_near(quantiles[0].1, 92.5, 1e-9);
+    }
+}
@@ -0,0 +1,279 @@
+//! Records made from inside the push loop
+//!
+//! Instrumentation that runs while the agent collects or pushes (a tracing
+//! layer counting log lines, middleware on the exporter's channel, the
+//! agent instrumenting its own exporter) records once per push, and each
+//! record can add series, drops and warnings that the next push carries
+//! and is recorded about in turn. The push loop runs in a task-local
+//! scope, and gauge, counter and histogram records made by name from
+//! inside it land here instead of in the agent's registries. They are
+//! sent with every batch, labeled `quarantined="true"`, but pass no
+//! filter, limit, memory budget or self-metric on the way in, so they
+//! can't feed back. At most `MAX_QUARANTINED_SERIES` series are kept;
+//! records of further ones are dropped. Handles (`counter_family`,
+//! `histogram_ms` and the like) record as usual.
+//!
+//! Separately, `Config::max_self_metric_fraction` warns when the agent's
+//! own series take up more of a batch than expected.
+
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+use parking_lot::Mutex;
+
+use crate::series;
+use crate::telemetry::metric_sample::Value;
+use crate::telemetry::{Metric, MetricSample, TelemetryBatch};
+use crate::Histogram;
+
+/// Label on every quarantined series
+pub(crate) const QUARANTINE_LABEL: &str = "quarantined";
+
+const MAX_QUARANTINED_SERIES: usize = 64;
+
+tokio::task_local! {
+    static IN_PUSH_LOOP: ();
+}
+
+/// Run `future`, a push loop, with its records quarantined
+pub(crate) fn scope<F: Future>(future: F) -> impl Future<Output = F::Output> {
+    IN_PUSH_LOOP.scope((), future)
+}
+
+/// Whether the caller runs inside a push loop
+#[inline]
+pub(crate) fn active() -> bool {
+    IN_PUSH_LOOP.try_with(|_| ()).is_ok()
+}
+
+enum Quarantined {
+    Gauge(f64),
+    Counter(u64),
+    Histogram(Histogram),
+}
+
+#[derive(Default)]
+pub(crate) struct Quarantine {
+    series: Mutex<HashMap<String, Quarantined>>,
+    records: AtomicU64,
+    dropped: AtomicU64,
+    /// Whether the last batch checked was over the self-metric fraction
+    over: AtomicBool,
+}
+
+impl Quarantine {
+    pub(crate) fn set_gauge(&self, name: &str, labels: &[(&str, &str)], value: f64) {
+        self.add_gauge(name, labels, value, true);
+    }
+
+    /// Add `delta` to a gauge, or set it to `delta` with `set`, returning
+    /// its new value
+    pub(crate) fn add_gauge(
+        &self,
+        name: &str,
+        labels: &[(&str, &str)],
+        delta: f64,
+        set: bool,
+    ) -> f64 {
+        self.with(
+            name,
+            labels,
+            Quarantined::Gauge(0.0),
+            |series| match series {
+                Quarantined::Gauge(value) => {
+                    *value = if set { delta } else { *value + delta };
+                    *value
+                }
+                _ => 0.0,
+            },
+        )
+        .unwrap_or(0.0)
+    }
+
+    pub(crate) fn add_counter(&self, name: &str, labels: &[(&str, &str)], n: u64) {
+        self.with(name, labels, Quarantined::Counter(0), |series| {
+            if let Quarantined::Counter(total) = series {
+                *total += n;
+            }
+        });
+    }
+
+    pub(crate) fn record_histogram(&self, name: &str, labels: &[(&str, &str)], value: f64) {
+        self.with(
+            name,
+            labels,
+            Quarantined::Histogram(Histogram::new()),
+            |series| {
+                if let Quarantined::Histogram(histogram) = series {
+                    histogram.record(value);
+                }
+            },
+        );
+    }
+
+    /// Apply `f` to series `name{labels}`, created as `empty` if there is
+    /// room; `None` if there isn't
+    fn with<R>(
+        &self,
+        name: &str,
+        labels: &[(&str, &str)],
+        empty: Quarantined,
+        f: impl FnOnce(&mut Quarantined) -> R,
+    ) -> Option<R> {
+        self.records.fetch_add(1, Ordering::Relaxed);
+        let mut labels = labels.to_vec();
+        labels.push((QUARANTINE_LABEL, "true"));
+        let key = series::encode(name, &labels);
+        let mut series = self.series.lock();
+        let len = series.len();
+        let entry = match series.get_mut(&key) {
+            Some(entry) => entry,
+            None if len < MAX_QUARANTINED_SERIES => series.entry(key).or_insert(empty),
+            None => {
+                self.dropped.fetch_add(1, Ordering::Relaxed);
+                return None;
+            }
+        };
+        Some(f(entry))
+    }
+
+    /// Every quarantined series, as sent: gauges and counters at their
+    /// current values, histograms with what was recorded since the last
+    /// collect
+    pub(crate) fn collect(&self, now: u64) -> Vec<Metric> {
+        let series = self.series.lock();
+        series
+            .iter()
+            .map(|(key, series)| {
+                let value = match series {
+                    Quarantined::Gauge(value) => Value::Gauge(*value),
+                    Quarantined::Counter(total) => Value::Counter(*total),
+                    Quarantined::Histogram(histogram) => {
+                        let (bounds, counts) = histogram.snapshot_and_reset();
+                        Value::Histogram(crate::telemetry::Histogram {
+                            bounds,
+                            counts,
+                            exemplars: Vec::new(),
+                        })
+                    }
+                };
+                let (name, labels) = series::decode(key);
+                Metric {
+                    name,
+                    labels,
+                    samples: vec![MetricSample {
+                        timestamp_ns: now,
+                        value: Some(value),
+                        window_start_ns: 0,
+                    }],
+                    series_id: 0,
+                    series_hash: 0,
+                }
+            })
+            .collect()
+    }
+
+    /// Records quarantined, and of those dropped for want of room, since
+    /// the last call
+    pub(crate) fn take_counts(&self) -> (u64, u64) {
+        (
+            self.records.swap(0, Ordering::Relaxed),
+            self.dropped.swap(0, Ordering::Relaxed),
+        )
+    }
+
+    /// Warn, once each time it happens, when the agent's own series are
+    /// more than `max` of `batch`'s; returns whether this batch warned
+    pub(crate) fn check_self_fraction(&self, batch: &TelemetryBatch, max: f64) -> bool {
+        if batch.metrics.is_empty() {
+            return false;
+        }
+        let own = batch.metrics.iter().filter(|m| is_self_metric(m)).count();
+        let fraction = own as f64 / batch.metrics.len() as f64;
+        if fraction <= max {
+            self.over.store(false, Ordering::Relaxed);
+            return false;
+        }
+        if self.over.swap(true, Ordering::Relaxed) {
+            return false;
+        }
+        tracing::warn!(
+            fraction,
+            max,
+            own,
+            total = batch.metrics.len(),
+            "agent metrics over max_self_metric_fraction of the batch; is something recording its pushes?"
+        );
+        true
+    }
+}
+
+fn is_self_metric(metric: &Metric) -> bool {
+    metric.name.starts_with("agent_") || metric.labels.contains_key(QUARANTINE_LABEL)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_only_the_push_loop_is_quarantined() {
+        assert!(!active());
+        assert!(scope(async { active() }).await);
+        // Nor what the push loop spawns
+        let spawned = scope(async { tokio::spawn(async { active() }).await.unwrap() }).await;
+        assert!(!spawned);
+    }
+
+    #[test]
+    fn test_series_are_labeled_and_bounded() {
+        let quarantine = Quarantine::default();
+        quarantine.add_counter("log_lines_total", &[("level", "debug")], 2);
+        quarantine.set_gauge("depth", &[], 3.0);
+        assert_eq!(quarantine.add_gauge("depth", &[], 1.5, false), 4.5);
+        quarantine.record_histogram("push_ms", &[], 12.0);
+        for i in 0..MAX_QUARANTINED_SERIES {
+            quarantine.add_counter(&format!("extra_{}", i), &[], 1);
+        }
+
+        let metrics = quarantine.collect(1);
+        assert_eq!(metrics.len(), MAX_QUARANTINED_SERIES);
+        assert!(metrics.iter().all(|m| m.labels[QUARANTINE_LABEL] == "true"));
+        let lines = metrics
+            .iter()
+            .find(|m| m.name == "log_lines_total")
+            .unwrap();
+        assert_eq!(lines.labels["level"], "debug");
+        assert_eq!(lines.samples[0].value, Some(Value::Counter(2)));
+        let (records, dropped) = quarantine.take_counts();
+        assert_eq!(records, 4 + MAX_QUARANTINED_SERIES as u64);
+        assert_eq!(dropped, 3);
+        assert_eq!(quarantine.take_counts(), (0, 0));
+    }
+
+    #[test]
+    fn test_self_fraction_warns_once_per_excursion() {
+        let metric = |name: &str| Metric {
+            name: name.to_string(),
+            ..Default::default()
+        };
+        let mostly_agent = TelemetryBatch {
+            metrics: vec![
+                metric("agent_push_ms"),
+                metric("agent_batches_sent"),
+                metric("rps"),
+            ],
+            ..Default::default()
+        };
+        let mostly_app = TelemetryBatch {
+            metrics: vec![metric("agent_push_ms"), metric("rps"), metric("latency_ms")],
+            ..Default::default()
+        };
+        let quarantine = Quarantine::default();
+        assert!(quarantine.check_self_fraction(&mostly_agent, 0.5));
+        assert!(!quarantine.check_self_fraction(&mostly_agent, 0.5));
+        assert!(!quarantine.check_self_fraction(&mostly_app, 0.5));
+        assert!(quarantine.check_self_fraction(&mostly_agent, 0.5));
+    }
+}
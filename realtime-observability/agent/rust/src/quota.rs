@@ -0,0 +1,146 @@
+//! Cap on the bytes an agent sends (`Config::bytes_per_hour`)
+//!
+//! A token bucket holding up to an hour's worth of bytes and refilled
+//! continuously at `bytes_per_hour`. Every collected batch is debited its
+//! encoded size before it is queued; a batch that doesn't fit is dropped.
+//! A small slice of the quota is a separate bucket for the quota's own
+//! `agent_quota_*` metrics, so a dropped batch's quota samples can still go
+//! out alone and the limiter stays visible while it is limiting.
+
+use std::time::Instant;
+
+/// Prefix of the metrics `ByteQuota::reserved` pays for
+pub(crate) const QUOTA_METRIC_PREFIX: &str = "agent_quota_";
+
+/// Part of the hourly quota held back for the quota's own metrics
+const RESERVE_FRACTION: u64 = 100;
+const MAX_RESERVE_BYTES: u64 = 4096;
+
+pub(crate) struct ByteQuota {
+    bytes_per_hour: u64,
+    main: Bucket,
+    reserve: Bucket,
+    /// Set while batches are being dropped, to log each exhaustion once
+    exhausted: bool,
+}
+
+impl ByteQuota {
+    pub(crate) fn new(bytes_per_hour: u64, now: Instant) -> Self {
+        let reserve = (bytes_per_hour / RESERVE_FRACTION).min(MAX_RESERVE_BYTES);
+        Self {
+            bytes_per_hour,
+            main: Bucket::new(bytes_per_hour - reserve, now),
+            reserve: Bucket::new(reserve, now),
+            exhausted: false,
+        }
+    }
+
+    /// Bytes batches can still use
+    pub(crate) fn remaining(&mut self, now: Instant) -> u64 {
+        self.main.refill(now);
+        self.main.tokens.max(0.0) as u64
+    }
+
+    /// Debit a batch of `bytes`, or refuse it if the quota can't cover it
+    pub(crate) fn take(&mut self, bytes: usize, now: Instant) -> bool {
+        if self.main.take(bytes, now) {
+            if self.exhausted {
+                self.exhausted = false;
+                tracing::info!("byte quota refilled, sending batches again");
+            }
+            return true;
+        }
+        if !self.exhausted {
+            self.exhausted = true;
+            tracing::warn!(
+                bytes_per_hour = self.bytes_per_hour,
+                "byte quota exhausted, dropping batches until it refills"
+            );
+        }
+        false
+    }
+
+    /// Debit `bytes` of quota metrics from the reserve
+    pub(crate) fn reserved(&mut self, bytes: usize, now: Instant) -> bool {
+        self.reserve.take(bytes, now)
+    }
+
+    /// Debit `bytes` that are sent regardless, such as an announce; the
+    /// batches after it wait until the quota has paid for them
+    pub(crate) fn charge(&mut self, bytes: usize, now: Instant) {
+        self.main.refill(now);
+        self.main.tokens -= bytes as f64;
+    }
+}
+
+/// Holds up to `capacity` bytes, refilled by `capacity` an hour
+struct Bucket {
+    capacity: f64,
+    tokens: f64,
+    refilled_at: Instant,
+}
+
+impl Bucket {
+    fn new(capacity: u64, now: Instant) -> Self {
+        Self {
+            capacity: capacity as f64,
+            tokens: capacity as f64,
+            refilled_at: now,
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let secs = now
+            .saturating_duration_since(self.refilled_at)
+            .as_secs_f64();
+        self.tokens = (self.tokens + secs * self.capacity / 3600.0).min(self.capacity);
+        self.refilled_at = now;
+    }
+
+    fn take(&mut self, bytes: usize, now: Instant) -> bool {
+        self.refill(now);
+        if self.tokens < bytes as f64 {
+            return false;
+        }
+        self.tokens -= bytes as f64;
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_refills_at_the_hourly_rate() {
+        let start = Instant::now();
+        let minute = Duration::from_secs(60);
+        // 1% of it is the reserve
+        let mut quota = ByteQuota::new(60_000, start);
+        assert_eq!(quota.remaining(start), 59_400);
+
+        assert!(quota.take(59_000, start));
+        assert!(!quota.take(1_000, start));
+        // A minute refills a sixtieth of the main bucket
+        assert!(quota.take(1_000, start + minute));
+        assert!(!quota.take(1_000, start + minute));
+        assert_eq!(quota.remaining(start + 2 * minute), 390 + 990);
+
+        // The reserve is untouched by all of that
+        assert!(quota.reserved(600, start + 2 * minute));
+        assert!(!quota.reserved(1, start + 2 * minute));
+    }
+
+    #[test]
+    fn test_charges_go_into_debt() {
+        let start = Instant::now();
+        let mut quota = ByteQuota::new(3_600_000, start);
+        quota.charge(3_600_000, start);
+        assert_eq!(quota.remaining(start), 0);
+        // The 4096 bytes of reserve leave the main bucket 4096 short,
+        // refilling at just under 1000 bytes a second
+        assert!(!quota.take(1, start + Duration::from_secs(4)));
+        assert!(quota.take(1, start + Duration::from_secs(5)));
+    }
+}
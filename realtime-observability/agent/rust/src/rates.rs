@@ -0,0 +1,121 @@
+//! Per-second rates of counters (`Config::emit_counter_rates`)
+//!
+//! An aggregator without a rate function can only store counters as they
+//! arrive, and a dashboard over it wants requests per second. With each
+//! counter the agent then sends a `{name}_per_second` gauge: the counter's
+//! growth since the previous collect over the time that actually passed
+//! between the two, measured rather than taken from the push interval, so
+//! a late or skipped tick spreads its growth over the time it covered.
+//!
+//! A series' first collect has nothing to measure from and sends no rate.
+//! A collect no time has passed since sends none either; its growth
+//! carries over to the next one.
+
+use std::collections::HashMap;
+
+use parking_lot::Mutex;
+use tokio::time::Instant;
+
+/// The previous collect of each counter series, by series key
+#[derive(Default)]
+pub(crate) struct CounterRates {
+    series: Mutex<HashMap<String, Seen>>,
+}
+
+struct Seen {
+    at: Instant,
+    /// Growth held over from collects no time had passed since
+    pending: u64,
+    /// The rate sent last
+    rate: f64,
+}
+
+impl CounterRates {
+    /// Rates per second of the counters collected `now`, from each series'
+    /// growth since its previous collect. Series missing from `deltas`
+    /// are forgotten. With `only_changed`, rates staying at 0 are left out.
+    pub(crate) fn collect<'a>(
+        &self,
+        now: Instant,
+        deltas: impl IntoIterator<Item = (&'a str, u64)>,
+        only_changed: bool,
+    ) -> Vec<(&'a str, f64)> {
+        let mut series = self.series.lock();
+        let mut previous = std::mem::take(&mut *series);
+        let mut rates = Vec::new();
+        for (key, delta) in deltas {
+            let Some((key_owned, seen)) = previous.remove_entry(key) else {
+                let seen = Seen {
+                    at: now,
+                    pending: 0,
+                    rate: 0.0,
+                };
+                series.insert(key.to_string(), seen);
+                continue;
+            };
+            let growth = seen.pending + delta;
+            let elapsed = now.saturating_duration_since(seen.at).as_secs_f64();
+            if elapsed <= 0.0 {
+                let seen = Seen {
+                    pending: growth,
+                    ..seen
+                };
+                series.insert(key_owned, seen);
+                continue;
+            }
+            let rate = growth as f64 / elapsed;
+            if !(only_changed && rate == 0.0 && seen.rate == 0.0) {
+                rates.push((key, rate));
+            }
+            let seen = Seen {
+                at: now,
+                pending: 0,
+                rate,
+            };
+            series.insert(key_owned, seen);
+        }
+        rates
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn test_rates_over_measured_time() {
+        let rates = CounterRates::default();
+        let start = Instant::now();
+        assert_eq!(rates.collect(start, [("a", 5)], false), []);
+
+        let later = start + Duration::from_millis(250);
+        assert_eq!(
+            rates.collect(later, [("a", 10), ("b", 3)], false),
+            [("a", 40.0)]
+        );
+        // No time passed: the growth waits for the next collect
+        assert_eq!(rates.collect(later, [("a", 6), ("b", 1)], false), []);
+        let later = later + Duration::from_secs(2);
+        assert_eq!(
+            rates.collect(later, [("a", 2), ("b", 1)], false),
+            [("a", 4.0), ("b", 1.0)]
+        );
+    }
+
+    #[test]
+    fn test_idle_rates_are_sent_once_when_only_changed() {
+        let rates = CounterRates::default();
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+        rates.collect(at(0), [("a", 1)], true);
+        assert_eq!(rates.collect(at(1), [("a", 2)], true), [("a", 2.0)]);
+        assert_eq!(rates.collect(at(2), [("a", 0)], true), [("a", 0.0)]);
+        assert_eq!(rates.collect(at(3), [("a", 0)], true), []);
+
+        // Forgotten when gone, so a series coming back starts over
+        rates.collect(at(4), [], true);
+        assert_eq!(rates.collect(at(5), [("a", 7)], true), []);
+    }
+}
@@ -0,0 +1,314 @@
+//! Thread-local micro-batching for hot recording loops
+//!
+//! `Agent::record_histogram` takes the registry lock, clones an `Arc` and
+//! does an atomic add per value. A `LocalRecorder` owned by one thread bins
+//! values into plain arrays instead and merges them into the agent's
+//! registries in one pass per series on `flush()`, on drop, and every
+//! `flush_every` records.
+//!
+//! Records are invisible to collection until flushed: a thread that records
+//! and then idles holds them back until it flushes or drops the recorder.
+//! Once `flush()` returns everything recorded before it is in the shared
+//! registries, whatever the thread does next.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use crate::agent::{configured_histogram_in, Agent};
+use crate::switches::Switch;
+use crate::{series, Histogram};
+
+/// Records between automatic flushes unless set with `flush_every`
+pub(crate) const DEFAULT_FLUSH_EVERY: usize = 4096;
+
+/// Buffers records on the calling thread; see `Agent::local`
+pub struct LocalRecorder<'a> {
+    agent: &'a Agent,
+    /// Pending bucket counts by name or labeled series key as recorded;
+    /// `None` for series the agent refused, whose records are dropped until
+    /// the next flush
+    histograms: Vec<(String, Option<LocalHistogram>)>,
+    /// Position in `histograms` by name or key as recorded
+    index: HashMap<String, usize>,
+    /// Position of the series recorded last, checked before hashing since
+    /// hot loops mostly record one series over and over
+    last: usize,
+    counters: HashMap<String, u64>,
+    pending: usize,
+    flush_every: usize,
+}
+
+struct LocalHistogram {
+    /// Series key within the agent's name and label limits
+    key: String,
+    /// The series' histogram when first recorded, for its bounds
+    hist: Arc<Histogram>,
+    enabled: Switch,
+    /// Overflow count last
+    counts: Vec<u64>,
+}
+
+impl<'a> LocalRecorder<'a> {
+    pub(crate) fn new(agent: &'a Agent) -> Self {
+        Self {
+            agent,
+            histograms: Vec::new(),
+            index: HashMap::new(),
+            last: 0,
+            counters: HashMap::new(),
+            pending: 0,
+            flush_every: DEFAULT_FLUSH_EVERY,
+        }
+    }
+
+    /// Flush automatically after this many records (at least one)
+    pub fn flush_every(mut self, records: usize) -> Self {
+        self.flush_every = records.max(1);
+        self
+    }
+
+    /// Buffered `Agent::record_histogram`
+    #[inline]
+    pub fn record_histogram(&mut self, name: &str, value: f64) {
+        if !self.agent.sample(name) {
+            return;
+        }
+        let agent = self.agent;
+        let i = match self.histograms.get(self.last) {
+            Some((key, _)) if key == name => self.last,
+            _ => self.position(name, || agent.metric_name(name).into_owned()),
+        };
+        self.record_at(i, value);
+    }
+
+    /// Buffered `Agent::record_histogram_with`
+    pub fn record_histogram_with(&mut self, name: &str, labels: &[(&str, &str)], value: f64) {
+        if !self.agent.sample(name) {
+            return;
+        }
+        let key = self.agent.series_key(&self.agent.metric_name(name), labels);
+        let i = self.position(&key, || key.clone());
+        self.record_at(i, value);
+    }
+
+    #[inline]
+    fn record_at(&mut self, i: usize, value: f64) {
+        self.last = i;
+        if let Some(local) = self.histograms[i].1.as_mut().filter(|l| l.enabled.is_on()) {
+            local.counts[local.hist.bucket_index(value)] += 1;
+        }
+        self.recorded();
+    }
+
+    /// Position of `key` in `histograms`, adding it as `series_key` if new
+    fn position(&mut self, key: &str, series_key: impl FnOnce() -> String) -> usize {
+        if let Some(&i) = self.index.get(key) {
+            return i;
+        }
+        let local = self.resolve(series_key());
+        self.histograms.push((key.to_string(), local));
+        self.index
+            .insert(key.to_string(), self.histograms.len() - 1);
+        self.histograms.len() - 1
+    }
+
+    /// Buffered `Agent::inc_counter`
+    #[inline]
+    pub fn inc_counter(&mut self, name: &str) {
+        self.add_counter(name, 1);
+    }
+
+    /// Buffered `Agent::add_counter`
+    #[inline]
+    pub fn add_counter(&mut self, name: &str, n: u64) {
+        match self.counters.get_mut(name) {
+            Some(pending) => *pending += n,
+            // Over-long names are counted under their cut form
+            None => {
+                *self
+                    .counters
+                    .entry(self.agent.metric_name(name).into_owned())
+                    .or_insert(0) += n
+            }
+        }
+        self.recorded();
+    }
+
+    /// Buffered `Agent::inc_counter_with`
+    pub fn inc_counter_with(&mut self, name: &str, labels: &[(&str, &str)]) {
+        let key = self.agent.series_key(&self.agent.metric_name(name), labels);
+        *self.counters.entry(key).or_insert(0) += 1;
+        self.recorded();
+    }
+
+    /// Merge everything buffered into the agent's registries
+    pub fn flush(&mut self) {
+        let agent = self.agent;
+        self.index.clear();
+        for (_, local) in self.histograms.drain(..) {
+            let Some(local) = local else { continue };
+            if !agent.memory.has_room(&agent.histograms, &local.key) {
+                continue;
+            }
+            let hist =
+                configured_histogram_in(&agent.histograms, &agent.latency_bounds, &local.key);
+            // The series may have switched bounds since it was first binned
+            hist.merge_counts(&local.hist.bounds(), &local.counts);
+        }
+        for (key, n) in self.counters.drain() {
+            let name = series::name(&key);
+            if !agent.switches.is_on(name)
+                || !agent.admit(name)
+                || !agent.memory.has_room(&agent.counters, &key)
+            {
+                continue;
+            }
+            match agent.counter_series(name, &key) {
+                Ok(counter) => counter.add(n),
+                Err(conflict) => agent.types.warn_once(&conflict),
+            }
+        }
+        self.pending = 0;
+    }
+
+    /// The series' current histogram, or `None` if the agent refuses it
+    fn resolve(&self, key: String) -> Option<LocalHistogram> {
+        let agent = self.agent;
+        if !agent.admit(series::name(&key)) || !agent.memory.has_room(&agent.histograms, &key) {
+            return None;
+        }
+        let hist = match agent.histogram_series(series::name(&key), &key) {
+            Ok(hist) => hist,
+            Err(conflict) => {
+                agent.types.warn_once(&conflict);
+                return None;
+            }
+        };
+        Some(LocalHistogram {
+            counts: vec![0; hist.bounds().len() + 1],
+            hist,
+            enabled: agent.switches.get(series::name(&key)),
+            key,
+        })
+    }
+
+    #[inline]
+    fn recorded(&mut self) {
+        self.pending += 1;
+        if self.pending >= self.flush_every {
+            self.flush();
+        }
+    }
+}
+
+impl Drop for LocalRecorder<'_> {
+    fn drop(&mut self) {
+        self.flush();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::telemetry::metric_sample::Value;
+    use crate::telemetry::TelemetryBatch;
+    use crate::Config;
+
+    fn histogram_counts(batch: &TelemetryBatch, name: &str) -> Vec<u64> {
+        batch
+            .metrics
+            .iter()
+            .filter(|m| m.name == name)
+            .flat_map(|m| &m.samples)
+            .find_map(|s| match &s.value {
+                Some(Value::Histogram(h)) => Some(h.counts.clone()),
+                _ => None,
+            })
+            .unwrap_or_default()
+    }
+
+    fn counter(batch: &TelemetryBatch, name: &str) -> Option<u64> {
+        batch
+            .metrics
+            .iter()
+            .filter(|m| m.name == name)
+            .flat_map(|m| &m.samples)
+            .find_map(|s| match s.value {
+                Some(Value::Counter(v)) => Some(v),
+                _ => None,
+            })
+    }
+
+    #[test]
+    fn test_records_are_held_until_flush() {
+        let agent = Agent::new(Config::default());
+        let mut local = agent.local();
+        local.record_histogram("parse_ms", 3.0);
+        local.record_histogram("parse_ms", 300.0);
+        local.inc_counter("parsed_total");
+
+        let batch = agent.collect_now();
+        assert_eq!(histogram_counts(&batch, "parse_ms").iter().sum::<u64>(), 0);
+        assert_eq!(counter(&batch, "parsed_total"), None);
+
+        local.flush();
+        let batch = agent.collect_now();
+        let counts = histogram_counts(&batch, "parse_ms");
+        assert_eq!(counts[1], 1);
+        assert_eq!(counts[7], 1);
+        assert_eq!(counter(&batch, "parsed_total"), Some(1));
+    }
+
+    #[test]
+    fn test_flushes_on_drop_and_every_n() {
+        let agent = Agent::new(Config::default());
+        {
+            let mut local = agent.local().flush_every(10);
+            for _ in 0..25 {
+                local.add_counter("rows_total", 2);
+            }
+            assert_eq!(agent.counters.lock()["rows_total"].value(), 40);
+        }
+        assert_eq!(agent.counters.lock()["rows_total"].value(), 50);
+    }
+
+    #[test]
+    fn test_flush_rebins_into_new_bounds() {
+        let agent = Agent::new(Config::default());
+        let mut local = agent.local();
+        local.record_histogram_with("parse_ms", &[("kind", "json")], 7.0);
+
+        // Bounds switch between the first record and the flush
+        let key = series::encode("parse_ms", &[("kind", "json")]);
+        let hist = Arc::new(Histogram::with_bounds(&[10.0, 100.0]));
+        agent.histograms.lock().insert(key, hist.clone());
+        local.flush();
+        assert_eq!(hist.counts(), vec![1, 0, 0]);
+    }
+
+    #[test]
+    fn test_flushed_records_survive_a_parked_thread() {
+        let agent = Arc::new(Agent::new(Config::default()));
+        let (flushed_tx, flushed_rx) = std::sync::mpsc::channel();
+        let (park_tx, park_rx) = std::sync::mpsc::channel::<()>();
+        let recorder = {
+            let agent = agent.clone();
+            std::thread::spawn(move || {
+                let mut local = agent.local();
+                for i in 0..100 {
+                    local.record_histogram("work_ms", i as f64);
+                }
+                local.flush();
+                flushed_tx.send(()).unwrap();
+                // Never returns while the test runs, so `local` never drops
+                let _ = park_rx.recv();
+            })
+        };
+
+        flushed_rx.recv().unwrap();
+        let batch = agent.collect_now();
+        assert_eq!(histogram_counts(&batch, "work_ms").iter().sum::<u64>(), 100);
+        drop(park_tx);
+        recorder.join().unwrap();
+    }
+}
@@ -0,0 +1,356 @@
+//! Re-reading the config file while running (`Agent::watch_config`)
+//!
+//! The watcher polls the file's modification time and size; with the
+//! `signal` feature on unix, SIGHUP also reloads it at once. The file is
+//! applied on top of the config in effect, and the result diffed against
+//! it:
+//!
+//! - `metadata`, `metric_filter` and `verbose_push` take effect at once.
+//!   Series a new filter rejects are dropped at the next collect.
+//! - `push_interval` goes to the push loop, which ticks at it from then on.
+//! - A new `aggregator_addr` is connected here, then handed to the push
+//!   loop, which pushes through it from the next batch, announcing again
+//!   under a new connection generation. The new aggregator is asked what
+//!   it accepts, as on start. If the connect fails, the old address stays.
+//! - Any other changed field is logged and ignored; it needs a restart.
+//!
+//! A file that can't be read or parsed leaves everything as it was.
+
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use parking_lot::Mutex;
+use tokio::sync::mpsc;
+
+use crate::agent::Reload;
+use crate::announce::Announcer;
+use crate::capabilities;
+use crate::config_file::merge;
+use crate::filter::SharedFilter;
+use crate::runtime::Transport;
+use crate::{Agent, Config, ConfigFileError, ServerCapabilities};
+
+impl Agent {
+    /// Reload `path` when it changes, checking every `poll_interval`, until
+    /// `stop()`; see `Config::from_file` for its format. Changes to the
+    /// push interval, aggregator address, metadata, metric filter and
+    /// `verbose_push` apply while running, other changes are logged and
+    /// ignored. Must be called within a Tokio runtime. Watching again
+    /// replaces the previous watch.
+    ///
+    /// With the `signal` feature on unix, SIGHUP reloads the file
+    /// regardless of its modification time; the signal then no longer
+    /// terminates the process. Agents on a `TransportPool` ignore interval
+    /// and address changes.
+    pub fn watch_config(
+        &self,
+        path: impl Into<PathBuf>,
+        poll_interval: Duration,
+    ) -> Result<(), ConfigFileError> {
+        let path = path.into();
+        let stamp = stamp(&path).map_err(ConfigFileError::Io)?;
+        let hangup = Hangup::install().map_err(ConfigFileError::Io)?;
+        let watcher = Watcher {
+            path,
+            stamp,
+            current: self.config.clone(),
+            filter: self.filter.clone(),
+            announcer: self.announcer.clone(),
+            verbose_push: self.verbose_push.clone(),
+            reload_tx: self.pool.is_none().then(|| self.reload_tx.clone()),
+            capabilities: self.capabilities.clone(),
+        };
+        let handle = tokio::spawn(watcher.run(poll_interval, hangup));
+        if let Some(previous) = self.config_task.lock().replace(handle) {
+            previous.abort();
+        }
+        Ok(())
+    }
+}
+
+/// What a change to the file changes: modification time and size
+type Stamp = (Option<SystemTime>, u64);
+
+fn stamp(path: &Path) -> io::Result<Stamp> {
+    let metadata = std::fs::metadata(path)?;
+    Ok((metadata.modified().ok(), metadata.len()))
+}
+
+/// Agent handles moved into the watcher task
+struct Watcher {
+    path: PathBuf,
+    stamp: Stamp,
+    /// The config in effect
+    current: Config,
+    filter: Arc<SharedFilter>,
+    announcer: Arc<Announcer>,
+    verbose_push: Arc<AtomicBool>,
+    /// `None` for agents on a `TransportPool`
+    reload_tx: Option<mpsc::UnboundedSender<Reload>>,
+    capabilities: Arc<Mutex<ServerCapabilities>>,
+}
+
+impl Watcher {
+    async fn run(mut self, poll_interval: Duration, mut hangup: Hangup) {
+        let mut poll = tokio::time::interval(poll_interval);
+        poll.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        loop {
+            tokio::select! {
+                _ = poll.tick() => {
+                    // A missing file (mid-swap, say) is checked again next time
+                    match stamp(&self.path) {
+                        Ok(stamp) if stamp != self.stamp => self.stamp = stamp,
+                        _ => continue,
+                    }
+                }
+                () = hangup.recv() => {}
+            }
+            let reloaded = std::fs::read_to_string(&self.path)
+                .map_err(ConfigFileError::Io)
+                .and_then(|text| merge(&self.current, &text));
+            match reloaded {
+                Ok(config) => self.apply(config).await,
+                Err(e) => tracing::warn!(
+                    path = %self.path.display(),
+                    error = %e,
+                    "config reload failed, keeping the current config"
+                ),
+            }
+        }
+    }
+
+    async fn apply(&mut self, new: Config) {
+        let current = &mut self.current;
+        let restart_only = [
+            ("service_name", new.service_name != current.service_name),
+            (
+                "service_version",
+                new.service_version != current.service_version,
+            ),
+            ("instance_id", new.instance_id != current.instance_id),
+            ("push_timeout", new.push_timeout != current.push_timeout),
+            (
+                "connect_timeout",
+                new.connect_timeout != current.connect_timeout,
+            ),
+            (
+                "handshake_timeout",
+                new.handshake_timeout != current.handshake_timeout,
+            ),
+            ("flush_timeout", new.flush_timeout != current.flush_timeout),
+            ("spool_dir", new.spool_dir != current.spool_dir),
+            ("lazy_connect", new.lazy_connect != current.lazy_connect),
+            (
+                "dedicated_thread",
+                new.dedicated_thread != current.dedicated_thread,
+            ),
+            (
+                "stdout_fallback_after",
+                new.stdout_fallback_after != current.stdout_fallback_after,
+            ),
+            (
+                "bytes_per_hour",
+                new.bytes_per_hour != current.bytes_per_hour,
+            ),
+            (
+                "drop_report_interval",
+                new.drop_report_interval != current.drop_report_interval,
+            ),
+            (
+                "checksum_batches",
+                new.checksum_batches != current.checksum_batches,
+            ),
+            (
+                "validate_before_send",
+                new.validate_before_send != current.validate_before_send,
+            ),
+            (
+                "timestamp_resolution",
+                new.timestamp_resolution != current.timestamp_resolution,
+            ),
+            (
+                "delta_timestamps",
+                new.delta_timestamps != current.delta_timestamps,
+            ),
+            ("intern_series", new.intern_series != current.intern_series),
+            ("hash_series", new.hash_series != current.hash_series),
+            (
+                "max_plausible_latency",
+                new.max_plausible_latency != current.max_plausible_latency,
+            ),
+            (
+                "cache_hit_ratio",
+                new.cache_hit_ratio != current.cache_hit_ratio,
+            ),
+            (
+                "emit_counter_rates",
+                new.emit_counter_rates != current.emit_counter_rates,
+            ),
+            ("pull_listener", new.pull_listener != current.pull_listener),
+            (
+                "pull_listener_public",
+                new.pull_listener_public != current.pull_listener_public,
+            ),
+            (
+                "compact_threshold",
+                new.compact_threshold != current.compact_threshold,
+            ),
+            (
+                "min_push_interval",
+                new.min_push_interval != current.min_push_interval,
+            ),
+            (
+                "max_collect_budget",
+                new.max_collect_budget != current.max_collect_budget,
+            ),
+            (
+                "retry_budget_ratio",
+                new.retry_budget_ratio != current.retry_budget_ratio,
+            ),
+            (
+                "retry_budget_reserve",
+                new.retry_budget_reserve != current.retry_budget_reserve,
+            ),
+            ("group_batches", new.group_batches != current.group_batches),
+            ("local_stats", new.local_stats != current.local_stats),
+            (
+                "local_stats_window",
+                new.local_stats_window != current.local_stats_window,
+            ),
+            (
+                "track_state_transitions",
+                new.track_state_transitions != current.track_state_transitions,
+            ),
+            (
+                "incremental_mode",
+                new.incremental_mode != current.incremental_mode,
+            ),
+            (
+                "resync_interval",
+                new.resync_interval != current.resync_interval,
+            ),
+            (
+                "allow_remote_config",
+                new.allow_remote_config != current.allow_remote_config,
+            ),
+            (
+                "max_events_per_batch",
+                new.max_events_per_batch != current.max_events_per_batch,
+            ),
+            (
+                "span_sample_rate",
+                new.span_sample_rate != current.span_sample_rate,
+            ),
+            (
+                "max_spans_per_batch",
+                new.max_spans_per_batch != current.max_spans_per_batch,
+            ),
+            (
+                "max_error_types",
+                new.max_error_types != current.max_error_types,
+            ),
+            (
+                "sanitize_names",
+                new.sanitize_names != current.sanitize_names,
+            ),
+            (
+                "max_self_metric_fraction",
+                new.max_self_metric_fraction != current.max_self_metric_fraction,
+            ),
+        ];
+        for (field, _) in restart_only.iter().filter(|(_, changed)| *changed) {
+            tracing::warn!(field, "config field can't change while running, ignored");
+        }
+
+        let mut applied = Vec::new();
+        if new.metric_filter != current.metric_filter {
+            self.filter.replace(new.metric_filter.clone());
+            current.metric_filter = new.metric_filter;
+            applied.push("metric_filter");
+        }
+        if new.metadata != current.metadata {
+            for key in current.metadata.keys() {
+                if !new.metadata.contains_key(key) {
+                    self.announcer.remove(key);
+                }
+            }
+            for (key, value) in &new.metadata {
+                if current.metadata.get(key) != Some(value) {
+                    self.announcer.set(key, value);
+                }
+            }
+            current.metadata = new.metadata;
+            applied.push("metadata");
+        }
+        if new.verbose_push != current.verbose_push {
+            self.verbose_push.store(new.verbose_push, Ordering::Relaxed);
+            current.verbose_push = new.verbose_push;
+            applied.push("verbose_push");
+        }
+        if let Some(reload_tx) = &self.reload_tx {
+            if new.push_interval < current.min_push_interval {
+                tracing::warn!(
+                    push_interval = ?new.push_interval,
+                    min_push_interval = ?current.min_push_interval,
+                    "reloaded push_interval is below min_push_interval, keeping the current one"
+                );
+            } else if new.push_interval != current.push_interval {
+                let _ = reload_tx.send(Reload::PushInterval(new.push_interval));
+                current.push_interval = new.push_interval;
+                applied.push("push_interval");
+            }
+            if new.aggregator_addr != current.aggregator_addr {
+                let connected = Transport::connect(new.aggregator_addr.clone(), current).await;
+                match connected {
+                    Ok(transport) => {
+                        if current.assume_capabilities.is_none() {
+                            let timeout = tokio::time::sleep(current.push_timeout);
+                            *self.capabilities.lock() =
+                                capabilities::probe(&transport, timeout).await;
+                        }
+                        let _ = reload_tx.send(Reload::Transport {
+                            transport: Box::new(transport),
+                            addr: new.aggregator_addr.clone(),
+                        });
+                        current.aggregator_addr = new.aggregator_addr;
+                        applied.push("aggregator_addr");
+                    }
+                    Err(e) => tracing::warn!(
+                        addr = %new.aggregator_addr,
+                        error = %e,
+                        "can't connect to the reloaded aggregator_addr, keeping the current one"
+                    ),
+                }
+            }
+        }
+        if !applied.is_empty() {
+            tracing::info!(fields = ?applied, "config reloaded");
+        }
+    }
+}
+
+/// SIGHUP, with the `signal` feature on unix; otherwise never fires
+struct Hangup {
+    #[cfg(all(unix, feature = "signal"))]
+    signal: tokio::signal::unix::Signal,
+}
+
+impl Hangup {
+    fn install() -> io::Result<Self> {
+        Ok(Self {
+            #[cfg(all(unix, feature = "signal"))]
+            signal: tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup())?,
+        })
+    }
+
+    async fn recv(&mut self) {
+        #[cfg(all(unix, feature = "signal"))]
+        if self.signal.recv().await.is_some() {
+            return;
+        }
+        std::future::pending().await
+    }
+}
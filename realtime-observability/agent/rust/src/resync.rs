@@ -0,0 +1,79 @@
+//! Full resyncs between incremental batches (`Config::incremental_mode`)
+//!
+//! An incremental batch carries only the `Last` gauges set and the
+//! counters moved since the previous collect; the aggregator keeps the
+//! last value it saw of the others. That holds only while it sees every
+//! batch, and batches do get lost: dropped from a full send queue,
+//! evicted over the memory budget, refused by the byte quota or failed
+//! with the connection. So every `resync_interval`, and on the first
+//! collect after each reconnect, the batch carries every series again and
+//! is marked `full_resync`. A gauge update lost with a batch is therefore
+//! back at the aggregator within one interval, or as soon as the
+//! connection it was lost with is replaced.
+
+use std::time::{Duration, Instant};
+
+use parking_lot::Mutex;
+
+pub(crate) struct Resync {
+    /// `None` outside incremental mode, where every batch is full
+    interval: Option<Duration>,
+    /// When the last full batch was collected, and in which connection
+    /// generation
+    last: Mutex<Option<(Instant, u64)>>,
+}
+
+impl Resync {
+    pub(crate) fn new(incremental: bool, interval: Duration) -> Self {
+        Self {
+            interval: incremental.then_some(interval),
+            last: Mutex::new(None),
+        }
+    }
+
+    /// Whether the batch collected now in connection `generation` must
+    /// carry every series; a `true` counts as the resync done
+    pub(crate) fn is_due(&self, now: Instant, generation: u64) -> bool {
+        let Some(interval) = self.interval else {
+            return true;
+        };
+        let mut last = self.last.lock();
+        let due = match *last {
+            None => true,
+            Some((at, seen)) => seen != generation || now.duration_since(at) >= interval,
+        };
+        if due {
+            *last = Some((now, generation));
+        }
+        due
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const INTERVAL: Duration = Duration::from_secs(30);
+
+    #[test]
+    fn test_resync_on_interval_and_new_generation() {
+        let resync = Resync::new(true, INTERVAL);
+        let start = Instant::now();
+        assert!(resync.is_due(start, 1));
+        assert!(!resync.is_due(start + Duration::from_secs(1), 1));
+        assert!(!resync.is_due(start + Duration::from_secs(29), 1));
+        assert!(resync.is_due(start + INTERVAL, 1));
+        assert!(!resync.is_due(start + INTERVAL, 1));
+        // Reconnected: the aggregator may have missed anything
+        assert!(resync.is_due(start + INTERVAL + Duration::from_secs(1), 2));
+        assert!(!resync.is_due(start + INTERVAL + Duration::from_secs(2), 2));
+    }
+
+    #[test]
+    fn test_every_batch_is_full_outside_incremental_mode() {
+        let resync = Resync::new(false, INTERVAL);
+        let now = Instant::now();
+        assert!(resync.is_due(now, 1));
+        assert!(resync.is_due(now, 1));
+    }
+}
@@ -0,0 +1,102 @@
+//! How often the push loop may push into a failing aggregator
+//! (`Config::retry_budget_ratio`, `Config::retry_budget_reserve`)
+//!
+//! A push made while the previous one failed is a retry, and spends a
+//! token. Successful pushes deposit `ratio` of a token each and every
+//! collected batch deposits `reserve`, up to `MAX_TOKENS`; the bucket
+//! starts full. Without a token, batches wait in the send queue, and the
+//! newest are dropped once it is full. However long an outage lasts, an
+//! agent then pushes into it at `reserve` times its push rate at most, so
+//! thousands of agents don't keep an aggregator that is coming back up
+//! down. Backoff between failures applies as well.
+
+/// Tokens the bucket holds at most, and starts with
+pub(crate) const MAX_TOKENS: f64 = 10.0;
+
+#[derive(Debug)]
+pub(crate) struct RetryBudget {
+    ratio: f64,
+    reserve: f64,
+    tokens: f64,
+    retries: u64,
+    denied: u64,
+}
+
+impl RetryBudget {
+    pub(crate) fn new(ratio: f64, reserve: f64) -> Self {
+        Self {
+            ratio,
+            reserve,
+            tokens: MAX_TOKENS,
+            retries: 0,
+            denied: 0,
+        }
+    }
+
+    pub(crate) fn on_success(&mut self) {
+        self.deposit(self.ratio);
+    }
+
+    pub(crate) fn on_batch(&mut self) {
+        self.deposit(self.reserve);
+    }
+
+    /// Spend a token on a retry, if there is one
+    pub(crate) fn try_retry(&mut self) -> bool {
+        if self.tokens < 1.0 {
+            self.denied += 1;
+            return false;
+        }
+        self.tokens -= 1.0;
+        self.retries += 1;
+        true
+    }
+
+    pub(crate) fn tokens(&self) -> f64 {
+        self.tokens
+    }
+
+    /// Retries made
+    pub(crate) fn retries(&self) -> u64 {
+        self.retries
+    }
+
+    /// Retries held back for want of a token
+    pub(crate) fn denied(&self) -> u64 {
+        self.denied
+    }
+
+    fn deposit(&mut self, tokens: f64) {
+        self.tokens = (self.tokens + tokens).min(MAX_TOKENS);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tokens_come_from_successes_and_batches() {
+        let mut budget = RetryBudget::new(0.5, 0.25);
+        for _ in 0..MAX_TOKENS as usize {
+            assert!(budget.try_retry());
+        }
+        assert!(!budget.try_retry());
+        budget.on_success();
+        budget.on_batch();
+        budget.on_batch();
+        assert!(budget.try_retry());
+        assert!(!budget.try_retry());
+        assert_eq!(budget.retries(), MAX_TOKENS as u64 + 1);
+        assert_eq!(budget.denied(), 2);
+    }
+
+    #[test]
+    fn test_bucket_is_capped() {
+        let mut budget = RetryBudget::new(0.5, 0.25);
+        for _ in 0..100 {
+            budget.on_success();
+        }
+        assert_eq!(budget.tokens(), MAX_TOKENS);
+    }
+}
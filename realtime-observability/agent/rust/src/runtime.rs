@@ -0,0 +1,431 @@
+//! Executor abstraction for the push loop
+//!
+//! The collector and sender only need to spawn tasks and sleep, so they run
+//! on any executor through `Spawner`. Channels come from `tokio::sync`,
+//! which does not need a Tokio runtime. The gRPC transport does: without
+//! `Config::tokio_handle` it uses the runtime `start_on` is called from,
+//! with it every connect and push is driven on that runtime instead.
+//!
+//! ```ignore
+//! // Needs the `smol` crate
+//! use std::time::Duration;
+//! use telemetry_agent::{Agent, BoxFuture, Config, Spawner};
+//!
+//! struct Smol;
+//!
+//! impl Spawner for Smol {
+//!     fn spawn(&self, future: BoxFuture) {
+//!         smol::spawn(future).detach();
+//!     }
+//!
+//!     fn sleep(&self, duration: Duration) -> BoxFuture {
+//!         Box::pin(async move {
+//!             smol::Timer::after(duration).await;
+//!         })
+//!     }
+//! }
+//!
+//! # async fn run(transport: tokio::runtime::Handle) -> Result<(), Box<dyn std::error::Error>> {
+//! let mut agent = Agent::new(Config {
+//!     tokio_handle: Some(transport),
+//!     ..Default::default()
+//! });
+//! agent.start_on(Smol).await?;
+//! # Ok(())
+//! # }
+//! ```
+
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::runtime::Handle;
+use tokio::sync::oneshot;
+use tonic::codegen::http::uri::PathAndQuery;
+use tonic::transport::{Channel, Endpoint};
+use tonic::{IntoStreamingRequest, Response, Status};
+
+use crate::arena::{MetricArena, RecyclingCodec};
+#[cfg(feature = "http")]
+use crate::http::HttpExporter;
+use crate::telemetry::telemetry_ingestor_client::TelemetryIngestorClient;
+use crate::telemetry::{
+    Ack, Capabilities, CapabilitiesRequest, Schema, SchemaRequest, TelemetryBatch,
+};
+use crate::Config;
+
+/// The method `TelemetryIngestorClient::stream_telemetry` calls
+const STREAM_TELEMETRY: &str = "/telemetry.TelemetryIngestor/StreamTelemetry";
+
+pub type BoxFuture = Pin<Box<dyn Future<Output = ()> + Send + 'static>>;
+
+/// Runs the agent's background tasks and timers
+pub trait Spawner: Send + Sync + 'static {
+    /// Run `future` to completion in the background
+    fn spawn(&self, future: BoxFuture);
+
+    /// A future that completes after `duration`
+    fn sleep(&self, duration: Duration) -> BoxFuture;
+}
+
+/// The default spawner, used by `Agent::start`
+#[derive(Clone)]
+pub struct TokioSpawner {
+    handle: Handle,
+}
+
+impl TokioSpawner {
+    /// Spawn onto the runtime this is called from. Panics outside a Tokio
+    /// runtime.
+    pub fn current() -> Self {
+        Self::new(Handle::current())
+    }
+
+    pub fn new(handle: Handle) -> Self {
+        Self { handle }
+    }
+}
+
+impl Spawner for TokioSpawner {
+    fn spawn(&self, future: BoxFuture) {
+        self.handle.spawn(future);
+    }
+
+    fn sleep(&self, duration: Duration) -> BoxFuture {
+        // The timer binds to the runtime it is created in
+        let _guard = self.handle.enter();
+        Box::pin(tokio::time::sleep(duration))
+    }
+}
+
+/// A spawned task that can be awaited and cancelled on any executor
+pub(crate) struct Task {
+    done: oneshot::Receiver<()>,
+    cancel: Option<oneshot::Sender<()>>,
+}
+
+impl Task {
+    pub(crate) fn spawn(
+        spawner: &dyn Spawner,
+        future: impl Future<Output = ()> + Send + 'static,
+    ) -> Self {
+        let (done_tx, done) = oneshot::channel();
+        let (cancel, cancel_rx) = oneshot::channel::<()>();
+        spawner.spawn(Box::pin(async move {
+            // Dropping the task handle detaches; only `abort` cancels
+            let cancelled = async {
+                if cancel_rx.await.is_err() {
+                    std::future::pending::<()>().await;
+                }
+            };
+            tokio::select! {
+                _ = future => {}
+                _ = cancelled => {}
+            }
+            let _ = done_tx.send(());
+        }));
+        Self {
+            done,
+            cancel: Some(cancel),
+        }
+    }
+
+    /// Wait for the task to finish or be cancelled
+    pub(crate) async fn join(&mut self) {
+        let _ = (&mut self.done).await;
+    }
+
+    /// Cancel the task at its next await point
+    pub(crate) fn abort(&mut self) {
+        if let Some(cancel) = self.cancel.take() {
+            let _ = cancel.send(());
+        }
+    }
+}
+
+/// A single-threaded Tokio runtime on an OS thread of its own, for
+/// `Config::dedicated_thread`. Its tasks keep running however busy the
+/// application's runtime is. Dropping it stops the runtime without
+/// waiting; `stop` waits.
+pub(crate) struct DedicatedThread {
+    handle: Handle,
+    stop: Option<oneshot::Sender<()>>,
+    /// Closed when the thread has shut its runtime down
+    exited: oneshot::Receiver<()>,
+    thread: Option<std::thread::JoinHandle<()>>,
+}
+
+impl DedicatedThread {
+    pub(crate) fn spawn() -> std::io::Result<Self> {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()?;
+        let handle = runtime.handle().clone();
+        let (stop, stop_rx) = oneshot::channel::<()>();
+        let (exited_tx, exited) = oneshot::channel::<()>();
+        let thread = std::thread::Builder::new()
+            .name("telemetry-push".to_string())
+            .spawn(move || {
+                // A current-thread runtime only runs its tasks within
+                // `block_on`
+                runtime.block_on(async {
+                    let _ = stop_rx.await;
+                });
+                drop(runtime);
+                drop(exited_tx);
+            })?;
+        Ok(Self {
+            handle,
+            stop: Some(stop),
+            exited,
+            thread: Some(thread),
+        })
+    }
+
+    pub(crate) fn handle(&self) -> Handle {
+        self.handle.clone()
+    }
+
+    /// Shut the runtime down and join the thread, giving up once `sleep`
+    /// completes; a thread still busy then is left to finish on its own.
+    /// Returns whether it was joined.
+    pub(crate) async fn stop(mut self, sleep: BoxFuture) -> bool {
+        if let Some(stop) = self.stop.take() {
+            let _ = stop.send(());
+        }
+        let exited = tokio::select! {
+            _ = &mut self.exited => true,
+            _ = sleep => false,
+        };
+        if let (true, Some(thread)) = (exited, self.thread.take()) {
+            let _ = thread.join();
+        }
+        exited
+    }
+}
+
+impl Drop for DedicatedThread {
+    fn drop(&mut self) {
+        if let Some(stop) = self.stop.take() {
+            let _ = stop.send(());
+        }
+    }
+}
+
+/// The ingestor client, optionally driven on a dedicated Tokio runtime
+#[derive(Clone)]
+pub(crate) struct Transport {
+    client: Client,
+    handle: Option<Handle>,
+}
+
+#[derive(Clone)]
+enum Client {
+    Grpc(Channel),
+    #[cfg(feature = "http")]
+    Http(HttpExporter),
+}
+
+impl Transport {
+    /// Connect to `addr`, on `Config::tokio_handle` if given, giving up on
+    /// the TCP connection after `Config::connect_timeout`. This returns
+    /// before the aggregator has said anything over HTTP/2; see
+    /// `Agent::await_handshake`. `http+post://` addresses only check the
+    /// URL; each push makes its own request.
+    pub(crate) async fn connect(
+        addr: String,
+        config: &Config,
+    ) -> Result<Self, tonic::transport::Error> {
+        let handle = config.tokio_handle.clone();
+        #[cfg(feature = "http")]
+        if addr.starts_with(crate::http::SCHEME) {
+            return Self::http(addr, config, handle);
+        }
+        let endpoint = endpoint(addr, config)?;
+        let channel = match &handle {
+            // The connection's background task lands on `handle` too
+            Some(handle) => handle
+                .spawn(async move { endpoint.connect().await })
+                .await
+                .expect("transport runtime shut down during connect")?,
+            None => endpoint.connect().await?,
+        };
+        Ok(Self {
+            client: Client::Grpc(channel),
+            handle,
+        })
+    }
+
+    /// Like `connect`, but the connection is made on first use
+    pub(crate) fn connect_lazy(
+        addr: String,
+        config: &Config,
+    ) -> Result<Self, tonic::transport::Error> {
+        let handle = config.tokio_handle.clone();
+        #[cfg(feature = "http")]
+        if addr.starts_with(crate::http::SCHEME) {
+            return Self::http(addr, config, handle);
+        }
+        let endpoint = endpoint(addr, config)?;
+        let channel = match &handle {
+            Some(handle) => {
+                let _guard = handle.enter();
+                endpoint.connect_lazy()
+            }
+            None => endpoint.connect_lazy(),
+        };
+        Ok(Self {
+            client: Client::Grpc(channel),
+            handle,
+        })
+    }
+
+    /// Push over a channel the caller built, on `handle` if given
+    pub(crate) fn from_channel(channel: Channel, handle: Option<Handle>) -> Self {
+        Self {
+            client: Client::Grpc(channel),
+            handle,
+        }
+    }
+
+    #[cfg(feature = "http")]
+    fn http(
+        addr: String,
+        config: &Config,
+        handle: Option<Handle>,
+    ) -> Result<Self, tonic::transport::Error> {
+        // Rejects the address exactly as a gRPC one would be
+        Endpoint::from_shared(addr.replacen(crate::http::SCHEME, "http://", 1))?;
+        Ok(Self {
+            client: Client::Http(HttpExporter::new(&addr, &config.http_headers)),
+            handle,
+        })
+    }
+
+    /// Push one stream of batches. Dropping the returned future cancels
+    /// the call, on whichever runtime it runs.
+    pub(crate) async fn push<S>(&self, stream: S) -> Result<Response<Ack>, Status>
+    where
+        S: IntoStreamingRequest<Message = TelemetryBatch> + Send + 'static,
+    {
+        match &self.client {
+            Client::Grpc(channel) => {
+                let mut client = TelemetryIngestorClient::new(channel.clone());
+                self.call(async move { client.stream_telemetry(stream).await })
+                    .await
+            }
+            #[cfg(feature = "http")]
+            Client::Http(http) => {
+                let http = http.clone();
+                let batches = stream.into_streaming_request().into_inner();
+                self.call(async move { http.push(batches).await.map(Response::new) })
+                    .await
+            }
+        }
+    }
+
+    /// `push`, handing each batch to `arena` once encoded, for the next
+    /// collect to reuse. Batches pushed over HTTP aren't recycled.
+    pub(crate) async fn push_recycling<S>(
+        &self,
+        stream: S,
+        arena: Arc<MetricArena>,
+    ) -> Result<Response<Ack>, Status>
+    where
+        S: IntoStreamingRequest<Message = TelemetryBatch> + Send + 'static,
+    {
+        match &self.client {
+            Client::Grpc(channel) => {
+                let mut grpc = tonic::client::Grpc::new(channel.clone());
+                self.call(async move {
+                    grpc.ready()
+                        .await
+                        .map_err(|e| Status::unknown(format!("Service was not ready: {}", e)))?;
+                    let path = PathAndQuery::from_static(STREAM_TELEMETRY);
+                    let request = stream.into_streaming_request();
+                    grpc.client_streaming(request, path, RecyclingCodec(arena))
+                        .await
+                })
+                .await
+            }
+            #[cfg(feature = "http")]
+            Client::Http(_) => self.push(stream).await,
+        }
+    }
+
+    pub(crate) async fn get_schema(&self, service: &str) -> Result<Response<Schema>, Status> {
+        let request = SchemaRequest {
+            service: service.to_string(),
+        };
+        match &self.client {
+            Client::Grpc(channel) => {
+                let mut client = TelemetryIngestorClient::new(channel.clone());
+                self.call(async move { client.get_schema(request).await })
+                    .await
+            }
+            #[cfg(feature = "http")]
+            Client::Http(_) => Err(Status::unimplemented("schemas are only served over gRPC")),
+        }
+    }
+
+    pub(crate) async fn get_capabilities(&self) -> Result<Response<Capabilities>, Status> {
+        match &self.client {
+            Client::Grpc(channel) => {
+                let mut client = TelemetryIngestorClient::new(channel.clone());
+                self.call(async move { client.get_capabilities(CapabilitiesRequest {}).await })
+                    .await
+            }
+            // There is no way to ask over HTTP; POSTed batches were always
+            // decoded whole
+            #[cfg(feature = "http")]
+            Client::Http(_) => Ok(Response::new(Capabilities {
+                events: true,
+                exemplars: true,
+                announce: true,
+                // Nor to ask a POST endpoint to add up timestamps
+                delta_timestamps: false,
+                // Nor whether it predates them
+                spans: false,
+                // Nor whether it keeps series between requests
+                series_ids: false,
+                // Nor whether it shards by series
+                series_hash: false,
+            })),
+        }
+    }
+
+    async fn call<T>(
+        &self,
+        call: impl Future<Output = Result<Response<T>, Status>> + Send + 'static,
+    ) -> Result<Response<T>, Status>
+    where
+        T: Send + 'static,
+    {
+        let Some(handle) = &self.handle else {
+            return call.await;
+        };
+        let mut task = AbortOnDrop(handle.spawn(call));
+        match (&mut task.0).await {
+            Ok(result) => result,
+            Err(e) => Err(Status::cancelled(format!("transport task ended: {}", e))),
+        }
+    }
+}
+
+/// The endpoint for `addr`, as `Config::endpoint_customizer` leaves it
+fn endpoint(addr: String, config: &Config) -> Result<Endpoint, tonic::transport::Error> {
+    let endpoint = Endpoint::from_shared(addr)?.connect_timeout(config.connect_timeout);
+    Ok(match &config.endpoint_customizer {
+        Some(customize) => customize(endpoint),
+        None => endpoint,
+    })
+}
+
+struct AbortOnDrop<T>(tokio::task::JoinHandle<T>);
+
+impl<T> Drop for AbortOnDrop<T> {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
@@ -0,0 +1,209 @@
+//! One-shot mode for short-lived jobs
+//!
+//! `run_scoped` runs a job against a fresh agent with no push loop, then
+//! pushes everything it recorded in one batch, retrying until
+//! `Config::final_flush_timeout`. Long jobs can push partial results along
+//! the way with `Agent::flush`.
+//!
+//! ```no_run
+//! use telemetry_agent::{run_scoped, Config};
+//!
+//! # async fn run() -> Result<(), telemetry_agent::AgentError> {
+//! let report = run_scoped(Config::default(), |agent| async move {
+//!     agent.inc_counter("rows_imported");
+//!     agent.flush().await.ok();
+//!     agent.add_counter("rows_imported", 41);
+//! })
+//! .await?;
+//! if !report.delivered {
+//!     eprintln!("telemetry lost: {:?}", report.error);
+//! }
+//! # Ok(())
+//! # }
+//! ```
+
+use std::future::Future;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use prost::Message;
+
+use crate::agent::{
+    add_counter_in, admit_to_quota, collect_metrics, finish_batch, publish_batch,
+    record_quota_gauge, report_push_error, requeue_events,
+};
+use crate::runtime::Transport;
+use crate::{name, Agent, AgentError, Config, JobReport, PushErrorKind};
+
+/// Wait before the first retry; doubled after every failure
+const INITIAL_BACKOFF: Duration = Duration::from_millis(50);
+const MAX_BACKOFF: Duration = Duration::from_secs(1);
+
+/// One-shot state of an agent created by `run_scoped`
+pub(crate) struct Scoped {
+    transport: Transport,
+    metrics_sent: AtomicUsize,
+}
+
+/// Run `job` against a new agent, then push what it recorded, retrying
+/// for up to `Config::final_flush_timeout`.
+///
+/// No connection is made and nothing is pushed until the job finishes or
+/// calls `Agent::flush`. A failed delivery is reported in the
+/// `JobReport`; `Err` means the configured address is unusable. Must be
+/// called within a Tokio runtime.
+pub async fn run_scoped<F, Fut>(config: Config, job: F) -> Result<JobReport, AgentError>
+where
+    F: FnOnce(Arc<Agent>) -> Fut,
+    Fut: Future<Output = ()>,
+{
+    let addr = config.aggregator_addr.clone();
+    let mut agent = Agent::new(config);
+    let transport = match Transport::connect_lazy(addr, &agent.config) {
+        Ok(transport) => transport,
+        Err(e) => {
+            let kind = PushErrorKind::from_transport_error(&e);
+            report_push_error(&agent.config, &agent.counters, kind, &e);
+            return Err(AgentError {
+                kind,
+                message: e.to_string(),
+            });
+        }
+    };
+    agent.scoped = Some(Scoped {
+        transport,
+        metrics_sent: AtomicUsize::new(0),
+    });
+
+    let agent = Arc::new(agent);
+    job(agent.clone()).await;
+
+    let result = agent.flush().await;
+    let scoped = agent.scoped.as_ref().expect("set above");
+    Ok(JobReport {
+        delivered: result.is_ok(),
+        metrics_sent: scoped.metrics_sent.load(Ordering::Relaxed),
+        error: result.err(),
+    })
+}
+
+impl Agent {
+    /// Push everything recorded so far, retrying for up to
+    /// `Config::final_flush_timeout`, and return the number of metrics
+    /// sent. Only for agents inside `run_scoped`; others push from their
+    /// own loop and get an `Other` error here.
+    ///
+    /// A batch that still fails after retrying is dropped, like a failed
+    /// push in the regular loop; counters and events are sent again with
+    /// the next flush, histogram samples are lost.
+    pub async fn flush(&self) -> Result<usize, AgentError> {
+        let Some(scoped) = &self.scoped else {
+            return Err(AgentError {
+                kind: PushErrorKind::Other,
+                message: "flush is only available inside run_scoped".to_string(),
+            });
+        };
+        let metrics = self
+            .push_collected(&scoped.transport, self.config.final_flush_timeout)
+            .await?;
+        scoped.metrics_sent.fetch_add(metrics, Ordering::Relaxed);
+        Ok(metrics)
+    }
+
+    /// Collect a batch and push it over `transport`, retrying for up to
+    /// `timeout`; returns the number of metrics sent
+    pub(crate) async fn push_collected(
+        &self,
+        transport: &Transport,
+        timeout: Duration,
+    ) -> Result<usize, AgentError> {
+        let config = &self.config;
+        let registries = self.registries();
+        if let Some(quota) = &self.quota {
+            record_quota_gauge(&registries, quota);
+        }
+        let mut batch = collect_metrics(config, &registries);
+        let capabilities = *self.capabilities.lock();
+        let dropped = capabilities.strip(&mut batch);
+        if dropped > 0 {
+            add_counter_in(
+                &self.counters,
+                name!("agent_events_dropped_total"),
+                dropped as u64,
+            );
+        }
+        publish_batch(&self.tap, &self.counters, &batch);
+        if let Some(quota) = &self.quota {
+            // Nothing of the batch left to send counts as nothing flushed
+            match admit_to_quota(&registries, &self.stats, quota, batch) {
+                Some(admitted) => batch = admitted,
+                None => return Ok(0),
+            }
+        }
+        let metrics = batch.metrics.len();
+        let deadline = Instant::now() + timeout;
+        let mut backoff = INITIAL_BACKOFF;
+
+        loop {
+            batch.sent_at_ns = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_nanos() as u64;
+            finish_batch(config, &self.stats, capabilities, None, &mut batch);
+            let announce = match capabilities.announce {
+                true => self.announcer.take_pending(config),
+                false => None,
+            };
+            if let (Some(quota), Some(announce)) = (&self.quota, &announce) {
+                quota.lock().charge(announce.encoded_len(), Instant::now());
+            }
+            let encoded_len = batch.encoded_len()
+                + announce
+                    .as_ref()
+                    .map_or(0, |announce| announce.encoded_len());
+            let message = batch.clone();
+            let stream = async_stream::stream! {
+                if let Some(announce) = announce {
+                    yield announce;
+                }
+                yield message;
+            };
+
+            let attempt_timeout = config
+                .push_timeout
+                .min(deadline.saturating_duration_since(Instant::now()));
+            let result = match tokio::time::timeout(attempt_timeout, transport.push(stream)).await {
+                Ok(result) => result,
+                Err(_) => Err(tonic::Status::deadline_exceeded(format!(
+                    "push not acknowledged within {:?}",
+                    attempt_timeout
+                ))),
+            };
+
+            let e = match result {
+                Ok(_) => {
+                    self.stats.sent(encoded_len);
+                    return Ok(metrics);
+                }
+                Err(e) => e,
+            };
+            self.announcer.mark_pending();
+            let kind = PushErrorKind::from_status(&e);
+            report_push_error(config, &self.counters, kind, &e);
+
+            let retry_in = backoff.min(deadline.saturating_duration_since(Instant::now()));
+            if !kind.is_retryable() || retry_in.is_zero() {
+                self.stats.dropped();
+                requeue_events(&registries, batch.events);
+                return Err(AgentError {
+                    kind,
+                    message: e.message().to_string(),
+                });
+            }
+            tracing::debug!(%kind, error = %e, ?retry_in, "flush failed, retrying");
+            tokio::time::sleep(retry_in).await;
+            backoff = (backoff * 2).min(MAX_BACKOFF);
+        }
+    }
+}
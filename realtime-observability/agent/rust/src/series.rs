@@ -0,0 +1,300 @@
+//! Series keys: a metric name plus labels, flattened into one string
+//!
+//! The registries are keyed by `String`. An unlabeled series is keyed by its
+//! bare name; a labeled one by `name{k=v,k2=v2}` with labels sorted by key
+//! and `\`, `,`, `=` and `}` escaped. Metric names must not contain `{`.
+//!
+//! The same form is what `series_hash` hashes, so an aggregator sharding
+//! by series can check the agent's `Metric::series_hash` or compute it
+//! for metrics sent without one.
+
+use std::collections::BTreeMap;
+
+use smallvec::SmallVec;
+use xxhash_rust::xxh64::Xxh64;
+
+/// Build the registry key for `name` with `labels`
+pub(crate) fn encode(name: &str, labels: &[(&str, &str)]) -> String {
+    if labels.is_empty() {
+        return name.to_string();
+    }
+
+    // Inline for the usual handful of labels: every request's latency key
+    // is built here, and a heap allocation is a good share of its cost
+    let mut sorted: SmallVec<[&(&str, &str); 4]> = labels.iter().collect();
+    sorted.sort_by_key(|(k, _)| *k);
+
+    let mut key = String::with_capacity(name.len() + 2 + labels.len() * 16);
+    key.push_str(name);
+    key.push('{');
+    for (i, (k, v)) in sorted.into_iter().enumerate() {
+        if i > 0 {
+            key.push(',');
+        }
+        escape_into(&mut key, k);
+        key.push('=');
+        escape_into(&mut key, v);
+    }
+    key.push('}');
+    key
+}
+
+/// XXH64, seed 0, of the canonical form of series `name{labels}`: `name`
+/// alone if there are no labels, otherwise `name{k1=v1,k2=v2}` with labels
+/// sorted by key, compared bytewise, and `\`, `,`, `=` and `}` in keys and
+/// values escaped with a leading `\`. If a key is given more than once,
+/// its last value counts, as in a metric's label map. Nothing else is
+/// normalized: names and labels are hashed as UTF-8, case and whitespace
+/// included, and an empty value is still a label.
+///
+/// ```
+/// use telemetry_agent::telemetry::series_hash;
+///
+/// assert_eq!(series_hash("up", &[]), 0x23af_5a21_7b07_8e8f);
+/// assert_eq!(
+///     series_hash("http_requests_total", &[("status", "200"), ("method", "GET")]),
+///     series_hash("http_requests_total", &[("method", "GET"), ("status", "200")]),
+/// );
+/// ```
+pub fn series_hash(name: &str, labels: &[(&str, &str)]) -> u64 {
+    let mut sorted: SmallVec<[&(&str, &str); 4]> = labels.iter().collect();
+    sorted.sort_by_key(|(k, _)| *k);
+    let last_of_key = sorted
+        .iter()
+        .enumerate()
+        .filter(|&(i, (k, _))| sorted.get(i + 1).is_none_or(|(next, _)| next != k))
+        .map(|(_, &&(k, v))| (k, v));
+    hash_sorted(name, last_of_key)
+}
+
+/// `series_hash` of labels already sorted by key, each key once, as in a
+/// `Metric`'s label map
+pub(crate) fn hash_sorted<'a>(
+    name: &str,
+    labels: impl IntoIterator<Item = (&'a str, &'a str)>,
+) -> u64 {
+    let mut hasher = Xxh64::new(0);
+    hasher.update(name.as_bytes());
+    let mut labeled = false;
+    for (k, v) in labels {
+        hasher.update(if labeled { b"," } else { b"{" });
+        labeled = true;
+        update_escaped(&mut hasher, k);
+        hasher.update(b"=");
+        update_escaped(&mut hasher, v);
+    }
+    if labeled {
+        hasher.update(b"}");
+    }
+    hasher.digest()
+}
+
+/// `escape_into`, straight into `hasher`
+fn update_escaped(hasher: &mut Xxh64, s: &str) {
+    let mut rest = s.as_bytes();
+    while let Some(i) = rest
+        .iter()
+        .position(|b| matches!(b, b'\\' | b',' | b'=' | b'}'))
+    {
+        hasher.update(&rest[..i]);
+        hasher.update(b"\\");
+        hasher.update(&rest[i..=i]);
+        rest = &rest[i + 1..];
+    }
+    hasher.update(rest);
+}
+
+fn escape_into(out: &mut String, s: &str) {
+    for c in s.chars() {
+        if matches!(c, '\\' | ',' | '=' | '}') {
+            out.push('\\');
+        }
+        out.push(c);
+    }
+}
+
+/// Metric name part of a key
+pub(crate) fn name(key: &str) -> &str {
+    match key.find('{') {
+        Some(i) => &key[..i],
+        None => key,
+    }
+}
+
+/// Whether `key` is the key of series `name{labels}`, without building it
+pub(crate) fn matches(key: &str, name: &str, labels: &BTreeMap<String, String>) -> bool {
+    let Some(rest) = key.strip_prefix(name) else {
+        return false;
+    };
+    if labels.is_empty() {
+        return rest.is_empty();
+    }
+    let mut rest = rest;
+    let mut open = '{';
+    for (k, v) in labels {
+        let label = rest
+            .strip_prefix(open)
+            .and_then(|rest| strip_escaped(rest, k))
+            .and_then(|rest| rest.strip_prefix('='))
+            .and_then(|rest| strip_escaped(rest, v));
+        match label {
+            Some(after) => rest = after,
+            None => return false,
+        }
+        open = ',';
+    }
+    rest == "}"
+}
+
+/// `key` after `s` as `escape_into` writes it, if it starts with that
+fn strip_escaped<'a>(key: &'a str, s: &str) -> Option<&'a str> {
+    let mut rest = key;
+    for c in s.chars() {
+        if matches!(c, '\\' | ',' | '=' | '}') {
+            rest = rest.strip_prefix('\\')?;
+        }
+        rest = rest.strip_prefix(c)?;
+    }
+    Some(rest)
+}
+
+/// Split a key back into its name and labels
+pub(crate) fn decode(key: &str) -> (String, BTreeMap<String, String>) {
+    let Some(open) = key.find('{') else {
+        return (key.to_string(), BTreeMap::new());
+    };
+
+    let mut labels = BTreeMap::new();
+    let mut current = String::new();
+    let mut pending_key: Option<String> = None;
+    let mut chars = key[open + 1..].chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => {
+                if let Some(escaped) = chars.next() {
+                    current.push(escaped);
+                }
+            }
+            '=' if pending_key.is_none() => pending_key = Some(std::mem::take(&mut current)),
+            ',' | '}' => {
+                if let Some(k) = pending_key.take() {
+                    labels.insert(k, std::mem::take(&mut current));
+                }
+                if c == '}' {
+                    break;
+                }
+            }
+            c => current.push(c),
+        }
+    }
+
+    (key[..open].to_string(), labels)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let key = encode("latency", &[("route", "/a,b"), ("outcome", "x=}\\")]);
+        assert_eq!(key, "latency{outcome=x\\=\\}\\\\,route=/a\\,b}");
+
+        let (name, labels) = decode(&key);
+        assert_eq!(name, "latency");
+        assert_eq!(labels["route"], "/a,b");
+        assert_eq!(labels["outcome"], "x=}\\");
+        assert_eq!(super::name(&key), "latency");
+    }
+
+    #[test]
+    fn test_matches() {
+        let labels = |pairs: &[(&str, &str)]| -> BTreeMap<String, String> {
+            pairs
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect()
+        };
+        let key = encode("latency", &[("route", "/a,b"), ("outcome", "x=}\\")]);
+        let (name, decoded) = decode(&key);
+        assert!(matches(&key, &name, &decoded));
+        assert!(matches("up", "up", &labels(&[])));
+
+        assert!(!matches(&key, "latency", &labels(&[("route", "/a,b")])));
+        assert!(!matches(&key, "latenc", &decoded));
+        assert!(!matches("up", "up", &labels(&[("a", "")])));
+        assert!(!matches("up{a=}", "up", &labels(&[])));
+        assert!(!matches("up{a=b}", "up", &labels(&[("a", "bc")])));
+    }
+
+    #[test]
+    fn test_unlabeled() {
+        assert_eq!(encode("up", &[]), "up");
+        let (name, labels) = decode("up");
+        assert_eq!(name, "up");
+        assert!(labels.is_empty());
+    }
+
+    /// Name, labels as given, and the hash
+    type Vector = (&'static str, &'static [(&'static str, &'static str)], u64);
+
+    /// Aggregators hashing series themselves must match these
+    #[test]
+    fn test_series_hash_vectors() {
+        let vectors: [Vector; 8] = [
+            ("up", &[], 0x23af_5a21_7b07_8e8f),
+            (
+                "http_requests_total",
+                &[("method", "GET"), ("status", "200")],
+                0x6a02_4181_9ef4_14d8,
+            ),
+            (
+                "http_requests_total",
+                &[("status", "200"), ("method", "GET")],
+                0x6a02_4181_9ef4_14d8,
+            ),
+            (
+                "latency",
+                &[("route", "/a,b"), ("outcome", "x=}\\")],
+                0xaf2e_6595_4f22_4718,
+            ),
+            ("jobs_total", &[("queue", "")], 0xf30f_0bdd_5907_16f9),
+            // The last value of a repeated key counts
+            (
+                "jobs_total",
+                &[("queue", "a"), ("queue", "b")],
+                0xf929_157c_c4d5_cd6b,
+            ),
+            (
+                "cpu_seconds_total",
+                &[
+                    ("mode", "idle"),
+                    ("cpu", "0"),
+                    ("host", "web-1.eu-west-1.internal"),
+                ],
+                0x8ecb_1ff1_8590_a48e,
+            ),
+            ("température", &[("ville", "Zürich")], 0x5604_ec02_dad9_69c0),
+        ];
+        for (name, labels, hash) in vectors {
+            assert_eq!(series_hash(name, labels), hash, "{} {:?}", name, labels);
+        }
+    }
+
+    proptest::proptest! {
+        #[test]
+        fn prop_series_hash_is_the_hash_of_the_key(
+            name in "[a-z_]{1,12}",
+            labels in proptest::collection::btree_map("\\PC{0,6}", "\\PC{0,6}", 0..5),
+        ) {
+            let labels: Vec<(&str, &str)> =
+                labels.iter().map(|(k, v)| (k.as_str(), v.as_str())).collect();
+            let key = encode(&name, &labels);
+            let hash = series_hash(&name, &labels);
+            proptest::prop_assert_eq!(hash, xxhash_rust::xxh64::xxh64(key.as_bytes(), 0));
+            let mut reversed = labels.clone();
+            reversed.reverse();
+            proptest::prop_assert_eq!(series_hash(&name, &reversed), hash);
+        }
+    }
+}
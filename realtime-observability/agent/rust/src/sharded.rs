@@ -0,0 +1,121 @@
+//! Sharded counters for extreme write contention
+//!
+//! Each thread is assigned a shard on first use and increments only that
+//! shard's cache-line-padded atomic, so concurrent `inc()` calls from
+//! different threads never touch the same cache line. Shards are summed at
+//! collect time.
+
+use std::cell::Cell;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use crossbeam::utils::CachePadded;
+
+use crate::switches::Switch;
+
+/// Number of shards; threads beyond this share shards round-robin
+pub(crate) const SHARDS: usize = 64;
+
+static NEXT_SHARD: AtomicUsize = AtomicUsize::new(0);
+
+thread_local! {
+    static SHARD: Cell<Option<usize>> = const { Cell::new(None) };
+}
+
+#[inline]
+pub(crate) fn shard_index() -> usize {
+    SHARD.with(|shard| match shard.get() {
+        Some(i) => i,
+        None => {
+            let i = NEXT_SHARD.fetch_add(1, Ordering::Relaxed) % SHARDS;
+            shard.set(Some(i));
+            i
+        }
+    })
+}
+
+pub(crate) struct ShardedCounter {
+    shards: Box<[CachePadded<AtomicU64>]>,
+}
+
+impl ShardedCounter {
+    pub(crate) fn new(initial: u64) -> Self {
+        let shards: Box<[CachePadded<AtomicU64>]> = (0..SHARDS)
+            .map(|_| CachePadded::new(AtomicU64::new(0)))
+            .collect();
+        shards[0].store(initial, Ordering::Relaxed);
+        Self { shards }
+    }
+
+    #[inline]
+    pub(crate) fn add(&self, n: u64) {
+        self.shards[shard_index()].fetch_add(n, Ordering::Relaxed);
+    }
+
+    /// Bytes allocated for the shards
+    pub(crate) fn heap_bytes(&self) -> usize {
+        self.shards.len() * std::mem::size_of::<CachePadded<AtomicU64>>()
+    }
+
+    pub(crate) fn sum(&self) -> u64 {
+        self.shards
+            .iter()
+            .map(|s| s.load(Ordering::Relaxed))
+            .fold(0u64, u64::wrapping_add)
+    }
+}
+
+/// Handle to a sharded counter, from `Agent::sharded_counter`
+#[derive(Clone)]
+pub struct ShardedCounterHandle {
+    pub(crate) counter: Arc<ShardedCounter>,
+    pub(crate) enabled: Switch,
+}
+
+impl ShardedCounterHandle {
+    /// One uncontended `fetch_add` on this thread's shard
+    #[inline]
+    pub fn inc(&self) {
+        self.add(1);
+    }
+
+    #[inline]
+    pub fn add(&self, n: u64) {
+        if !self.enabled.is_on() {
+            return;
+        }
+        self.counter.add(n);
+    }
+
+    /// Current total across shards
+    pub fn get(&self) -> u64 {
+        self.counter.sum()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sharded_sum_across_threads() {
+        let handle = ShardedCounterHandle {
+            counter: Arc::new(ShardedCounter::new(5)),
+            enabled: Switch::default(),
+        };
+        let threads: Vec<_> = (0..16)
+            .map(|_| {
+                let handle = handle.clone();
+                std::thread::spawn(move || {
+                    for _ in 0..10_000 {
+                        handle.inc();
+                    }
+                })
+            })
+            .collect();
+        for t in threads {
+            t.join().unwrap();
+        }
+        assert_eq!(handle.get(), 5 + 16 * 10_000);
+    }
+}
@@ -0,0 +1,112 @@
+//! Flushing on the way out, without the host runtime
+//!
+//! `Agent::stop().await` needs the runtime the agent was started on to
+//! still be running. At the tail of `main`, in a `Drop` or after a signal
+//! began shutting the runtime down, it may not be: the push loop has been
+//! cancelled and its connection closed, and awaiting `stop` deadlocks or
+//! never resumes. `Agent::flush_blocking` instead collects a batch and
+//! pushes it from a thread of its own, over a new connection driven by a
+//! new single-threaded runtime, and blocks the caller until it is done.
+//! `shutdown_guard` does the same when dropped.
+//!
+//! ```no_run
+//! use std::sync::Arc;
+//! use telemetry_agent::{shutdown_guard, Agent, Config};
+//!
+//! #[tokio::main]
+//! async fn main() {
+//!     let mut agent = Agent::new(Config::default());
+//!     agent.start().await.unwrap();
+//!     let agent = Arc::new(agent);
+//!     // Dropped last, once the runtime is gone, whichever way main ends
+//!     let _guard = shutdown_guard(agent.clone());
+//!     // ...
+//! }
+//! ```
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::runtime::Transport;
+use crate::{Agent, AgentError, Config, PushErrorKind};
+
+/// Longest a dropped `ShutdownGuard` holds up the process
+const GUARD_FLUSH_CAP: Duration = Duration::from_secs(2);
+
+/// Flushes its agent with `Agent::flush_blocking` when dropped; from
+/// `shutdown_guard`
+#[must_use = "the flush happens when the guard is dropped"]
+pub struct ShutdownGuard {
+    agent: Arc<Agent>,
+}
+
+/// Hold the returned guard in `main`: when it drops, everything `agent`
+/// recorded since its last push is pushed, waiting at most 2s, whether or
+/// not the runtime `agent` was started on is still running. A failed
+/// flush is logged.
+pub fn shutdown_guard(agent: Arc<Agent>) -> ShutdownGuard {
+    ShutdownGuard { agent }
+}
+
+impl Drop for ShutdownGuard {
+    fn drop(&mut self) {
+        match self.agent.flush_blocking(GUARD_FLUSH_CAP) {
+            Ok(metrics) => tracing::debug!(metrics, "flushed at shutdown"),
+            Err(e) => tracing::warn!(error = %e, "flush at shutdown failed"),
+        }
+    }
+}
+
+impl Agent {
+    /// Push everything recorded since the last push to
+    /// `Config::aggregator_addr` and wait for it, retrying for up to
+    /// `timeout`; returns the number of metrics sent. For teardown paths
+    /// that can't await, or whose runtime may be shutting down: the push
+    /// runs on a thread and runtime of its own, over a new connection.
+    ///
+    /// The agent's own push loop, if still running, is left alone. Blocks
+    /// the calling thread, so don't call it from async code that can
+    /// `stop` instead.
+    pub fn flush_blocking(&self, timeout: Duration) -> Result<usize, AgentError> {
+        std::thread::scope(|scope| {
+            std::thread::Builder::new()
+                .name("telemetry-flush".to_string())
+                .spawn_scoped(scope, || self.flush_on_own_runtime(timeout))
+                .map_err(|e| AgentError {
+                    kind: PushErrorKind::Other,
+                    message: format!("could not start a flush thread: {}", e),
+                })?
+                .join()
+                .unwrap_or_else(|_| {
+                    Err(AgentError {
+                        kind: PushErrorKind::Other,
+                        message: "flush thread panicked".to_string(),
+                    })
+                })
+        })
+    }
+
+    fn flush_on_own_runtime(&self, timeout: Duration) -> Result<usize, AgentError> {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .map_err(|e| AgentError {
+                kind: PushErrorKind::Other,
+                message: format!("could not start a flush runtime: {}", e),
+            })?;
+        // Not on `Config::tokio_handle`, which may be the dying runtime
+        let config = Config {
+            tokio_handle: None,
+            ..self.config.clone()
+        };
+        let flush = async {
+            let transport = Transport::connect_lazy(config.aggregator_addr.clone(), &config)
+                .map_err(|e| AgentError {
+                    kind: PushErrorKind::from_transport_error(&e),
+                    message: e.to_string(),
+                })?;
+            self.push_collected(&transport, timeout).await
+        };
+        runtime.block_on(flush)
+    }
+}
@@ -0,0 +1,166 @@
+//! Operator signals, like most daemons: `kill -USR1` flushes and dumps
+//! diagnostics to the log, `kill -USR2` toggles verbose push logging
+//!
+//! The dump goes out through `tracing` at info level: `Diagnostics`, then
+//! one line per counter and histogram as `into_state` would return them.
+//! Nothing is reset, so the dump doesn't steal samples from the push loop.
+//!
+//! Separately, `install_sigterm_handler` flushes within a termination
+//! grace period on SIGTERM, then exits.
+
+use std::io;
+#[cfg(unix)]
+use std::sync::atomic::{AtomicBool, Ordering};
+#[cfg(unix)]
+use std::sync::Arc;
+use std::time::Duration;
+#[cfg(unix)]
+use std::time::Instant;
+
+#[cfg(unix)]
+use tokio::sync::Notify;
+
+#[cfg(unix)]
+use crate::agent::{diagnostics_of, FinalFlush, Registries, DEDICATED_THREAD_GRACE};
+#[cfg(unix)]
+use crate::diagnostics::PushStats;
+#[cfg(unix)]
+use crate::state::state_of;
+use crate::Agent;
+
+impl Agent {
+    /// Handle SIGUSR1 and SIGUSR2 on the current Tokio runtime until
+    /// `stop()`. SIGUSR1 logs a diagnostics dump and wakes the push loop
+    /// for a batch ahead of its next tick; SIGUSR2 toggles logging of every
+    /// push. Installing again replaces the previous handlers. A no-op on
+    /// non-unix platforms.
+    ///
+    /// Tokio keeps its handler installed after the task ends, so once this
+    /// has been called the signals no longer terminate the process.
+    pub fn install_signal_handlers(&self) -> io::Result<()> {
+        #[cfg(unix)]
+        {
+            use tokio::signal::unix::{signal, SignalKind};
+
+            let mut usr1 = signal(SignalKind::user_defined1())?;
+            let mut usr2 = signal(SignalKind::user_defined2())?;
+            let sink = SignalSink {
+                instance_id: self.config.instance_id.clone(),
+                registries: self.registries(),
+                stats: self.stats.clone(),
+                flush_requests: self.flush_requests.clone(),
+                verbose_push: self.verbose_push.clone(),
+            };
+
+            let handle = tokio::spawn(async move {
+                loop {
+                    tokio::select! {
+                        Some(()) = usr1.recv() => sink.dump_and_flush(),
+                        Some(()) = usr2.recv() => sink.toggle_verbose(),
+                        else => break,
+                    }
+                }
+            });
+            if let Some(previous) = self.signal_task.lock().replace(handle) {
+                previous.abort();
+            }
+        }
+        Ok(())
+    }
+
+    /// On SIGTERM, shut down as `shutdown_with_deadline` would with
+    /// `grace` left, as in a Kubernetes pod's termination grace period,
+    /// log the `ShutdownReport`, then exit the process with status 0.
+    /// Handled on the current Tokio runtime until `stop()`; a `stop()`
+    /// after SIGTERM waits for the same flush. Applications with shutdown
+    /// of their own should call `shutdown_with_deadline` from it instead.
+    /// A no-op on non-unix platforms.
+    ///
+    /// Fails if the agent isn't running its own push loop.
+    pub fn install_sigterm_handler(&self, grace: Duration) -> io::Result<()> {
+        #[cfg(unix)]
+        {
+            use tokio::signal::unix::{signal, SignalKind};
+
+            let Some(shutdown_tx) = self.shutdown_tx.as_ref().map(|tx| tx.downgrade()) else {
+                return Err(io::Error::other("agent is not running its own push loop"));
+            };
+            let mut sigterm = signal(SignalKind::terminate())?;
+            let slot = self.final_flush.clone();
+            let stats = self.stats.clone();
+            let drain = self.drain.clone();
+            let started_at = self.started_at.unwrap_or_else(Instant::now);
+
+            let handle = tokio::spawn(async move {
+                if sigterm.recv().await.is_none() {
+                    return;
+                }
+                let deadline = Instant::now() + grace;
+                let final_flush = {
+                    let mut slot = slot.lock();
+                    // `stop()` got there first, or the agent is gone
+                    let Some(tx) = shutdown_tx.upgrade().filter(|_| slot.is_none()) else {
+                        return;
+                    };
+                    drain.requested.store(true, Ordering::Relaxed);
+                    let final_flush = Arc::new(FinalFlush::with_deadline(deadline));
+                    if tx.try_send(final_flush.clone()).is_err() {
+                        return;
+                    }
+                    *slot = Some(final_flush.clone());
+                    final_flush
+                };
+                tracing::info!(?grace, "SIGTERM: flushing before exit");
+                let stopped = final_flush.done.notified();
+                let limit = tokio::time::Instant::from_std(deadline + DEDICATED_THREAD_GRACE);
+                if tokio::time::timeout_at(limit, stopped).await.is_err() {
+                    tracing::warn!(?grace, "SIGTERM: push loop did not stop in time");
+                }
+                let report = final_flush.report(&stats, &drain, started_at);
+                tracing::info!(?report, "SIGTERM: agent stopped, exiting");
+                std::process::exit(0);
+            });
+            if let Some(previous) = self.sigterm_task.lock().replace(handle) {
+                previous.abort();
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Agent handles moved into the signal task
+#[cfg(unix)]
+struct SignalSink {
+    instance_id: String,
+    registries: Registries,
+    stats: Arc<PushStats>,
+    flush_requests: Arc<Notify>,
+    verbose_push: Arc<AtomicBool>,
+}
+
+#[cfg(unix)]
+impl SignalSink {
+    fn dump_and_flush(&self) {
+        let diagnostics = diagnostics_of(&self.stats, &self.registries);
+        tracing::info!(?diagnostics, "SIGUSR1: agent diagnostics");
+        let state = state_of(&self.instance_id, &self.registries);
+        for (name, value) in &state.counters {
+            tracing::info!(counter = %name, value, "SIGUSR1: metric snapshot");
+        }
+        for (name, hist) in &state.histograms {
+            tracing::info!(
+                histogram = %name,
+                bounds = ?hist.bounds,
+                counts = ?hist.counts,
+                "SIGUSR1: metric snapshot"
+            );
+        }
+        // Stored if the collector is mid-tick, so the flush isn't lost
+        self.flush_requests.notify_one();
+    }
+
+    fn toggle_verbose(&self) {
+        let verbose = !self.verbose_push.fetch_xor(true, Ordering::Relaxed);
+        tracing::info!(verbose, "SIGUSR2: verbose push logging toggled");
+    }
+}
@@ -0,0 +1,216 @@
+//! Service level objectives over a pair of counters
+//!
+//! `Agent::slo("checkout", spec)` maintains `slo_checkout_good_total` and
+//! `slo_checkout_total`; the aggregator sees two plain counters, and
+//! availability is their ratio over whatever range it queries. The handle
+//! also keeps the last `SloSpec::window` of outcomes in memory, bucketed
+//! into 60 slots, so the process can check its own error budget without a
+//! round trip.
+
+use std::time::Duration;
+#[cfg(not(feature = "noop"))]
+use std::{collections::VecDeque, sync::Arc, time::Instant};
+
+#[cfg(not(feature = "noop"))]
+use parking_lot::Mutex;
+
+#[cfg(not(feature = "noop"))]
+use crate::CounterHandle;
+
+/// Objective for `Agent::slo`
+#[derive(Debug, Clone, PartialEq)]
+pub struct SloSpec {
+    /// Fraction of events that must be good, such as 0.999
+    pub objective: f64,
+    /// `SloHandle::record_latency` counts latencies up to this as good;
+    /// `None` counts every latency as good
+    pub latency_threshold_ms: Option<f64>,
+    /// How far back `SloHandle::current_error_budget_remaining` looks
+    pub window: Duration,
+}
+
+impl Default for SloSpec {
+    fn default() -> Self {
+        Self {
+            objective: 0.999,
+            latency_threshold_ms: None,
+            window: Duration::from_secs(3600),
+        }
+    }
+}
+
+/// Slots the local window is divided into; outcomes leave it a slot at a
+/// time
+#[cfg(not(feature = "noop"))]
+const SLOTS: u64 = 60;
+
+/// Handle from `Agent::slo`. Clones share counters and window; a second
+/// `Agent::slo` call for the same name shares the counters only.
+#[cfg(not(feature = "noop"))]
+#[derive(Clone)]
+pub struct SloHandle {
+    pub(crate) good: CounterHandle,
+    pub(crate) total: CounterHandle,
+    pub(crate) objective: f64,
+    pub(crate) latency_threshold_ms: Option<f64>,
+    pub(crate) window: Arc<Mutex<Window>>,
+}
+
+#[cfg(not(feature = "noop"))]
+impl SloHandle {
+    pub(crate) fn new(spec: SloSpec, good: CounterHandle, total: CounterHandle) -> Self {
+        Self {
+            good,
+            total,
+            objective: spec.objective,
+            latency_threshold_ms: spec.latency_threshold_ms,
+            window: Arc::new(Mutex::new(Window::new(spec.window, Instant::now()))),
+        }
+    }
+
+    #[inline]
+    pub fn record_success(&self) {
+        self.record(true);
+    }
+
+    #[inline]
+    pub fn record_failure(&self) {
+        self.record(false);
+    }
+
+    /// Good if `ms` is within `SloSpec::latency_threshold_ms`
+    #[inline]
+    pub fn record_latency(&self, ms: f64) {
+        let good = match self.latency_threshold_ms {
+            Some(threshold) => ms <= threshold,
+            None => true,
+        };
+        self.record(good);
+    }
+
+    fn record(&self, good: bool) {
+        if good {
+            self.good.inc();
+        }
+        self.total.inc();
+        self.window.lock().record(Instant::now(), good);
+    }
+
+    /// Share of the window's error budget left: 1.0 with no failures, 0.0
+    /// once failures reach `1 - objective` of events, negative past that.
+    /// 1.0 with no events in the window.
+    pub fn current_error_budget_remaining(&self) -> f64 {
+        let (good, total) = self.window.lock().totals(Instant::now());
+        budget_remaining(self.objective, good, total)
+    }
+
+    /// How fast the window's failures spend the budget: 1.0 spends exactly
+    /// all of it over the window, 10.0 all of it in a tenth of the window.
+    /// 0.0 with no events in the window.
+    pub fn burn_rate(&self) -> f64 {
+        let (good, total) = self.window.lock().totals(Instant::now());
+        burn_rate(self.objective, good, total)
+    }
+}
+
+#[cfg(not(feature = "noop"))]
+fn budget_remaining(objective: f64, good: u64, total: u64) -> f64 {
+    let bad = total - good;
+    if bad == 0 {
+        return 1.0;
+    }
+    1.0 - bad as f64 / ((1.0 - objective) * total as f64)
+}
+
+#[cfg(not(feature = "noop"))]
+fn burn_rate(objective: f64, good: u64, total: u64) -> f64 {
+    if total == 0 {
+        return 0.0;
+    }
+    (total - good) as f64 / total as f64 / (1.0 - objective)
+}
+
+/// Good and total outcomes per slot, oldest first
+#[cfg(not(feature = "noop"))]
+pub(crate) struct Window {
+    started: Instant,
+    slot_len: Duration,
+    /// (slot index since `started`, good, total)
+    slots: VecDeque<(u64, u64, u64)>,
+}
+
+#[cfg(not(feature = "noop"))]
+impl Window {
+    fn new(window: Duration, now: Instant) -> Self {
+        Self {
+            started: now,
+            slot_len: (window / SLOTS as u32).max(Duration::from_millis(1)),
+            slots: VecDeque::new(),
+        }
+    }
+
+    fn slot(&mut self, now: Instant) -> u64 {
+        let slot = (now.saturating_duration_since(self.started).as_nanos()
+            / self.slot_len.as_nanos()) as u64;
+        while let Some(&(oldest, _, _)) = self.slots.front() {
+            if oldest + SLOTS > slot {
+                break;
+            }
+            self.slots.pop_front();
+        }
+        slot
+    }
+
+    fn record(&mut self, now: Instant, good: bool) {
+        let slot = self.slot(now);
+        match self.slots.back_mut() {
+            Some(last) if last.0 == slot => {
+                last.1 += good as u64;
+                last.2 += 1;
+            }
+            _ => self.slots.push_back((slot, good as u64, 1)),
+        }
+    }
+
+    /// Good and total outcomes in the window ending at `now`
+    fn totals(&mut self, now: Instant) -> (u64, u64) {
+        self.slot(now);
+        self.slots.iter().fold((0, 0), |(good, total), slot| {
+            (good + slot.1, total + slot.2)
+        })
+    }
+}
+
+#[cfg(all(test, not(feature = "noop")))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_budget_math() {
+        assert_eq!(budget_remaining(0.99, 0, 0), 1.0);
+        assert_eq!(budget_remaining(0.99, 1000, 1000), 1.0);
+        assert!((budget_remaining(0.99, 995, 1000) - 0.5).abs() < 1e-9);
+        assert!(budget_remaining(0.99, 990, 1000).abs() < 1e-9);
+        assert!((budget_remaining(0.99, 980, 1000) + 1.0).abs() < 1e-9);
+
+        assert_eq!(burn_rate(0.99, 0, 0), 0.0);
+        assert!((burn_rate(0.99, 990, 1000) - 1.0).abs() < 1e-9);
+        assert!((burn_rate(0.999, 990, 1000) - 10.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_outcomes_leave_the_window() {
+        let start = Instant::now();
+        let minute = Duration::from_secs(60);
+        let mut window = Window::new(Duration::from_secs(3600), start);
+        window.record(start, false);
+        window.record(start + minute, true);
+        window.record(start + minute, true);
+        assert_eq!(window.totals(start + 2 * minute), (2, 3));
+
+        // The first slot leaves an hour after it opened
+        assert_eq!(window.totals(start + 60 * minute - minute / 2), (2, 3));
+        assert_eq!(window.totals(start + 60 * minute), (2, 2));
+        assert_eq!(window.totals(start + 61 * minute), (0, 0));
+    }
+}
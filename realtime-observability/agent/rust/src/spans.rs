@@ -0,0 +1,332 @@
+//! Sampled trace spans (`Agent::start_span`)
+//!
+//! Sampling happens at the head: a root span is kept at
+//! `Config::span_sample_rate`, and its descendants share its decision, so
+//! a trace is sent whole or not at all. An unsampled span takes no ids,
+//! clock reads or allocations. Completed sampled spans are queued for the
+//! next batch, at most `Config::max_spans_per_batch` of them; beyond that
+//! the oldest are dropped and counted in `agent_spans_dropped_total`.
+//!
+//! An aggregator that doesn't report `Capabilities::spans` gets each
+//! sampled span's duration in the `span_duration_ms{span, outcome}`
+//! histogram instead, so its counts are at the sample rate.
+//!
+//! The current span is task-local: spans started from a future run in
+//! `SpanGuard::scope`, or a closure run in `SpanGuard::in_scope`, are its
+//! children.
+
+use std::cell::Cell;
+use std::collections::hash_map::RandomState;
+use std::collections::{BTreeMap, VecDeque};
+use std::future::Future;
+use std::hash::{BuildHasher, Hasher};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use parking_lot::Mutex;
+
+use crate::agent::Latency;
+use crate::capabilities::ServerCapabilities;
+use crate::series;
+use crate::telemetry::{Span, SpanStatus};
+use crate::{Config, Outcome};
+
+/// Histogram sampled spans are recorded into for aggregators without
+/// span support
+pub(crate) const SPAN_DURATION_METRIC: &str = "span_duration_ms";
+
+/// Attributes kept per span; later ones are ignored
+const MAX_SPAN_ATTRIBUTES: usize = 16;
+
+tokio::task_local! {
+    static CURRENT: Parent;
+}
+
+/// What a span started now descends from
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum Parent {
+    /// Nothing: the span is a root, sampled on its own
+    Root,
+    Sampled {
+        trace_id: u128,
+        span_id: u64,
+    },
+    /// A span that wasn't sampled, nor are its descendants
+    Unsampled,
+}
+
+/// The innermost `SpanGuard::scope` or `in_scope` of the running task
+pub(crate) fn current() -> Parent {
+    CURRENT.try_with(|parent| *parent).unwrap_or(Parent::Root)
+}
+
+/// Completed spans waiting for the next batch, shared by every span of
+/// an agent
+pub(crate) struct SpanSink {
+    spans: Mutex<VecDeque<Span>>,
+    cap: usize,
+    rate: f64,
+    dropped: AtomicU64,
+    capabilities: Arc<Mutex<ServerCapabilities>>,
+}
+
+impl SpanSink {
+    pub(crate) fn new(config: &Config, capabilities: Arc<Mutex<ServerCapabilities>>) -> Self {
+        Self {
+            spans: Mutex::new(VecDeque::new()),
+            cap: config.max_spans_per_batch,
+            rate: config.span_sample_rate,
+            dropped: AtomicU64::new(0),
+            capabilities,
+        }
+    }
+
+    /// Whether a new root span is sampled
+    pub(crate) fn sample(&self) -> bool {
+        sampled(self.rate, random())
+    }
+
+    fn push(&self, span: Span) {
+        let mut spans = self.spans.lock();
+        spans.push_back(span);
+        if spans.len() > self.cap {
+            spans.pop_front();
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Everything queued, oldest first
+    pub(crate) fn drain(&self) -> Vec<Span> {
+        self.spans.lock().drain(..).collect()
+    }
+
+    /// Spans dropped since the last call
+    pub(crate) fn take_dropped(&self) -> u64 {
+        self.dropped.swap(0, Ordering::Relaxed)
+    }
+}
+
+/// A span from `Agent::start_span` or `SpanGuard::child`, recorded when
+/// dropped
+#[must_use = "the span ends when the guard is dropped"]
+pub struct SpanGuard {
+    /// `None` if the span isn't sampled
+    active: Option<Box<Active>>,
+}
+
+struct Active {
+    trace_id: u128,
+    span_id: u64,
+    parent_span_id: u64,
+    name: String,
+    start_ns: u64,
+    start: Instant,
+    outcome: Outcome,
+    attributes: BTreeMap<String, String>,
+    sink: Arc<SpanSink>,
+    /// Records into `span_duration_ms` without span support
+    fallback: Latency,
+}
+
+impl SpanGuard {
+    pub(crate) fn unsampled() -> Self {
+        Self { active: None }
+    }
+
+    /// A sampled span, under `parent` if it has one
+    pub(crate) fn start(
+        name: &str,
+        parent: Option<(u128, u64)>,
+        sink: Arc<SpanSink>,
+        fallback: Latency,
+    ) -> Self {
+        let (trace_id, parent_span_id) = match parent {
+            Some(parent) => parent,
+            None => (random_id(), 0),
+        };
+        Self {
+            active: Some(Box::new(Active {
+                trace_id,
+                span_id: random().max(1),
+                parent_span_id,
+                name: name.to_string(),
+                start_ns: SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap()
+                    .as_nanos() as u64,
+                start: Instant::now(),
+                outcome: Outcome::Success,
+                attributes: BTreeMap::new(),
+                sink,
+                fallback,
+            })),
+        }
+    }
+
+    /// A span for part of this one's work, sampled if this one is
+    pub fn child(&self, name: &str) -> SpanGuard {
+        match &self.active {
+            Some(active) => Self::start(
+                name,
+                Some((active.trace_id, active.span_id)),
+                active.sink.clone(),
+                active.fallback.clone(),
+            ),
+            None => Self::unsampled(),
+        }
+    }
+
+    /// Run `future` with this span as the current one: spans it starts
+    /// with `Agent::start_span` are children of this one
+    pub fn scope<F: Future>(&self, future: F) -> impl Future<Output = F::Output> {
+        CURRENT.scope(self.as_parent(), future)
+    }
+
+    /// `scope` for synchronous code
+    pub fn in_scope<R>(&self, f: impl FnOnce() -> R) -> R {
+        CURRENT.sync_scope(self.as_parent(), f)
+    }
+
+    /// Mark the span as failed
+    pub fn fail(&mut self) {
+        self.set_outcome(Outcome::Error);
+    }
+
+    pub fn set_outcome(&mut self, outcome: Outcome) {
+        if let Some(active) = &mut self.active {
+            active.outcome = outcome;
+        }
+    }
+
+    /// Attach `key = value` to the span. A span keeps up to 16
+    /// attributes; attributes are dropped with unsampled spans.
+    pub fn set_attribute(&mut self, key: &str, value: &str) {
+        let Some(active) = &mut self.active else {
+            return;
+        };
+        if active.attributes.len() < MAX_SPAN_ATTRIBUTES || active.attributes.contains_key(key) {
+            active.attributes.insert(key.to_string(), value.to_string());
+        }
+    }
+
+    pub fn is_sampled(&self) -> bool {
+        self.active.is_some()
+    }
+
+    /// The trace's id, if the span is sampled
+    pub fn trace_id(&self) -> Option<u128> {
+        self.active.as_ref().map(|active| active.trace_id)
+    }
+
+    /// The span's id, if it is sampled
+    pub fn span_id(&self) -> Option<u64> {
+        self.active.as_ref().map(|active| active.span_id)
+    }
+
+    fn as_parent(&self) -> Parent {
+        match &self.active {
+            Some(active) => Parent::Sampled {
+                trace_id: active.trace_id,
+                span_id: active.span_id,
+            },
+            None => Parent::Unsampled,
+        }
+    }
+}
+
+impl Drop for SpanGuard {
+    fn drop(&mut self) {
+        let Some(active) = self.active.take() else {
+            return;
+        };
+        let duration = active.start.elapsed();
+        if !active.sink.capabilities.lock().spans {
+            let key = series::encode(
+                &active.fallback.name,
+                &[("span", &active.name), ("outcome", active.outcome.as_str())],
+            );
+            active
+                .fallback
+                .record(&key, duration.as_secs_f64() * 1000.0);
+            return;
+        }
+        let status = match active.outcome {
+            Outcome::Success => SpanStatus::Ok,
+            Outcome::Error => SpanStatus::Error,
+        };
+        let Active {
+            trace_id,
+            span_id,
+            parent_span_id,
+            name,
+            start_ns,
+            attributes,
+            sink,
+            ..
+        } = *active;
+        sink.push(Span {
+            trace_id: trace_id.to_be_bytes().to_vec(),
+            span_id,
+            parent_span_id,
+            name,
+            start_ns,
+            duration_ns: duration.as_nanos() as u64,
+            status: status as i32,
+            attributes,
+        });
+    }
+}
+
+/// Whether `draw`, uniform over `u64`, falls within `rate`
+fn sampled(rate: f64, draw: u64) -> bool {
+    if rate >= 1.0 {
+        return true;
+    }
+    // The top 53 bits, as a fraction in [0, 1)
+    ((draw >> 11) as f64 / (1u64 << 53) as f64) < rate
+}
+
+/// A nonzero trace id
+fn random_id() -> u128 {
+    (((random() as u128) << 64) | random() as u128).max(1)
+}
+
+/// SplitMix64 over a per-thread seed
+fn random() -> u64 {
+    thread_local! {
+        static STATE: Cell<u64> = Cell::new(RandomState::new().build_hasher().finish());
+    }
+    STATE.with(|state| {
+        let next = state.get().wrapping_add(0x9e37_79b9_7f4a_7c15);
+        state.set(next);
+        let mut z = next;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sample_rate_is_respected() {
+        let draws = 100_000;
+        for rate in [0.0, 0.01, 0.1, 0.5] {
+            let kept = (0..draws).filter(|_| sampled(rate, random())).count();
+            let expected = rate * draws as f64;
+            // Four standard deviations of a binomial count
+            let tolerance = 4.0 * (draws as f64 * rate * (1.0 - rate)).sqrt();
+            assert!(
+                (kept as f64 - expected).abs() <= tolerance,
+                "rate {}: kept {} of {}",
+                rate,
+                kept,
+                draws
+            );
+        }
+        assert!((0..1000).all(|_| sampled(1.0, random())));
+    }
+}
@@ -0,0 +1,195 @@
+//! On-disk spool for batches not pushed by the end of shutdown
+//! (`Config::spool_dir`)
+//!
+//! A spool file is a run of records, each `[len: u32][crc32c: u32][bytes]`
+//! with little-endian integers, where `bytes` is `TelemetryBatch::to_bytes`
+//! and the CRC-32C covers `bytes`. Files are written under a temporary name
+//! and renamed into place, so a crash mid-write leaves no partial file
+//! behind; `SpoolReplay` still copes with files that were truncated or
+//! bit-rotted afterwards.
+
+use std::io::{self, Write};
+use std::path::Path;
+
+use crate::codec::crc32c;
+use crate::telemetry::TelemetryBatch;
+
+/// Bytes before each record's payload: its length and checksum
+const HEADER_LEN: usize = 8;
+
+/// `batch` framed as one spool record
+fn frame(batch: &TelemetryBatch) -> Vec<u8> {
+    let bytes = batch.to_bytes();
+    let mut record = Vec::with_capacity(HEADER_LEN + bytes.len());
+    record.extend_from_slice(&(bytes.len() as u32).to_le_bytes());
+    record.extend_from_slice(&crc32c(&bytes).to_le_bytes());
+    record.extend_from_slice(&bytes);
+    record
+}
+
+/// Write `batches` to `path` as one spool file, through a temporary file
+/// renamed into place once synced
+pub(crate) fn write(path: &Path, batches: &[&TelemetryBatch]) -> io::Result<()> {
+    let tmp = path.with_extension("tmp");
+    let written = std::fs::File::create(&tmp).and_then(|mut file| {
+        for batch in batches {
+            file.write_all(&frame(batch))?;
+        }
+        file.sync_all()
+    });
+    match written.and_then(|()| std::fs::rename(&tmp, path)) {
+        Ok(()) => Ok(()),
+        Err(e) => {
+            let _ = std::fs::remove_file(&tmp);
+            Err(e)
+        }
+    }
+}
+
+/// Batches read back from a spool file
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct SpoolReplay {
+    /// Records whose checksum matched, in file order
+    pub batches: Vec<TelemetryBatch>,
+    /// Records skipped because their checksum did not match or, matching,
+    /// they did not decode
+    pub corrupt: usize,
+    /// The file ended inside a record, which was dropped
+    pub truncated: bool,
+}
+
+impl SpoolReplay {
+    /// Read the spool file at `path`
+    pub fn read(path: &Path) -> io::Result<Self> {
+        Ok(Self::parse(&std::fs::read(path)?))
+    }
+
+    /// Read spool records from `bytes`, skipping corrupt ones and stopping
+    /// at a record cut short
+    pub fn parse(bytes: &[u8]) -> Self {
+        let mut replay = Self::default();
+        let mut rest = bytes;
+        while !rest.is_empty() {
+            let Some((header, body)) = rest.split_first_chunk::<HEADER_LEN>() else {
+                replay.truncated = true;
+                break;
+            };
+            let len = u32::from_le_bytes([header[0], header[1], header[2], header[3]]) as usize;
+            let crc = u32::from_le_bytes([header[4], header[5], header[6], header[7]]);
+            // A corrupt length usually lands here too
+            if len > body.len() {
+                replay.truncated = true;
+                break;
+            }
+            let (record, next) = body.split_at(len);
+            rest = next;
+            let batch = match crc32c(record) == crc {
+                true => TelemetryBatch::from_bytes(record).ok(),
+                false => None,
+            };
+            match batch {
+                Some(batch) => replay.batches.push(batch),
+                None => replay.corrupt += 1,
+            }
+        }
+        if replay.corrupt > 0 || replay.truncated {
+            tracing::warn!(
+                corrupt = replay.corrupt,
+                truncated = replay.truncated,
+                recovered = replay.batches.len(),
+                "spool file damaged"
+            );
+        }
+        replay
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Agent, Config};
+
+    fn batches() -> Vec<TelemetryBatch> {
+        let agent = Agent::new(Config::default());
+        (0..3)
+            .map(|i| {
+                agent.add_counter("jobs_total", i + 1);
+                agent.collect_now()
+            })
+            .collect()
+    }
+
+    /// Three framed records and the offset each starts at
+    fn spool(batches: &[TelemetryBatch]) -> (Vec<u8>, Vec<usize>) {
+        let mut bytes = Vec::new();
+        let mut starts = Vec::new();
+        for batch in batches {
+            starts.push(bytes.len());
+            bytes.extend(frame(batch));
+        }
+        (bytes, starts)
+    }
+
+    #[test]
+    fn test_write_then_read_round_trips() {
+        let batches = batches();
+        let dir = std::env::temp_dir().join(format!("agent-spool-rt-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("batches.bin");
+        write(&path, &batches.iter().collect::<Vec<_>>()).unwrap();
+
+        assert!(!path.with_extension("tmp").exists());
+        let replay = SpoolReplay::read(&path).unwrap();
+        assert_eq!(replay.batches, batches);
+        assert_eq!((replay.corrupt, replay.truncated), (0, false));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_flipped_bytes_skip_only_their_record() {
+        let batches = batches();
+        let (bytes, starts) = spool(&batches);
+        let ends = [starts[1], starts[2], bytes.len()];
+        for (damaged, (&start, &end)) in starts.iter().zip(&ends).enumerate() {
+            // The checksum, the first payload byte and the last one
+            for offset in [start + 4, start + HEADER_LEN, end - 1] {
+                let mut corrupt = bytes.clone();
+                corrupt[offset] ^= 0x40;
+                let replay = SpoolReplay::parse(&corrupt);
+
+                let mut expected = batches.clone();
+                expected.remove(damaged);
+                assert_eq!(replay.batches, expected, "byte {} flipped", offset);
+                assert_eq!(replay.corrupt, 1, "byte {} flipped", offset);
+                assert!(!replay.truncated);
+            }
+        }
+    }
+
+    #[test]
+    fn test_truncated_tail_keeps_the_records_before_it() {
+        let batches = batches();
+        let (bytes, starts) = spool(&batches);
+        for (whole, &start) in starts.iter().enumerate() {
+            // Inside the header, right after it, and one byte short
+            let next = starts.get(whole + 1).copied().unwrap_or(bytes.len());
+            for cut in [start + 3, start + HEADER_LEN, next - 1] {
+                let replay = SpoolReplay::parse(&bytes[..cut]);
+                assert_eq!(replay.batches, batches[..whole], "cut at {}", cut);
+                assert_eq!(replay.corrupt, 0);
+                assert!(replay.truncated, "cut at {}", cut);
+            }
+        }
+        assert_eq!(SpoolReplay::parse(&[]), SpoolReplay::default());
+    }
+
+    #[test]
+    fn test_corrupt_length_stops_the_replay() {
+        let batches = batches();
+        let (mut bytes, starts) = spool(&batches);
+        bytes[starts[1] + 3] = 0xff;
+        let replay = SpoolReplay::parse(&bytes);
+        assert_eq!(replay.batches, batches[..1]);
+        assert!(replay.truncated);
+    }
+}
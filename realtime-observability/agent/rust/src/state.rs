@@ -0,0 +1,120 @@
+//! Carry cumulative agent state across restarts within one process
+
+use std::collections::HashMap;
+
+#[cfg(not(feature = "noop"))]
+use crate::agent::{add_counter_in, Registries};
+use crate::Unit;
+#[cfg(not(feature = "noop"))]
+use crate::{series, Agent, Config, Histogram, MetricType};
+#[cfg(not(feature = "noop"))]
+use std::sync::Arc;
+
+/// Snapshot of everything a replacement agent needs to continue where
+/// the previous one stopped. Gauges are not carried over.
+#[derive(Debug, Clone, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct AgentState {
+    pub instance_id: String,
+    pub counters: HashMap<String, u64>,
+    pub histograms: HashMap<String, HistogramState>,
+}
+
+/// Histogram counts not yet pushed, with their bounds
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct HistogramState {
+    pub bounds: Vec<f64>,
+    /// One count per bound plus the overflow bucket
+    pub counts: Vec<u64>,
+    pub unit: Option<Unit>,
+}
+
+/// Counters and unpushed histogram counts in `registries`, without
+/// resetting anything
+#[cfg(not(feature = "noop"))]
+pub(crate) fn state_of(instance_id: &str, registries: &Registries) -> AgentState {
+    let mut counters: HashMap<String, u64> = registries
+        .counters
+        .lock()
+        .iter()
+        .map(|(name, counter)| (name.clone(), counter.value()))
+        .collect();
+    // Sharded counters come back as plain ones and are re-sharded when
+    // the new agent registers them again
+    for (name, counter) in registries.sharded.lock().iter() {
+        counters.insert(name.clone(), counter.sum());
+    }
+
+    let units = registries.units.lock();
+    let histograms = registries
+        .histograms
+        .lock()
+        .iter()
+        .map(|(name, hist)| {
+            let state = HistogramState {
+                bounds: hist.bounds(),
+                counts: hist.counts(),
+                unit: units.get(name).copied(),
+            };
+            (name.clone(), state)
+        })
+        .collect();
+
+    AgentState {
+        instance_id: instance_id.to_string(),
+        counters,
+        histograms,
+    }
+}
+
+#[cfg(not(feature = "noop"))]
+impl Agent {
+    /// Stop this agent and return its counters and histograms.
+    ///
+    /// Dropping the agent ends its push loop; counts recorded after the last
+    /// push are included in the returned state.
+    pub fn into_state(self) -> AgentState {
+        state_of(&self.config.instance_id, &self.registries())
+    }
+
+    /// Create an agent that resumes from a previous agent's state, keeping
+    /// its instance id so the aggregator sees one continuous series
+    pub fn with_state(mut config: Config, state: AgentState) -> Self {
+        if !state.instance_id.is_empty() {
+            config.instance_id = state.instance_id;
+        }
+        let agent = Agent::new(config);
+
+        {
+            for (name, value) in state.counters {
+                if !agent.types.claim(series::name(&name), MetricType::Counter) {
+                    continue;
+                }
+                add_counter_in(&agent.counters, &name, value);
+            }
+        }
+
+        {
+            let mut units = agent.units.lock();
+            let mut histograms = agent.histograms.lock();
+            for (name, hist) in state.histograms {
+                if !agent
+                    .types
+                    .claim(series::name(&name), MetricType::Histogram)
+                {
+                    continue;
+                }
+                if let Some(unit) = hist.unit {
+                    units.insert(name.clone(), unit);
+                }
+                let restored =
+                    Histogram::with_bounds(&hist.bounds).in_epoch(Some(agent.epoch.clone()));
+                restored.add_counts(&hist.counts);
+                histograms.insert(name, Arc::new(restored));
+            }
+        }
+
+        agent
+    }
+}
@@ -0,0 +1,124 @@
+//! State sets (`Agent::set_state`)
+//!
+//! A state machine is sent as one gauge per state it can be in,
+//! `name{state}`, 1 for the current state and 0 for the others (the
+//! Prometheus StateSet pattern), instead of one gauge of magic numbers.
+//! Dashboards show the state by name, and `sum by (state)` counts
+//! instances per state across a fleet.
+//!
+//! The first `set_state` of a name registers its states. Later calls must
+//! pass the same states, and a state outside them is refused.
+
+use std::fmt;
+
+#[cfg(not(feature = "noop"))]
+use crate::GaugeHandle;
+
+/// Returned by `Agent::set_state` for a state or state list that doesn't
+/// match the set's registration
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum InvalidState {
+    /// `state` is not one of the set's states
+    Unknown {
+        name: String,
+        state: String,
+        allowed: Vec<String>,
+    },
+    /// The set is registered with other states than those requested
+    Mismatch {
+        name: String,
+        registered: Vec<String>,
+        requested: Vec<String>,
+    },
+}
+
+impl fmt::Display for InvalidState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            InvalidState::Unknown {
+                name,
+                state,
+                allowed,
+            } => write!(
+                f,
+                "state set {:?} has states {:?}, not {:?}",
+                name, allowed, state
+            ),
+            InvalidState::Mismatch {
+                name,
+                registered,
+                requested,
+            } => write!(
+                f,
+                "state set {:?} is registered with states {:?} but was requested with {:?}",
+                name, registered, requested
+            ),
+        }
+    }
+}
+
+impl std::error::Error for InvalidState {}
+
+/// One registered state set: a gauge per state and which one is current
+#[cfg(not(feature = "noop"))]
+pub(crate) struct StateSet {
+    name: String,
+    states: Box<[String]>,
+    /// One per state, in `states` order
+    gauges: Box<[GaugeHandle]>,
+    current: Option<usize>,
+}
+
+#[cfg(not(feature = "noop"))]
+impl StateSet {
+    pub(crate) fn new(name: &str, states: &[&str], gauges: Box<[GaugeHandle]>) -> Self {
+        Self {
+            name: name.to_string(),
+            states: states.iter().map(|s| s.to_string()).collect(),
+            gauges,
+            current: None,
+        }
+    }
+
+    /// Whether `states` are the ones the set was registered with, in order
+    pub(crate) fn check(&self, states: &[&str]) -> Result<(), InvalidState> {
+        if self
+            .states
+            .iter()
+            .map(String::as_str)
+            .eq(states.iter().copied())
+        {
+            return Ok(());
+        }
+        Err(InvalidState::Mismatch {
+            name: self.name.clone(),
+            registered: self.states.to_vec(),
+            requested: states.iter().map(|s| s.to_string()).collect(),
+        })
+    }
+
+    /// Make `state` the current state, returning the state it replaces, if
+    /// any and different. The caller holds the gauge registry lock so a
+    /// collect never sees the set halfway through.
+    pub(crate) fn enter(&mut self, state: &str) -> Result<Option<&str>, InvalidState> {
+        let Some(index) = self.states.iter().position(|s| s == state) else {
+            return Err(unknown_state(&self.name, state, &self.states));
+        };
+        for (i, gauge) in self.gauges.iter().enumerate() {
+            gauge.set(if i == index { 1.0 } else { 0.0 });
+        }
+        match self.current.replace(index) {
+            Some(previous) if previous != index => Ok(Some(&self.states[previous])),
+            _ => Ok(None),
+        }
+    }
+}
+
+#[cfg(not(feature = "noop"))]
+pub(crate) fn unknown_state(name: &str, state: &str, allowed: &[impl AsRef<str>]) -> InvalidState {
+    InvalidState::Unknown {
+        name: name.to_string(),
+        state: state.to_string(),
+        allowed: allowed.iter().map(|s| s.as_ref().to_string()).collect(),
+    }
+}
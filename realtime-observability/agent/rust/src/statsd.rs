@@ -0,0 +1,310 @@
+//! StatsD / DogStatsD ingestion over UDP
+//!
+//! Supported: `name:value|c`, `|g`, `|ms`, `|h`, an optional `|@rate`
+//! sample rate on counters, and `|#tag:value,...` tags which become labels.
+//! Gauges are absolute; relative `+N`/`-N` gauge updates are rejected.
+//! Lines that fail to parse increment `agent_statsd_errors_total`.
+
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use parking_lot::Mutex;
+use tokio::net::UdpSocket;
+use tokio::task::JoinHandle;
+
+use crate::agent::{
+    add_counter_in, histogram_in, set_gauge_in, CounterRegistry, GaugeRegistry, HistogramRegistry,
+};
+use crate::drops::DropLog;
+use crate::limits::Limits;
+use crate::memory::MemoryAccount;
+use crate::{name, Agent};
+
+/// Pause after a failed receive, doubled while receives keep failing
+const RECV_BACKOFF: Duration = Duration::from_millis(10);
+const MAX_RECV_BACKOFF: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum StatsdKind {
+    Counter,
+    Gauge,
+    Timer,
+    Histogram,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct StatsdLine<'a> {
+    pub name: &'a str,
+    pub value: f64,
+    pub kind: StatsdKind,
+    pub sample_rate: f64,
+    pub tags: Vec<(&'a str, &'a str)>,
+}
+
+/// Parse one StatsD line, returning `None` if it is malformed
+pub(crate) fn parse_line(line: &str) -> Option<StatsdLine<'_>> {
+    let (name, rest) = line.split_once(':')?;
+    if name.is_empty() {
+        return None;
+    }
+
+    let mut parts = rest.split('|');
+    let raw_value = parts.next()?;
+    let kind = match parts.next()? {
+        "c" => StatsdKind::Counter,
+        "g" => StatsdKind::Gauge,
+        "ms" => StatsdKind::Timer,
+        "h" => StatsdKind::Histogram,
+        _ => return None,
+    };
+    if kind == StatsdKind::Gauge && raw_value.starts_with(['+', '-']) {
+        return None;
+    }
+    let value: f64 = raw_value.parse().ok()?;
+    if !value.is_finite() {
+        return None;
+    }
+
+    let mut sample_rate = 1.0;
+    let mut tags = Vec::new();
+    for part in parts {
+        if let Some(rate) = part.strip_prefix('@') {
+            sample_rate = rate.parse().ok().filter(|r: &f64| *r > 0.0 && *r <= 1.0)?;
+        } else if let Some(raw_tags) = part.strip_prefix('#') {
+            for tag in raw_tags.split(',').filter(|t| !t.is_empty()) {
+                // Bare DogStatsD tags have no value
+                tags.push(tag.split_once(':').unwrap_or((tag, "")));
+            }
+        } else {
+            return None;
+        }
+    }
+
+    Some(StatsdLine {
+        name,
+        value,
+        kind,
+        sample_rate,
+        tags,
+    })
+}
+
+impl Agent {
+    /// Listen for StatsD datagrams on `addr` and feed them into this
+    /// agent's registries. Returns the bound address. The listener stops
+    /// with `stop()`, or when the agent is dropped.
+    pub async fn listen_statsd(&self, addr: SocketAddr) -> io::Result<SocketAddr> {
+        let socket = UdpSocket::bind(addr).await?;
+        let local_addr = socket.local_addr()?;
+        let sink = self.statsd_sink();
+
+        let handle = tokio::spawn(async move {
+            let mut buf = vec![0u8; 64 * 1024];
+            // Consecutive failed receives
+            let mut failures = 0;
+            loop {
+                let len = match socket.recv(&mut buf).await {
+                    Ok(len) => {
+                        if failures > 0 {
+                            tracing::info!(failures, "statsd receive recovered");
+                        }
+                        failures = 0;
+                        len
+                    }
+                    Err(e) => {
+                        if failures == 0 {
+                            tracing::warn!(error = %e, "statsd receive failed");
+                        }
+                        failures += 1;
+                        tokio::time::sleep(recv_backoff(failures)).await;
+                        continue;
+                    }
+                };
+                match std::str::from_utf8(&buf[..len]) {
+                    Ok(datagram) => sink.ingest(datagram),
+                    Err(_) => sink.error(),
+                }
+            }
+        });
+        self.statsd_tasks.push(handle);
+
+        Ok(local_addr)
+    }
+
+    fn statsd_sink(&self) -> StatsdSink {
+        StatsdSink {
+            gauges: self.gauges.clone(),
+            counters: self.counters.clone(),
+            histograms: self.histograms.clone(),
+            memory: self.memory.clone(),
+            drops: self.drops.clone(),
+            limits: self.limits,
+        }
+    }
+}
+
+/// Wait before receiving again after the `failures`th failure in a row
+fn recv_backoff(failures: u32) -> Duration {
+    let doublings = failures.saturating_sub(1).min(16);
+    RECV_BACKOFF
+        .saturating_mul(1 << doublings)
+        .min(MAX_RECV_BACKOFF)
+}
+
+/// An agent's listener tasks, which end with `stop()` or, failing that,
+/// when the agent is dropped
+#[derive(Default)]
+pub(crate) struct StatsdTasks(Mutex<Vec<JoinHandle<()>>>);
+
+impl StatsdTasks {
+    fn push(&self, task: JoinHandle<()>) {
+        self.0.lock().push(task);
+    }
+
+    pub(crate) fn abort(&self) {
+        for task in self.0.lock().drain(..) {
+            task.abort();
+        }
+    }
+}
+
+impl Drop for StatsdTasks {
+    fn drop(&mut self) {
+        self.abort();
+    }
+}
+
+/// Registry handles moved into the listener task
+struct StatsdSink {
+    gauges: GaugeRegistry,
+    counters: CounterRegistry,
+    histograms: HistogramRegistry,
+    memory: Arc<MemoryAccount>,
+    drops: Arc<DropLog>,
+    limits: Limits,
+}
+
+impl StatsdSink {
+    fn ingest(&self, datagram: &str) {
+        for line in datagram.lines().filter(|l| !l.trim().is_empty()) {
+            let Some(line) = parse_line(line.trim()) else {
+                self.error();
+                continue;
+            };
+            let name = self.limits.name(&self.counters, &self.drops, line.name);
+            let key = self
+                .limits
+                .key(&self.counters, &self.drops, &name, &line.tags);
+            match line.kind {
+                StatsdKind::Counter => {
+                    let n = (line.value / line.sample_rate).round();
+                    if n < 0.0 {
+                        self.error();
+                    } else if self.memory.has_room(&self.counters, &key) {
+                        add_counter_in(&self.counters, &key, n as u64);
+                    }
+                }
+                StatsdKind::Gauge => {
+                    if self.memory.has_room(&self.gauges, &key) {
+                        set_gauge_in(&self.gauges, &key, line.value);
+                    }
+                }
+                StatsdKind::Timer | StatsdKind::Histogram => {
+                    if self.memory.has_room(&self.histograms, &key) {
+                        histogram_in(&self.histograms, &key).record(line.value);
+                    }
+                }
+            }
+        }
+    }
+
+    fn error(&self) {
+        add_counter_in(&self.counters, name!("agent_statsd_errors_total"), 1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::telemetry::metric_sample::Value;
+    use crate::Config;
+
+    #[test]
+    fn test_parse_line() {
+        let line = parse_line("requests:3|c|@0.5|#env:prod,canary").unwrap();
+        assert_eq!(line.name, "requests");
+        assert_eq!(line.value, 3.0);
+        assert_eq!(line.kind, StatsdKind::Counter);
+        assert_eq!(line.sample_rate, 0.5);
+        assert_eq!(line.tags, vec![("env", "prod"), ("canary", "")]);
+
+        assert_eq!(
+            parse_line("db.query:12.5|ms").unwrap().kind,
+            StatsdKind::Timer
+        );
+        assert!(parse_line("no_type:1").is_none());
+        assert!(parse_line("bad:abc|c").is_none());
+        assert!(parse_line("depth:+1|g").is_none());
+        assert!(parse_line(":1|c").is_none());
+        assert!(parse_line("x:1|c|@2").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_listen_statsd() {
+        let agent = Agent::new(Config::default());
+        let addr = agent
+            .listen_statsd("127.0.0.1:0".parse().unwrap())
+            .await
+            .unwrap();
+
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        client
+            .send_to(
+                b"hits:2|c|#route:/a\nqueue:7|g\nrender:12|ms\ngarbage",
+                addr,
+            )
+            .await
+            .unwrap();
+
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        let batch = agent.collect_now();
+        let find = |name: &str| batch.metrics.iter().find(|m| m.name == name).unwrap();
+
+        let hits = find("hits");
+        assert_eq!(hits.labels["route"], "/a");
+        assert_eq!(hits.samples[0].value, Some(Value::Counter(2)));
+        assert_eq!(find("queue").samples[0].value, Some(Value::Gauge(7.0)));
+        assert!(matches!(
+            find("render").samples[0].value,
+            Some(Value::Histogram(_))
+        ));
+        assert_eq!(
+            find("agent_statsd_errors_total").samples[0].value,
+            Some(Value::Counter(1))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_listener_ends_with_the_agent() {
+        let agent = Agent::new(Config::default());
+        let addr = agent
+            .listen_statsd("127.0.0.1:0".parse().unwrap())
+            .await
+            .unwrap();
+        // Never stopped: the port is free again once the task is gone
+        drop(agent);
+        let deadline = std::time::Instant::now() + Duration::from_secs(5);
+        while UdpSocket::bind(addr).await.is_err() {
+            assert!(std::time::Instant::now() < deadline, "listener leaked");
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    }
+
+    #[test]
+    fn test_recv_backoff_doubles_up_to_a_second() {
+        let backoffs: Vec<_> = (1..=9).map(|n| recv_backoff(n).as_millis()).collect();
+        assert_eq!(backoffs, [10, 20, 40, 80, 160, 320, 640, 1000, 1000]);
+    }
+}
@@ -0,0 +1,150 @@
+//! Runtime on/off switches per metric name
+//!
+//! Every handle holds its metric's `Switch` and checks it before anything
+//! else, so a disabled handle costs one relaxed load. The string API finds
+//! the switch cached with the series it looks up anyway, also one relaxed
+//! load; only a record creating a series looks the switch up by name, and
+//! that only once some metric has been disabled.
+//!
+//! A switch covers every label set of its name. Disabled metrics stay
+//! registered: their series are left out of batches and pick up where they
+//! were when switched back on.
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, OnceLock};
+
+use parking_lot::Mutex;
+
+/// Whether one metric name records; on unless switched off
+#[derive(Clone)]
+pub(crate) struct Switch(Arc<AtomicBool>);
+
+impl Switch {
+    #[inline]
+    pub(crate) fn is_on(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+impl Default for Switch {
+    fn default() -> Self {
+        Self(Arc::new(AtomicBool::new(true)))
+    }
+}
+
+/// A series' copy of its metric's `Switch`, looked up on first use
+#[derive(Default)]
+pub(crate) struct CachedSwitch(OnceLock<Switch>);
+
+impl CachedSwitch {
+    #[inline]
+    pub(crate) fn is_on(&self, switches: &Switches, name: &str) -> bool {
+        self.0.get_or_init(|| switches.get(name)).is_on()
+    }
+}
+
+/// A series caching its metric's switch
+pub(crate) trait Switched {
+    fn switch(&self) -> &CachedSwitch;
+}
+
+#[derive(Default)]
+pub(crate) struct Switches {
+    by_name: Mutex<HashMap<String, Switch>>,
+    /// `set_prefix` calls in order, for names not seen yet; a later call
+    /// for the same prefix replaces the earlier one
+    prefixes: Mutex<Vec<(String, bool)>>,
+    /// Set once anything is switched off, and never cleared; until then
+    /// `is_on` needs no lookup
+    any_off: AtomicBool,
+}
+
+impl Switches {
+    /// The switch for metric `name`, created from the latest matching
+    /// prefix rule if new
+    pub(crate) fn get(&self, name: &str) -> Switch {
+        let mut by_name = self.by_name.lock();
+        if let Some(switch) = by_name.get(name) {
+            return switch.clone();
+        }
+        let switch = Switch(Arc::new(AtomicBool::new(self.rule(name))));
+        by_name.insert(name.to_string(), switch.clone());
+        switch
+    }
+
+    /// Whether metric `name` records; for paths without a handle
+    #[inline]
+    pub(crate) fn is_on(&self, name: &str) -> bool {
+        if !self.any_off.load(Ordering::Relaxed) {
+            return true;
+        }
+        self.get(name).is_on()
+    }
+
+    /// Hold the name table, as a lookup by name does
+    #[cfg(test)]
+    pub(crate) fn lock(&self) -> impl Drop + '_ {
+        self.by_name.lock()
+    }
+
+    fn rule(&self, name: &str) -> bool {
+        self.prefixes
+            .lock()
+            .iter()
+            .rev()
+            .find(|(prefix, _)| name.starts_with(prefix.as_str()))
+            .is_none_or(|&(_, on)| on)
+    }
+
+    pub(crate) fn set(&self, name: &str, on: bool) {
+        if !on {
+            self.any_off.store(true, Ordering::Relaxed);
+        }
+        self.get(name).0.store(on, Ordering::Relaxed);
+    }
+
+    /// Switch every metric starting with `prefix`, including ones first
+    /// used later
+    pub(crate) fn set_prefix(&self, prefix: &str, on: bool) {
+        if !on {
+            self.any_off.store(true, Ordering::Relaxed);
+        }
+        {
+            let mut prefixes = self.prefixes.lock();
+            prefixes.retain(|(p, _)| p != prefix);
+            prefixes.push((prefix.to_string(), on));
+        }
+        for (name, switch) in self.by_name.lock().iter() {
+            if name.starts_with(prefix) {
+                switch.0.store(on, Ordering::Relaxed);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_latest_rule_wins() {
+        let switches = Switches::default();
+        let early = switches.get("debug_io_wait");
+        assert!(switches.is_on("debug_io_wait"));
+
+        switches.set_prefix("debug_", false);
+        assert!(!early.is_on());
+        assert!(!switches.is_on("debug_parse"));
+        assert!(switches.is_on("requests_total"));
+
+        switches.set_prefix("debug_io_", true);
+        assert!(early.is_on());
+        assert!(switches.is_on("debug_io_read"));
+        assert!(!switches.is_on("debug_parse"));
+
+        switches.set("debug_io_read", false);
+        assert!(!switches.is_on("debug_io_read"));
+        assert!(switches.is_on("debug_io_wait"));
+    }
+}
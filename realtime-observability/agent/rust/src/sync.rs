@@ -0,0 +1,124 @@
+//! Atomics behind the recording hot path, and what they guarantee
+//!
+//! Recording never takes a lock, so every metric is a handful of atomics
+//! read and reset by the collector. The guarantees:
+//!
+//! - **Exactly once.** A histogram sample or counter increment is counted
+//!   in exactly one batch, even when it races with collection: increments
+//!   and the collector's reset are single read-modify-write operations on
+//!   the same atomic.
+//! - **Recorded before collection, collected.** A sample recorded on some
+//!   thread before that thread hands off to the collector (`collect_now` on
+//!   the same thread, or any signal that synchronizes with the collector,
+//!   such as a channel send the collector receives) is in the batch that
+//!   collection produces. The hand-off is the happens-before edge; the
+//!   buckets themselves need no ordering stronger than `Relaxed`.
+//! - **Windowed gauges.** A set is published with `Release` on the window's
+//!   sample count and read back with `Acquire`, so a collect that sees the
+//!   count also sees the value. A collect that sees no count leaves the
+//!   value untouched for the next window.
+//! - **One cut per batch.** Within an agent, counters, histograms and
+//!   windowed gauges are drained at one epoch boundary (see `epoch`): a
+//!   write that finished before collection started is in the batch, one
+//!   that started after is not, across all three registries and across the
+//!   buckets of a histogram.
+//! - **Bound switches.** A histogram given new bounds at a snapshot
+//!   forwards later records to its replacement. Records that raced the
+//!   switch into the old one go out with the next batch, in the new bounds.
+//!
+//! Not guaranteed:
+//!
+//! - Samples recorded concurrently with collection, with no hand-off, may
+//!   land in either the current or the next batch.
+//! - Two writes of one request are cut separately, so a request whose
+//!   counter increment and histogram sample straddle the boundary shows up
+//!   in two batches.
+//! - Outside an agent, histograms and windowed gauges have no epoch: buckets
+//!   are reset one at a time, and a windowed-gauge set racing `take` can be
+//!   split across two windows, its value in one and its count in the next.
+//! - `Last` gauges, the inflight gauge, push statistics and sharded counters
+//!   are plain `Relaxed` values read at collect time, not at the cut: each
+//!   read is some value the metric held, with no ordering against other
+//!   metrics.
+//!
+//! The histogram and gauge atomics come from here so the loom tests below
+//! can model them; run those with
+//! `RUSTFLAGS="--cfg loom" cargo test --release --lib sync::`.
+
+#[cfg(loom)]
+pub(crate) use loom::sync::atomic::{AtomicU64, AtomicU8};
+#[cfg(not(loom))]
+pub(crate) use std::sync::atomic::{AtomicU64, AtomicU8};
+
+#[cfg(all(test, loom))]
+mod tests {
+    use loom::sync::atomic::AtomicBool;
+    use loom::sync::Arc;
+    use loom::thread;
+    use std::sync::atomic::Ordering;
+
+    use crate::gauge::Gauge;
+    use crate::{GaugeAggregation, Histogram};
+
+    fn total(hist: &Histogram) -> u64 {
+        hist.snapshot_and_reset().1.iter().sum()
+    }
+
+    #[test]
+    fn test_record_racing_snapshot_counts_once() {
+        loom::model(|| {
+            let hist = Arc::new(Histogram::with_bounds(&[1.0]));
+            let recorder = {
+                let hist = hist.clone();
+                thread::spawn(move || {
+                    hist.record(0.5);
+                    hist.record(2.0);
+                })
+            };
+            let first = total(&hist);
+            recorder.join().unwrap();
+
+            assert_eq!(first + total(&hist), 2);
+        });
+    }
+
+    #[test]
+    fn test_record_before_handoff_is_collected() {
+        loom::model(|| {
+            let hist = Arc::new(Histogram::with_bounds(&[1.0]));
+            let flushed = Arc::new(AtomicBool::new(false));
+            let recorder = {
+                let (hist, flushed) = (hist.clone(), flushed.clone());
+                thread::spawn(move || {
+                    hist.record(0.5);
+                    // Stands in for whatever wakes the collector
+                    flushed.store(true, Ordering::Release);
+                })
+            };
+            if flushed.load(Ordering::Acquire) {
+                assert_eq!(total(&hist), 1);
+            }
+            recorder.join().unwrap();
+        });
+    }
+
+    #[test]
+    fn test_windowed_gauge_value_follows_count() {
+        loom::model(|| {
+            let gauge = Arc::new(Gauge::new(GaugeAggregation::Max));
+            let setter = {
+                let gauge = gauge.clone();
+                thread::spawn(move || gauge.set(5.0))
+            };
+            let first = gauge.take();
+            setter.join().unwrap();
+            let second = gauge.take();
+
+            // Exactly one window reports the value, never the identity
+            match (first, second) {
+                (Some(v), None) | (None, Some(v)) => assert_eq!(v, 5.0),
+                other => panic!("unexpected windows {:?}", other),
+            }
+        });
+    }
+}
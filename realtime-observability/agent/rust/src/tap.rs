@@ -0,0 +1,82 @@
+//! Collected batches for in-process consumers (`Agent::subscribe_batches`)
+//!
+//! The push loop hands each batch it collects to a broadcast channel
+//! before sending it, so subscribers see it whether or not the push
+//! succeeds. Sending never waits: a subscriber more than `CAPACITY`
+//! batches behind loses the oldest and gets `RecvError::Lagged` for them,
+//! and each batch lost that way is counted in
+//! `agent_batch_tap_lagged_total`.
+
+use std::sync::Arc;
+
+use tokio::sync::broadcast;
+
+use crate::telemetry::TelemetryBatch;
+
+/// Batches kept for the slowest subscriber
+const CAPACITY: usize = 16;
+
+pub(crate) struct BatchTap {
+    sender: broadcast::Sender<Arc<TelemetryBatch>>,
+}
+
+impl Default for BatchTap {
+    fn default() -> Self {
+        let (sender, _) = broadcast::channel(CAPACITY);
+        BatchTap { sender }
+    }
+}
+
+impl BatchTap {
+    pub(crate) fn subscribe(&self) -> broadcast::Receiver<Arc<TelemetryBatch>> {
+        self.sender.subscribe()
+    }
+
+    /// Hand `batch` to every subscriber; returns whether that pushed out a
+    /// batch some subscriber had not received yet. Free without
+    /// subscribers.
+    pub(crate) fn publish(&self, batch: &TelemetryBatch) -> bool {
+        if self.sender.receiver_count() == 0 {
+            return false;
+        }
+        let overwrites = self.sender.len() >= CAPACITY;
+        // Fails only if the last subscriber went away since the check
+        let _ = self.sender.send(Arc::new(batch.clone()));
+        overwrites
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn batch(seq: u64) -> TelemetryBatch {
+        TelemetryBatch {
+            sent_at_ns: seq,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_publish_without_subscribers_is_free() {
+        let tap = BatchTap::default();
+        assert!(!tap.publish(&batch(1)));
+        let mut late = tap.subscribe();
+        assert!(late.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_lagging_subscriber_loses_the_oldest() {
+        let tap = BatchTap::default();
+        let mut slow = tap.subscribe();
+        let lost = (0..CAPACITY as u64 + 3)
+            .filter(|&seq| tap.publish(&batch(seq)))
+            .count();
+        assert_eq!(lost, 3);
+        assert!(matches!(
+            slow.try_recv(),
+            Err(broadcast::error::TryRecvError::Lagged(3))
+        ));
+        assert_eq!(slow.try_recv().unwrap().sent_at_ns, 3);
+    }
+}
@@ -0,0 +1,146 @@
+//! Counters fed from totals kept elsewhere (`Agent::set_monotonic_total`)
+//!
+//! Setting a total only stores it. Each collect compares the latest total
+//! with the one the previous collect saw and adds the growth to the
+//! series' counter, which reports it like any other increment. The first
+//! total seen is only a starting point; a total lower than the previous
+//! one means its source was reset, handled per `TotalReset`.
+
+use std::collections::HashMap;
+
+use parking_lot::Mutex;
+
+use crate::TotalReset;
+
+/// Latest totals by series key
+#[derive(Default)]
+pub(crate) struct Totals {
+    series: Mutex<HashMap<String, Total>>,
+}
+
+struct Total {
+    latest: u64,
+    /// What the growth is measured from; `None` until the first collect
+    baseline: Option<u64>,
+}
+
+impl Totals {
+    pub(crate) fn set(&self, key: &str, value: u64) {
+        let mut series = self.series.lock();
+        match series.get_mut(key) {
+            Some(total) => total.latest = value,
+            None => {
+                series.insert(
+                    key.to_string(),
+                    Total {
+                        latest: value,
+                        baseline: None,
+                    },
+                );
+            }
+        }
+    }
+
+    /// Call `add` with every total's growth since the last collect; totals
+    /// it returns false for are forgotten
+    pub(crate) fn collect(&self, reset: TotalReset, mut add: impl FnMut(&str, u64) -> bool) {
+        self.series
+            .lock()
+            .retain(|key, total| add(key, total.growth(reset)));
+    }
+}
+
+impl Total {
+    fn growth(&mut self, reset: TotalReset) -> u64 {
+        let latest = self.latest;
+        let Some(baseline) = self.baseline.replace(latest) else {
+            return 0;
+        };
+        match (latest.checked_sub(baseline), reset) {
+            (Some(growth), _) => growth,
+            (None, TotalReset::CountNewValue) => latest,
+            (None, TotalReset::Ignore) => 0,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn collect(totals: &Totals, reset: TotalReset) -> Vec<(String, u64)> {
+        let mut growth = Vec::new();
+        totals.collect(reset, |key, n| {
+            growth.push((key.to_string(), n));
+            true
+        });
+        growth.sort();
+        growth
+    }
+
+    #[test]
+    fn test_first_total_is_the_starting_point() {
+        let totals = Totals::default();
+        totals.set("page_reads", 1_000);
+        assert_eq!(
+            collect(&totals, TotalReset::default()),
+            [("page_reads".to_string(), 0)]
+        );
+        totals.set("page_reads", 1_250);
+        assert_eq!(
+            collect(&totals, TotalReset::default()),
+            [("page_reads".to_string(), 250)]
+        );
+    }
+
+    #[test]
+    fn test_unchanged_total_adds_nothing() {
+        let totals = Totals::default();
+        totals.set("page_reads", 10);
+        collect(&totals, TotalReset::default());
+        totals.set("page_reads", 15);
+        totals.set("page_reads", 17);
+        assert_eq!(
+            collect(&totals, TotalReset::default()),
+            [("page_reads".to_string(), 7)]
+        );
+        assert_eq!(
+            collect(&totals, TotalReset::default()),
+            [("page_reads".to_string(), 0)]
+        );
+        totals.set("page_reads", 17);
+        assert_eq!(
+            collect(&totals, TotalReset::default()),
+            [("page_reads".to_string(), 0)]
+        );
+    }
+
+    #[test]
+    fn test_resets() {
+        for (reset, growth) in [(TotalReset::CountNewValue, 4), (TotalReset::Ignore, 0)] {
+            let totals = Totals::default();
+            totals.set("page_reads", 100);
+            collect(&totals, reset);
+            totals.set("page_reads", 4);
+            assert_eq!(
+                collect(&totals, reset),
+                [("page_reads".to_string(), growth)]
+            );
+            // The reset value is the new baseline
+            totals.set("page_reads", 10);
+            assert_eq!(collect(&totals, reset), [("page_reads".to_string(), 6)]);
+        }
+    }
+
+    #[test]
+    fn test_rejected_totals_are_forgotten() {
+        let totals = Totals::default();
+        totals.set("kept", 1);
+        totals.set("filtered", 1);
+        totals.collect(TotalReset::default(), |key, _| key == "kept");
+        assert_eq!(
+            collect(&totals, TotalReset::default()),
+            [("kept".to_string(), 0)]
+        );
+    }
+}
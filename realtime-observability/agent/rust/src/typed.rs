@@ -0,0 +1,122 @@
+//! Unit-typed histogram handles
+//!
+//! `Agent::histogram_ms` and `Agent::histogram_bytes` return handles whose
+//! `record` only accepts the matching Rust type, so seconds and
+//! milliseconds can no longer be mixed into one series. The unit is sent as
+//! a `unit` label on every sample.
+
+use std::fmt;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::switches::Switch;
+use crate::Histogram;
+
+/// Unit attached to a typed histogram
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Unit {
+    Milliseconds,
+    Bytes,
+}
+
+impl Unit {
+    /// Value of the `unit` label
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Unit::Milliseconds => "ms",
+            Unit::Bytes => "bytes",
+        }
+    }
+
+    pub(crate) fn new_histogram(&self) -> Histogram {
+        match self {
+            Unit::Milliseconds => Histogram::new(),
+            // 64 B .. 64 MiB
+            Unit::Bytes => Histogram::exponential(64.0, 4.0, 11),
+        }
+    }
+
+    /// The unit a histogram recorded without one is in: bytes if it has
+    /// their bounds, else milliseconds, as `record_histogram` and
+    /// `configure_latency` are for latencies
+    pub(crate) fn of_bounds(bounds: &[f64]) -> Unit {
+        match Unit::Bytes.new_histogram().bounds() == bounds {
+            true => Unit::Bytes,
+            false => Unit::Milliseconds,
+        }
+    }
+}
+
+/// Returned when a name is registered again with a different unit
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UnitMismatch {
+    pub name: String,
+    pub registered: Unit,
+    pub requested: Unit,
+}
+
+impl fmt::Display for UnitMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "histogram {:?} is registered in {} but was requested in {}",
+            self.name,
+            self.registered.as_str(),
+            self.requested.as_str()
+        )
+    }
+}
+
+impl std::error::Error for UnitMismatch {}
+
+/// Histogram recording durations in milliseconds
+#[derive(Clone)]
+pub struct HistogramMs {
+    pub(crate) hist: Arc<Histogram>,
+    pub(crate) enabled: Switch,
+}
+
+impl HistogramMs {
+    #[inline]
+    pub fn record(&self, duration: Duration) {
+        if !self.enabled.is_on() {
+            return;
+        }
+        self.hist.record(duration.as_secs_f64() * 1000.0);
+    }
+}
+
+/// Histogram recording sizes in bytes
+#[derive(Clone)]
+pub struct HistogramBytes {
+    pub(crate) hist: Arc<Histogram>,
+    pub(crate) enabled: Switch,
+}
+
+impl HistogramBytes {
+    #[inline]
+    pub fn record<B: ByteCount>(&self, bytes: B) {
+        if !self.enabled.is_on() {
+            return;
+        }
+        self.hist.record(bytes.byte_count() as f64);
+    }
+}
+
+/// Integer types accepted by `HistogramBytes::record`
+pub trait ByteCount {
+    fn byte_count(self) -> u64;
+}
+
+impl ByteCount for u64 {
+    fn byte_count(self) -> u64 {
+        self
+    }
+}
+
+impl ByteCount for usize {
+    fn byte_count(self) -> u64 {
+        self as u64
+    }
+}
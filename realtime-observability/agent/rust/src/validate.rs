@@ -0,0 +1,590 @@
+//! Invariants of the batches this agent sends (`telemetry::validate`)
+//!
+//! `validate_batch` checks a batch as it goes on the wire, after series
+//! hashes, series ids, timestamp encoding and the checksum:
+//!
+//! 1. A metric sent without a series id has a name, without `{`, that
+//!    follows the snake_case rules of `name!` but for a trailing `…`
+//!    where it was cut to `Config::max_metric_name_len`, and no label
+//!    with an empty key. The agent only holds names to those rules with
+//!    `Config::sanitize_names`, so `Config::validate_before_send` ignores
+//!    `InvalidName` without it.
+//! 2. A metric sent by series id has no name or labels of its own.
+//! 3. A series definition has a nonzero id and a name, and follows the
+//!    rules of 1.
+//! 4. A metric's `series_hash`, if set, is `telemetry::series_hash` of
+//!    its name and labels. Metrics whose id is defined in an earlier
+//!    batch aren't checked.
+//! 5. A metric has at least one sample, and every sample a value.
+//! 6. A histogram's bounds are finite and strictly increasing, but for
+//!    an optional trailing `+Inf`.
+//! 7. A histogram has one more count than finite bounds.
+//! 8. An exemplar's bucket is one of the histogram's counts.
+//! 9. Every sample has a timestamp, with `base_timestamp_ns` added, that
+//!    is after the Unix epoch when read as the signed nanoseconds most
+//!    receivers use and at most `MAX_TIMESTAMP_AHEAD` past the clock of
+//!    whoever validates, and a window start that is no later.
+//! 10. A checksum, if set, matches the batch.
+//!
+//! `Config::validate_before_send` runs it on every batch the agent sends.
+
+#[cfg(not(feature = "noop"))]
+use std::collections::HashMap;
+use std::fmt;
+use std::time::Duration;
+#[cfg(not(feature = "noop"))]
+use std::time::{SystemTime, UNIX_EPOCH};
+
+#[cfg(not(feature = "noop"))]
+use crate::diagnostics::PushStats;
+#[cfg(not(feature = "noop"))]
+use crate::limits::MARKER;
+#[cfg(not(feature = "noop"))]
+use crate::naming;
+#[cfg(not(feature = "noop"))]
+use crate::telemetry::metric_sample::Value;
+#[cfg(not(feature = "noop"))]
+use crate::telemetry::{Histogram, TelemetryBatch};
+
+/// How far past the validating clock a sample's timestamp may be, for
+/// clocks set apart and batches shifted onto the aggregator's clock
+pub const MAX_TIMESTAMP_AHEAD: Duration = Duration::from_secs(24 * 3600);
+
+/// An invariant a batch breaks, from `validate_batch`; `metric` indexes
+/// `TelemetryBatch::metrics` and `sample` that metric's `samples`.
+///
+/// Exhaustive, so an invariant added later breaks the build of a receiver
+/// matching on it rather than falling into a catch-all. Variants are only
+/// ever added, never changed or removed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Violation {
+    /// A metric sent without a series id has no name
+    EmptyName { metric: usize },
+    /// A metric name contains `{`, which would start the labels of its
+    /// series key
+    BraceInName { metric: usize },
+    /// A metric has a label with an empty key
+    EmptyLabelKey { metric: usize },
+    /// A metric name breaks a naming rule of `name!`, given as `rule`
+    InvalidName { metric: usize, rule: &'static str },
+    /// A metric sent by series id also has a name or labels
+    NamedSeriesId { metric: usize },
+    /// A series definition has id 0, or a name or labels that break the
+    /// rules for metrics
+    InvalidSeriesDefinition { definition: usize },
+    /// A metric's `series_hash` isn't the hash of its name and labels
+    SeriesHashMismatch { metric: usize },
+    /// A metric has no samples
+    NoSamples { metric: usize },
+    /// A sample has no value
+    MissingValue { metric: usize, sample: usize },
+    /// A histogram's bounds are not finite and strictly increasing
+    BoundsNotIncreasing { metric: usize, sample: usize },
+    /// A histogram doesn't have one more count than finite bounds
+    CountsLength {
+        metric: usize,
+        sample: usize,
+        bounds: usize,
+        counts: usize,
+    },
+    /// An exemplar's bucket index is past the histogram's last count
+    ExemplarOutOfRange { metric: usize, sample: usize },
+    /// A sample's timestamp is 0
+    MissingTimestamp { metric: usize, sample: usize },
+    /// A sample's window starts after its timestamp
+    WindowStartAfterTimestamp { metric: usize, sample: usize },
+    /// A sample's timestamp is past `i64::MAX`, so before the Unix epoch
+    /// to a receiver reading it as signed
+    TimestampBeforeEpoch { metric: usize, sample: usize },
+    /// A sample's timestamp is more than `MAX_TIMESTAMP_AHEAD` past the
+    /// validating clock
+    TimestampInFuture { metric: usize, sample: usize },
+    /// The batch doesn't match its checksum
+    ChecksumMismatch,
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            Violation::EmptyName { metric } => write!(f, "metric {} has no name", metric),
+            Violation::BraceInName { metric } => {
+                write!(f, "name of metric {} contains `{{`", metric)
+            }
+            Violation::EmptyLabelKey { metric } => {
+                write!(f, "metric {} has a label with an empty key", metric)
+            }
+            Violation::InvalidName { metric, rule } => {
+                write!(f, "name of metric {} breaks a rule: {}", metric, rule)
+            }
+            Violation::NamedSeriesId { metric } => write!(
+                f,
+                "metric {} is sent by series id but has a name or labels",
+                metric
+            ),
+            Violation::InvalidSeriesDefinition { definition } => {
+                write!(f, "series definition {} is invalid", definition)
+            }
+            Violation::SeriesHashMismatch { metric } => write!(
+                f,
+                "series hash of metric {} doesn't match its name and labels",
+                metric
+            ),
+            Violation::NoSamples { metric } => write!(f, "metric {} has no samples", metric),
+            Violation::MissingValue { metric, sample } => {
+                write!(f, "sample {} of metric {} has no value", sample, metric)
+            }
+            Violation::BoundsNotIncreasing { metric, sample } => write!(
+                f,
+                "histogram bounds of sample {} of metric {} are not finite and increasing",
+                sample, metric
+            ),
+            Violation::CountsLength {
+                metric,
+                sample,
+                bounds,
+                counts,
+            } => write!(
+                f,
+                "histogram of sample {} of metric {} has {} counts for {} bounds",
+                sample, metric, counts, bounds
+            ),
+            Violation::ExemplarOutOfRange { metric, sample } => write!(
+                f,
+                "exemplar of sample {} of metric {} is past the last bucket",
+                sample, metric
+            ),
+            Violation::MissingTimestamp { metric, sample } => {
+                write!(f, "sample {} of metric {} has no timestamp", sample, metric)
+            }
+            Violation::WindowStartAfterTimestamp { metric, sample } => write!(
+                f,
+                "window of sample {} of metric {} starts after its timestamp",
+                sample, metric
+            ),
+            Violation::TimestampBeforeEpoch { metric, sample } => write!(
+                f,
+                "timestamp of sample {} of metric {} is before the Unix epoch",
+                sample, metric
+            ),
+            Violation::TimestampInFuture { metric, sample } => write!(
+                f,
+                "timestamp of sample {} of metric {} is more than {:?} ahead",
+                sample, metric, MAX_TIMESTAMP_AHEAD
+            ),
+            Violation::ChecksumMismatch => write!(f, "batch doesn't match its checksum"),
+        }
+    }
+}
+
+/// Every invariant `batch` breaks, in the order of the list in the module
+/// docs for each metric; empty for a valid batch
+#[cfg(not(feature = "noop"))]
+pub fn validate_batch(batch: &TelemetryBatch) -> Vec<Violation> {
+    violations(batch, true)
+}
+
+/// `validate_batch`, holding names to the naming rules only if
+/// `check_names`
+#[cfg(not(feature = "noop"))]
+fn violations(batch: &TelemetryBatch, check_names: bool) -> Vec<Violation> {
+    let check_name = |name: &str| match check_names {
+        true => name_rule(name),
+        false => Ok(()),
+    };
+    let latest_ns = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .saturating_add(MAX_TIMESTAMP_AHEAD)
+        .as_nanos()
+        .min(u64::MAX as u128) as u64;
+    let mut violations = Vec::new();
+    let mut definitions = HashMap::with_capacity(batch.series_definitions.len());
+    for (definition, series) in batch.series_definitions.iter().enumerate() {
+        let valid = series.id != 0
+            && !series.name.is_empty()
+            && !series.name.contains('{')
+            && check_name(&series.name).is_ok()
+            && !series.labels.contains_key("");
+        if !valid {
+            violations.push(Violation::InvalidSeriesDefinition { definition });
+        }
+        definitions.insert(series.id, series);
+    }
+
+    for (m, metric) in batch.metrics.iter().enumerate() {
+        let series = match metric.series_id {
+            0 => {
+                if metric.name.is_empty() {
+                    violations.push(Violation::EmptyName { metric: m });
+                }
+                if metric.name.contains('{') {
+                    violations.push(Violation::BraceInName { metric: m });
+                } else if let Err(rule) = check_name(&metric.name) {
+                    // An empty name already counts as `EmptyName`
+                    if !metric.name.is_empty() {
+                        violations.push(Violation::InvalidName { metric: m, rule });
+                    }
+                }
+                if metric.labels.contains_key("") {
+                    violations.push(Violation::EmptyLabelKey { metric: m });
+                }
+                Some((&metric.name, &metric.labels))
+            }
+            id => {
+                if !metric.name.is_empty() || !metric.labels.is_empty() {
+                    violations.push(Violation::NamedSeriesId { metric: m });
+                }
+                definitions
+                    .get(&id)
+                    .map(|series| (&series.name, &series.labels))
+            }
+        };
+        if let Some((name, labels)) = series.filter(|_| metric.series_hash != 0) {
+            let labels = labels.iter().map(|(k, v)| (k.as_str(), v.as_str()));
+            if crate::series::hash_sorted(name, labels) != metric.series_hash {
+                violations.push(Violation::SeriesHashMismatch { metric: m });
+            }
+        }
+
+        if metric.samples.is_empty() {
+            violations.push(Violation::NoSamples { metric: m });
+        }
+        for (s, sample) in metric.samples.iter().enumerate() {
+            match &sample.value {
+                None => violations.push(Violation::MissingValue {
+                    metric: m,
+                    sample: s,
+                }),
+                Some(Value::Histogram(histogram)) => {
+                    check_histogram(histogram, m, s, &mut violations)
+                }
+                Some(_) => {}
+            }
+            let timestamp_ns = batch.base_timestamp_ns.saturating_add(sample.timestamp_ns);
+            if timestamp_ns == 0 {
+                violations.push(Violation::MissingTimestamp {
+                    metric: m,
+                    sample: s,
+                });
+            } else if timestamp_ns > i64::MAX as u64 {
+                violations.push(Violation::TimestampBeforeEpoch {
+                    metric: m,
+                    sample: s,
+                });
+            } else if timestamp_ns > latest_ns {
+                violations.push(Violation::TimestampInFuture {
+                    metric: m,
+                    sample: s,
+                });
+            } else if sample.window_start_ns > timestamp_ns {
+                violations.push(Violation::WindowStartAfterTimestamp {
+                    metric: m,
+                    sample: s,
+                });
+            }
+        }
+    }
+
+    if batch.verify_checksum() == Some(false) {
+        violations.push(Violation::ChecksumMismatch);
+    }
+    violations
+}
+
+/// The naming rule `name` breaks, once a `…` from cutting it is dropped
+#[cfg(not(feature = "noop"))]
+fn name_rule(name: &str) -> Result<(), &'static str> {
+    naming::check(name.strip_suffix(MARKER).unwrap_or(name))
+}
+
+#[cfg(not(feature = "noop"))]
+fn check_histogram(histogram: &Histogram, metric: usize, sample: usize, out: &mut Vec<Violation>) {
+    let mut finite = &histogram.bounds[..];
+    if finite.last() == Some(&f64::INFINITY) {
+        finite = &finite[..finite.len() - 1];
+    }
+    let increasing = finite.iter().all(|b| b.is_finite()) && finite.windows(2).all(|w| w[0] < w[1]);
+    if !increasing {
+        out.push(Violation::BoundsNotIncreasing { metric, sample });
+    }
+    let buckets = finite.len() + 1;
+    if histogram.counts.len() != buckets {
+        out.push(Violation::CountsLength {
+            metric,
+            sample,
+            bounds: histogram.bounds.len(),
+            counts: histogram.counts.len(),
+        });
+    }
+    if histogram
+        .exemplars
+        .iter()
+        .any(|exemplar| exemplar.bucket_index as usize >= buckets)
+    {
+        out.push(Violation::ExemplarOutOfRange { metric, sample });
+    }
+}
+
+/// `Config::validate_before_send` on a batch about to be sent: counts it
+/// if invalid, and in a debug build panics with what it breaks. Names are
+/// only held to the naming rules with `sanitize_names`.
+#[cfg(not(feature = "noop"))]
+pub(crate) fn check_before_send(stats: &PushStats, batch: &TelemetryBatch, sanitize_names: bool) {
+    let violations = violations(batch, sanitize_names);
+    if violations.is_empty() {
+        return;
+    }
+    stats.invalid_batch();
+    let list = violations
+        .iter()
+        .map(Violation::to_string)
+        .collect::<Vec<_>>()
+        .join("; ");
+    if cfg!(debug_assertions) {
+        panic!("sending an invalid batch: {}", list);
+    }
+    tracing::debug!(violations = %list, "sending an invalid batch");
+}
+
+#[cfg(all(test, not(feature = "noop")))]
+mod tests {
+    use super::*;
+    use crate::agent::finish_batch;
+    use crate::interning::SeriesIds;
+    use crate::telemetry::{Exemplar, Metric, MetricSample, SeriesDefinition};
+    use crate::{Agent, Config, Resolution, ServerCapabilities};
+    use std::panic::{self, AssertUnwindSafe};
+    use std::time::Duration;
+
+    fn histogram(bounds: Vec<f64>, counts: Vec<u64>) -> Option<Value> {
+        Some(Value::Histogram(Histogram {
+            bounds,
+            counts,
+            exemplars: Vec::new(),
+        }))
+    }
+
+    fn metric(name: &str, value: Option<Value>) -> Metric {
+        Metric {
+            name: name.to_string(),
+            samples: vec![MetricSample {
+                timestamp_ns: 1_700_000_000_000_000_000,
+                value,
+                ..Default::default()
+            }],
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_sent_batches_are_valid() {
+        let config = Config {
+            explicit_inf_bound: true,
+            timestamp_resolution: Resolution::Millis,
+            delta_timestamps: true,
+            intern_series: true,
+            hash_series: true,
+            checksum_batches: true,
+            ..Default::default()
+        };
+        let agent = Agent::new(config.clone());
+        agent.inc_counter("requests_total");
+        agent.inc_counter_with("requests_total", &[("route", "/a,b=c}")]);
+        agent.set_gauge("queue_depth", 3.0);
+        agent.record_histogram("latency_ms", 12.0);
+        agent.record_histogram_with("latency_ms", &[("route", "/a")], 1e9);
+        let ids = SeriesIds::new(Duration::from_secs(30));
+        for _ in 0..2 {
+            let mut batch = agent.collect_now();
+            finish_batch(
+                &config,
+                &PushStats::default(),
+                ServerCapabilities::ALL,
+                Some(&ids),
+                &mut batch,
+            );
+            assert!(batch.metrics.iter().all(|m| m.series_id != 0));
+            assert_eq!(validate_batch(&batch), []);
+        }
+    }
+
+    #[test]
+    fn test_each_invariant() {
+        let mut by_id = metric("", Some(Value::Counter(1)));
+        by_id.series_id = 7;
+        by_id.labels.insert("route".to_string(), "/a".to_string());
+        let mut hashed = metric("up", Some(Value::Gauge(1.0)));
+        hashed.series_hash = 1;
+        let mut exemplar = metric("size_bytes", histogram(vec![1.0], vec![0, 1]));
+        if let Some(Value::Histogram(histogram)) = &mut exemplar.samples[0].value {
+            histogram.exemplars.push(Exemplar {
+                bucket_index: 2,
+                ..Default::default()
+            });
+        }
+        let mut untimed = metric("up", Some(Value::Gauge(1.0)));
+        untimed.samples[0].timestamp_ns = 0;
+        let mut windowed = metric("up", Some(Value::Gauge(1.0)));
+        windowed.samples[0].window_start_ns = u64::MAX;
+        let mut labeled = metric("up{zone=a}", Some(Value::Gauge(1.0)));
+        labeled.labels.insert(String::new(), "a".to_string());
+        let mut unsigned = metric("up", Some(Value::Gauge(1.0)));
+        unsigned.samples[0].timestamp_ns = i64::MAX as u64 + 1;
+        let mut ahead = metric("up", Some(Value::Gauge(1.0)));
+        ahead.samples[0].timestamp_ns = (SystemTime::now() + 2 * MAX_TIMESTAMP_AHEAD)
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_nanos() as u64;
+
+        let mut batch = TelemetryBatch {
+            metrics: vec![
+                metric("", Some(Value::Counter(1))),
+                labeled,
+                by_id,
+                hashed,
+                Metric {
+                    name: "up".to_string(),
+                    ..Default::default()
+                },
+                metric("up", None),
+                metric("latency_ms", histogram(vec![5.0, 1.0], vec![0, 0, 0])),
+                metric("latency_ms", histogram(vec![f64::NAN], vec![0, 0])),
+                metric("latency_ms", histogram(vec![1.0, 5.0], vec![0, 0])),
+                metric(
+                    "latency_ms",
+                    histogram(vec![1.0, 5.0, f64::INFINITY], vec![0, 0, 0]),
+                ),
+                exemplar,
+                untimed,
+                windowed,
+                metric("requestCount", Some(Value::Counter(1))),
+                metric("requests__total", Some(Value::Counter(1))),
+                // Cut to `max_metric_name_len`
+                metric("requests_to…", Some(Value::Counter(1))),
+                unsigned,
+                ahead,
+            ],
+            series_definitions: vec![
+                SeriesDefinition {
+                    id: 0,
+                    name: "up".to_string(),
+                    ..Default::default()
+                },
+                SeriesDefinition {
+                    id: 9,
+                    name: "Up".to_string(),
+                    ..Default::default()
+                },
+            ],
+            checksum: Some(0),
+            ..Default::default()
+        };
+        batch.checksum = Some(batch.compute_checksum() ^ 1);
+
+        assert_eq!(
+            validate_batch(&batch),
+            [
+                Violation::InvalidSeriesDefinition { definition: 0 },
+                Violation::InvalidSeriesDefinition { definition: 1 },
+                Violation::EmptyName { metric: 0 },
+                Violation::BraceInName { metric: 1 },
+                Violation::EmptyLabelKey { metric: 1 },
+                Violation::NamedSeriesId { metric: 2 },
+                Violation::SeriesHashMismatch { metric: 3 },
+                Violation::NoSamples { metric: 4 },
+                Violation::MissingValue {
+                    metric: 5,
+                    sample: 0
+                },
+                Violation::BoundsNotIncreasing {
+                    metric: 6,
+                    sample: 0
+                },
+                Violation::BoundsNotIncreasing {
+                    metric: 7,
+                    sample: 0
+                },
+                Violation::CountsLength {
+                    metric: 8,
+                    sample: 0,
+                    bounds: 2,
+                    counts: 2
+                },
+                Violation::ExemplarOutOfRange {
+                    metric: 10,
+                    sample: 0
+                },
+                Violation::MissingTimestamp {
+                    metric: 11,
+                    sample: 0
+                },
+                Violation::WindowStartAfterTimestamp {
+                    metric: 12,
+                    sample: 0
+                },
+                Violation::InvalidName {
+                    metric: 13,
+                    rule: "metric names are snake_case: `_` between words, no uppercase"
+                },
+                Violation::InvalidName {
+                    metric: 14,
+                    rule: "metric names don't contain `__`"
+                },
+                Violation::TimestampBeforeEpoch {
+                    metric: 16,
+                    sample: 0
+                },
+                Violation::TimestampInFuture {
+                    metric: 17,
+                    sample: 0
+                },
+                Violation::ChecksumMismatch,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_delta_timestamps_are_offsets() {
+        let mut batch = TelemetryBatch {
+            metrics: vec![metric("up", Some(Value::Gauge(1.0)))],
+            base_timestamp_ns: 1_700_000_000_000_000_000,
+            ..Default::default()
+        };
+        batch.metrics[0].samples[0].timestamp_ns = 0;
+        batch.metrics[0].samples[0].window_start_ns = batch.base_timestamp_ns;
+        assert_eq!(validate_batch(&batch), []);
+    }
+
+    #[test]
+    fn test_invalid_batches_are_counted() {
+        let stats = PushStats::default();
+        let batch = TelemetryBatch {
+            metrics: vec![metric("", Some(Value::Counter(1)))],
+            ..Default::default()
+        };
+        // A debug build panics, so tests catch what the agent sends wrong
+        let checked =
+            panic::catch_unwind(AssertUnwindSafe(|| check_before_send(&stats, &batch, true)));
+        assert_eq!(checked.is_err(), cfg!(debug_assertions));
+        assert_eq!(stats.invalid_batches(), 1);
+        assert_eq!(
+            Config::default().validate_before_send,
+            cfg!(debug_assertions)
+        );
+
+        let agent = Agent::new(Config::default());
+        agent.inc_counter("requests_total");
+        check_before_send(&stats, &agent.collect_now(), true);
+        assert_eq!(stats.invalid_batches(), 1);
+
+        // Names are the caller's to choose unless the agent sanitizes them
+        agent.inc_counter("requestCount");
+        let batch = agent.collect_now();
+        check_before_send(&stats, &batch, false);
+        assert_eq!(stats.invalid_batches(), 1);
+        let checked =
+            panic::catch_unwind(AssertUnwindSafe(|| check_before_send(&stats, &batch, true)));
+        assert_eq!(checked.is_err(), cfg!(debug_assertions));
+        assert_eq!(stats.invalid_batches(), 2);
+    }
+}
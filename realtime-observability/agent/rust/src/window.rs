@@ -0,0 +1,220 @@
+//! Histograms accumulated over more than one push (`ResetPolicy`)
+//!
+//! Every collect still drains the histogram's ended epoch, so no record is
+//! lost or counted twice; for a histogram whose policy isn't `EveryPush`
+//! the drained counts are added to a per-series window instead of being
+//! sent alone. Each push sends the window's totals so far, with the
+//! window's start in `MetricSample::window_start_ns`. The window closes on
+//! the first collect at or past its deadline: that batch still carries the
+//! full window, and the next one starts empty.
+
+use std::collections::HashMap;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use parking_lot::{Mutex, MutexGuard};
+
+use crate::ResetPolicy;
+
+/// Reset policies by metric name, and the open windows by series key
+pub(crate) struct Windows {
+    policies: Mutex<HashMap<String, ResetPolicy>>,
+    open: Mutex<HashMap<String, Window>>,
+    /// When the last collect cut, as an `Instant` and in Unix nanoseconds.
+    /// A window opened by the next collect starts here, since its first
+    /// counts were recorded after it.
+    last_cut: Mutex<(Instant, u64)>,
+}
+
+struct Window {
+    start: Instant,
+    start_ns: u64,
+    bounds: Vec<f64>,
+    counts: Vec<u64>,
+}
+
+impl Default for Windows {
+    fn default() -> Self {
+        Self::new(
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_nanos() as u64,
+        )
+    }
+}
+
+impl Windows {
+    /// `now_ns` is the current time in Unix nanoseconds
+    pub(crate) fn new(now_ns: u64) -> Self {
+        Self {
+            policies: Mutex::default(),
+            open: Mutex::default(),
+            last_cut: Mutex::new((Instant::now(), now_ns)),
+        }
+    }
+
+    /// Use `policy` for every series of histogram `name` from the next
+    /// collect on. A series whose policy changes starts a new window.
+    pub(crate) fn set_policy(&self, name: &str, policy: ResetPolicy) {
+        let previous = self.policies.lock().insert(name.to_string(), policy);
+        if previous.is_some_and(|previous| previous != policy) {
+            self.open
+                .lock()
+                .retain(|key, _| crate::series::name(key) != name);
+        }
+    }
+
+    /// Begin folding one collect's histograms, cut at `now_ns`
+    pub(crate) fn collect(&self, now_ns: u64) -> Collect<'_> {
+        let now = Instant::now();
+        let previous = std::mem::replace(&mut *self.last_cut.lock(), (now, now_ns));
+        Collect {
+            policies: self.policies.lock(),
+            open: self.open.lock(),
+            previous,
+            now,
+        }
+    }
+}
+
+/// One collect's view of the windows; see `Windows::collect`
+pub(crate) struct Collect<'a> {
+    policies: MutexGuard<'a, HashMap<String, ResetPolicy>>,
+    open: MutexGuard<'a, HashMap<String, Window>>,
+    previous: (Instant, u64),
+    now: Instant,
+}
+
+impl Collect<'_> {
+    /// The counts to send for series `key` of histogram `name`, given the
+    /// counts drained this collect, along with the window start in Unix
+    /// nanoseconds, or 0 for an `EveryPush` histogram
+    pub(crate) fn fold(
+        &mut self,
+        name: &str,
+        key: &str,
+        bounds: &[f64],
+        counts: Vec<u64>,
+    ) -> (Vec<u64>, u64) {
+        let policy = self.policies.get(name).copied().unwrap_or_default();
+        if policy == ResetPolicy::EveryPush {
+            return (counts, 0);
+        }
+        let (start, start_ns) = self.previous;
+        let window = self.open.entry(key.to_string()).or_insert_with(|| Window {
+            start,
+            start_ns,
+            bounds: bounds.to_vec(),
+            counts: vec![0; counts.len()],
+        });
+        // Reconfigured bounds can't be added to the old counts
+        if window.bounds != bounds {
+            *window = Window {
+                start,
+                start_ns,
+                bounds: bounds.to_vec(),
+                counts: vec![0; counts.len()],
+            };
+        }
+        for (total, count) in window.counts.iter_mut().zip(&counts) {
+            *total += count;
+        }
+        let sent = (window.counts.clone(), window.start_ns);
+        if let ResetPolicy::Every(period) = policy {
+            if self.now.duration_since(window.start) >= period {
+                self.open.remove(key);
+            }
+        }
+        sent
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_windows_accumulate_until_the_deadline() {
+        let windows = Windows::new(1);
+        windows.set_policy("session_s", ResetPolicy::Every(Duration::from_millis(30)));
+        windows.set_policy("held_s", ResetPolicy::Never);
+        let bounds = [1.0];
+
+        let mut collect = windows.collect(2);
+        assert_eq!(
+            collect.fold("rtt_ms", "rtt_ms", &bounds, vec![1, 0]),
+            (vec![1, 0], 0)
+        );
+        assert_eq!(
+            collect.fold("held_s", "held_s", &bounds, vec![1, 0]),
+            (vec![1, 0], 1)
+        );
+        assert_eq!(
+            collect.fold("session_s", "session_s{user=a}", &bounds, vec![0, 2]),
+            (vec![0, 2], 1)
+        );
+        drop(collect);
+
+        let mut collect = windows.collect(3);
+        assert_eq!(
+            collect.fold("held_s", "held_s", &bounds, vec![1, 1]),
+            (vec![2, 1], 1)
+        );
+        assert_eq!(
+            collect.fold("session_s", "session_s{user=a}", &bounds, vec![1, 0]),
+            (vec![1, 2], 1)
+        );
+        drop(collect);
+
+        std::thread::sleep(Duration::from_millis(40));
+        // The closing collect still carries the whole window
+        let mut collect = windows.collect(4);
+        assert_eq!(
+            collect.fold("session_s", "session_s{user=a}", &bounds, vec![1, 0]),
+            (vec![2, 2], 1)
+        );
+        drop(collect);
+
+        let mut collect = windows.collect(5);
+        assert_eq!(
+            collect.fold("session_s", "session_s{user=a}", &bounds, vec![0, 1]),
+            (vec![0, 1], 4)
+        );
+        assert_eq!(
+            collect.fold("held_s", "held_s", &bounds, vec![0, 0]),
+            (vec![2, 1], 1)
+        );
+    }
+
+    #[test]
+    fn test_new_bounds_or_policy_start_a_new_window() {
+        let windows = Windows::new(1);
+        windows.set_policy("held_s", ResetPolicy::Never);
+        windows
+            .collect(2)
+            .fold("held_s", "held_s", &[1.0], vec![1, 1]);
+
+        let mut collect = windows.collect(3);
+        assert_eq!(
+            collect.fold("held_s", "held_s", &[1.0, 2.0], vec![1, 0, 0]),
+            (vec![1, 0, 0], 2)
+        );
+        drop(collect);
+
+        windows.set_policy("held_s", ResetPolicy::Never);
+        assert_eq!(
+            windows
+                .collect(4)
+                .fold("held_s", "held_s", &[1.0, 2.0], vec![1, 0, 0]),
+            (vec![2, 0, 0], 2)
+        );
+        windows.set_policy("held_s", ResetPolicy::Every(Duration::from_secs(60)));
+        assert_eq!(
+            windows
+                .collect(5)
+                .fold("held_s", "held_s", &[1.0, 2.0], vec![1, 0, 0]),
+            (vec![1, 0, 0], 4)
+        );
+    }
+}
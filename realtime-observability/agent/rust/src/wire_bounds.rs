@@ -0,0 +1,129 @@
+//! Coarser histogram bounds on the wire (`Agent::configure_wire_bounds`)
+//!
+//! A histogram can keep a hundred fine buckets for `local_stats` quantiles
+//! while the aggregator only needs a dozen. At collect, each fine bucket's
+//! count goes to the wire bucket that covers it. Every wire bound must be
+//! one of the fine bounds, so no fine bucket straddles a wire bound and
+//! the folded counts are exactly what recording into the wire bounds would
+//! have counted. A histogram whose bounds no longer hold the wire bounds,
+//! such as after the aggregator's schema replaced them, goes out unfolded.
+
+use std::fmt;
+
+#[cfg(not(feature = "noop"))]
+use std::collections::HashMap;
+#[cfg(not(feature = "noop"))]
+use std::sync::Arc;
+
+#[cfg(not(feature = "noop"))]
+use parking_lot::Mutex;
+
+/// Returned by `Agent::configure_wire_bounds` for a wire bound that is not
+/// one of the fine bounds
+#[derive(Debug, Clone, PartialEq)]
+pub struct MisalignedWireBounds {
+    pub name: String,
+    pub bound: f64,
+}
+
+impl fmt::Display for MisalignedWireBounds {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "wire bound {} of histogram {:?} is not one of its bounds",
+            self.bound, self.name
+        )
+    }
+}
+
+impl std::error::Error for MisalignedWireBounds {}
+
+/// Wire bounds by histogram name
+#[cfg(not(feature = "noop"))]
+#[derive(Default)]
+pub(crate) struct WireBounds {
+    bounds: Mutex<HashMap<String, Arc<[f64]>>>,
+}
+
+#[cfg(not(feature = "noop"))]
+impl WireBounds {
+    pub(crate) fn set(&self, name: &str, bounds: Vec<f64>) {
+        self.bounds.lock().insert(name.to_string(), bounds.into());
+    }
+
+    /// All wire bounds, for one collect
+    pub(crate) fn snapshot(&self) -> HashMap<String, Arc<[f64]>> {
+        self.bounds.lock().clone()
+    }
+}
+
+/// The first bound in `wire` that is not one of `fine`
+pub(crate) fn misaligned(fine: &[f64], wire: &[f64]) -> Option<f64> {
+    wire.iter().copied().find(|bound| !fine.contains(bound))
+}
+
+/// The wire bucket of each bucket of `fine`, overflow last, or `None` if
+/// `wire` does not line up with `fine`
+pub(crate) fn bucket_map(fine: &[f64], wire: &[f64]) -> Option<Vec<usize>> {
+    if misaligned(fine, wire).is_some() {
+        return None;
+    }
+    let wire_bucket = |upper: f64| wire.partition_point(|&bound| bound < upper);
+    let mut map: Vec<usize> = fine.iter().map(|&upper| wire_bucket(upper)).collect();
+    map.push(wire.len());
+    Some(map)
+}
+
+/// `counts` of the fine buckets added up into `buckets` wire buckets
+pub(crate) fn fold(counts: &[u64], map: &[usize], buckets: usize) -> Vec<u64> {
+    let mut folded = vec![0; buckets + 1];
+    for (&count, &bucket) in counts.iter().zip(map) {
+        folded[bucket] += count;
+    }
+    folded
+}
+
+#[cfg(all(test, not(feature = "noop")))]
+mod tests {
+    use super::*;
+    use crate::{BucketSpec, Histogram};
+
+    #[test]
+    fn test_folded_counts_equal_coarse_recording() {
+        let fine = BucketSpec::Exponential {
+            start: 0.5,
+            factor: 1.1,
+            count: 120,
+        }
+        .bounds();
+        // Every tenth fine bound
+        let wire: Vec<f64> = fine.iter().copied().step_by(10).collect();
+        let fine_hist = Histogram::with_bounds(&fine);
+        let wire_hist = Histogram::with_bounds(&wire);
+        // Values on, between and past the bounds
+        let values = (0..5_000)
+            .map(|i| i as f64 * 0.37)
+            .chain(fine.iter().copied())
+            .chain([0.0, -1.0, 1e9]);
+        for value in values {
+            fine_hist.record(value);
+            wire_hist.record(value);
+        }
+
+        let (bounds, counts) = fine_hist.snapshot_and_reset();
+        let map = bucket_map(&bounds, &wire).unwrap();
+        assert_eq!(
+            fold(&counts, &map, wire.len()),
+            wire_hist.snapshot_and_reset().1
+        );
+    }
+
+    #[test]
+    fn test_misaligned_wire_bounds_are_refused() {
+        let fine = [1.0, 2.0, 5.0, 10.0];
+        assert_eq!(bucket_map(&fine, &[2.0, 10.0]), Some(vec![0, 0, 1, 1, 2]));
+        assert_eq!(bucket_map(&fine, &[]), Some(vec![0; 5]));
+        assert_eq!(misaligned(&fine, &[2.0, 7.5, 10.0]), Some(7.5));
+        assert_eq!(bucket_map(&fine, &[2.0, 7.5]), None);
+    }
+}
@@ -0,0 +1,86 @@
+//! Allocations per collect, counted by a global allocator: a collect
+//! reusing the last batch (`Agent::recycle`, and the push loop as it
+//! sends) must allocate a fraction of what a collect from scratch does.
+#![cfg(not(feature = "noop"))]
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+
+use telemetry_agent::{Agent, Config};
+
+/// Counts allocations made on the current thread, so tests running
+/// alongside don't add to them
+struct Counting;
+
+thread_local! {
+    static ALLOCATIONS: Cell<usize> = const { Cell::new(0) };
+}
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let _ = ALLOCATIONS.try_with(|n| n.set(n.get() + 1));
+        System.alloc(layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let _ = ALLOCATIONS.try_with(|n| n.set(n.get() + 1));
+        System.realloc(ptr, layout, new_size)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static COUNTING: Counting = Counting;
+
+fn allocations_by<T>(f: impl FnOnce() -> T) -> (T, usize) {
+    let before = ALLOCATIONS.with(Cell::get);
+    let result = f();
+    (result, ALLOCATIONS.with(Cell::get) - before)
+}
+
+/// 5,000 series, labeled as a web service's would be
+fn record(agent: &Agent, round: u64) {
+    for i in 0..2500 {
+        let route = format!("/api/v2/orders/{}", i % 500);
+        let status = ["200", "404", "500", "503", "302"][i / 500];
+        agent.inc_counter_with(
+            "http_requests_total",
+            &[("route", &route), ("status", status)],
+        );
+    }
+    for i in 0..2000 {
+        let queue = format!("queue-{}", i);
+        agent.set_gauge_with("queue_depth", &[("queue", &queue)], round as f64);
+    }
+    for i in 0..500 {
+        let route = format!("/api/v2/orders/{}", i);
+        agent.record_histogram_with("latency_ms", &[("route", &route)], round as f64);
+    }
+}
+
+#[test]
+fn test_recycled_collects_allocate_a_fifth() {
+    let agent = Agent::new(Config::default());
+    record(&agent, 1);
+    let (batch, from_scratch) = allocations_by(|| agent.collect_now());
+    assert!(batch.metrics.len() >= 5000);
+
+    let mut previous = batch;
+    for round in 2..=3 {
+        let len = previous.metrics.len();
+        agent.recycle(previous);
+        record(&agent, round);
+        let (batch, reusing) = allocations_by(|| agent.collect_now());
+        assert!(
+            reusing * 5 <= from_scratch,
+            "{} allocations reusing the last batch, {} from scratch",
+            reusing,
+            from_scratch
+        );
+        assert_eq!(batch.metrics.len(), len);
+        previous = batch;
+    }
+}
@@ -0,0 +1,116 @@
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+#[cfg(not(feature = "noop"))]
+#[test]
+fn test_debug_metrics_record_in_debug_builds() {
+    use telemetry_agent::proto::metric_sample::Value;
+    use telemetry_agent::{debug_counter, debug_histogram, Agent, Config};
+
+    let agent = Agent::new(Config::default());
+    debug_counter!(agent, "debug_probes_total");
+    debug_counter!(agent, "debug_probes_total", 2);
+    debug_histogram!(agent, "debug_chain_len", 3.0);
+    let scope = agent.debug_scope();
+    assert!(scope.is_enabled());
+    scope.inc_counter("debug_scoped_total");
+    debug_counter!(scope, "debug_scoped_total");
+
+    let batch = agent.collect_now();
+    let counter = |name: &str| {
+        batch
+            .metrics
+            .iter()
+            .find(|m| m.name == name)
+            .and_then(|m| m.samples[0].value.clone())
+    };
+    assert_eq!(counter("debug_probes_total"), Some(Value::Counter(3)));
+    assert_eq!(counter("debug_scoped_total"), Some(Value::Counter(2)));
+    assert!(batch.metrics.iter().any(|m| m.name == "debug_chain_len"));
+}
+
+#[test]
+fn test_debug_metrics_leave_no_trace_in_release() {
+    // The macros are expanded the same way for `noop`, which builds in
+    // seconds; every marker sits where only evaluating the macro's
+    // arguments would keep it
+    let off = build_fixture("off", &["noop"]);
+    assert!(contains(&off, b"marker_control"));
+    assert!(!contains(&off, b"marker_counter_name"));
+    assert!(!contains(&off, b"marker_histogram_value"));
+    let run = Command::new(fixture_binary("off")).output().unwrap();
+    assert!(run.status.success(), "{:?}", run);
+
+    let on = build_fixture("on", &["noop", "debug-metrics"]);
+    assert!(contains(&on, b"marker_counter_name"));
+    assert!(contains(&on, b"marker_histogram_value"));
+}
+
+const FIXTURE_MAIN: &str = r#"
+// `agent` and `expensive` go unused when the macros expand to nothing
+#![allow(unused)]
+
+use telemetry_agent::{debug_counter, debug_histogram, Agent, Config};
+
+fn main() {
+    let agent = Agent::new(Config::default());
+    debug_counter!(agent, std::hint::black_box("marker_counter_name"));
+    debug_histogram!(agent, "chain_len", expensive());
+    println!("{}", std::hint::black_box("marker_control"));
+}
+
+fn expensive() -> f64 {
+    panic!("{}", std::hint::black_box("marker_histogram_value"))
+}
+"#;
+
+fn fixture_dir(name: &str) -> PathBuf {
+    Path::new(env!("CARGO_TARGET_TMPDIR"))
+        .join("debug_metrics")
+        .join(name)
+}
+
+fn fixture_binary(name: &str) -> PathBuf {
+    Path::new(env!("CARGO_TARGET_TMPDIR"))
+        .join("debug_metrics/target/release")
+        .join(format!("fixture_{}{}", name, std::env::consts::EXE_SUFFIX))
+}
+
+/// Build a binary calling the macros in release mode, with the agent's
+/// `features`, and return its bytes
+fn build_fixture(name: &str, features: &[&str]) -> Vec<u8> {
+    let dir = fixture_dir(name);
+    std::fs::create_dir_all(dir.join("src")).unwrap();
+    let manifest = format!(
+        "[package]\nname = \"fixture_{}\"\nversion = \"0.0.0\"\nedition = \"2021\"\npublish = false\n\n\
+         [dependencies]\ntelemetry-agent = {{ path = {:?}, default-features = false, features = {:?} }}\n",
+        name,
+        env!("CARGO_MANIFEST_DIR"),
+        features,
+    );
+    std::fs::write(dir.join("Cargo.toml"), manifest).unwrap();
+    std::fs::write(dir.join("src/main.rs"), FIXTURE_MAIN).unwrap();
+    // Pinned to the agent's own dependency versions, with no registry
+    // lookups
+    std::fs::copy(
+        Path::new(env!("CARGO_MANIFEST_DIR")).join("Cargo.lock"),
+        dir.join("Cargo.lock"),
+    )
+    .unwrap();
+
+    let cargo = std::env::var("CARGO").unwrap_or_else(|_| "cargo".to_string());
+    let status = Command::new(cargo)
+        .args(["build", "--release", "--quiet", "--manifest-path"])
+        .arg(dir.join("Cargo.toml"))
+        .arg("--target-dir")
+        .arg(fixture_dir("target"))
+        .env_remove("RUSTFLAGS")
+        .status()
+        .unwrap();
+    assert!(status.success());
+    std::fs::read(fixture_binary(name)).unwrap()
+}
+
+fn contains(haystack: &[u8], needle: &[u8]) -> bool {
+    haystack.windows(needle.len()).any(|w| w == needle)
+}
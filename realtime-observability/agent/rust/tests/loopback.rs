@@ -0,0 +1,501 @@
+#![cfg(not(feature = "noop"))]
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use telemetry_agent::telemetry::{AGENT_VERSION, SCHEMA_VERSION};
+use telemetry_agent::{
+    run_scoped, shutdown_guard, Agent, BoxFuture, Config, LocalAggregator, PoolConfig,
+    PushErrorKind, SeriesValue, Spawner, TelemetryIngestorServer, TokioSpawner, TransportPool,
+};
+use tokio_stream::wrappers::TcpListenerStream;
+
+async fn serve(aggregator: &LocalAggregator) -> std::net::SocketAddr {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(
+        tonic::transport::Server::builder()
+            .add_service(TelemetryIngestorServer::new(aggregator.clone()))
+            .serve_with_incoming(TcpListenerStream::new(listener)),
+    );
+    addr
+}
+
+#[tokio::test]
+async fn test_agent_pushes_to_local_aggregator() {
+    let aggregator = LocalAggregator::new();
+    let addr = serve(&aggregator).await;
+
+    let mut agent = Agent::new(Config {
+        aggregator_addr: format!("http://{}", addr),
+        push_interval: Duration::from_millis(10),
+        ..Default::default()
+    });
+    agent.start().await.unwrap();
+
+    agent.set_gauge("queue_depth", 7.0);
+    agent.add_counter("jobs_total", 3);
+    agent.inc_counter_with("hits", &[("route", "/a")]);
+    for value in [2.0, 3.0, 40.0] {
+        agent.record_histogram("render_ms", value);
+    }
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    agent.stop().await.unwrap();
+
+    assert_eq!(
+        aggregator.query("queue_depth").series[0].value,
+        SeriesValue::Gauge(7.0)
+    );
+    assert_eq!(
+        aggregator.query("jobs_total").series[0].value,
+        SeriesValue::Counter(3)
+    );
+    assert_eq!(aggregator.query("hits").series[0].labels["route"], "/a");
+
+    match &aggregator.query("render_ms").series[0].value {
+        SeriesValue::Histogram { bounds, counts } => {
+            assert_eq!(counts.len(), bounds.len() + 1);
+            assert_eq!(counts.iter().sum::<u64>(), 3);
+        }
+        other => panic!("render_ms is {:?}", other),
+    }
+}
+
+#[tokio::test]
+async fn test_restarted_agent_resumes_where_it_stopped() {
+    let aggregator = LocalAggregator::new();
+    let addr = serve(&aggregator).await;
+    let config = Config {
+        aggregator_addr: format!("http://{}", addr),
+        push_interval: Duration::from_millis(10),
+        ..Default::default()
+    };
+    let jobs = || match aggregator.query("jobs_total").series[..] {
+        [ref series] => series.value.clone(),
+        ref other => panic!("jobs_total is {:?}", other),
+    };
+    let render_total = || match &aggregator.query("render_ms").series[0].value {
+        SeriesValue::Histogram { counts, .. } => counts.iter().sum::<u64>(),
+        other => panic!("render_ms is {:?}", other),
+    };
+
+    let mut agent = Agent::new(config.clone());
+    agent.start().await.unwrap();
+    agent.add_counter("jobs_total", 3);
+    agent.record_histogram("render_ms", 2.0);
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    agent.stop().await.unwrap();
+    assert_eq!(jobs(), SeriesValue::Counter(3));
+    assert_eq!(render_total(), 1);
+    // Recorded after the final push, so only the state carries it
+    agent.record_histogram("render_ms", 40.0);
+    let instance_id = agent.instance_id().to_string();
+
+    let mut agent = Agent::with_state(config, agent.into_state());
+    assert_eq!(agent.instance_id(), instance_id);
+    agent.start().await.unwrap();
+    agent.inc_counter("jobs_total");
+    let deadline = std::time::Instant::now() + Duration::from_secs(5);
+    let mut seen = Vec::new();
+    while jobs() != SeriesValue::Counter(4) && std::time::Instant::now() < deadline {
+        seen.push(jobs());
+        tokio::time::sleep(Duration::from_millis(5)).await;
+    }
+    agent.stop().await.unwrap();
+
+    // The total seen by the aggregator never went back
+    assert!(seen
+        .iter()
+        .all(|value| matches!(value, SeriesValue::Counter(n) if *n >= 3)));
+    assert_eq!(jobs(), SeriesValue::Counter(4));
+    assert_eq!(render_total(), 2);
+}
+
+#[tokio::test]
+async fn test_checksummed_batches_are_accepted() {
+    let aggregator = LocalAggregator::new();
+    let addr = serve(&aggregator).await;
+
+    let mut agent = Agent::new(Config {
+        aggregator_addr: format!("http://{}", addr),
+        push_interval: Duration::from_millis(10),
+        checksum_batches: true,
+        ..Default::default()
+    });
+    agent.start().await.unwrap();
+    agent.add_counter("jobs_total", 3);
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    agent.stop().await.unwrap();
+
+    assert_eq!(
+        aggregator.query("jobs_total").series[0].value,
+        SeriesValue::Counter(3)
+    );
+    assert!(agent.diagnostics().last_push_error.is_none());
+}
+
+#[tokio::test]
+async fn test_pooled_agents_share_transport() {
+    let aggregator = LocalAggregator::new();
+    let addr = serve(&aggregator).await;
+
+    let pool = TransportPool::with_config(
+        &format!("http://{}", addr),
+        PoolConfig {
+            push_interval: Duration::from_millis(10),
+            ..Default::default()
+        },
+    )
+    .unwrap();
+    let tenant = |service: &str| {
+        Agent::new_with_transport(
+            Config {
+                service_name: service.to_string(),
+                ..Default::default()
+            },
+            pool.clone(),
+        )
+    };
+    let a = tenant("tenant-a");
+    let b = tenant("tenant-b");
+
+    a.set_gauge("a_up", 1.0);
+    b.set_gauge("b_up", 2.0);
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    let diagnostics = pool.diagnostics();
+    let services: Vec<&str> = diagnostics
+        .agents
+        .iter()
+        .map(|agent| agent.service.as_str())
+        .collect();
+    assert_eq!(services, vec!["tenant-a", "tenant-b"]);
+    assert!(diagnostics
+        .agents
+        .iter()
+        .all(|agent| agent.batches_sent > 0));
+    assert!(diagnostics.bytes_sent > 0);
+    pool.shutdown().await;
+
+    assert_eq!(
+        aggregator.query("a_up").series[0].value,
+        SeriesValue::Gauge(1.0)
+    );
+    assert_eq!(
+        aggregator.query("b_up").series[0].value,
+        SeriesValue::Gauge(2.0)
+    );
+
+    drop(a);
+    assert_eq!(pool.diagnostics().agents.len(), 1);
+}
+
+/// Host executor stand-in that counts what it is asked to run
+struct CountingSpawner {
+    inner: TokioSpawner,
+    spawned: Arc<AtomicUsize>,
+}
+
+impl Spawner for CountingSpawner {
+    fn spawn(&self, future: BoxFuture) {
+        self.spawned.fetch_add(1, Ordering::Relaxed);
+        self.inner.spawn(future);
+    }
+
+    fn sleep(&self, duration: Duration) -> BoxFuture {
+        self.inner.sleep(duration)
+    }
+}
+
+#[tokio::test]
+async fn test_custom_spawner_with_transport_runtime() {
+    let aggregator = LocalAggregator::new();
+    let addr = serve(&aggregator).await;
+    let transport = tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(1)
+        .enable_all()
+        .build()
+        .unwrap();
+
+    let spawned = Arc::new(AtomicUsize::new(0));
+    let mut agent = Agent::new(Config {
+        aggregator_addr: format!("http://{}", addr),
+        push_interval: Duration::from_millis(10),
+        tokio_handle: Some(transport.handle().clone()),
+        ..Default::default()
+    });
+    agent
+        .start_on(CountingSpawner {
+            inner: TokioSpawner::current(),
+            spawned: spawned.clone(),
+        })
+        .await
+        .unwrap();
+
+    agent.set_gauge("queue_depth", 3.0);
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    agent.stop().await.unwrap();
+    transport.shutdown_background();
+
+    // The push loop
+    assert_eq!(spawned.load(Ordering::Relaxed), 1);
+    assert_eq!(
+        aggregator.query("queue_depth").series[0].value,
+        SeriesValue::Gauge(3.0)
+    );
+}
+
+#[tokio::test]
+async fn test_run_scoped_flushes_on_exit() {
+    let aggregator = LocalAggregator::new();
+    let addr = serve(&aggregator).await;
+
+    let report = run_scoped(
+        Config {
+            aggregator_addr: format!("http://{}", addr),
+            ..Default::default()
+        },
+        |agent| async move {
+            agent.inc_counter("rows_imported");
+            let partial = agent.flush().await.unwrap();
+            assert!(partial > 0);
+            agent.add_counter("rows_imported", 41);
+            agent.record_histogram("row_ms", 3.0);
+        },
+    )
+    .await
+    .unwrap();
+
+    assert!(report.delivered);
+    assert_eq!(report.error, None);
+    assert_eq!(
+        aggregator.query("rows_imported").series[0].value,
+        SeriesValue::Counter(42)
+    );
+    assert_eq!(aggregator.query("row_ms").series.len(), 1);
+    // Both batches carry `rows_imported` and `inflight`
+    assert!(report.metrics_sent >= 4);
+}
+
+#[tokio::test]
+async fn test_run_scoped_reports_undelivered() {
+    // Nothing listens on a port that was just released
+    let addr = {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        listener.local_addr().unwrap()
+    };
+
+    let started = std::time::Instant::now();
+    let report = run_scoped(
+        Config {
+            aggregator_addr: format!("http://{}", addr),
+            final_flush_timeout: Duration::from_millis(300),
+            ..Default::default()
+        },
+        |agent| async move { agent.inc_counter("rows_imported") },
+    )
+    .await
+    .unwrap();
+
+    assert!(!report.delivered);
+    assert_eq!(report.metrics_sent, 0);
+    assert!(report.error.unwrap().kind.is_retryable());
+    assert!(started.elapsed() < Duration::from_secs(2));
+
+    let err = run_scoped(
+        Config {
+            aggregator_addr: "not a uri".to_string(),
+            ..Default::default()
+        },
+        |_| async {},
+    )
+    .await
+    .unwrap_err();
+    assert_eq!(err.kind, PushErrorKind::Other);
+}
+
+#[cfg(feature = "serde")]
+#[tokio::test]
+async fn test_manifest_metrics_are_in_the_first_batch() {
+    let aggregator = LocalAggregator::new();
+    let addr = serve(&aggregator).await;
+    let manifest: telemetry_agent::MetricManifest =
+        serde_json::from_str(include_str!("manifest.json")).unwrap();
+
+    let aggregator = &aggregator;
+    run_scoped(
+        Config {
+            aggregator_addr: format!("http://{}", addr),
+            push_interval: Duration::from_secs(60),
+            ..Default::default()
+        },
+        |agent| async move {
+            agent.register_manifest(manifest).unwrap();
+            agent.flush().await.unwrap();
+
+            let value = |name: &str| aggregator.query(name).series[0].value.clone();
+            assert_eq!(value("orders_total"), SeriesValue::Counter(0));
+            assert_eq!(value("payment_failures_total"), SeriesValue::Counter(0));
+            assert_eq!(value("queue_depth"), SeriesValue::Gauge(0.0));
+            assert_eq!(value("workers"), SeriesValue::Gauge(8.0));
+            for (name, declared) in [("payment_ms", 3), ("upload_size", 6)] {
+                match value(name) {
+                    SeriesValue::Histogram { bounds, counts } => {
+                        assert_eq!(bounds.len(), declared);
+                        assert_eq!(counts.iter().sum::<u64>(), 0);
+                    }
+                    other => panic!("{} is {:?}", name, other),
+                }
+            }
+        },
+    )
+    .await
+    .unwrap();
+}
+
+#[test]
+fn test_shutdown_guard_flushes_after_the_runtime_is_gone() {
+    // The aggregator outlives the agent's runtime
+    let server = tokio::runtime::Runtime::new().unwrap();
+    let aggregator = LocalAggregator::new();
+    let addr = server.block_on(serve(&aggregator));
+
+    let host = tokio::runtime::Runtime::new().unwrap();
+    let agent = host.block_on(async {
+        let mut agent = Agent::new(Config {
+            aggregator_addr: format!("http://{}", addr),
+            push_interval: Duration::from_secs(60),
+            ..Default::default()
+        });
+        agent.start().await.unwrap();
+        Arc::new(agent)
+    });
+    let guard = shutdown_guard(agent.clone());
+    agent.add_counter("jobs_total", 5);
+    // As at the end of `#[tokio::main]`: the push loop is cancelled
+    drop(host);
+    drop(guard);
+
+    assert_eq!(
+        aggregator.query("jobs_total").series[0].value,
+        SeriesValue::Counter(5)
+    );
+}
+
+#[test]
+fn test_flush_blocking_without_a_runtime() {
+    let server = tokio::runtime::Runtime::new().unwrap();
+    let aggregator = LocalAggregator::new();
+    let addr = server.block_on(serve(&aggregator));
+
+    // Never started: no runtime of its own, no push loop
+    let agent = Agent::new(Config {
+        aggregator_addr: format!("http://{}", addr),
+        ..Default::default()
+    });
+    agent.set_gauge("queue_depth", 4.0);
+    assert!(agent.flush_blocking(Duration::from_secs(2)).unwrap() > 0);
+    assert_eq!(
+        aggregator.query("queue_depth").series[0].value,
+        SeriesValue::Gauge(4.0)
+    );
+
+    // Nothing listens on a port that was just released
+    let addr = std::net::TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap();
+    let agent = Agent::new(Config {
+        aggregator_addr: format!("http://{}", addr),
+        ..Default::default()
+    });
+    let started = std::time::Instant::now();
+    let err = agent
+        .flush_blocking(Duration::from_millis(300))
+        .unwrap_err();
+    assert!(err.kind.is_retryable());
+    assert!(started.elapsed() < Duration::from_secs(2));
+}
+
+/// Whether a counter added while every worker of the agent's runtime is
+/// blocked reaches the aggregator before they are free again
+fn delivered_while_blocked(dedicated_thread: bool) -> bool {
+    let server = tokio::runtime::Runtime::new().unwrap();
+    let aggregator = LocalAggregator::new();
+    let addr = server.block_on(serve(&aggregator));
+
+    let host = tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(2)
+        .enable_all()
+        .build()
+        .unwrap();
+    let mut agent = host.block_on(async {
+        let mut agent = Agent::new(Config {
+            aggregator_addr: format!("http://{}", addr),
+            push_interval: Duration::from_millis(20),
+            dedicated_thread,
+            ..Default::default()
+        });
+        agent.start().await.unwrap();
+        agent
+    });
+    // As a CPU-bound handler or a blocking call on the executor would
+    let blockers: Vec<_> = (0..2)
+        .map(|_| host.spawn(async { std::thread::sleep(Duration::from_millis(800)) }))
+        .collect();
+    std::thread::sleep(Duration::from_millis(50));
+    agent.add_counter("jobs_total", 1);
+    std::thread::sleep(Duration::from_millis(300));
+    let delivered = !aggregator.query("jobs_total").series.is_empty();
+
+    host.block_on(async {
+        for blocker in blockers {
+            blocker.await.unwrap();
+        }
+        let report = agent.stop().await.unwrap();
+        assert!(report.final_flush_delivered);
+    });
+    delivered
+}
+
+#[test]
+fn test_dedicated_thread_pushes_while_the_runtime_is_blocked() {
+    assert!(delivered_while_blocked(true));
+    assert!(!delivered_while_blocked(false));
+}
+
+/// FNV-1a over the proto's declarations, ignoring comments and whitespace
+fn proto_fingerprint() -> u64 {
+    let path = concat!(env!("CARGO_MANIFEST_DIR"), "/../../proto/telemetry.proto");
+    let proto = std::fs::read_to_string(path).unwrap();
+    proto
+        .lines()
+        .map(|line| line.split("//").next().unwrap())
+        .flat_map(str::split_whitespace)
+        .flat_map(str::bytes)
+        .fold(0xcbf2_9ce4_8422_2325, |hash, b| {
+            (hash ^ b as u64).wrapping_mul(0x0100_0000_01b3)
+        })
+}
+
+#[test]
+fn test_batches_carry_versions() {
+    // A failure here means telemetry.proto changed: bump SCHEMA_VERSION if
+    // the change alters what batches mean, then update the fingerprint
+    assert_eq!(
+        (SCHEMA_VERSION, proto_fingerprint()),
+        (2, 0x3cd1_71b3_2c6f_5d79)
+    );
+
+    let agent = Agent::new(Config {
+        service_version: "2.3.1".to_string(),
+        ..Default::default()
+    });
+    agent.inc_counter("jobs_total");
+    let batch = agent.collect_now();
+    assert_eq!(batch.agent_version, AGENT_VERSION);
+    assert_eq!(batch.agent_version, env!("CARGO_PKG_VERSION"));
+    assert_eq!(batch.service_version, "2.3.1");
+    assert_eq!(batch.schema_version, SCHEMA_VERSION);
+}
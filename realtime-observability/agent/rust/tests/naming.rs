@@ -0,0 +1,6 @@
+// A `name!` literal that breaks the naming rules fails to compile, with
+// the rule it breaks
+#[test]
+fn test_invalid_name_literals_fail_to_compile() {
+    trybuild::TestCases::new().compile_fail("tests/ui/*.rs");
+}
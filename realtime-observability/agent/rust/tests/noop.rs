@@ -0,0 +1,148 @@
+#![cfg(feature = "noop")]
+
+use std::time::Duration;
+
+use telemetry_agent::proto::{compat, pretty, TelemetryBatch};
+use telemetry_agent::{
+    shutdown_guard, Agent, BucketSpec, BulkCollect, Config, GaugeAggregation, MetricManifest,
+    Outcome, ResetPolicy, Severity, SloSpec,
+};
+
+#[test]
+fn test_agent_is_zero_sized() {
+    assert_eq!(std::mem::size_of::<Agent>(), 0);
+}
+
+#[test]
+// Guards have no `Drop` here; the drops mirror real call sites
+#[allow(clippy::drop_non_drop)]
+fn test_api_compiles_and_records_nothing() {
+    let agent = Agent::new(Config::default());
+
+    agent.set_gauge("queue_depth", 3.0);
+    assert!(agent.try_set_gauge("queue_depth", 3.0).is_ok());
+    assert_eq!(agent.metric_type("queue_depth"), None);
+    agent.register_gauge("cpu", GaugeAggregation::Max);
+    agent.set_gauge_with("cpu", &[("core", "0")], 0.5);
+    assert_eq!(agent.add_gauge("active_workers", 1.0), 0.0);
+    assert_eq!(agent.try_sub_gauge("active_workers", 1.0), Ok(0.0));
+    agent.inc_counter("requests_total");
+    agent.inc_counter(telemetry_agent::name!("requests_total"));
+    assert!(agent.try_inc_counter("queue_depth").is_ok());
+    assert!(agent.try_record_histogram("queue_depth", 1.0).is_ok());
+    agent.add_counter("bytes_total", 512);
+    agent.inc_counter_with("requests_total", &[("route", "/a")]);
+    agent.sharded_counter("hot").inc();
+    let tap = agent.tap_histogram("latency_ms", 1024);
+    agent.record_histogram("latency_ms", 4.0);
+    assert!(tap.drain().is_empty());
+    assert_eq!(tap.dropped(), 0);
+    let shards = agent.bulk_counter("items_processed", "shard", 1024, BulkCollect::Changed);
+    shards.inc(7);
+    assert_eq!((shards.get(7), shards.len()), (0, 1024));
+    agent.set_metric_enabled("hot", false);
+    agent.set_prefix_enabled("debug_", false);
+    agent.assign_group("latency_giant", "giant");
+    agent
+        .counter_family("http_requests", &["method"])
+        .unwrap()
+        .with(&["GET"])
+        .inc();
+    agent.register_histogram("latency", ResetPolicy::Never);
+    agent.record_histogram("latency", 12.0);
+    agent.record_histogram_with("latency", &[("route", "/a")], 12.0);
+    agent
+        .histogram_ms("db")
+        .unwrap()
+        .record(Duration::from_millis(4));
+    agent.histogram_bytes("payload").unwrap().record(1024usize);
+    let mut local = agent.local().flush_every(64);
+    local.record_histogram("parse", 0.2);
+    local.inc_counter("parsed_total");
+    local.flush();
+    agent.record_error("timeout");
+    agent.record_error_detailed("timeout", "upstream took 30s");
+    agent.emit_event("deploy", Severity::Info, &[("version", "1.4.2")]);
+    let slo = agent.slo("checkout", SloSpec::default());
+    slo.record_latency(300.0);
+    slo.record_failure();
+    assert_eq!(slo.current_error_budget_remaining(), 1.0);
+    let cache = agent.cache("session_cache");
+    cache.hit();
+    cache.miss();
+    cache.eviction();
+    cache.size(2);
+    assert!(agent
+        .set_state("router_state", "ready", &["starting", "ready"])
+        .is_ok());
+    assert!(agent
+        .configure_wire_bounds(
+            "parse_ms",
+            BucketSpec::Explicit(vec![1.0, 2.0]),
+            BucketSpec::Explicit(vec![3.0]),
+        )
+        .is_ok());
+    assert!(agent
+        .register_manifest(MetricManifest::new().counter("orders_total", "Orders placed"))
+        .is_ok());
+
+    let mut guard = agent.track_request_named("checkout");
+    guard.mark("first_byte");
+    assert_eq!(guard.elapsed(), Duration::ZERO);
+    drop(guard.child());
+    assert_eq!(guard.children(), 0);
+    assert_eq!(agent.inflight(), 0);
+    assert!(agent.local_stats().latencies.is_empty());
+    guard.fail();
+    assert_eq!(guard.outcome(), Outcome::Error);
+    drop(guard);
+    drop(agent.start_timer("render"));
+
+    let mut span = agent.start_span("checkout");
+    span.set_attribute("route", "/pay");
+    span.fail();
+    assert!(!span.is_sampled());
+    assert_eq!(span.trace_id(), None);
+    assert_eq!(span.in_scope(|| span.child("load_cart").span_id()), None);
+    drop(span);
+
+    assert!(agent.collect_now().metrics.is_empty());
+    assert!(agent.last_errors().is_empty());
+    assert!(agent.error_types().is_empty());
+    assert_eq!(agent.diagnostics().batches_sent, 0);
+    assert!(agent.set_push_interval(Duration::from_millis(50)).is_ok());
+    agent.install_panic_hook();
+    assert!(agent
+        .burst_mode(Duration::from_secs(60), Duration::from_millis(50))
+        .is_ok());
+    assert_eq!(agent.estimated_clock_skew(), None);
+    assert_eq!(agent.queue_pressure(), 0.0);
+    assert_eq!(agent.memory_usage().total(), 0);
+    assert!(TelemetryBatch::from_bytes(&agent.collect_now().to_bytes()).is_err());
+    let sealed = TelemetryBatch {
+        checksum: Some(agent.collect_now().compute_checksum()),
+        ..agent.collect_now()
+    };
+    assert_eq!(sealed.verify_checksum(), None);
+    let mut delta = TelemetryBatch {
+        base_timestamp_ns: 1,
+        ..agent.collect_now()
+    };
+    delta.resolve_timestamps();
+    assert_eq!(delta.base_timestamp_ns, 1);
+    assert!(pretty::render(&agent.collect_now()).is_empty());
+    assert!(compat::decode_any_version(&agent.collect_now().to_bytes()).is_err());
+    assert_eq!(
+        compat::SeriesTable::new().resolve(&mut agent.collect_now()),
+        0
+    );
+    assert!(agent.collect_now().to_string().is_empty());
+
+    assert_eq!(agent.flush_blocking(Duration::from_secs(1)).unwrap(), 0);
+
+    let state = agent.into_state();
+    drop(shutdown_guard(std::sync::Arc::new(Agent::new(
+        Config::default(),
+    ))));
+    let _restored = Agent::with_state(Config::default(), state);
+}
@@ -0,0 +1,132 @@
+//! Rough wall-clock ceilings on the recording API, so a gross regression
+//! fails `cargo test` without anyone running `benches/overhead.rs`. The
+//! ceilings are far above the bench numbers quoted in the rustdoc: they
+//! catch a lock or allocation storm, not a few percent.
+#![cfg(not(feature = "noop"))]
+
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+
+use telemetry_agent::{Agent, Config};
+
+/// Unoptimized builds run the same code many times slower
+const SLACK: u32 = if cfg!(debug_assertions) { 30 } else { 1 };
+
+/// Best average time of `f` over a few rounds, which shrugs off a round
+/// that lost the CPU to another test
+fn per_call(iterations: u32, mut f: impl FnMut(u32)) -> Duration {
+    (0..5)
+        .map(|_| {
+            let start = Instant::now();
+            for i in 0..iterations {
+                f(i);
+            }
+            start.elapsed() / iterations
+        })
+        .min()
+        .unwrap()
+}
+
+fn assert_under(what: &str, took: Duration, ceiling: Duration) {
+    let ceiling = ceiling * SLACK;
+    assert!(
+        took < ceiling,
+        "{} took {:?} per call, over its ceiling of {:?}",
+        what,
+        took,
+        ceiling
+    );
+}
+
+#[test]
+fn test_hot_recording_is_under_a_microsecond() {
+    let agent = Agent::new(Config::default());
+    agent.inc_counter("requests_total");
+    let micro = Duration::from_micros(1);
+    assert_under(
+        "inc_counter",
+        per_call(10_000, |_| agent.inc_counter(black_box("requests_total"))),
+        micro,
+    );
+    assert_under(
+        "set_gauge",
+        per_call(10_000, |i| {
+            agent.set_gauge(black_box("queue_depth"), i as f64)
+        }),
+        micro,
+    );
+    assert_under(
+        "record_histogram",
+        per_call(10_000, |i| {
+            agent.record_histogram(black_box("parse_ms"), i as f64)
+        }),
+        micro,
+    );
+}
+
+#[test]
+fn test_tapped_histograms_are_under_a_microsecond() {
+    let agent = Agent::new(Config::default());
+    // Drained often enough never to drop, as a reader would
+    let tap = agent.tap_histogram("parse_ms", 100_000);
+    assert_under(
+        "record_histogram, tapped",
+        per_call(10_000, |i| {
+            agent.record_histogram(black_box("parse_ms"), i as f64)
+        }),
+        Duration::from_micros(1),
+    );
+    assert_eq!(tap.drain().len(), 50_000);
+    assert_eq!(tap.dropped(), 0);
+}
+
+#[test]
+fn test_new_series_is_under_ten_microseconds() {
+    let agent = Agent::new(Config::default());
+    let names: Vec<String> = (0..50_000).map(|i| format!("metric_{}", i)).collect();
+    let mut names = names.iter();
+    assert_under(
+        "inc_counter on a new series",
+        per_call(10_000, |_| agent.inc_counter(names.next().unwrap())),
+        Duration::from_micros(10),
+    );
+}
+
+#[test]
+fn test_request_guard_is_under_five_microseconds() {
+    let agent = Agent::new(Config::default());
+    drop(agent.track_request_named("checkout"));
+    assert_under(
+        "track_request_named guard create and drop",
+        per_call(10_000, |_| {
+            drop(black_box(agent.track_request_named("checkout")))
+        }),
+        Duration::from_micros(5),
+    );
+}
+
+#[test]
+fn test_collecting_ten_thousand_metrics_is_under_100ms() {
+    let agent = Agent::new(Config::default());
+    let names: Vec<String> = (0..10_000).map(|i| format!("metric_{}", i)).collect();
+    let took = (0..5)
+        .map(|_| {
+            for (i, name) in names.iter().enumerate() {
+                match i % 3 {
+                    0 => agent.inc_counter(name),
+                    1 => agent.set_gauge(name, i as f64),
+                    _ => agent.record_histogram(name, i as f64),
+                }
+            }
+            let start = Instant::now();
+            black_box(agent.collect_now());
+            start.elapsed()
+        })
+        .min()
+        .unwrap();
+    assert_under(
+        "collect_now of 10k metrics",
+        took,
+        Duration::from_millis(100),
+    );
+}
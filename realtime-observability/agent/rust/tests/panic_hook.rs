@@ -0,0 +1,58 @@
+#![cfg(not(feature = "noop"))]
+
+use std::panic;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+
+use telemetry_agent::proto::metric_sample::Value;
+use telemetry_agent::{Agent, Config};
+
+// The hook is process-wide, so this is the only test in this binary
+#[test]
+fn test_panics_are_counted_and_the_previous_hook_still_runs() {
+    let previous_ran = Arc::new(AtomicUsize::new(0));
+    let ran = previous_ran.clone();
+    panic::set_hook(Box::new(move |_| {
+        ran.fetch_add(1, Ordering::Relaxed);
+    }));
+
+    let agent = Agent::new(Config {
+        error_message_limit: 32,
+        ..Default::default()
+    });
+    agent.install_panic_hook();
+    // A second install must not count each panic twice
+    agent.install_panic_hook();
+
+    assert!(thread::spawn(|| panic!("unnamed")).join().is_err());
+    let errors = agent.last_errors();
+    let panic = errors.iter().find(|e| e.error_type == "panic").unwrap();
+    assert!(panic.message.starts_with("unnamed at tests/panic_hook.rs:"));
+
+    let worker = thread::Builder::new()
+        .name("worker".to_string())
+        .spawn(|| panic!("cache poisoned: {}", "x".repeat(1000)))
+        .unwrap();
+    assert!(worker.join().is_err());
+    let errors = agent.last_errors();
+    let panic = errors.iter().find(|e| e.error_type == "panic").unwrap();
+    assert!(panic.message.starts_with("cache poisoned: xx"));
+    assert!(panic.message.len() <= 32);
+
+    assert_eq!(previous_ran.load(Ordering::Relaxed), 2);
+    let batch = agent.collect_now();
+    let panics: Vec<_> = batch
+        .metrics
+        .iter()
+        .filter(|m| m.name == "panics_total")
+        .map(|m| (m.labels.get("thread").cloned(), m.samples[0].value.clone()))
+        .collect();
+    assert_eq!(
+        panics,
+        [
+            (None, Some(Value::Counter(1))),
+            (Some("worker".to_string()), Some(Value::Counter(1))),
+        ]
+    );
+}
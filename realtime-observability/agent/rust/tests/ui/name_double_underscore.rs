@@ -0,0 +1,3 @@
+fn main() {
+    let _ = telemetry_agent::name!("request__count");
+}
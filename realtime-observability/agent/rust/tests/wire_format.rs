@@ -0,0 +1,271 @@
+//! The agent→aggregator encoding is an API: these tests pin it.
+//!
+//! `full_batch` sets every field of every message, with every sample
+//! variant, and must encode byte for byte as the newest `full-*` fixture;
+//! a renumbered or retyped field fails here even if the proto fingerprint
+//! in `loopback.rs` was updated along with it. Every fixture in
+//! `fixtures/wire` must keep decoding, with `from_bytes` and with
+//! `compat::decode_any_version`.
+//!
+//! Fixtures are never rewritten. A deliberate change to the encoding gets
+//! a new fixture next to the old ones, written by running this test with
+//! `WRITE_WIRE_FIXTURES=1`, and `WIRE_VERSION` or `SCHEMA_VERSION` bumped
+//! as the change calls for.
+#![cfg(not(feature = "noop"))]
+
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use telemetry_agent::telemetry::compat::{decode_any_version, SeriesTable};
+use telemetry_agent::telemetry::metric_sample::Value;
+use telemetry_agent::telemetry::{
+    series_hash, Announce, Event, Exemplar, Histogram, Metric, MetricSample, SeriesDefinition,
+    Severity, Span, SpanStatus, TelemetryBatch,
+};
+use telemetry_agent::{DecodeError, WIRE_VERSION};
+
+fn fixtures() -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/wire")
+}
+
+/// Compare `bytes` with fixture `name`, or write it with
+/// `WRITE_WIRE_FIXTURES=1`
+fn assert_fixture(name: &str, bytes: &[u8]) {
+    let path = fixtures().join(name);
+    if std::env::var_os("WRITE_WIRE_FIXTURES").is_some() {
+        std::fs::create_dir_all(fixtures()).unwrap();
+        std::fs::write(&path, bytes).unwrap();
+        return;
+    }
+    let fixture = std::fs::read(&path).unwrap_or_else(|e| panic!("{}: {}", path.display(), e));
+    assert!(
+        fixture == bytes,
+        "encoding differs from {}: a field was renumbered or retyped\n\
+         fixture: {:02x?}\n\
+         encoded: {:02x?}",
+        name,
+        fixture,
+        bytes
+    );
+}
+
+fn labels(pairs: &[(&str, &str)]) -> BTreeMap<String, String> {
+    pairs
+        .iter()
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .collect()
+}
+
+fn sample(value: Value) -> MetricSample {
+    MetricSample {
+        timestamp_ns: 1_700_000_000_000_000_000,
+        value: Some(value),
+        window_start_ns: 0,
+    }
+}
+
+/// Every field set to something other than its default
+fn full_batch() -> TelemetryBatch {
+    let mut batch = TelemetryBatch {
+        service: "checkout".to_string(),
+        instance: "host-1".to_string(),
+        metrics: vec![
+            Metric {
+                name: "queue_depth".to_string(),
+                labels: labels(&[("queue", "orders")]),
+                samples: vec![sample(Value::Gauge(4.5))],
+                series_id: 0,
+                series_hash: 0,
+            },
+            Metric {
+                name: "requests_total".to_string(),
+                labels: labels(&[("route", "/pay"), ("status", "200")]),
+                samples: vec![sample(Value::Counter(1_234))],
+                series_id: 0,
+                series_hash: series_hash("requests_total", &[("route", "/pay"), ("status", "200")]),
+            },
+            Metric {
+                name: "latency_ms".to_string(),
+                labels: labels(&[("outcome", "success")]),
+                samples: vec![MetricSample {
+                    window_start_ns: 1_699_999_940_000_000_000,
+                    ..sample(Value::Histogram(Histogram {
+                        bounds: vec![1.0, 10.0, 100.0, f64::INFINITY],
+                        counts: vec![3, 7, 1, 0],
+                        exemplars: vec![Exemplar {
+                            bucket_index: 1,
+                            value: 7.25,
+                            trace_id: (1..=16).collect(),
+                            timestamp_ns: 1_699_999_990_000_000_000,
+                        }],
+                    }))
+                }],
+                series_id: 0,
+                series_hash: 0,
+            },
+            // Sent by id, named in series_definitions
+            Metric {
+                name: String::new(),
+                labels: BTreeMap::new(),
+                samples: vec![sample(Value::Gauge(0.25))],
+                series_id: 7,
+                series_hash: series_hash("cpu_usage", &[("core", "0")]),
+            },
+        ],
+        announce: Some(Announce {
+            metadata: labels(&[("region", "eu-west-1"), ("os", "linux")]),
+        }),
+        events: vec![Event {
+            timestamp_ns: 1_699_999_999_000_000_000,
+            name: "deploy".to_string(),
+            severity: Severity::Warn as i32,
+            attributes: labels(&[("version", "1.4.2")]),
+        }],
+        sent_at_ns: 1_700_000_000_500_000_000,
+        agent_version: "0.1.0".to_string(),
+        service_version: "1.4.2".to_string(),
+        schema_version: 2,
+        instance_epoch: 0x0123_4567_89ab_cdef,
+        connection_generation: 3,
+        draining: true,
+        checksum: None,
+        base_timestamp_ns: 1_000,
+        full_resync: true,
+        spans: vec![Span {
+            trace_id: (1..=16).collect(),
+            span_id: 0x0011_2233_4455_6677,
+            parent_span_id: 0x8899_aabb_ccdd_eeff,
+            name: "charge".to_string(),
+            start_ns: 1_699_999_999_500_000_000,
+            duration_ns: 12_500_000,
+            status: SpanStatus::Error as i32,
+            attributes: labels(&[("provider", "stripe")]),
+        }],
+        series_definitions: vec![SeriesDefinition {
+            id: 7,
+            name: "cpu_usage".to_string(),
+            labels: labels(&[("core", "0")]),
+        }],
+    };
+    batch.checksum = Some(batch.compute_checksum());
+    batch
+}
+
+/// A batch as agents sent them before batches carried versions, epochs
+/// or checksums: names, labels and values only
+fn minimal_batch() -> TelemetryBatch {
+    TelemetryBatch {
+        service: "checkout".to_string(),
+        instance: "host-1".to_string(),
+        metrics: vec![
+            Metric {
+                name: "jobs_total".to_string(),
+                labels: BTreeMap::new(),
+                samples: vec![sample(Value::Counter(3))],
+                series_id: 0,
+                series_hash: 0,
+            },
+            Metric {
+                name: "render_ms".to_string(),
+                labels: BTreeMap::new(),
+                samples: vec![sample(Value::Histogram(Histogram {
+                    bounds: vec![5.0, 50.0],
+                    counts: vec![1, 2, 0],
+                    exemplars: Vec::new(),
+                }))],
+                series_id: 0,
+                series_hash: 0,
+            },
+        ],
+        ..Default::default()
+    }
+}
+
+#[test]
+fn test_full_batch_encoding_is_pinned() {
+    assert_fixture("full-v1-series-hash.bin", &full_batch().to_bytes());
+}
+
+#[test]
+fn test_minimal_batch_encoding_is_pinned() {
+    assert_fixture("minimal-v1.bin", &minimal_batch().to_bytes());
+}
+
+#[test]
+fn test_every_fixture_still_decodes() {
+    let mut decoded = 0;
+    for entry in std::fs::read_dir(fixtures()).unwrap() {
+        let path = entry.unwrap().path();
+        let bytes = std::fs::read(&path).unwrap();
+        let name = path.display();
+        let batch = TelemetryBatch::from_bytes(&bytes)
+            .unwrap_or_else(|e| panic!("{} no longer decodes: {}", name, e));
+        let compat = decode_any_version(&bytes)
+            .unwrap_or_else(|e| panic!("{} no longer decodes: {}", name, e));
+        assert_eq!(compat.wire_version, Some(WIRE_VERSION), "{}", name);
+        assert_ne!(compat.checksum_valid, Some(false), "{}", name);
+        assert_eq!(compat.batch.service, batch.service, "{}", name);
+        assert_eq!(compat.batch.metrics.len(), batch.metrics.len(), "{}", name);
+        decoded += 1;
+    }
+    assert!(decoded >= 2);
+}
+
+#[test]
+fn test_decode_any_version_normalizes() {
+    let batch = full_batch();
+    let decoded = decode_any_version(&batch.to_bytes()).unwrap();
+    assert_eq!(decoded.schema_version, 2);
+    assert_eq!(decoded.checksum_valid, Some(true));
+    // Delta timestamps made absolute
+    assert_eq!(decoded.batch.base_timestamp_ns, 0);
+    assert_eq!(
+        decoded.batch.metrics[0].samples[0].timestamp_ns,
+        1_700_000_000_000_000_000 + 1_000
+    );
+    // No explicit +Inf bound
+    match &decoded.batch.metrics[2].samples[0].value {
+        Some(Value::Histogram(h)) => {
+            assert_eq!(h.bounds, [1.0, 10.0, 100.0]);
+            assert_eq!(h.counts.len(), h.bounds.len() + 1);
+        }
+        other => panic!("latency_ms is {:?}", other),
+    }
+    // Named from the definitions it carries
+    let mut named = decoded.batch.clone();
+    assert_eq!(SeriesTable::new().resolve(&mut named), 0);
+    assert_eq!(named.metrics[3].name, "cpu_usage");
+    assert_eq!(named.metrics[3].labels, labels(&[("core", "0")]));
+    assert!(named.series_definitions.is_empty());
+
+    // Batches from before versions were sent: schema 0, no checksum
+    let decoded = decode_any_version(&minimal_batch().to_bytes()).unwrap();
+    assert_eq!(decoded.schema_version, 0);
+    assert_eq!(decoded.checksum_valid, None);
+    assert_eq!(decoded.batch, minimal_batch());
+}
+
+#[test]
+fn test_decode_any_version_tolerates_unknown_fields_and_bare_messages() {
+    let mut bytes = minimal_batch().to_bytes();
+    // Field 99 as a varint and field 100 as bytes, from some later version
+    bytes.extend([0x98, 0x06, 0x2a]);
+    bytes.extend([0xa2, 0x06, 0x03, b'n', b'e', b'w']);
+    let decoded = decode_any_version(&bytes).unwrap();
+    assert_eq!(decoded.batch, minimal_batch());
+
+    // As carried by gRPC, with no version byte
+    let bare = decode_any_version(&bytes[1..]).unwrap();
+    assert_eq!(bare.wire_version, None);
+    assert_eq!(bare.batch, minimal_batch());
+
+    assert!(matches!(decode_any_version(&[]), Err(DecodeError::Empty)));
+    assert!(matches!(
+        decode_any_version(&[2, 0x0a, 0x00]),
+        Err(DecodeError::UnsupportedVersion(2))
+    ));
+    assert!(matches!(
+        decode_any_version(&[WIRE_VERSION, 0x0a, 0x05]),
+        Err(DecodeError::Protobuf(_))
+    ));
+}
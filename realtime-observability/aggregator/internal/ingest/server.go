@@ -1,8 +1,11 @@
 package ingest
 
 import (
+	"context"
 	"io"
 	"log"
+	"math"
+	"time"
 
 	"github.com/yourorg/aggregator/internal/buffer"
 	"github.com/yourorg/aggregator/internal/ws"
@@ -27,10 +30,15 @@ func NewServer(registry *buffer.Registry, hub *ws.Hub) *Server {
 
 // StreamTelemetry handles the client streaming RPC
 func (s *Server) StreamTelemetry(stream grpc.ClientStreamingServer[pb.TelemetryBatch, pb.Ack]) error {
+	// Echoed in the ack so agents can estimate their clock skew
+	var receivedAt uint64
 	for {
 		batch, err := stream.Recv()
+		if receivedAt == 0 {
+			receivedAt = uint64(time.Now().UnixNano())
+		}
 		if err == io.EOF {
-			return stream.SendAndClose(&pb.Ack{Ok: true})
+			return stream.SendAndClose(&pb.Ack{Ok: true, ReceivedAtNs: receivedAt})
 		}
 		if err != nil {
 			log.Printf("Error receiving batch: %v", err)
@@ -39,6 +47,9 @@ func (s *Server) StreamTelemetry(stream grpc.ClientStreamingServer[pb.TelemetryB
 
 		log.Printf("Received batch from service=%s instance=%s metrics=%d",
 			batch.Service, batch.Instance, len(batch.Metrics))
+		if batch.Draining {
+			log.Printf("Instance draining: service=%s instance=%s", batch.Service, batch.Instance)
+		}
 
 		// Process each metric in the batch
 		for _, metric := range batch.Metrics {
@@ -50,6 +61,13 @@ func (s *Server) StreamTelemetry(stream grpc.ClientStreamingServer[pb.TelemetryB
 	}
 }
 
+// GetCapabilities tells agents which optional batch contents to send.
+// Events and announces are accepted and ignored; exemplars are dropped
+// with the histogram.
+func (s *Server) GetCapabilities(ctx context.Context, req *pb.CapabilitiesRequest) (*pb.Capabilities, error) {
+	return &pb.Capabilities{Events: true, Exemplars: true, Announce: true}, nil
+}
+
 // processMetric routes metrics to appropriate ring buffers
 func (s *Server) processMetric(service, instance string, metric *pb.Metric) {
 	for _, sample := range metric.Samples {
@@ -71,10 +89,16 @@ func (s *Server) processMetric(service, instance string, metric *pb.Metric) {
 			})
 
 		case *pb.MetricSample_Histogram:
+			// Agents may send +Inf as the last bound; the buffer keeps the
+			// implicit form with one more count than bounds
+			bounds := v.Histogram.Bounds
+			if n := len(bounds); n > 0 && n == len(v.Histogram.Counts) && math.IsInf(bounds[n-1], 1) {
+				bounds = bounds[:n-1]
+			}
 			ring := s.registry.GetHistogramRing(service, metric.Name)
 			ring.Push(buffer.HistogramData{
 				Ts:     ts,
-				Bounds: v.Histogram.Bounds,
+				Bounds: bounds,
 				Counts: v.Histogram.Counts,
 			})
 		}